@@ -0,0 +1,355 @@
+use edn_derive::Serialize;
+use edn_rs::{Edn, EdnError};
+use futures::future::{FutureExt, LocalBoxFuture};
+use transistor::edn_rs;
+use transistor::types::http::{Action, Order};
+use transistor::types::{query::Query, CruxId};
+
+use crate::config::{Config, DEFAULT_ACCOUNT_TYPE};
+use crate::crux::{CruxClient, TX_LOG_PAGE_SIZE};
+use crate::ledger::{self, SystemAccount};
+use crate::localstore::{self, Op, Transaction};
+use crate::{DbAccount, DbAccountOperation, DbError};
+
+/// Documents written per transaction by migrations.
+const BATCH_SIZE: usize = 500;
+
+/// The updated documents of a migration, once listed.
+type Documents<'a> = LocalBoxFuture<'a, Result<Vec<Action>, DbError>>;
+
+/// A rewrite of the documents written before some change, listing the
+/// updated documents.
+struct Migration {
+    name: &'static str,
+    documents: fn(&CruxClient) -> Documents<'_>,
+}
+
+/// Migrations in the order they were introduced. A store that had the first
+/// `n` is at schema version `n`.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "account-types",
+        documents: |client| account_types(client).boxed_local(),
+    },
+    Migration {
+        name: "operation-postings",
+        documents: |client| operation_postings(client).boxed_local(),
+    },
+    Migration {
+        name: "system-account-documents",
+        documents: |client| system_account_documents(client).boxed_local(),
+    },
+    Migration {
+        name: "signed-account-amounts",
+        documents: |client| signed_account_amounts(client).boxed_local(),
+    },
+];
+
+/// Id of the document holding the schema version, which every transaction
+/// written by migrations puts.
+pub fn schema_id() -> CruxId {
+    CruxId::new("smaug-schema")
+}
+
+fn schema(version: usize) -> Action {
+    Action::Put(
+        format!(
+            "{{:crux.db/id {}, :schema/version {}}}",
+            edn_rs::to_string(schema_id()),
+            version
+        ),
+        None,
+    )
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MigrationRun {
+    name: String,
+    version: usize,
+    documents: usize,
+}
+
+pub async fn schema_version(client: &CruxClient) -> Result<usize, DbError> {
+    let schema = client.entity(edn_rs::to_string(schema_id())).await?;
+
+    Ok(schema[":schema/version"].to_uint().unwrap_or(0))
+}
+
+/// Runs the migrations the store didn't have yet, or only counts the
+/// documents they would update with `dry_run`.
+///
+/// The new schema version is written with the last batch of each migration,
+/// so one that was interrupted runs again, skipping the documents it
+/// updated.
+pub async fn migrate(client: &CruxClient, dry_run: bool) -> Result<Vec<MigrationRun>, DbError> {
+    let version = schema_version(client).await?;
+    let mut runs = Vec::new();
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let mut actions = (migration.documents)(client).await?;
+        runs.push(MigrationRun {
+            name: migration.name.to_string(),
+            version: index + 1,
+            documents: actions.len(),
+        });
+
+        if dry_run {
+            continue;
+        }
+
+        loop {
+            let rest = actions.split_off(actions.len().min(BATCH_SIZE));
+            let done = rest.is_empty();
+            actions.push(schema(if done { index + 1 } else { index }));
+            client.tx_log(actions).await?;
+
+            if done {
+                break;
+            }
+            actions = rest;
+        }
+    }
+
+    Ok(runs)
+}
+
+/// Puts `doc` as a correction of the current version of `id`, so histories
+/// don't get an entry for the migration.
+async fn correct(client: &CruxClient, id: &CruxId, doc: String) -> Result<Action, DbError> {
+    let valid_time = client
+        .entity_history(edn_rs::to_string(id.clone()), Order::Desc, false)
+        .await?
+        .history
+        .first()
+        .map(|version| version.db___valid_time);
+
+    Ok(Action::Put(doc, valid_time))
+}
+
+/// Customer accounts created before products get the default type written
+/// down.
+async fn account_types(client: &CruxClient) -> Result<Vec<Action>, DbError> {
+    let query = Query::find(vec!["?account"])?
+        .where_clause(vec!["?account :account/amount ?amount"])?
+        .build()?;
+
+    let system_ids = SystemAccount::all()
+        .into_iter()
+        .map(SystemAccount::id)
+        .collect::<Vec<CruxId>>();
+
+    let mut actions = Vec::new();
+
+    for row in client.query(query).await? {
+        let account_id = CruxId::new(&row[0]);
+        if system_ids.contains(&account_id) {
+            continue;
+        }
+
+        let mut account: DbAccount =
+            edn_rs::from_edn(&client.entity(edn_rs::to_string(account_id.clone())).await?)?;
+        if account.account___type.is_some() {
+            continue;
+        }
+
+        account.account___type = Some(DEFAULT_ACCOUNT_TYPE.to_string());
+        actions.push(correct(client, &account_id, edn_rs::to_string(account)).await?);
+    }
+
+    Ok(actions)
+}
+
+/// Operations written before the ledger get the postings they stood for.
+async fn operation_postings(client: &CruxClient) -> Result<Vec<Action>, DbError> {
+    let query = Query::find(vec!["?account-operation"])?
+        .where_clause(vec!["?account-operation :account-operation/type ?type"])?
+        .build()?;
+
+    let mut actions = Vec::new();
+
+    for row in client.query(query).await? {
+        let operation_id = CruxId::new(&row[0]);
+        let mut operation: DbAccountOperation = edn_rs::from_edn(
+            &client
+                .entity(edn_rs::to_string(operation_id.clone()))
+                .await?,
+        )?;
+        if operation.account_operation___postings.is_some() {
+            continue;
+        }
+
+        if let Some(entry) = ledger::legacy_entry(&operation) {
+            operation.account_operation___postings = Some(entry.into_postings());
+            actions.push(correct(client, &operation_id, edn_rs::to_string(operation)).await?);
+        }
+    }
+
+    Ok(actions)
+}
+
+/// System accounts used to have documents, read and written back by every
+/// operation posting to them. Their balances are summed from postings now,
+/// so the stale documents are deleted.
+async fn system_account_documents(client: &CruxClient) -> Result<Vec<Action>, DbError> {
+    let mut actions = Vec::new();
+
+    for system in SystemAccount::all() {
+        let id = edn_rs::to_string(system.id());
+        if client.entity(id.clone()).await? != Edn::Nil {
+            actions.push(Action::Delete(id, None));
+        }
+    }
+
+    Ok(actions)
+}
+
+/// Account amounts became signed when products allowed overdrafts. Amounts
+/// written before are read as they are, unless they're past `i64::MAX`,
+/// which no correction can guess: the migration fails naming those
+/// accounts, to be corrected by hand first.
+async fn signed_account_amounts(client: &CruxClient) -> Result<Vec<Action>, DbError> {
+    let query = Query::find(vec!["?account", "?amount"])?
+        .where_clause(vec!["?account :account/amount ?amount"])?
+        .build()?;
+
+    let too_large = client
+        .query(query)
+        .await?
+        .into_iter()
+        .filter(|row| row[1].parse::<i64>().is_err())
+        .map(|row| row[0].clone())
+        .collect::<Vec<String>>();
+
+    if !too_large.is_empty() {
+        return Err(DbError::EdnError(EdnError::Deserialize(format!(
+            "account amounts past {}: {}",
+            i64::MAX,
+            too_large.join(", ")
+        ))));
+    }
+
+    Ok(Vec::new())
+}
+
+/// Every transaction of the log, to be written to a file.
+pub async fn export(client: &CruxClient) -> Result<Vec<Transaction>, DbError> {
+    let mut transactions = Vec::new();
+
+    loop {
+        let after_tx_id = transactions
+            .last()
+            .map(|transaction: &Transaction| transaction.tx_id);
+        let page = localstore::read_log(&client.tx_log_tail(after_tx_id, TX_LOG_PAGE_SIZE).await?)?;
+        let done = page.len() < TX_LOG_PAGE_SIZE;
+
+        transactions.extend(page);
+        if done {
+            return Ok(transactions);
+        }
+    }
+}
+
+/// Whether the store has no account yet.
+pub async fn is_empty(client: &CruxClient) -> Result<bool, DbError> {
+    let query = Query::find(vec!["?account"])?
+        .where_clause(vec!["?account :account/amount ?amount"])?
+        .build()?;
+
+    Ok(client.query(query).await?.is_empty())
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ImportRun {
+    transactions: usize,
+    documents: usize,
+}
+
+/// Writes exported transactions, one for one, keeping the valid time of
+/// every document.
+///
+/// Projection checkpoints are left out, since they point into the exported
+/// log: the projections rebuild from the imported one instead.
+pub async fn import(
+    client: &CruxClient,
+    transactions: Vec<Transaction>,
+) -> Result<ImportRun, DbError> {
+    let mut import_run = ImportRun::default();
+
+    for transaction in transactions {
+        let actions = transaction
+            .ops
+            .into_iter()
+            .filter(|op| !op.id().starts_with(":projection-"))
+            .map(Op::into_action)
+            .collect::<Vec<Action>>();
+
+        if actions.is_empty() {
+            continue;
+        }
+
+        import_run.transactions += 1;
+        import_run.documents += actions.len();
+        client.tx_log(actions).await?;
+    }
+
+    Ok(import_run)
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ConfigCheck {
+    storage: String,
+    products: Vec<String>,
+    problems: Vec<String>,
+}
+
+impl ConfigCheck {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Looks for settings that parse but can't work: limits of account types
+/// no product has, rate limits of paths no request has and a store that
+/// can't be read.
+pub fn check_config(config: &Config) -> ConfigCheck {
+    let mut products = config.products.keys().cloned().collect::<Vec<String>>();
+    products.sort();
+
+    let mut problems = Vec::new();
+
+    let mut limits = config.limits.keys().collect::<Vec<&String>>();
+    limits.sort();
+    for account_type in limits {
+        if !config.products.contains_key(account_type) {
+            problems.push(format!(
+                "limits of account type {}, which isn't a product",
+                account_type
+            ));
+        }
+    }
+
+    for rule in &config.rate_limits.routes {
+        if !rule.path.starts_with('/') {
+            problems.push(format!(
+                "rate limits of path {}, which doesn't start with /",
+                rule.path
+            ));
+        }
+    }
+
+    let storage = match &config.crux_store {
+        Some(path) => {
+            if let Err(error) = localstore::open(path) {
+                problems.push(format!("couldn't read the store {}: {}", path, error));
+            }
+            format!("in-process store {}", path)
+        }
+        None => format!("Crux at http://{}:{}", config.crux_host, config.crux_port),
+    };
+
+    ConfigCheck {
+        storage,
+        products,
+        problems,
+    }
+}
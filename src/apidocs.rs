@@ -0,0 +1,947 @@
+use std::collections::BTreeMap;
+
+use crate::reconcile::Mismatch;
+use crate::{
+    RequestAccount, ResponseAccount, ResponseAccountHistoryElement, ResponseAccountOperation,
+    ResponseAccountSummary, ResponseFeeQuote, ResponseLimits, ResponseWebhook,
+};
+
+/// Shape of a value in the API description.
+#[derive(Clone, Debug)]
+pub enum Schema {
+    String,
+    Integer,
+    /// One of the keywords.
+    Keyword(Vec<String>),
+    /// May be `nil` or left out.
+    Optional(Box<Schema>),
+    Array(Box<Schema>),
+    /// A map with a name, and the schema of each of its keys.
+    Object(&'static str, Vec<(String, Schema)>),
+}
+
+/// A type whose shape is published in the API description.
+pub trait Describe {
+    fn describe() -> Schema;
+}
+
+impl Describe for String {
+    fn describe() -> Schema {
+        Schema::String
+    }
+}
+
+impl Describe for usize {
+    fn describe() -> Schema {
+        Schema::Integer
+    }
+}
+
+impl Describe for i64 {
+    fn describe() -> Schema {
+        Schema::Integer
+    }
+}
+
+impl<T: Describe> Describe for Option<T> {
+    fn describe() -> Schema {
+        Schema::Optional(Box::new(T::describe()))
+    }
+}
+
+impl<T: Describe> Describe for Vec<T> {
+    fn describe() -> Schema {
+        Schema::Array(Box::new(T::describe()))
+    }
+}
+
+/// Describes a struct from its fields, or an enum from its variants, as
+/// `edn_derive` writes them. The build breaks when the list stops matching
+/// the type, so the description can't drift from it.
+macro_rules! documented {
+    ($name:ident { $($field:ident: $type:ty),* $(,)? }) => {
+        impl $crate::apidocs::Describe for $name {
+            fn describe() -> $crate::apidocs::Schema {
+                fn fields(value: $name) {
+                    let $name { $($field),* } = value;
+                    $(let _: $type = $field;)*
+                }
+                let _ = fields;
+
+                $crate::apidocs::Schema::Object(
+                    stringify!($name),
+                    vec![$((
+                        $crate::apidocs::field_keyword(stringify!($field)),
+                        <$type as $crate::apidocs::Describe>::describe(),
+                    )),*],
+                )
+            }
+        }
+    };
+    (enum $name:ident { $($variant:ident),* $(,)? }) => {
+        impl $crate::apidocs::Describe for $name {
+            fn describe() -> $crate::apidocs::Schema {
+                fn variants(value: $name) {
+                    match value {
+                        $($name::$variant => ()),*
+                    }
+                }
+                let _ = variants;
+
+                $crate::apidocs::Schema::Keyword(vec![
+                    $(transistor::edn_rs::to_string($name::$variant)),*
+                ])
+            }
+        }
+    };
+}
+
+/// Keyword `edn_derive` writes a field as.
+pub fn field_keyword(field: &str) -> String {
+    format!(
+        ":{}",
+        field
+            .replace("___", "/")
+            .replace("__", ".")
+            .replace('_', "-")
+    )
+}
+
+/// `ResponseAccount` as `response-account`.
+fn kebab(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .fold(String::new(), |mut kebab, (i, c)| {
+            if c.is_uppercase() && i > 0 {
+                kebab.push('-');
+            }
+            kebab.extend(c.to_lowercase());
+            kebab
+        })
+}
+
+struct Route {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    query: &'static [(&'static str, &'static str)],
+    request: Option<Schema>,
+    responses: Vec<(u16, &'static str, Option<Schema>)>,
+}
+
+/// Body of a deposit or withdrawal.
+fn amount_request() -> Schema {
+    Schema::Object(
+        "RequestAmount",
+        vec![(String::from(":amount"), Schema::Integer)],
+    )
+}
+
+fn limits_request() -> Schema {
+    Schema::Object(
+        "RequestLimits",
+        vec![
+            (
+                String::from(":max-per-operation"),
+                Schema::Optional(Box::new(Schema::Integer)),
+            ),
+            (
+                String::from(":max-daily-total"),
+                Schema::Optional(Box::new(Schema::Integer)),
+            ),
+            (
+                String::from(":max-daily-count"),
+                Schema::Optional(Box::new(Schema::Integer)),
+            ),
+        ],
+    )
+}
+
+fn webhook_request() -> Schema {
+    Schema::Object(
+        "RequestWebhook",
+        vec![
+            (String::from(":url"), Schema::String),
+            (String::from(":secret"), Schema::String),
+            (
+                String::from(":events"),
+                Schema::Optional(Box::new(Schema::Array(Box::new(Schema::String)))),
+            ),
+            (
+                String::from(":account-id"),
+                Schema::Optional(Box::new(Schema::String)),
+            ),
+        ],
+    )
+}
+
+fn transfer_request() -> Schema {
+    Schema::Object(
+        "RequestTransfer",
+        vec![
+            (String::from(":amount"), Schema::Integer),
+            (String::from(":target-account-id"), Schema::String),
+        ],
+    )
+}
+
+fn routes() -> Vec<Route> {
+    let operation_responses = || {
+        vec![
+            (
+                200,
+                "The account after the operation.",
+                Some(ResponseAccount::describe()),
+            ),
+            (
+                400,
+                "Unreadable body, or an amount over 9223372036854775807.",
+                None,
+            ),
+            (404, "No such account.", None),
+            (
+                409,
+                "Not enough funds, or the account changed meanwhile.",
+                None,
+            ),
+            (422, "Over a limit or breaking a product rule.", None),
+            (
+                503,
+                "Crux is unavailable, retry after the Retry-After header.",
+                None,
+            ),
+            (
+                504,
+                "Crux didn't answer, and the operation may have been written: check before retrying.",
+                None,
+            ),
+        ]
+    };
+
+    vec![
+        Route {
+            method: "post",
+            path: "/accounts",
+            summary: "Create an account.",
+            query: &[],
+            request: Some(RequestAccount::describe()),
+            responses: vec![
+                (
+                    201,
+                    "The account created.",
+                    Some(ResponseAccount::describe()),
+                ),
+                (
+                    400,
+                    "Unreadable body, or an amount over 9223372036854775807.",
+                    None,
+                ),
+                (422, "Breaking a product rule.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}",
+            summary: "Get an account.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (200, "The account.", Some(ResponseAccount::describe())),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "post",
+            path: "/accounts/{account_id}/deposit",
+            summary: "Deposit into an account.",
+            query: &[],
+            request: Some(amount_request()),
+            responses: operation_responses(),
+        },
+        Route {
+            method: "post",
+            path: "/accounts/{account_id}/withdraw",
+            summary: "Withdraw from an account.",
+            query: &[],
+            request: Some(amount_request()),
+            responses: operation_responses(),
+        },
+        Route {
+            method: "post",
+            path: "/accounts/{account_id}/transfer",
+            summary: "Transfer from an account to another.",
+            query: &[],
+            request: Some(transfer_request()),
+            responses: operation_responses(),
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/events",
+            summary: "Follow the operations touching an account as server-sent events.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "A text/event-stream of operations, resuming after the Last-Event-ID header.",
+                    None,
+                ),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/summary",
+            summary: "Get an account's totals from the projections, which may lag behind writes.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The account's balance, inflow, outflow and operations.",
+                    Some(ResponseAccountSummary::describe()),
+                ),
+                (404, "No such account, or not projected yet.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/history",
+            summary: "Get the account's balance after every change, latest first.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The account's history.",
+                    Some(Vec::<ResponseAccountHistoryElement>::describe()),
+                ),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/operations",
+            summary: "Get the operations touching an account.",
+            query: &[
+                (
+                    "principal",
+                    "Only operations by this principal. Admins only.",
+                ),
+                (
+                    "client-id",
+                    "Only operations from this client. Admins only.",
+                ),
+                (
+                    "source-ip",
+                    "Only operations from this address. Admins only.",
+                ),
+                (
+                    "channel",
+                    "Only operations from api, admin or scheduler. Admins only.",
+                ),
+            ],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The account's operations, with their audit for admins.",
+                    Some(Vec::<ResponseAccountOperation>::describe()),
+                ),
+                (400, "Unknown channel.", None),
+                (403, "Audit filters used by someone not an admin.", None),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/operations/export",
+            summary: "Download the operations touching an account for accounting tools.",
+            query: &[
+                ("format", "csv (the default), ofx or qif."),
+                (
+                    "from",
+                    "Only operations from this RFC 3339 time or date on.",
+                ),
+                (
+                    "to",
+                    "Only operations before this RFC 3339 time, or up to this date.",
+                ),
+            ],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The operations as a CSV, OFX or QIF file, with signed amounts.",
+                    None,
+                ),
+                (400, "Unknown format, or a bad from or to.", None),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/statement",
+            summary: "Download a bank statement of an account for ERPs.",
+            query: &[
+                ("format", "camt053 (the default) or mt940."),
+                (
+                    "from",
+                    "Start of the statement, as an RFC 3339 time or date.",
+                ),
+                (
+                    "to",
+                    "End of the statement, as an RFC 3339 time, or its last date.",
+                ),
+            ],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The statement as camt.053.001.02 XML or MT940, with opening and closing balances.",
+                    None,
+                ),
+                (400, "Unknown format, or a bad from or to.", None),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/limits",
+            summary: "Get the limits applied to an account.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The account's limits, with the defaults of its type.",
+                    Some(ResponseLimits::describe()),
+                ),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "put",
+            path: "/accounts/{account_id}/limits",
+            summary: "Set the limits of an account, overriding the defaults of its type.",
+            query: &[],
+            request: Some(limits_request()),
+            responses: vec![
+                (
+                    200,
+                    "The account's limits.",
+                    Some(ResponseLimits::describe()),
+                ),
+                (400, "Unreadable body.", None),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/accounts/{account_id}/fee-quote",
+            summary: "Get the fee an operation would be charged.",
+            query: &[
+                ("operation", "withdraw or transfer."),
+                ("amount", "Amount of the operation."),
+            ],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The fee and the total debited.",
+                    Some(ResponseFeeQuote::describe()),
+                ),
+                (400, "Unknown operation, or a bad amount.", None),
+                (404, "No such account.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/products",
+            summary: "List the products accounts can be opened with.",
+            query: &[],
+            request: None,
+            responses: vec![(200, "Every product and its rules.", None)],
+        },
+        Route {
+            method: "get",
+            path: "/ws",
+            summary: "Open a WebSocket to run operations and follow accounts.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (101, "Switched to the WebSocket protocol.", None),
+                (400, "Not a WebSocket handshake.", None),
+            ],
+        },
+        Route {
+            method: "post",
+            path: "/webhooks",
+            summary: "Register a URL to be sent signed operations. Admins only.",
+            query: &[],
+            request: Some(webhook_request()),
+            responses: vec![
+                (
+                    201,
+                    "The webhook registered.",
+                    Some(ResponseWebhook::describe()),
+                ),
+                (400, "Unreadable body, a bad URL or an empty secret.", None),
+                (403, "Not an admin.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/webhooks/{webhook_id}/deliveries",
+            summary: "Get the deliveries of a webhook and their attempts. Admins only.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (200, "The webhook's deliveries.", None),
+                (403, "Not an admin.", None),
+                (404, "No such webhook.", None),
+            ],
+        },
+        Route {
+            method: "post",
+            path: "/imports",
+            summary: "Create accounts from a CSV or EDN file. Admins only.",
+            query: &[(
+                "format",
+                "csv or edn, read from Content-Type when left out.",
+            )],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "A report of every row, as CSV with Accept: text/csv.",
+                    None,
+                ),
+                (400, "Unknown format, or an unreadable file.", None),
+                (403, "Not an admin.", None),
+            ],
+        },
+        Route {
+            method: "post",
+            path: "/payment-initiations",
+            summary: "Execute the credit transfers of a pain.001 XML file between accounts.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "A pain.002 XML report with the status of every instruction.",
+                    None,
+                ),
+                (400, "Not a pain.001 file.", None),
+                (409, "A file with the same MsgId is being executed.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/reconciliation",
+            summary: "Replay the log and list the accounts whose stored amount differs.",
+            query: &[("format", "edn (the default) or csv.")],
+            request: None,
+            responses: vec![
+                (
+                    200,
+                    "The accounts that don't add up.",
+                    Some(Vec::<Mismatch>::describe()),
+                ),
+                (400, "Unknown format.", None),
+            ],
+        },
+        Route {
+            method: "get",
+            path: "/api-docs",
+            summary: "This description, as OpenAPI 3 with Accept: application/json.",
+            query: &[],
+            request: None,
+            responses: vec![(200, "The routes and the maps they take and answer.", None)],
+        },
+        Route {
+            method: "get",
+            path: "/metrics",
+            summary: "Metrics in the Prometheus text format.",
+            query: &[],
+            request: None,
+            responses: vec![(200, "The metrics.", None)],
+        },
+        Route {
+            method: "get",
+            path: "/health/live",
+            summary: "Tell whether the process is up.",
+            query: &[],
+            request: None,
+            responses: vec![(200, "Up.", None)],
+        },
+        Route {
+            method: "get",
+            path: "/health/ready",
+            summary: "Tell whether Crux can be reached.",
+            query: &[],
+            request: None,
+            responses: vec![
+                (200, "Ready, with the status of every dependency.", None),
+                (503, "Crux is down.", None),
+            ],
+        },
+    ]
+}
+
+/// Every named map in `schema`, nested ones included.
+fn collect_objects(schema: &Schema, objects: &mut BTreeMap<&'static str, Vec<(String, Schema)>>) {
+    match schema {
+        Schema::Optional(inner) | Schema::Array(inner) => collect_objects(inner, objects),
+        Schema::Object(name, fields) => {
+            for (_, field) in fields {
+                collect_objects(field, objects);
+            }
+            objects.insert(name, fields.clone());
+        }
+        Schema::String | Schema::Integer | Schema::Keyword(_) => (),
+    }
+}
+
+fn objects(routes: &[Route]) -> BTreeMap<&'static str, Vec<(String, Schema)>> {
+    let mut objects = BTreeMap::new();
+
+    for route in routes {
+        let schemas = route.request.iter().chain(
+            route
+                .responses
+                .iter()
+                .filter_map(|(_, _, body)| body.as_ref()),
+        );
+        for schema in schemas {
+            collect_objects(schema, &mut objects);
+        }
+    }
+
+    objects
+}
+
+fn edn_string(s: &str) -> String {
+    format!("{:?}", s)
+}
+
+fn edn_schema(schema: &Schema) -> String {
+    match schema {
+        Schema::String => String::from("{:type :string}"),
+        Schema::Integer => String::from("{:type :integer}"),
+        Schema::Keyword(keywords) => {
+            format!("{{:type :keyword, :one-of #{{{}}}}}", keywords.join(" "))
+        }
+        Schema::Optional(inner) => {
+            let inner = edn_schema(inner);
+            format!("{}, :optional true}}", &inner[..inner.len() - 1])
+        }
+        Schema::Array(inner) => format!("{{:type :vector, :of {}}}", edn_schema(inner)),
+        Schema::Object(name, _) => format!("{{:type :map, :ref :{}}}", kebab(name)),
+    }
+}
+
+/// The API description in EDN: every route, and the keys of every map sent
+/// or answered.
+pub fn to_edn() -> String {
+    let routes = routes();
+
+    let edn_routes = routes
+        .iter()
+        .map(|route| {
+            let query = route
+                .query
+                .iter()
+                .map(|(name, description)| format!(":{} {}", name, edn_string(description)))
+                .collect::<Vec<String>>()
+                .join(", ");
+            let responses = route
+                .responses
+                .iter()
+                .map(|(status, description, body)| {
+                    format!(
+                        "{} {{:description {}, :body {}}}",
+                        status,
+                        edn_string(description),
+                        body.as_ref().map_or_else(|| String::from("nil"), edn_schema)
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+
+            format!(
+                "{{:method :{}, :path {}, :summary {}, :query {{{}}}, :request {}, :responses {{{}}}}}",
+                route.method,
+                edn_string(route.path),
+                edn_string(route.summary),
+                query,
+                route
+                    .request
+                    .as_ref()
+                    .map_or_else(|| String::from("nil"), edn_schema),
+                responses
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n  ");
+
+    let types = objects(&routes)
+        .iter()
+        .map(|(name, fields)| {
+            let fields = fields
+                .iter()
+                .map(|(key, schema)| format!("{} {}", key, edn_schema(schema)))
+                .collect::<Vec<String>>()
+                .join(", ");
+
+            format!(":{} {{{}}}", kebab(name), fields)
+        })
+        .collect::<Vec<String>>()
+        .join("\n  ");
+
+    format!(
+        "{{:info {{:title \"smaug\", :version {}}}\n :routes\n [{}]\n :types\n {{{}}}}}",
+        edn_string(env!("CARGO_PKG_VERSION")),
+        edn_routes,
+        types
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_schema(schema: &Schema) -> String {
+    match schema {
+        Schema::String => String::from("{\"type\": \"string\"}"),
+        Schema::Integer => String::from("{\"type\": \"integer\"}"),
+        Schema::Keyword(keywords) => format!(
+            "{{\"type\": \"string\", \"format\": \"edn-keyword\", \"enum\": [{}]}}",
+            keywords
+                .iter()
+                .map(|keyword| json_string(keyword))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Schema::Optional(inner) => {
+            format!(
+                "{{\"allOf\": [{}], \"nullable\": true}}",
+                json_schema(inner)
+            )
+        }
+        Schema::Array(inner) => {
+            format!("{{\"type\": \"array\", \"items\": {}}}", json_schema(inner))
+        }
+        Schema::Object(name, _) => format!("{{\"$ref\": \"#/components/schemas/{}\"}}", name),
+    }
+}
+
+fn json_content(schema: &Schema) -> String {
+    format!(
+        "{{\"application/edn\": {{\"schema\": {}}}}}",
+        json_schema(schema)
+    )
+}
+
+/// The API description as an OpenAPI 3 document. Keys are the EDN keywords
+/// smaug reads and writes.
+pub fn to_openapi() -> String {
+    let routes = routes();
+
+    let mut paths: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for route in &routes {
+        let mut parameters = route
+            .path
+            .split('/')
+            .filter(|segment| segment.starts_with('{'))
+            .map(|segment| {
+                format!(
+                    "{{\"name\": {}, \"in\": \"path\", \"required\": true, \"schema\": {{\"type\": \"string\"}}}}",
+                    json_string(segment.trim_matches(|c| c == '{' || c == '}'))
+                )
+            })
+            .collect::<Vec<String>>();
+        parameters.extend(route.query.iter().map(|(name, description)| {
+            format!(
+                "{{\"name\": {}, \"in\": \"query\", \"description\": {}, \"schema\": {{\"type\": \"string\"}}}}",
+                json_string(name),
+                json_string(description)
+            )
+        }));
+
+        let responses = route
+            .responses
+            .iter()
+            .map(|(status, description, body)| match body {
+                Some(body) => format!(
+                    "\"{}\": {{\"description\": {}, \"content\": {}}}",
+                    status,
+                    json_string(description),
+                    json_content(body)
+                ),
+                None => format!(
+                    "\"{}\": {{\"description\": {}}}",
+                    status,
+                    json_string(description)
+                ),
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let request = route.request.as_ref().map_or_else(String::new, |request| {
+            format!(
+                ", \"requestBody\": {{\"required\": true, \"content\": {}}}",
+                json_content(request)
+            )
+        });
+
+        paths.entry(route.path).or_default().push(format!(
+            "\"{}\": {{\"summary\": {}, \"parameters\": [{}]{}, \"responses\": {{{}}}}}",
+            route.method,
+            json_string(route.summary),
+            parameters.join(", "),
+            request,
+            responses
+        ));
+    }
+
+    let paths = paths
+        .iter()
+        .map(|(path, operations)| format!("{}: {{{}}}", json_string(path), operations.join(", ")))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let schemas = objects(&routes)
+        .iter()
+        .map(|(name, fields)| {
+            let properties = fields
+                .iter()
+                .map(|(key, schema)| format!("{}: {}", json_string(key), json_schema(schema)))
+                .collect::<Vec<String>>()
+                .join(", ");
+            let required = fields
+                .iter()
+                .filter(|(_, schema)| !matches!(schema, Schema::Optional(_)))
+                .map(|(key, _)| json_string(key))
+                .collect::<Vec<String>>()
+                .join(", ");
+
+            format!(
+                "\"{}\": {{\"type\": \"object\", \"properties\": {{{}}}, \"required\": [{}]}}",
+                name, properties, required
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    format!(
+        "{{\"openapi\": \"3.0.3\", \"info\": {{\"title\": \"smaug\", \"version\": {}, \"description\": \"Bodies are EDN maps keyed by the keywords below.\"}}, \"paths\": {{{}}}, \"components\": {{\"schemas\": {{{}}}}}}}",
+        json_string(env!("CARGO_PKG_VERSION")),
+        paths,
+        schemas
+    )
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn html_schema(schema: &Schema) -> String {
+    match schema {
+        Schema::String => String::from("string"),
+        Schema::Integer => String::from("integer"),
+        Schema::Keyword(keywords) => format!("one of {}", html_escape(&keywords.join(" "))),
+        Schema::Optional(inner) => format!("{}, optional", html_schema(inner)),
+        Schema::Array(inner) => format!("vector of {}", html_schema(inner)),
+        Schema::Object(name, _) => format!("<a href=\"#{0}\">{0}</a>", name),
+    }
+}
+
+/// A page listing the routes and the maps they take and answer.
+pub fn to_html() -> String {
+    let routes = routes();
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>smaug API</title></head><body>\n<h1>smaug API</h1>\n<p>Also as <a href=\"/api-docs\">EDN</a>, and as OpenAPI 3 with <code>Accept: application/json</code>.</p>\n",
+    );
+
+    for route in &routes {
+        html.push_str(&format!(
+            "<h2>{} {}</h2>\n<p>{}</p>\n",
+            route.method.to_uppercase(),
+            html_escape(route.path),
+            html_escape(route.summary)
+        ));
+        if let Some(request) = &route.request {
+            html.push_str(&format!("<p>Body: {}</p>\n", html_schema(request)));
+        }
+        for (name, description) in route.query {
+            html.push_str(&format!(
+                "<p>Query <code>{}</code>: {}</p>\n",
+                name,
+                html_escape(description)
+            ));
+        }
+        html.push_str("<ul>\n");
+        for (status, description, body) in &route.responses {
+            html.push_str(&format!(
+                "<li>{} {}{}</li>\n",
+                status,
+                html_escape(description),
+                body.as_ref()
+                    .map_or_else(String::new, |body| format!(" {}", html_schema(body)))
+            ));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    for (name, fields) in objects(&routes) {
+        html.push_str(&format!("<h2 id=\"{0}\">{0}</h2>\n<table>\n", name));
+        for (key, schema) in &fields {
+            html.push_str(&format!(
+                "<tr><td><code>{}</code></td><td>{}</td></tr>\n",
+                key,
+                html_schema(schema)
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn every_registered_route_is_described() {
+        let registered = crate::API_ROUTES
+            .iter()
+            .map(|(method, path)| (method.to_lowercase(), path.to_string()))
+            .collect::<BTreeSet<(String, String)>>();
+        let described = routes()
+            .iter()
+            .map(|route| (route.method.to_string(), route.path.to_string()))
+            .collect::<BTreeSet<(String, String)>>();
+
+        assert_eq!(registered, described);
+    }
+}
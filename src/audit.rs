@@ -0,0 +1,357 @@
+use actix_web::dev::Payload;
+use actix_web::http::HeaderMap;
+use actix_web::{web, Error, FromRequest, HttpRequest};
+use edn_derive::{Deserialize, Serialize};
+use futures::future::{self, Ready};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use transistor::edn_rs;
+
+use crate::config::Config;
+use crate::telemetry::RequestId;
+use crate::State;
+
+/// Principal authenticated by the gateway in front of smaug, which must
+/// strip it from client requests.
+pub const PRINCIPAL_HEADER: &str = "X-Authenticated-Principal";
+/// The `:gateway-secret`, sent by the gateway so the principal it forwards
+/// can be told from one a client made up.
+pub const GATEWAY_SECRET_HEADER: &str = "X-Gateway-Secret";
+pub const CLIENT_ID_HEADER: &str = "X-Client-Id";
+pub const REASON_HEADER: &str = "X-Audit-Reason";
+
+/// Longest value kept from a header, longer ones are cut.
+const MAX_HEADER_LENGTH: usize = 512;
+
+/// Where an operation was started from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Api,
+    Admin,
+    Scheduler,
+}
+
+documented!(
+    enum Channel {
+        Api,
+        Admin,
+        Scheduler,
+    }
+);
+
+impl Channel {
+    fn from_param(param: &str) -> Option<Self> {
+        match param {
+            "api" => Some(Channel::Api),
+            "admin" => Some(Channel::Admin),
+            "scheduler" => Some(Channel::Scheduler),
+            _ => None,
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbAudit {
+    pub audit___principal: Option<String>,  // :audit/principal
+    pub audit___client_id: Option<String>,  // :audit/client-id
+    pub audit___source_ip: Option<String>,  // :audit/source-ip
+    pub audit___user_agent: Option<String>, // :audit/user-agent
+    pub audit___channel: Channel,           // :audit/channel
+    pub audit___reason: Option<String>,     // :audit/reason
+}
+
+/// Who started an operation, from where and why, stored on the operations
+/// written for it.
+#[derive(Clone, Debug)]
+pub struct Audit {
+    pub request_id: Option<String>,
+    pub record: DbAudit,
+}
+
+impl Audit {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            request_id: None,
+            record: DbAudit {
+                audit___principal: None,
+                audit___client_id: None,
+                audit___source_ip: None,
+                audit___user_agent: None,
+                audit___channel: channel,
+                audit___reason: None,
+            },
+        }
+    }
+
+    /// Whether the request was made by one of the configured admins.
+    pub fn is_admin(&self, config: &Config) -> bool {
+        self.record
+            .audit___principal
+            .as_ref()
+            .is_some_and(|principal| config.admins.contains(principal))
+    }
+}
+
+/// The principal forwarded by the gateway, if the request carries the
+/// gateway secret. Without a configured secret no principal is trusted.
+pub(crate) fn principal(headers: &HeaderMap, gateway_secret: Option<&str>) -> Option<String> {
+    if !is_from_gateway(headers, gateway_secret) {
+        return None;
+    }
+
+    header(headers, PRINCIPAL_HEADER)
+}
+
+/// Whether the request carries the gateway secret. Without a configured
+/// secret none does.
+pub(crate) fn is_from_gateway(headers: &HeaderMap, gateway_secret: Option<&str>) -> bool {
+    match (gateway_secret, headers.get(GATEWAY_SECRET_HEADER)) {
+        (Some(secret), Some(sent)) => constant_time_eq(sent.as_bytes(), secret.as_bytes()),
+        _ => false,
+    }
+}
+
+/// Compares secrets in a time that doesn't tell how much of them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub(crate) fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.chars().take(MAX_HEADER_LENGTH).collect())
+}
+
+/// The client's address. Forwarded addresses are only trusted on requests
+/// carrying the gateway secret, as any client can send `Forwarded` or
+/// `X-Forwarded-For`; other requests get the address they connect from.
+///
+/// `forwarded` is the address in the request's forwarding headers, or else
+/// `peer`, the one it connects from.
+pub(crate) fn client_ip(
+    headers: &HeaderMap,
+    forwarded: Option<&str>,
+    peer: Option<SocketAddr>,
+    gateway_secret: Option<&str>,
+) -> Option<String> {
+    if is_from_gateway(headers, gateway_secret) {
+        forwarded.map(source_ip)
+    } else {
+        peer.map(|address| address.ip().to_string())
+    }
+}
+
+/// The client's address without port, as forwarded by proxies when they
+/// say so.
+fn source_ip(remote: &str) -> String {
+    remote
+        .parse::<SocketAddr>()
+        .map_or_else(|_| remote.to_string(), |address| address.ip().to_string())
+}
+
+impl FromRequest for Audit {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let headers = request.headers();
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId::from_headers(headers));
+        let gateway_secret = request
+            .app_data::<web::Data<State>>()
+            .and_then(|data| data.config.gateway_secret.clone());
+
+        future::ok(Audit {
+            request_id: Some(request_id.0),
+            record: DbAudit {
+                audit___principal: principal(headers, gateway_secret.as_deref()),
+                audit___client_id: header(headers, CLIENT_ID_HEADER),
+                audit___source_ip: client_ip(
+                    headers,
+                    request.connection_info().remote(),
+                    request.peer_addr(),
+                    gateway_secret.as_deref(),
+                ),
+                audit___user_agent: header(headers, "User-Agent"),
+                audit___channel: Channel::Api,
+                audit___reason: header(headers, REASON_HEADER),
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResponseAudit {
+    principal: Option<String>,
+    client_id: Option<String>,
+    source_ip: Option<String>,
+    user_agent: Option<String>,
+    channel: Channel,
+    reason: Option<String>,
+}
+
+documented!(ResponseAudit {
+    principal: Option<String>,
+    client_id: Option<String>,
+    source_ip: Option<String>,
+    user_agent: Option<String>,
+    channel: Channel,
+    reason: Option<String>,
+});
+
+impl From<DbAudit> for ResponseAudit {
+    fn from(db_audit: DbAudit) -> Self {
+        Self {
+            principal: db_audit.audit___principal,
+            client_id: db_audit.audit___client_id,
+            source_ip: db_audit.audit___source_ip,
+            user_agent: db_audit.audit___user_agent,
+            channel: db_audit.audit___channel,
+            reason: db_audit.audit___reason,
+        }
+    }
+}
+
+/// Filters on audit fields from the operations listing query, which only
+/// admins may use.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    principal: Option<String>,
+    client_id: Option<String>,
+    source_ip: Option<String>,
+    channel: Option<Channel>,
+}
+
+impl AuditFilter {
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let channel = match query.get("channel") {
+            Some(channel) => Some(
+                Channel::from_param(channel)
+                    .ok_or_else(|| format!("unknown channel {}", channel))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            principal: query.get("principal").cloned(),
+            client_id: query.get("client-id").cloned(),
+            source_ip: query.get("source-ip").cloned(),
+            channel,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.principal.is_none()
+            && self.client_id.is_none()
+            && self.source_ip.is_none()
+            && self.channel.is_none()
+    }
+
+    /// Operations written before audit metadata existed only match an empty
+    /// filter.
+    pub fn matches(&self, audit: Option<&DbAudit>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        let audit = match audit {
+            Some(audit) => audit,
+            None => return false,
+        };
+
+        matches_field(&self.principal, &audit.audit___principal)
+            && matches_field(&self.client_id, &audit.audit___client_id)
+            && matches_field(&self.source_ip, &audit.audit___source_ip)
+            && self
+                .channel
+                .is_none_or(|channel| channel == audit.audit___channel)
+    }
+}
+
+fn matches_field(filter: &Option<String>, value: &Option<String>) -> bool {
+    filter.is_none() || filter == value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn principals_are_trusted_with_the_gateway_secret() {
+        let headers = headers(&[
+            ("x-authenticated-principal", "support@bank.example"),
+            ("x-gateway-secret", "s3cret"),
+        ]);
+
+        assert_eq!(
+            principal(&headers, Some("s3cret")),
+            Some(String::from("support@bank.example"))
+        );
+    }
+
+    #[test]
+    fn principals_are_ignored_without_the_gateway_secret() {
+        let forged = headers(&[("x-authenticated-principal", "support@bank.example")]);
+        assert_eq!(principal(&forged, Some("s3cret")), None);
+
+        let wrong = headers(&[
+            ("x-authenticated-principal", "support@bank.example"),
+            ("x-gateway-secret", "s3cre"),
+        ]);
+        assert_eq!(principal(&wrong, Some("s3cret")), None);
+
+        let unconfigured = headers(&[
+            ("x-authenticated-principal", "support@bank.example"),
+            ("x-gateway-secret", ""),
+        ]);
+        assert_eq!(principal(&unconfigured, None), None);
+    }
+
+    #[test]
+    fn forwarded_addresses_are_trusted_with_the_gateway_secret() {
+        let peer = "10.0.0.2:41000".parse().ok();
+        let gateway = headers(&[("x-gateway-secret", "s3cret")]);
+
+        assert_eq!(
+            client_ip(&gateway, Some("203.0.113.7:5000"), peer, Some("s3cret")),
+            Some(String::from("203.0.113.7"))
+        );
+    }
+
+    #[test]
+    fn forwarded_addresses_are_ignored_without_the_gateway_secret() {
+        let request = actix_web::test::TestRequest::default()
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("forwarded", "for=203.0.113.7")
+            .peer_addr("198.51.100.4:41000".parse().unwrap())
+            .to_http_request();
+
+        let audit = Audit::from_request(&request, &mut Payload::None)
+            .into_inner()
+            .unwrap();
+        assert_eq!(
+            audit.record.audit___source_ip,
+            Some(String::from("198.51.100.4"))
+        );
+    }
+}
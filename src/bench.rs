@@ -0,0 +1,182 @@
+use actix::prelude::*;
+use actix_web::{web, App, HttpResponse, HttpServer};
+use edn_derive::Serialize;
+use edn_rs::Edn;
+use futures::future;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use transistor::edn_rs;
+
+use crate::config::Config;
+use crate::crux::{ClientConfig, CruxClient};
+use crate::events::EventBroker;
+use crate::metrics::Timed;
+use crate::{DbAccount, DbExecutor, GetAccount};
+
+/// Threads of the `SyncArbiter` that ran the blocking executor.
+const BLOCKING_THREADS: usize = 3;
+
+const ACCOUNT_ID: &str = "bench-account";
+
+/// Requests per second of the blocking executor and of the async one,
+/// reading an account from a Crux stand-in that answers after `latency-ms`.
+#[derive(Serialize, Clone, Debug)]
+pub struct Report {
+    requests: usize,
+    latency_ms: usize,
+    blocking: Run,
+    non_blocking: Run,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Run {
+    failed: usize,
+    millis: usize,
+    per_second: usize,
+}
+
+impl Run {
+    fn new(requests: usize, failed: usize, started: Instant) -> Self {
+        let millis = started.elapsed().as_millis().max(1) as usize;
+
+        Self {
+            failed,
+            millis,
+            per_second: requests * 1000 / millis,
+        }
+    }
+}
+
+/// Sends `requests` account reads at once to each executor, against a Crux
+/// stand-in on a local port. The async executor uses the client settings in
+/// `config`.
+pub async fn run(config: Config, requests: usize, latency: Duration) -> std::io::Result<Report> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(latency)
+            .route("/entity", web::post().to(entity))
+    })
+    .listen(listener)?;
+    let server = server.run();
+
+    let uri = format!("http://127.0.0.1:{}", port);
+    let blocking = SyncArbiter::start(BLOCKING_THREADS, move || BlockingExecutor {
+        http: reqwest::blocking::Client::new(),
+        uri: uri.clone(),
+    });
+    let started = Instant::now();
+    let failed = future::join_all((0..requests).map(|_| blocking.send(Fetch)))
+        .await
+        .into_iter()
+        .filter(|result| !matches!(result, Ok(Ok(_))))
+        .count();
+    let blocking = Run::new(requests, failed, started);
+
+    let config = Config {
+        crux_host: String::from("127.0.0.1"),
+        crux_port: port.to_string(),
+        crux_store: None,
+        // Every read goes to the stand-in, as the blocking executor's do.
+        crux_client: ClientConfig {
+            account_cache_max_size: 0,
+            ..config.crux_client
+        },
+        ..config
+    };
+    let executor = DbExecutor(
+        CruxClient::new(&config),
+        Arc::new(config),
+        EventBroker::default().start(),
+    )
+    .start();
+    let started = Instant::now();
+    let failed = future::join_all((0..requests).map(|_| {
+        executor.send(Timed::new(GetAccount {
+            account_id: String::from(ACCOUNT_ID),
+        }))
+    }))
+    .await
+    .into_iter()
+    .filter(|result| !matches!(result, Ok(Ok(_))))
+    .count();
+    let non_blocking = Run::new(requests, failed, started);
+
+    server.stop(false).await;
+
+    Ok(Report {
+        requests,
+        latency_ms: latency.as_millis() as usize,
+        blocking,
+        non_blocking,
+    })
+}
+
+/// Answers every entity request with the same account, once `latency` has
+/// passed. The body is read, or the connection couldn't be kept alive.
+async fn entity(latency: web::Data<Duration>, _: String) -> HttpResponse {
+    tokio::time::delay_for(*latency.get_ref()).await;
+
+    HttpResponse::Ok()
+        .content_type("application/edn")
+        .body(format!(
+            "{{:crux.db/id :{} :account/amount 100 :account/type \"default\" \
+         :account/limits nil :account/interest-rate nil :account/external-id nil}}",
+            ACCOUNT_ID
+        ))
+}
+
+/// The executor as it was, making one blocking call to Crux at a time on
+/// each of its threads.
+struct BlockingExecutor {
+    http: reqwest::blocking::Client,
+    uri: String,
+}
+
+impl Actor for BlockingExecutor {
+    type Context = SyncContext<Self>;
+}
+
+struct Fetch;
+
+impl Message for Fetch {
+    type Result = Result<DbAccount, String>;
+}
+
+impl Handler<Fetch> for BlockingExecutor {
+    type Result = Result<DbAccount, String>;
+
+    fn handle(&mut self, _: Fetch, _: &mut Self::Context) -> Self::Result {
+        let body = self
+            .http
+            .post(&format!("{}/entity", self.uri))
+            .body(format!("{{:eid :{}}}", ACCOUNT_ID))
+            .send()
+            .and_then(|response| response.text())
+            .map_err(|error| error.to_string())?;
+        let edn = Edn::from_str(&body).map_err(|error| format!("{:?}", error))?;
+
+        edn_rs::from_edn(&edn).map_err(|error| format!("{:?}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run with `cargo test -- --ignored` to print the report.
+    #[test]
+    #[ignore]
+    fn the_async_executor_outpaces_the_blocking_one() {
+        let report = actix::System::new("test")
+            .block_on(run(Config::default(), 1000, Duration::from_millis(20)))
+            .unwrap();
+        println!("{}", edn_rs::to_string(report.clone()));
+
+        assert_eq!(report.blocking.failed, 0);
+        assert_eq!(report.non_blocking.failed, 0);
+        assert!(report.non_blocking.per_second > report.blocking.per_second * 2);
+    }
+}
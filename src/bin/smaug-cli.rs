@@ -0,0 +1,717 @@
+//! Command-line client for the smaug API.
+//!
+//! ```sh
+//! smaug-cli account create --amount 1000 --type savings
+//! smaug-cli --output table operations <account-id>
+//! ```
+
+use edn_rs::Edn;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::Method;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+use std::time::Duration;
+use transistor::edn_rs;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8000";
+
+const USAGE: &str = "usage: smaug-cli [options] <command>
+
+commands:
+  account create --amount <amount> [--type <type>] [--interest-rate <basis-points>]
+  account get <account-id>
+  deposit <account-id> <amount>
+  withdraw <account-id> <amount>
+  transfer <source-account-id> <target-account-id> <amount>
+  history <account-id>
+  operations <account-id> [--by-principal <p>] [--by-client-id <c>] [--by-source-ip <ip>]
+                          [--channel api|admin|scheduler]
+  summary <account-id>
+  limits get <account-id>
+  limits set <account-id> [--max-per-operation <n>] [--max-daily-total <n>] [--max-daily-count <n>]
+  fee-quote <account-id> withdraw|transfer <amount>
+  products
+  events <account-id> [--last-event-id <id>]
+  webhooks create <webhook-url> --secret <secret> [--events <type>,...] [--account-id <id>]
+  webhooks deliveries <webhook-id>
+  reconciliation
+
+options:
+  --url <url>                  smaug's address, or SMAUG_URL (http://127.0.0.1:8000)
+  --token <token>              sent as a bearer token, or SMAUG_TOKEN
+  --principal <principal>      sent as X-Authenticated-Principal, or SMAUG_PRINCIPAL
+  --gateway-secret <secret>    sent as X-Gateway-Secret, or SMAUG_GATEWAY_SECRET
+  --client-id <id>             sent as X-Client-Id, or SMAUG_CLIENT_ID
+  --reason <reason>            sent as X-Audit-Reason
+  -o, --output edn|json|table  how to print answers (edn)
+
+exit codes:
+  0 success, 2 bad usage, 3 not found, 4 refused (400, 403, 409, 422),
+  5 try again later (429, 503), 6 server error, 7 smaug unreachable";
+
+/// Why the command failed, deciding its exit code.
+enum Failure {
+    Usage(String),
+    Http(reqwest::StatusCode, String),
+    Unreachable(reqwest::Error),
+    /// The connection broke while events were coming.
+    Lost(std::io::Error),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Usage(_) => 2,
+            Failure::Http(status, _) => match status.as_u16() {
+                404 => 3,
+                400 | 403 | 409 | 422 => 4,
+                429 | 503 => 5,
+                _ => 6,
+            },
+            Failure::Unreachable(_) | Failure::Lost(_) => 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Output {
+    Edn,
+    Json,
+    Table,
+}
+
+/// Positional arguments and `--name value` options, taken as they are read
+/// so leftovers can be refused.
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, Failure> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut args = args;
+
+        while let Some(arg) = args.next() {
+            let name = match arg.as_str() {
+                "-o" => Some("output"),
+                arg => arg.strip_prefix("--"),
+            };
+
+            match name {
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| Failure::Usage(format!("missing value for --{}", name)))?;
+                    options.insert(name.to_string(), value);
+                }
+                None => positional.push(arg),
+            }
+        }
+
+        Ok(Self {
+            positional,
+            options,
+        })
+    }
+
+    fn option(&mut self, name: &str) -> Option<String> {
+        self.options.remove(name)
+    }
+
+    fn option_or_env(&mut self, name: &str, variable: &str) -> Option<String> {
+        self.option(name).or_else(|| std::env::var(variable).ok())
+    }
+
+    fn number_option(&mut self, name: &str) -> Result<Option<usize>, Failure> {
+        self.option(name)
+            .map(|value| number(name, &value))
+            .transpose()
+    }
+
+    /// The positional arguments of a command expecting `names`.
+    fn positional(&self, names: &[&str]) -> Result<Vec<String>, Failure> {
+        if self.positional.len() != names.len() {
+            return Err(Failure::Usage(format!(
+                "expected {}",
+                names
+                    .iter()
+                    .map(|name| format!("<{}>", name))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            )));
+        }
+
+        Ok(self.positional.clone())
+    }
+
+    fn finish(&self) -> Result<(), Failure> {
+        match self.options.keys().next() {
+            Some(name) => Err(Failure::Usage(format!("unknown option --{}", name))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn number(name: &str, value: &str) -> Result<usize, Failure> {
+    value
+        .parse()
+        .map_err(|_| Failure::Usage(format!("{} must be a non-negative number", name)))
+}
+
+struct Api {
+    client: Client,
+    url: String,
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Api {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.headers.iter().fold(
+            self.client
+                .request(method, &format!("{}{}", self.url, path))
+                .header("Accept", "application/edn"),
+            |request, (name, value)| request.header(*name, value),
+        )
+    }
+
+    fn get(&self, path: &str) -> Result<String, Failure> {
+        send(self.request(Method::GET, path))
+    }
+
+    fn send_edn(&self, method: Method, path: &str, body: String) -> Result<String, Failure> {
+        send(
+            self.request(method, path)
+                .header("Content-Type", "application/edn")
+                .body(body),
+        )
+    }
+}
+
+fn send(request: RequestBuilder) -> Result<String, Failure> {
+    let response = request.send().map_err(Failure::Unreachable)?;
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get("Retry-After")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let body = response.text().map_err(Failure::Unreachable)?;
+
+    if status.is_success() {
+        return Ok(body);
+    }
+
+    let detail = match retry_after {
+        Some(seconds) if body.is_empty() => format!("retry after {} seconds", seconds),
+        Some(seconds) => format!("{} (retry after {} seconds)", body, seconds),
+        None => body,
+    };
+    Err(Failure::Http(status, detail))
+}
+
+/// Prints the data of every server-sent event as it comes, until smaug
+/// closes the stream.
+fn follow(request: RequestBuilder, output: Output) -> Result<(), Failure> {
+    let response = request.send().map_err(Failure::Unreachable)?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().map_err(Failure::Unreachable)?;
+        return Err(Failure::Http(status, body));
+    }
+
+    let mut data = Vec::new();
+    for line in BufReader::new(response).lines() {
+        let line = line.map_err(Failure::Lost)?;
+
+        if line.is_empty() {
+            if !data.is_empty() {
+                print(&data.join("\n"), output);
+                data.clear();
+            }
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value).to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// EDN map of the settings that are set.
+fn edn_map(entries: Vec<(&str, Option<String>)>) -> String {
+    let entries = entries
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| format!(":{} {}", key, value)))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    format!("{{{}}}", entries)
+}
+
+fn edn_string(s: &str) -> String {
+    format!("{:?}", s)
+}
+
+fn run(args: Vec<String>) -> Result<(String, Output), Failure> {
+    let mut args = Args::parse(args.into_iter())?;
+
+    let output = match args.option("output").as_deref() {
+        None | Some("edn") => Output::Edn,
+        Some("json") => Output::Json,
+        Some("table") => Output::Table,
+        Some(output) => return Err(Failure::Usage(format!("unknown output {}", output))),
+    };
+
+    let mut headers = Vec::new();
+    if let Some(token) = args.option_or_env("token", "SMAUG_TOKEN") {
+        headers.push(("Authorization", format!("Bearer {}", token)));
+    }
+    if let Some(principal) = args.option_or_env("principal", "SMAUG_PRINCIPAL") {
+        headers.push(("X-Authenticated-Principal", principal));
+    }
+    if let Some(secret) = args.option_or_env("gateway-secret", "SMAUG_GATEWAY_SECRET") {
+        headers.push(("X-Gateway-Secret", secret));
+    }
+    if let Some(client_id) = args.option_or_env("client-id", "SMAUG_CLIENT_ID") {
+        headers.push(("X-Client-Id", client_id));
+    }
+    if let Some(reason) = args.option("reason") {
+        headers.push(("X-Audit-Reason", reason));
+    }
+
+    let api = Api {
+        client: Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(Failure::Unreachable)?,
+        url: args
+            .option_or_env("url", "SMAUG_URL")
+            .unwrap_or_else(|| String::from(DEFAULT_URL))
+            .trim_end_matches('/')
+            .to_string(),
+        headers,
+    };
+
+    if args.positional.is_empty() {
+        return Err(Failure::Usage(String::from("missing command")));
+    }
+    let command = args.positional.remove(0);
+    let subcommand = match command.as_str() {
+        "account" | "limits" | "webhooks" if !args.positional.is_empty() => {
+            Some(args.positional.remove(0))
+        }
+        _ => None,
+    };
+
+    let body = match (command.as_str(), subcommand.as_deref()) {
+        ("account", Some("create")) => {
+            args.positional(&[])?;
+            let amount = args
+                .number_option("amount")?
+                .ok_or_else(|| Failure::Usage(String::from("missing --amount")))?;
+            let body = edn_map(vec![
+                ("amount", Some(amount.to_string())),
+                ("account-type", args.option("type").map(|t| edn_string(&t))),
+                (
+                    "interest-rate",
+                    args.number_option("interest-rate")?.map(|r| r.to_string()),
+                ),
+            ]);
+            args.finish()?;
+
+            api.send_edn(Method::POST, "/accounts", body)?
+        }
+        ("account", Some("get")) => {
+            let ids = args.positional(&["account-id"])?;
+            args.finish()?;
+
+            api.get(&format!("/accounts/{}", ids[0]))?
+        }
+        ("deposit", None) | ("withdraw", None) => {
+            let values = args.positional(&["account-id", "amount"])?;
+            let amount = number("amount", &values[1])?;
+            args.finish()?;
+
+            api.send_edn(
+                Method::POST,
+                &format!("/accounts/{}/{}", values[0], command),
+                format!("{{:amount {}}}", amount),
+            )?
+        }
+        ("transfer", None) => {
+            let values = args.positional(&["source-account-id", "target-account-id", "amount"])?;
+            let amount = number("amount", &values[2])?;
+            args.finish()?;
+
+            api.send_edn(
+                Method::POST,
+                &format!("/accounts/{}/transfer", values[0]),
+                format!(
+                    "{{:amount {}, :target-account-id {}}}",
+                    amount,
+                    edn_string(&values[1])
+                ),
+            )?
+        }
+        ("history", None) | ("summary", None) => {
+            let ids = args.positional(&["account-id"])?;
+            args.finish()?;
+
+            api.get(&format!("/accounts/{}/{}", ids[0], command))?
+        }
+        ("operations", None) => {
+            let ids = args.positional(&["account-id"])?;
+            let filters = [
+                ("principal", args.option("by-principal")),
+                ("client-id", args.option("by-client-id")),
+                ("source-ip", args.option("by-source-ip")),
+                ("channel", args.option("channel")),
+            ];
+            args.finish()?;
+
+            let query = filters
+                .iter()
+                .filter_map(|(name, value)| value.as_ref().map(|value| (*name, value.as_str())))
+                .collect::<Vec<(&str, &str)>>();
+
+            send(
+                api.request(Method::GET, &format!("/accounts/{}/operations", ids[0]))
+                    .query(&query),
+            )?
+        }
+        ("limits", Some("get")) => {
+            let ids = args.positional(&["account-id"])?;
+            args.finish()?;
+
+            api.get(&format!("/accounts/{}/limits", ids[0]))?
+        }
+        ("limits", Some("set")) => {
+            let ids = args.positional(&["account-id"])?;
+            let body = edn_map(vec![
+                (
+                    "max-per-operation",
+                    args.number_option("max-per-operation")?
+                        .map(|n| n.to_string()),
+                ),
+                (
+                    "max-daily-total",
+                    args.number_option("max-daily-total")?
+                        .map(|n| n.to_string()),
+                ),
+                (
+                    "max-daily-count",
+                    args.number_option("max-daily-count")?
+                        .map(|n| n.to_string()),
+                ),
+            ]);
+            args.finish()?;
+
+            api.send_edn(Method::PUT, &format!("/accounts/{}/limits", ids[0]), body)?
+        }
+        ("fee-quote", None) => {
+            let values = args.positional(&["account-id", "operation", "amount"])?;
+            let amount = number("amount", &values[2])?;
+            args.finish()?;
+
+            send(
+                api.request(Method::GET, &format!("/accounts/{}/fee-quote", values[0]))
+                    .query(&[
+                        ("operation", values[1].as_str()),
+                        ("amount", &amount.to_string()),
+                    ]),
+            )?
+        }
+        ("products", None) => {
+            args.positional(&[])?;
+            args.finish()?;
+
+            api.get("/products")?
+        }
+        ("events", None) => {
+            let ids = args.positional(&["account-id"])?;
+            let last_event_id = args.option("last-event-id");
+            args.finish()?;
+
+            // Events come until smaug goes away, so only connecting may
+            // time out.
+            let client = Client::builder()
+                .connect_timeout(Duration::from_secs(30))
+                .timeout(None)
+                .build()
+                .map_err(Failure::Unreachable)?;
+            let api = Api { client, ..api };
+            let mut request = api
+                .request(Method::GET, &format!("/accounts/{}/events", ids[0]))
+                .header("Accept", "text/event-stream");
+            if let Some(last_event_id) = last_event_id {
+                request = request.header("Last-Event-ID", last_event_id);
+            }
+
+            follow(request, output)?;
+            String::new()
+        }
+        ("webhooks", Some("create")) => {
+            let url = args.positional(&["webhook-url"])?.remove(0);
+            let secret = args
+                .option("secret")
+                .ok_or_else(|| Failure::Usage(String::from("missing --secret")))?;
+            let body = edn_map(vec![
+                ("url", Some(edn_string(&url))),
+                ("secret", Some(edn_string(&secret))),
+                (
+                    "events",
+                    args.option("events").map(|events| {
+                        format!(
+                            "[{}]",
+                            events
+                                .split(',')
+                                .map(|event| format!(":{}", event.trim()))
+                                .collect::<Vec<String>>()
+                                .join(" ")
+                        )
+                    }),
+                ),
+                (
+                    "account-id",
+                    args.option("account-id").map(|id| edn_string(&id)),
+                ),
+            ]);
+            args.finish()?;
+
+            api.send_edn(Method::POST, "/webhooks", body)?
+        }
+        ("webhooks", Some("deliveries")) => {
+            let ids = args.positional(&["webhook-id"])?;
+            args.finish()?;
+
+            api.get(&format!("/webhooks/{}/deliveries", ids[0]))?
+        }
+        ("reconciliation", None) => {
+            args.positional(&[])?;
+            args.finish()?;
+
+            api.get("/reconciliation")?
+        }
+        _ => {
+            return Err(Failure::Usage(format!(
+                "unknown command {}",
+                std::iter::once(command.as_str())
+                    .chain(subcommand.as_deref())
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            )))
+        }
+    };
+
+    Ok((body, output))
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// JSON with keywords as strings without their colon.
+fn to_json(edn: &Edn) -> String {
+    match edn {
+        Edn::Map(_) => format!(
+            "{{{}}}",
+            edn.map_iter()
+                .into_iter()
+                .flatten()
+                .map(|(key, value)| format!(
+                    "{}: {}",
+                    json_string(key.trim_start_matches(':')),
+                    to_json(value)
+                ))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Edn::Vector(_) | Edn::List(_) => format!(
+            "[{}]",
+            edn.iter()
+                .into_iter()
+                .flatten()
+                .map(to_json)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Edn::Set(_) => format!(
+            "[{}]",
+            edn.set_iter()
+                .into_iter()
+                .flatten()
+                .map(to_json)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Edn::Key(key) => json_string(key.trim_start_matches(':')),
+        Edn::Str(s) => json_string(s),
+        Edn::Int(_) | Edn::UInt(_) | Edn::Double(_) | Edn::Bool(_) => edn.to_string(),
+        Edn::Nil | Edn::Empty => String::from("null"),
+        _ => json_string(&edn.to_string()),
+    }
+}
+
+/// Compact EDN, for nested values in tables.
+fn to_edn(edn: &Edn) -> String {
+    let join = |items: Vec<String>| items.join(" ");
+
+    match edn {
+        Edn::Map(_) => format!(
+            "{{{}}}",
+            edn.map_iter()
+                .into_iter()
+                .flatten()
+                .map(|(key, value)| format!("{} {}", key, to_edn(value)))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Edn::Vector(_) | Edn::List(_) => format!(
+            "[{}]",
+            join(edn.iter().into_iter().flatten().map(to_edn).collect())
+        ),
+        Edn::Set(_) => format!(
+            "#{{{}}}",
+            join(edn.set_iter().into_iter().flatten().map(to_edn).collect())
+        ),
+        Edn::Str(s) => edn_string(s),
+        Edn::Nil => String::from("nil"),
+        _ => edn.to_string(),
+    }
+}
+
+fn cell(edn: &Edn) -> String {
+    match edn {
+        Edn::Str(s) => s.clone(),
+        Edn::Key(key) => key.trim_start_matches(':').to_string(),
+        Edn::Nil => String::new(),
+        _ => to_edn(edn),
+    }
+}
+
+/// Columns of the maps in a vector, or the keys and values of a map.
+fn to_table(edn: &Edn) -> String {
+    let (header, rows) = match edn {
+        Edn::Map(_) => (
+            vec![String::from("key"), String::from("value")],
+            edn.map_iter()
+                .into_iter()
+                .flatten()
+                .map(|(key, value)| vec![key.trim_start_matches(':').to_string(), cell(value)])
+                .collect::<Vec<Vec<String>>>(),
+        ),
+        Edn::Vector(_) | Edn::List(_) => {
+            let items = edn.iter().into_iter().flatten().collect::<Vec<&Edn>>();
+            let mut columns: Vec<String> = Vec::new();
+            for item in &items {
+                for (key, _) in item.map_iter().into_iter().flatten() {
+                    if !columns.contains(key) {
+                        columns.push(key.clone());
+                    }
+                }
+            }
+
+            if columns.is_empty() {
+                (
+                    vec![String::from("value")],
+                    items.iter().map(|item| vec![cell(item)]).collect(),
+                )
+            } else {
+                let rows = items
+                    .iter()
+                    .map(|item| {
+                        columns
+                            .iter()
+                            .map(|column| cell(&item[column.as_str()]))
+                            .collect()
+                    })
+                    .collect();
+                (
+                    columns
+                        .iter()
+                        .map(|column| column.trim_start_matches(':').to_string())
+                        .collect(),
+                    rows,
+                )
+            }
+        }
+        _ => return cell(edn),
+    };
+
+    let widths = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain(std::iter::once(header[i].chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<usize>>();
+
+    std::iter::once(&header)
+        .chain(rows.iter())
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(value, width)| format!("{:width$}", value, width = width))
+                .collect::<Vec<String>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn print(body: &str, output: Output) {
+    let edn = match Edn::from_str(body) {
+        Ok(edn) if !body.trim().is_empty() => edn,
+        _ => {
+            if !body.is_empty() {
+                println!("{}", body);
+            }
+            return;
+        }
+    };
+
+    match output {
+        Output::Edn => println!("{}", body),
+        Output::Json => println!("{}", to_json(&edn)),
+        Output::Table => println!("{}", to_table(&edn)),
+    }
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+
+    match run(args) {
+        Ok((body, output)) => print(&body, output),
+        Err(failure) => {
+            match &failure {
+                Failure::Usage(message) => eprintln!("{}\n\n{}", message, USAGE),
+                Failure::Http(status, body) if body.is_empty() => eprintln!("HTTP {}", status),
+                Failure::Http(status, body) => eprintln!("HTTP {}: {}", status, body),
+                Failure::Unreachable(error) => eprintln!("couldn't reach smaug: {}", error),
+                Failure::Lost(error) => eprintln!("lost the connection to smaug: {}", error),
+            }
+            std::process::exit(failure.exit_code());
+        }
+    }
+}
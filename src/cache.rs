@@ -0,0 +1,393 @@
+use edn_rs::Edn;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use transistor::edn_rs;
+use transistor::types::http::Action;
+use transistor::types::CruxId;
+
+use crate::crux::ClientConfig;
+use crate::metrics;
+use crate::DbAccount;
+
+/// Accounts read from Crux or written through the client, so the hot ones
+/// aren't fetched again for every request that only reads them. Operations
+/// writing an account back read it from Crux instead.
+///
+/// Entries are kept for `ttl` and stamped with the transaction that wrote
+/// them, as far as this process knows. A read only fills the cache if no
+/// write to its account was seen since the read started, so a slow read
+/// can't put back what a write replaced. Writes made by other processes are
+/// seen once the entry expires.
+pub struct AccountCache {
+    ttl: Duration,
+    max_size: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: BTreeMap<CruxId, Entry>,
+    /// Bumped by every write and invalidation.
+    version: u64,
+    /// Latest version of the entries dropped to make room, whose writes
+    /// reads started before them mustn't undo.
+    dropped_version: u64,
+}
+
+struct Entry {
+    /// `None` once invalidated.
+    account: Option<DbAccount>,
+    tx_id: Option<usize>,
+    version: u64,
+    cached_at: Instant,
+}
+
+/// A lookup that missed, to fill the cache with what was read instead.
+pub struct Miss {
+    id: CruxId,
+    version: u64,
+}
+
+impl Miss {
+    pub fn id(&self) -> &CruxId {
+        &self.id
+    }
+}
+
+pub enum Lookup {
+    Hit(DbAccount),
+    Miss(Miss),
+}
+
+/// What a transaction does to the accounts it touches: a new version for
+/// accounts put as of now, nothing known for anything else.
+pub struct AccountWrites(Vec<(CruxId, Option<DbAccount>)>);
+
+impl AccountWrites {
+    pub fn of(actions: &[Action]) -> Self {
+        let writes = actions
+            .iter()
+            .filter_map(|action| match action {
+                // Only accounts have an amount, and nothing else shares
+                // their ids.
+                Action::Put(document, valid_time) if document.contains(":account/amount") => {
+                    let account = edn_rs::from_str::<DbAccount>(document).ok()?;
+                    let id = account.crux__db___id.clone();
+
+                    // Put at another valid time, it may not be the current
+                    // version.
+                    Some((id, valid_time.map_or(Some(account), |_| None)))
+                }
+                Action::Delete(id, _) | Action::Evict(id) => {
+                    let id = edn_rs::from_edn::<CruxId>(&Edn::from_str(id).ok()?).ok()?;
+
+                    Some((id, None))
+                }
+                _ => None,
+            })
+            .collect();
+
+        Self(writes)
+    }
+}
+
+impl AccountCache {
+    pub fn new(config: &ClientConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.account_cache_ttl_seconds as u64),
+            max_size: config.account_cache_max_size,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.max_size > 0 && self.ttl > Duration::from_secs(0)
+    }
+
+    pub fn get(&self, id: &CruxId) -> Lookup {
+        let state = self.state.lock().unwrap();
+
+        let account = state
+            .entries
+            .get(id)
+            .filter(|entry| entry.cached_at.elapsed() < self.ttl)
+            .and_then(|entry| entry.account.clone());
+
+        if self.is_enabled() {
+            metrics::record_account_cache_lookup(account.is_some());
+        }
+
+        match account {
+            Some(account) => Lookup::Hit(account),
+            None => Lookup::Miss(Miss {
+                id: id.clone(),
+                version: state.version,
+            }),
+        }
+    }
+
+    /// A miss for `id` without looking it up, to fill the cache with an
+    /// account read from Crux anyway.
+    pub fn miss(&self, id: &CruxId) -> Miss {
+        Miss {
+            id: id.clone(),
+            version: self.state.lock().unwrap().version,
+        }
+    }
+
+    /// Caches `account`, read from Crux after `miss`, unless a write to it
+    /// was seen in the meantime.
+    pub fn fill(&self, miss: Miss, account: DbAccount) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        let written_since = match state.entries.get(&miss.id) {
+            Some(entry) => entry.version > miss.version,
+            None => state.dropped_version > miss.version,
+        };
+        if written_since {
+            return;
+        }
+
+        let tx_id = state.entries.get(&miss.id).and_then(|entry| entry.tx_id);
+        let version = state.version;
+        self.insert(&mut state, miss.id, Some(account), tx_id, version);
+    }
+
+    /// Updates the accounts written by transaction `tx_id`, and forgets the
+    /// ones it changed in other ways.
+    pub fn written(&self, writes: AccountWrites, tx_id: usize) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        for (id, account) in writes.0 {
+            let newer = state
+                .entries
+                .get(&id)
+                .and_then(|entry| entry.tx_id)
+                .is_some_and(|cached| cached > tx_id);
+            if newer {
+                continue;
+            }
+
+            state.version += 1;
+            let version = state.version;
+            self.insert(&mut state, id, account, Some(tx_id), version);
+        }
+    }
+
+    /// Forgets the accounts of a transaction that failed, which may still
+    /// have been written.
+    pub fn invalidate(&self, writes: AccountWrites) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        for (id, _) in writes.0 {
+            state.version += 1;
+            let version = state.version;
+            let tx_id = state.entries.get(&id).and_then(|entry| entry.tx_id);
+            self.insert(&mut state, id, None, tx_id, version);
+        }
+    }
+
+    fn insert(
+        &self,
+        state: &mut State,
+        id: CruxId,
+        account: Option<DbAccount>,
+        tx_id: Option<usize>,
+        version: u64,
+    ) {
+        state.entries.insert(
+            id,
+            Entry {
+                account,
+                tx_id,
+                version,
+                cached_at: Instant::now(),
+            },
+        );
+
+        if state.entries.len() <= self.max_size {
+            return;
+        }
+
+        // Expired entries go first, then the oldest one.
+        let ttl = self.ttl;
+        let mut dropped = state
+            .entries
+            .values()
+            .filter(|entry| entry.cached_at.elapsed() >= ttl)
+            .map(|entry| entry.version)
+            .collect::<Vec<u64>>();
+        state
+            .entries
+            .retain(|_, entry| entry.cached_at.elapsed() < ttl);
+
+        while state.entries.len() > self.max_size {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.cached_at)
+                .map(|(id, _)| id.clone())
+                .expect("the cache is over its size");
+            if let Some(entry) = state.entries.remove(&oldest) {
+                dropped.push(entry.version);
+            }
+        }
+
+        if let Some(version) = dropped.into_iter().max() {
+            state.dropped_version = state.dropped_version.max(version);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl: Duration, max_size: usize) -> AccountCache {
+        AccountCache {
+            ttl,
+            max_size,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn account(id: &str, amount: i64) -> DbAccount {
+        DbAccount {
+            crux__db___id: CruxId::new(id),
+            account___amount: amount,
+            account___type: None,
+            account___limits: None,
+            account___interest_rate: None,
+            account___external_id: None,
+        }
+    }
+
+    fn put(account: &DbAccount) -> AccountWrites {
+        AccountWrites(vec![(account.crux__db___id.clone(), Some(account.clone()))])
+    }
+
+    fn miss(cache: &AccountCache, id: &str) -> Miss {
+        match cache.get(&CruxId::new(id)) {
+            Lookup::Miss(miss) => miss,
+            Lookup::Hit(_) => panic!("{} is cached", id),
+        }
+    }
+
+    /// The amount cached for `id`, if any.
+    fn amount(cache: &AccountCache, id: &str) -> Option<i64> {
+        match cache.get(&CruxId::new(id)) {
+            Lookup::Hit(account) => Some(account.account___amount),
+            Lookup::Miss(_) => None,
+        }
+    }
+
+    #[test]
+    fn reads_fill_the_cache() {
+        let cache = cache(Duration::from_secs(30), 10);
+
+        let read = miss(&cache, "alice");
+        cache.fill(read, account("alice", 10));
+        assert_eq!(amount(&cache, "alice"), Some(10));
+    }
+
+    #[test]
+    fn reads_started_before_a_write_dont_replace_it() {
+        let cache = cache(Duration::from_secs(30), 10);
+
+        let read = miss(&cache, "alice");
+        cache.written(put(&account("alice", 20)), 1);
+        cache.fill(read, account("alice", 10));
+        assert_eq!(amount(&cache, "alice"), Some(20));
+    }
+
+    #[test]
+    fn writes_of_older_transactions_dont_replace_newer_ones() {
+        let cache = cache(Duration::from_secs(30), 10);
+
+        cache.written(put(&account("alice", 20)), 2);
+        cache.written(put(&account("alice", 10)), 1);
+        assert_eq!(amount(&cache, "alice"), Some(20));
+
+        cache.written(put(&account("alice", 30)), 3);
+        assert_eq!(amount(&cache, "alice"), Some(30));
+    }
+
+    #[test]
+    fn failed_transactions_forget_their_accounts() {
+        let cache = cache(Duration::from_secs(30), 10);
+        cache.written(put(&account("alice", 10)), 1);
+
+        let read = cache.miss(&CruxId::new("alice"));
+        cache.invalidate(put(&account("alice", 20)));
+        assert_eq!(amount(&cache, "alice"), None);
+
+        // A read started before the failure may have missed its write.
+        cache.fill(read, account("alice", 10));
+        assert_eq!(amount(&cache, "alice"), None);
+
+        let read = miss(&cache, "alice");
+        cache.fill(read, account("alice", 20));
+        assert_eq!(amount(&cache, "alice"), Some(20));
+    }
+
+    #[test]
+    fn accounts_put_at_other_valid_times_are_forgotten() {
+        let cache = cache(Duration::from_secs(30), 10);
+        cache.written(put(&account("alice", 10)), 1);
+
+        let writes = AccountWrites::of(&[Action::Put(
+            edn_rs::to_string(account("alice", 20)),
+            Some("2020-01-01T00:00:00Z".parse().unwrap()),
+        )]);
+        cache.written(writes, 2);
+        assert_eq!(amount(&cache, "alice"), None);
+    }
+
+    #[test]
+    fn entries_expire() {
+        let cache = cache(Duration::from_millis(20), 10);
+        cache.written(put(&account("alice", 10)), 1);
+        assert_eq!(amount(&cache, "alice"), Some(10));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(amount(&cache, "alice"), None);
+    }
+
+    #[test]
+    fn reads_dont_bring_back_what_was_dropped_for_room() {
+        let cache = cache(Duration::from_secs(30), 1);
+
+        let read = miss(&cache, "alice");
+        cache.written(put(&account("alice", 20)), 1);
+        cache.written(put(&account("bob", 5)), 2);
+        assert_eq!(amount(&cache, "alice"), None);
+        assert_eq!(amount(&cache, "bob"), Some(5));
+
+        cache.fill(read, account("alice", 10));
+        assert_eq!(amount(&cache, "alice"), None);
+    }
+
+    #[test]
+    fn disabled_caches_keep_nothing() {
+        let cache = cache(Duration::from_secs(30), 0);
+
+        cache.written(put(&account("alice", 10)), 1);
+        let read = miss(&cache, "alice");
+        cache.fill(read, account("alice", 10));
+        assert_eq!(amount(&cache, "alice"), None);
+    }
+}
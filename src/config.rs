@@ -0,0 +1,223 @@
+use edn_rs::{Edn, EdnError};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use transistor::edn_rs;
+
+use crate::crux::ClientConfig;
+use crate::exports::ExportConfig;
+use crate::fees::FeeSchedule;
+use crate::interest::InterestConfig;
+use crate::limits::DbLimits;
+use crate::products::Product;
+use crate::ratelimit::RateLimitConfig;
+use crate::webhooks::WebhookConfig;
+
+/// Path of the configuration file used when `SMAUG_CONFIG` is not set.
+const DEFAULT_PATH: &str = "smaug.edn";
+
+/// Account type used for accounts created without one.
+pub const DEFAULT_ACCOUNT_TYPE: &str = "default";
+
+/// Service configuration, read from an EDN file such as:
+///
+/// ```edn
+/// {:crux {:host "localhost" :port "3000" :timeout-ms 5000 :retries 2
+///         :breaker {:failures 5 :open-seconds 30} :max-concurrency 64
+///         :account-cache {:ttl-seconds 30 :max-size 10000}}
+///  :products {:checking {:overdraft 50000}
+///             :savings {:min-balance 1000 :interest-rate 150}}
+///  :limits {:default {:max-per-operation 100000
+///                     :max-daily-total 500000
+///                     :max-daily-count 20}
+///           :savings {:max-daily-count 2}}
+///  :fees {:withdraw [{:type :free-count :free 3 :fee {:type :flat :amount 50}}]
+///         :transfer [{:type :percentage :basis-points 50 :min 10}]}
+///  :interest {:day-count :act-365 :rounding :half-even}
+///  :webhooks {:max-attempts 8 :backoff-seconds 30}
+///  :rate-limits {:default {:client {:burst 100 :per-second 20}}}
+///  :exports {:currency "EUR" :decimals 2}
+///  :admins ["support@bank.example"]
+///  :gateway-secret "..."}
+/// ```
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub crux_host: String,
+    pub crux_port: String,
+    /// File of the in-process store used instead of Crux, if any.
+    pub crux_store: Option<String>,
+    /// Timeouts, retries and circuit breaker of the Crux client.
+    pub crux_client: ClientConfig,
+    /// Products by name, including the built-in ones.
+    pub products: HashMap<String, Product>,
+    /// Default limits per account type.
+    pub limits: HashMap<String, DbLimits>,
+    pub fees: FeeSchedule,
+    pub interest: InterestConfig,
+    pub webhooks: WebhookConfig,
+    pub rate_limits: RateLimitConfig,
+    pub exports: ExportConfig,
+    /// Principals allowed to see and filter by audit metadata.
+    pub admins: HashSet<String>,
+    /// Secret the gateway sends in `X-Gateway-Secret`, without which the
+    /// principal it forwards isn't trusted.
+    pub gateway_secret: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            crux_host: String::from("localhost"),
+            crux_port: String::from("3000"),
+            crux_store: None,
+            crux_client: ClientConfig::default(),
+            products: Product::builtin(),
+            limits: HashMap::new(),
+            fees: FeeSchedule::default(),
+            interest: InterestConfig::default(),
+            webhooks: WebhookConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            exports: ExportConfig::default(),
+            admins: HashSet::new(),
+            gateway_secret: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Edn(EdnError),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "couldn't read configuration file: {}", e),
+            ConfigError::Edn(e) => write!(f, "couldn't parse configuration file: {:?}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(io_error: std::io::Error) -> Self {
+        ConfigError::Io(io_error)
+    }
+}
+
+impl From<EdnError> for ConfigError {
+    fn from(edn_error: EdnError) -> Self {
+        ConfigError::Edn(edn_error)
+    }
+}
+
+impl Config {
+    /// Reads the file pointed by `SMAUG_CONFIG`, falling back to `smaug.edn`
+    /// and then to the defaults if that doesn't exist either.
+    pub fn load() -> Result<Self, ConfigError> {
+        match std::env::var("SMAUG_CONFIG") {
+            Ok(path) => Self::from_file(&path),
+            Err(_) if std::path::Path::new(DEFAULT_PATH).exists() => Self::from_file(DEFAULT_PATH),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path)?;
+
+        Ok(Self::from_str(&content)?)
+    }
+
+    /// Limits applied to accounts of `account_type` that don't override them.
+    pub fn default_limits(&self, account_type: &str) -> DbLimits {
+        let default = self
+            .limits
+            .get(DEFAULT_ACCOUNT_TYPE)
+            .cloned()
+            .unwrap_or_default();
+
+        match self.limits.get(account_type) {
+            Some(limits) => limits.clone().or(default),
+            None => default,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = EdnError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let edn = Edn::from_str(s)?;
+        let default = Self::default();
+
+        let mut products = default.products;
+        if let Some(iter) = edn[":products"].map_iter() {
+            for (name, settings) in iter {
+                let name = name.trim_start_matches(':');
+
+                products.insert(name.to_string(), Product::from_settings(name, settings)?);
+            }
+        }
+
+        let limits = match edn[":limits"].map_iter() {
+            Some(iter) => iter
+                .map(|(account_type, limits)| {
+                    let account_type = account_type.trim_start_matches(':').to_string();
+
+                    Ok((account_type, DbLimits::from_settings(limits)?))
+                })
+                .collect::<Result<HashMap<String, DbLimits>, EdnError>>()?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            crux_host: string_or(&edn[":crux"][":host"], default.crux_host)?,
+            crux_port: string_or(&edn[":crux"][":port"], default.crux_port)?,
+            crux_store: match &edn[":crux"][":store"] {
+                Edn::Nil => None,
+                store => Some(edn_rs::from_edn(store)?),
+            },
+            crux_client: ClientConfig::from_settings(&edn[":crux"])?,
+            products,
+            limits,
+            fees: FeeSchedule::from_settings(&edn[":fees"])?,
+            interest: InterestConfig::from_settings(&edn[":interest"])?,
+            webhooks: WebhookConfig::from_settings(&edn[":webhooks"])?,
+            rate_limits: RateLimitConfig::from_settings(&edn[":rate-limits"])?,
+            exports: ExportConfig::from_settings(&edn[":exports"])?,
+            admins: match edn[":admins"].iter() {
+                Some(iter) => iter
+                    .map(edn_rs::from_edn)
+                    .collect::<Result<HashSet<String>, EdnError>>()?,
+                None => HashSet::new(),
+            },
+            gateway_secret: match &edn[":gateway-secret"] {
+                Edn::Nil => None,
+                secret => Some(edn_rs::from_edn(secret)?),
+            },
+        })
+    }
+}
+
+/// Reads an optional string setting, which may also be written as a number.
+pub fn string_or(edn: &Edn, default: String) -> Result<String, EdnError> {
+    match edn {
+        Edn::Nil => Ok(default),
+        Edn::Str(_) | Edn::UInt(_) | Edn::Int(_) => Ok(edn_rs::from_edn(edn)?),
+        _ => Err(EdnError::Deserialize(format!(
+            "couldn't convert {} into a string setting",
+            edn
+        ))),
+    }
+}
+
+/// Reads an optional non-negative number setting.
+pub fn uint(edn: &Edn) -> Result<Option<usize>, EdnError> {
+    match edn {
+        Edn::Nil => Ok(None),
+        Edn::UInt(u) => Ok(Some(*u)),
+        _ => Err(EdnError::Deserialize(format!(
+            "couldn't convert {} into a non-negative number setting",
+            edn
+        ))),
+    }
+}
@@ -0,0 +1,960 @@
+use edn_rs::{Edn, EdnError};
+use futures::Future;
+use lazy_static::lazy_static;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::RequestBuilder;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing_futures::Instrument;
+use transistor::edn_rs;
+use transistor::types::error::CruxError;
+use transistor::types::http::{Action, Order};
+use transistor::types::query::Query;
+use transistor::types::response::{EntityHistoryResponse, TxLogResponse};
+use uuid::Uuid;
+
+use crate::cache::{AccountCache, AccountWrites};
+use crate::config::{self, Config};
+use crate::localstore::{self, LocalStore};
+use crate::metrics;
+
+lazy_static! {
+    /// Shared by every client, so all of them stop calling Crux together.
+    static ref BREAKER: Breaker = Breaker::default();
+}
+
+/// Timeouts, retries and circuit breaker settings of the Crux client, read
+/// from the `:crux` entry of the configuration file:
+///
+/// ```edn
+/// {:timeout-ms 5000 :timeouts-ms {:tx-log 10000}
+///  :retries 2 :backoff-ms 50 :max-backoff-ms 1000
+///  :breaker {:failures 5 :open-seconds 30}
+///  :max-concurrency 64
+///  :account-cache {:ttl-seconds 30 :max-size 10000}}
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub timeout_ms: usize,
+    /// Timeouts overriding `timeout_ms` by call, e.g. `tx-log`.
+    pub timeouts_ms: HashMap<String, usize>,
+    /// Retries of a failed read. Writes are never retried.
+    pub retries: usize,
+    /// Wait before the first retry, doubled before every other one.
+    pub backoff_ms: usize,
+    pub max_backoff_ms: usize,
+    /// Consecutive failed calls that open the circuit breaker.
+    pub breaker_failures: usize,
+    /// How long the open circuit breaker fails calls before trying again.
+    pub breaker_open_seconds: usize,
+    /// Calls made to Crux at once, and connections kept open to it. Other
+    /// calls wait for one of them to finish.
+    pub max_concurrency: usize,
+    /// How long accounts are cached. No account is with 0.
+    pub account_cache_ttl_seconds: usize,
+    /// Accounts cached at most. None is with 0.
+    pub account_cache_max_size: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 5000,
+            timeouts_ms: HashMap::new(),
+            retries: 2,
+            backoff_ms: 50,
+            max_backoff_ms: 1000,
+            breaker_failures: 5,
+            breaker_open_seconds: 30,
+            max_concurrency: 64,
+            account_cache_ttl_seconds: 30,
+            account_cache_max_size: 10000,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        let default = Self::default();
+
+        let timeouts_ms = match edn[":timeouts-ms"].map_iter() {
+            Some(iter) => iter
+                .map(|(call, timeout)| {
+                    let call = call.trim_start_matches(':').to_string();
+                    let timeout = config::uint(timeout)?.ok_or_else(|| {
+                        EdnError::Deserialize(format!("missing timeout for {}", call))
+                    })?;
+
+                    Ok((call, timeout))
+                })
+                .collect::<Result<HashMap<String, usize>, EdnError>>()?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            timeout_ms: config::uint(&edn[":timeout-ms"])?.unwrap_or(default.timeout_ms),
+            timeouts_ms,
+            retries: config::uint(&edn[":retries"])?.unwrap_or(default.retries),
+            backoff_ms: config::uint(&edn[":backoff-ms"])?.unwrap_or(default.backoff_ms),
+            max_backoff_ms: config::uint(&edn[":max-backoff-ms"])?
+                .unwrap_or(default.max_backoff_ms),
+            breaker_failures: config::uint(&edn[":breaker"][":failures"])?
+                .unwrap_or(default.breaker_failures),
+            breaker_open_seconds: config::uint(&edn[":breaker"][":open-seconds"])?
+                .unwrap_or(default.breaker_open_seconds),
+            max_concurrency: config::uint(&edn[":max-concurrency"])?
+                .unwrap_or(default.max_concurrency)
+                .max(1),
+            account_cache_ttl_seconds: config::uint(&edn[":account-cache"][":ttl-seconds"])?
+                .unwrap_or(default.account_cache_ttl_seconds),
+            account_cache_max_size: config::uint(&edn[":account-cache"][":max-size"])?
+                .unwrap_or(default.account_cache_max_size),
+        })
+    }
+
+    fn timeout(&self, call: &str) -> Duration {
+        let timeout_ms = self
+            .timeouts_ms
+            .get(&call.replace('_', "-"))
+            .copied()
+            .unwrap_or(self.timeout_ms);
+
+        Duration::from_millis(timeout_ms as u64)
+    }
+
+    /// Wait before the `retry`th retry: half the exponential backoff, plus
+    /// up to as much again at random so calls don't retry in lockstep.
+    fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(32) as u32;
+        let backoff_ms = self
+            .backoff_ms
+            .saturating_mul(2usize.saturating_pow(exponent))
+            .min(self.max_backoff_ms) as u128;
+        let jitter_ms = Uuid::new_v4().as_u128() % (backoff_ms / 2 + 1);
+
+        Duration::from_millis((backoff_ms - backoff_ms / 2 + jitter_ms) as u64)
+    }
+}
+
+/// A failed call to Crux.
+#[derive(Debug)]
+pub enum Error {
+    /// Crux couldn't be reached, timed out or failed on its side. The same
+    /// call may work later.
+    Retryable(CruxError),
+    /// Crux refused the call or answered something unreadable. Retrying
+    /// won't help.
+    Fatal(CruxError),
+    /// A transaction was sent but no answer came back, so it may have been
+    /// written. Sending it again could write it twice.
+    Unknown(CruxError),
+    /// Crux wasn't called because the circuit breaker is open.
+    CircuitOpen { retry_after: Duration },
+    /// The in-process store couldn't write its file.
+    Store(std::io::Error),
+}
+
+impl Error {
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, Error::Fatal(_) | Error::Unknown(_) | Error::Store(_))
+    }
+
+    /// Whether a transaction may have been written despite the error.
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, Error::Unknown(_))
+    }
+
+    /// Seconds to tell clients to wait, rounded up, when known.
+    pub fn retry_after_seconds(&self) -> Option<u64> {
+        match self {
+            Error::CircuitOpen { retry_after } => {
+                Some((retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).max(1))
+            }
+            Error::Retryable(_) | Error::Fatal(_) | Error::Unknown(_) | Error::Store(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Retryable(error) | Error::Fatal(error) => write!(f, "{}", error),
+            Error::Unknown(error) => write!(f, "transaction outcome unknown: {}", error),
+            Error::CircuitOpen { retry_after } => write!(
+                f,
+                "circuit breaker open for another {}ms",
+                retry_after.as_millis()
+            ),
+            Error::Store(error) => write!(f, "couldn't write the in-process store: {}", error),
+        }
+    }
+}
+
+impl From<CruxError> for Error {
+    fn from(crux_error: CruxError) -> Self {
+        match &crux_error {
+            CruxError::RequestError(error)
+                if !error.is_builder()
+                    && error.status().is_none_or(|status| status.is_server_error()) =>
+            {
+                Error::Retryable(crux_error)
+            }
+            _ => Error::Fatal(crux_error),
+        }
+    }
+}
+
+impl From<EdnError> for Error {
+    fn from(edn_error: EdnError) -> Self {
+        Error::Fatal(edn_error.into())
+    }
+}
+
+/// Whether Crux refused the connection, so nothing was sent.
+fn is_refused(error: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+
+    while let Some(error) = source {
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            return io_error.kind() == std::io::ErrorKind::ConnectionRefused;
+        }
+        source = error.source();
+    }
+
+    false
+}
+
+#[derive(Debug)]
+enum BreakerState {
+    Closed {
+        failures: usize,
+    },
+    Open {
+        until: Instant,
+    },
+    /// One call is trying whether Crux is back.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Breaker(Mutex<BreakerState>);
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker(Mutex::new(BreakerState::Closed { failures: 0 }))
+    }
+}
+
+/// A call let through the breaker. Its outcome is recorded when it ends;
+/// dropped before that, as when the request making it goes away, a probe
+/// gives its turn to the next call instead of keeping the breaker half open.
+struct BreakerPermit<'a> {
+    breaker: &'a Breaker,
+    probe: bool,
+}
+
+impl BreakerPermit<'_> {
+    fn record(mut self, result: &Result<(), &Error>, config: &ClientConfig) {
+        self.probe = false;
+        self.breaker.record(result, config);
+    }
+}
+
+impl Drop for BreakerPermit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            let mut state = self.breaker.0.lock().unwrap();
+            if matches!(*state, BreakerState::HalfOpen) {
+                *state = BreakerState::Open {
+                    until: Instant::now(),
+                };
+            }
+        }
+    }
+}
+
+impl Breaker {
+    /// Lets a call through, or tells how long to wait before calling again.
+    fn acquire(&self) -> Result<BreakerPermit<'_>, Duration> {
+        let mut state = self.0.lock().unwrap();
+        let now = Instant::now();
+
+        let probe = match *state {
+            BreakerState::Closed { .. } => false,
+            BreakerState::Open { until } if until > now => return Err(until - now),
+            BreakerState::Open { .. } => {
+                *state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::HalfOpen => return Err(Duration::from_secs(1)),
+        };
+
+        Ok(BreakerPermit {
+            breaker: self,
+            probe,
+        })
+    }
+
+    /// Counts the outcome of a call let through. Only failures retrying
+    /// could fix count, as Crux did answer the others.
+    fn record(&self, result: &Result<(), &Error>, config: &ClientConfig) {
+        let mut state = self.0.lock().unwrap();
+        let failed = matches!(result, Err(error) if error.is_retryable());
+
+        *state = match (&*state, failed) {
+            (_, false) => BreakerState::Closed { failures: 0 },
+            (BreakerState::Closed { failures }, true) if failures + 1 < config.breaker_failures => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (_, true) => {
+                if !matches!(*state, BreakerState::Open { .. }) {
+                    tracing::warn!(
+                        open_seconds = config.breaker_open_seconds as u64,
+                        "Crux circuit breaker opened"
+                    );
+                }
+                BreakerState::Open {
+                    until: Instant::now() + Duration::from_secs(config.breaker_open_seconds as u64),
+                }
+            }
+        };
+
+        metrics::set_crux_circuit_open(matches!(*state, BreakerState::Open { .. }));
+    }
+}
+
+/// The Crux client, tracing and timing every call. Calls time out, reads
+/// are retried and none are made while the circuit breaker is open.
+///
+/// Clones share their connections, their `max_concurrency` calls and their
+/// account cache, so every part of the service draws from the same pool and
+/// sees the accounts the others wrote.
+///
+/// Calls go to the in-process store instead when the configuration has a
+/// `:store`.
+#[derive(Clone)]
+pub struct CruxClient {
+    http: reqwest::Client,
+    uri: String,
+    config: ClientConfig,
+    permits: Arc<Semaphore>,
+    accounts: Arc<AccountCache>,
+    store: Option<Arc<LocalStore>>,
+}
+
+impl CruxClient {
+    pub fn new(config: &Config) -> Self {
+        let client_config = config.crux_client.clone();
+
+        Self {
+            http: reqwest::Client::builder()
+                .pool_max_idle_per_host(client_config.max_concurrency)
+                .build()
+                .expect("the Crux HTTP client has a valid configuration"),
+            uri: format!("http://{}:{}", config.crux_host, config.crux_port),
+            permits: Arc::new(Semaphore::new(client_config.max_concurrency)),
+            accounts: Arc::new(AccountCache::new(&client_config)),
+            config: client_config,
+            store: config.crux_store.as_ref().map(|path| {
+                localstore::open(path).expect("the in-process store is opened at startup")
+            }),
+        }
+    }
+
+    pub async fn entity(&self, id: String) -> Result<Edn, Error> {
+        if let Some(store) = &self.store {
+            return timed("entity", async { store.entity(&id) }).await;
+        }
+
+        let id = &id;
+        self.read("entity", move || async move {
+            let body = self
+                .send(
+                    "entity",
+                    self.http
+                        .post(&format!("{}/entity", self.uri))
+                        .body(format!("{{:eid {}}}", id)),
+                )
+                .await?;
+
+            Ok(Edn::from_str(&body.replace("#inst", ""))?)
+        })
+        .await
+    }
+
+    pub async fn entity_history(
+        &self,
+        id: String,
+        order: Order,
+        with_docs: bool,
+    ) -> Result<EntityHistoryResponse, Error> {
+        if let Some(store) = &self.store {
+            return timed("entity_history", async {
+                store.entity_history(&id, order, with_docs)
+            })
+            .await;
+        }
+
+        let url = &format!(
+            "{}/entity-history/{}?sort-order={}&with-docs={}",
+            self.uri,
+            id,
+            edn_rs::to_string(order),
+            with_docs
+        );
+
+        self.read("entity_history", move || async move {
+            let body = self.send("entity_history", self.http.get(url)).await?;
+
+            EntityHistoryResponse::from_str(&body.replace("#inst", ""))
+        })
+        .await
+    }
+
+    /// Accounts read or written through this client.
+    pub fn accounts(&self) -> &AccountCache {
+        &self.accounts
+    }
+
+    /// Submits a transaction, then updates the accounts it wrote in the
+    /// cache. It isn't retried, as a call that timed out may still have
+    /// been written, so its accounts are forgotten instead, and the error
+    /// says the outcome is unknown unless Crux couldn't even be reached.
+    pub async fn tx_log(&self, actions: Vec<Action>) -> Result<TxLogResponse, Error> {
+        let writes = AccountWrites::of(&actions);
+        let result = self.submit(actions).await;
+
+        match &result {
+            Ok(response) => self.accounts.written(writes, response.tx___tx_id),
+            Err(_) => self.accounts.invalidate(writes),
+        }
+
+        result
+    }
+
+    /// Submits a transaction guarded by `Match` actions like `tx_log` does,
+    /// then waits for Crux to index it and tells whether it was committed,
+    /// which it isn't when one of them didn't match. Only the accounts of a
+    /// committed transaction are cached.
+    pub async fn tx_log_matched(&self, actions: Vec<Action>) -> Result<bool, Error> {
+        let writes = AccountWrites::of(&actions);
+        let result = match self.submit(actions).await {
+            Ok(response) => self
+                .tx_committed(response.tx___tx_id)
+                .await
+                .map(|committed| (response.tx___tx_id, committed)),
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok((tx_id, true)) => {
+                self.accounts.written(writes, tx_id);
+                Ok(true)
+            }
+            Ok((_, false)) => {
+                self.accounts.invalidate(writes);
+                Ok(false)
+            }
+            Err(error) => {
+                self.accounts.invalidate(writes);
+                Err(error)
+            }
+        }
+    }
+
+    /// Whether transaction `tx_id` was committed, once Crux indexed it.
+    /// Crux keeps the transactions whose matches failed in its log, so
+    /// readers of the log ask about those with matches.
+    pub async fn tx_committed(&self, tx_id: usize) -> Result<bool, Error> {
+        if let Some(store) = &self.store {
+            return timed("tx_committed", async { store.tx_committed(tx_id) }).await;
+        }
+
+        self.read("tx_committed", move || async move {
+            self.send(
+                "tx_committed",
+                self.http
+                    .get(&format!("{}/await-tx", self.uri))
+                    .query(&[("tx-id", tx_id)]),
+            )
+            .await?;
+            let body = self
+                .send(
+                    "tx_committed",
+                    self.http
+                        .get(&format!("{}/tx-committed", self.uri))
+                        .query(&[("tx-id", tx_id)]),
+                )
+                .await?;
+
+            edn_rs::from_str::<bool>(body.trim()).map_err(CruxError::from)
+        })
+        .await
+    }
+
+    /// Sends a transaction, once. A call that got no answer may still have
+    /// been written, so its error says the outcome is unknown unless Crux
+    /// couldn't even be reached.
+    async fn submit(&self, actions: Vec<Action>) -> Result<TxLogResponse, Error> {
+        self.post_transaction(actions)
+            .await
+            .map_err(|error| match error {
+                Error::Retryable(CruxError::RequestError(error)) if !is_refused(&error) => {
+                    Error::Unknown(CruxError::RequestError(error))
+                }
+                error => error,
+            })
+    }
+
+    async fn post_transaction(&self, actions: Vec<Action>) -> Result<TxLogResponse, Error> {
+        if let Some(store) = &self.store {
+            return timed("tx_log", async { store.tx_log(actions) }).await;
+        }
+
+        let actions = &actions
+            .into_iter()
+            .map(edn_rs::to_string)
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        self.call("tx_log", false, move || async move {
+            let body = self
+                .send(
+                    "tx_log",
+                    self.http
+                        .post(&format!("{}/tx-log", self.uri))
+                        .body(format!("[{}]", actions)),
+                )
+                .await?;
+
+            Ok(edn_rs::from_str(&body.replace("#inst", ""))?)
+        })
+        .await
+    }
+
+    /// At most `limit` transactions of the log after `after_tx_id`, with
+    /// the operations of every transaction.
+    ///
+    /// Crux streams the whole log after `after_tx_id`, so its answer is only
+    /// read up to the `limit`th transaction.
+    pub async fn tx_log_tail(
+        &self,
+        after_tx_id: Option<usize>,
+        limit: usize,
+    ) -> Result<String, Error> {
+        if let Some(store) = &self.store {
+            return timed("tx_log_tail", async {
+                store.tx_log_tail(after_tx_id, limit)
+            })
+            .await;
+        }
+
+        self.read("tx_log_tail", move || async move {
+            let mut request = self
+                .http
+                .get(&format!("{}/tx-log", self.uri))
+                .header("Accept", "application/edn")
+                .header(CONTENT_TYPE, "application/edn")
+                .timeout(self.config.timeout("tx_log_tail"))
+                .query(&[("with-ops", "true")]);
+            if let Some(after_tx_id) = after_tx_id {
+                request = request.query(&[("after-tx-id", after_tx_id.to_string())]);
+            }
+
+            let mut response = request.send().await?;
+            if response.status().is_server_error() {
+                response.error_for_status_ref()?;
+            }
+
+            let mut page = LogPage::new(limit);
+            while let Some(chunk) = response.chunk().await? {
+                if page.push(&chunk) {
+                    break;
+                }
+            }
+
+            Ok(page.into_string())
+        })
+        .await
+    }
+
+    pub async fn query(&self, query: Query) -> Result<BTreeSet<Vec<String>>, Error> {
+        let query = &edn_rs::to_string(query);
+
+        if let Some(store) = &self.store {
+            return timed("query", async { store.query(query) }).await;
+        }
+
+        self.read("query", move || async move {
+            let body = self
+                .send(
+                    "query",
+                    self.http
+                        .post(&format!("{}/query", self.uri))
+                        .body(query.clone()),
+                )
+                .await?;
+
+            Ok(query_results(&Edn::from_str(&body)?)?)
+        })
+        .await
+    }
+
+    /// Asks Crux for its status, once and with `timeout`, but waiting for a
+    /// free call like any other. The circuit breaker is left alone, as
+    /// this is how to tell whether Crux is back.
+    pub async fn status(&self, timeout: Duration) -> Result<(), Error> {
+        // The in-process store is up as long as the process is.
+        if self.store.is_some() {
+            return Ok(());
+        }
+
+        let _permit = self.permits.acquire().await;
+        let _in_flight = metrics::CruxInFlight::new();
+
+        timed("status", async {
+            self.http
+                .get(&format!("{}/", self.uri))
+                .header("Accept", "application/edn")
+                .timeout(timeout)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|error| Error::from(CruxError::RequestError(error)))
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sends `request` with the timeout of `call`. Only server errors fail
+    /// it, as Crux answers some requests, such as for a missing entity,
+    /// with a body callers read.
+    async fn send(&self, call: &str, request: RequestBuilder) -> Result<String, CruxError> {
+        let response = request
+            .header(CONTENT_TYPE, "application/edn")
+            .timeout(self.config.timeout(call))
+            .send()
+            .await?;
+
+        if response.status().is_server_error() {
+            response.error_for_status_ref()?;
+        }
+
+        Ok(response.text().await?)
+    }
+
+    async fn read<T, F>(&self, call: &str, f: impl Fn() -> F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, CruxError>>,
+    {
+        self.call(call, true, f).await
+    }
+
+    /// Makes `call` through the circuit breaker, retrying it after failures
+    /// that may not happen again when `retry` is set. Waits for one of the
+    /// `max_concurrency` calls to be free first, but not while backing off.
+    async fn call<T, F>(&self, call: &str, retry: bool, f: impl Fn() -> F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, CruxError>>,
+    {
+        let mut retries = 0;
+
+        loop {
+            let breaker = BREAKER
+                .acquire()
+                .map_err(|retry_after| Error::CircuitOpen { retry_after })?;
+
+            let result = {
+                let _permit = self.permits.acquire().await;
+                let _in_flight = metrics::CruxInFlight::new();
+
+                timed(call, async { f().await.map_err(Error::from) }).await
+            };
+            breaker.record(&result.as_ref().map(|_| ()), &self.config);
+
+            match result {
+                Err(Error::Retryable(_)) if retry && retries < self.config.retries => {
+                    retries += 1;
+                    metrics::record_crux_retry(call);
+                    tokio::time::delay_for(self.config.backoff(retries)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl CruxClient {
+    /// A client over a new in-process store, in a file of its own.
+    pub fn in_process() -> Self {
+        let path = std::env::temp_dir().join(format!("smaug-test-{}.edn", Uuid::new_v4()));
+
+        Self::new(&Config {
+            crux_store: Some(path.to_string_lossy().into_owned()),
+            ..Config::default()
+        })
+    }
+}
+
+/// Transactions read at once when walking the log.
+pub const TX_LOG_PAGE_SIZE: usize = 1000;
+
+/// The first transactions of the log as Crux streams it, a vector of maps,
+/// kept whole.
+struct LogPage {
+    limit: usize,
+    bytes: Vec<u8>,
+    /// Where the last complete transaction ends.
+    end: usize,
+    transactions: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    /// Closing delimiter of the log, once it started.
+    close: Option<u8>,
+}
+
+impl LogPage {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            bytes: Vec::new(),
+            end: 0,
+            transactions: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            close: None,
+        }
+    }
+
+    /// Adds the next bytes of the log, telling whether `limit` transactions
+    /// were read.
+    fn push(&mut self, chunk: &[u8]) -> bool {
+        let start = self.bytes.len();
+        self.bytes.extend_from_slice(chunk);
+
+        for index in start..self.bytes.len() {
+            let byte = self.bytes[index];
+
+            if self.escaped {
+                self.escaped = false;
+                continue;
+            }
+
+            match byte {
+                b'\\' => self.escaped = true,
+                b'"' => self.in_string = !self.in_string,
+                _ if self.in_string => (),
+                b'[' | b'(' | b'{' => {
+                    if self.depth == 0 {
+                        self.close = Some(if byte == b'(' { b')' } else { b']' });
+                    }
+                    self.depth += 1;
+                }
+                b']' | b')' | b'}' => {
+                    self.depth = self.depth.saturating_sub(1);
+
+                    if self.depth == 1 {
+                        self.end = index + 1;
+                        self.transactions += 1;
+                        if self.transactions >= self.limit {
+                            return true;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        false
+    }
+
+    fn into_string(mut self) -> String {
+        if self.transactions >= self.limit {
+            self.bytes.truncate(self.end);
+            self.bytes.extend(self.close);
+        }
+
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+}
+
+/// Rows answered to a query, a set or a list of vectors of values.
+fn query_results(edn: &Edn) -> Result<BTreeSet<Vec<String>>, EdnError> {
+    let rows = match edn.set_iter() {
+        Some(iter) => iter.collect::<Vec<&Edn>>(),
+        None => edn
+            .iter()
+            .ok_or_else(|| EdnError::Deserialize(format!("unexpected query results: {}", edn)))?
+            .collect(),
+    };
+
+    rows.into_iter()
+        .map(|row| {
+            row.to_vec()
+                .ok_or_else(|| EdnError::Deserialize(format!("unexpected query row: {}", row)))
+        })
+        .collect()
+}
+
+/// Runs a call to Crux in its own span, recording it in the metrics as
+/// `call`.
+pub async fn timed<T, E: Debug>(call: &str, f: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let span = tracing::debug_span!("crux", call);
+
+    async {
+        let start = Instant::now();
+        let result = f.await;
+        metrics::observe_crux_call(call, start, &result);
+
+        match &result {
+            Ok(_) => tracing::debug!(elapsed_ms = start.elapsed().as_millis() as u64, "Crux call"),
+            Err(error) => tracing::warn!(
+                elapsed_ms = start.elapsed().as_millis() as u64,
+                error = ?error,
+                "Crux call failed"
+            ),
+        }
+
+        result
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "({:crux.tx/tx-id 0 :crux.api/tx-ops [[:crux.tx/put {:note \"a ] in a string\"}]]}\n\
+                       {:crux.tx/tx-id 1 :crux.api/tx-ops [[:crux.tx/put {:note \"an \\\" escaped quote\"}]]}\n\
+                       {:crux.tx/tx-id 2 :crux.api/tx-ops []})";
+
+    fn page(limit: usize, chunk_size: usize) -> (bool, String) {
+        let mut page = LogPage::new(limit);
+        let full = LOG
+            .as_bytes()
+            .chunks(chunk_size)
+            .any(|chunk| page.push(chunk));
+
+        (full, page.into_string())
+    }
+
+    /// The ids of the transactions in `page`, which must end where the log
+    /// does.
+    fn tx_ids(page: &str) -> Vec<usize> {
+        assert!(page.ends_with("})"));
+
+        page.split(":crux.tx/tx-id ")
+            .skip(1)
+            .map(|tx| tx[..1].parse().unwrap())
+            .collect()
+    }
+
+    fn client(port: u16) -> CruxClient {
+        CruxClient::new(&Config {
+            crux_host: String::from("127.0.0.1"),
+            crux_port: port.to_string(),
+            crux_client: ClientConfig {
+                timeout_ms: 200,
+                ..ClientConfig::default()
+            },
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn unanswered_transactions_have_an_unknown_outcome() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Accepts the transaction, never answering it.
+        std::thread::spawn(move || {
+            let connections = listener.incoming().collect::<Vec<_>>();
+            drop(connections);
+        });
+
+        actix::System::new("test").block_on(async move {
+            let error = client(port).tx_log(Vec::new()).await.unwrap_err();
+            assert!(error.is_unknown());
+            assert!(!error.is_retryable());
+        });
+    }
+
+    #[test]
+    fn refused_transactions_can_be_retried() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        actix::System::new("test").block_on(async move {
+            let error = client(port).tx_log(Vec::new()).await.unwrap_err();
+            assert!(!error.is_unknown());
+            assert!(error.is_retryable());
+        });
+    }
+
+    /// A breaker opened by a failure, letting calls through again at once.
+    fn opened_breaker() -> (Breaker, ClientConfig) {
+        let config = ClientConfig {
+            breaker_failures: 1,
+            breaker_open_seconds: 0,
+            ..ClientConfig::default()
+        };
+        let breaker = Breaker::default();
+        let down = Error::Retryable(CruxError::QueryError(String::from("down")));
+        breaker.acquire().unwrap().record(&Err(&down), &config);
+
+        (breaker, config)
+    }
+
+    #[test]
+    fn one_probe_is_let_through_an_opened_breaker() {
+        let (breaker, config) = opened_breaker();
+
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+
+        probe.record(&Ok(()), &config);
+        assert!(breaker.acquire().is_ok());
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[test]
+    fn dropped_probes_let_the_next_call_try() {
+        let (breaker, _) = opened_breaker();
+
+        drop(breaker.acquire().unwrap());
+        let probe = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_err());
+        drop(probe);
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[test]
+    fn log_pages_stop_after_the_limit() {
+        for chunk_size in &[1, 7, LOG.len()] {
+            let (full, page) = page(2, *chunk_size);
+            assert!(full);
+            assert_eq!(tx_ids(&page), vec![0, 1]);
+        }
+    }
+
+    #[test]
+    fn short_log_pages_are_kept_whole() {
+        let (full, page) = page(TX_LOG_PAGE_SIZE, 5);
+        assert!(!full);
+        assert_eq!(page, LOG);
+        assert_eq!(tx_ids(&page), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,330 @@
+use actix::prelude::*;
+use futures::channel::mpsc::UnboundedSender;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use transistor::edn_rs;
+use transistor::types::CruxId;
+
+use crate::crux::CruxClient;
+use crate::ledger;
+use crate::{DbAccount, DbAccountOperation, DbError, DbExecutor, ResponseAccountOperation};
+
+/// How often subscribers get a heartbeat, so idle connections aren't closed
+/// by proxies and dead ones are noticed.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// An operation that touched `account_id`, with the balance it left.
+#[derive(Clone, Debug)]
+pub struct AccountEvent {
+    pub account_id: CruxId,
+    pub operation: DbAccountOperation,
+    pub balance: i64,
+}
+
+impl AccountEvent {
+    /// Id the event is resumed from, the operation id without colon.
+    pub fn id(&self) -> String {
+        let mut id_without_colon = edn_rs::to_string(self.operation.crux__db___id.clone());
+        id_without_colon.remove(0);
+        id_without_colon
+    }
+
+    /// Operation type without namespace, e.g. `deposit`.
+    pub fn name(&self) -> String {
+        let operation_type = edn_rs::to_string(self.operation.account_operation___type.clone());
+
+        operation_type
+            .rsplit('/')
+            .next()
+            .unwrap_or(&operation_type)
+            .to_string()
+    }
+
+    pub fn to_edn(&self) -> String {
+        let mut account_id_without_colon = edn_rs::to_string(self.account_id.clone());
+        account_id_without_colon.remove(0);
+
+        format!(
+            "{{:account-id {}, :balance {}, :operation {}}}",
+            edn_rs::to_string(account_id_without_colon),
+            self.balance,
+            edn_rs::to_string(ResponseAccountOperation::from(self.operation.clone())),
+        )
+    }
+
+    /// The event framed for a Server-Sent Events stream. Every line of the
+    /// EDN gets its own `data:` field, as strings in it, like request ids,
+    /// may span lines, which clients join back with newlines.
+    pub fn to_sse(&self) -> String {
+        let data = self
+            .to_edn()
+            .replace("\r\n", "\n")
+            .split(['\n', '\r'])
+            .map(|line| format!("data: {}\n", line))
+            .collect::<String>();
+
+        format!(
+            "id: {}\nevent: {}\n{}\n",
+            single_line(&self.id()),
+            single_line(&self.name()),
+            data
+        )
+    }
+}
+
+/// `field` without the line breaks that would end an SSE field early.
+fn single_line(field: &str) -> String {
+    field.replace(['\n', '\r'], "")
+}
+
+/// Events for `operations`, written together in one transaction, on every
+/// account in `accounts` they touch, oldest first. `accounts` hold the
+/// balances the transaction ended with.
+pub fn account_events(
+    operations: &[DbAccountOperation],
+    accounts: &[&DbAccount],
+) -> Vec<AccountEvent> {
+    let mut events = Vec::new();
+
+    for account in accounts {
+        let account_id = &account.crux__db___id;
+        let mut balance = account.account___amount;
+
+        for (index, operation) in operations.iter().enumerate().rev() {
+            if !touches(operation, account_id) {
+                continue;
+            }
+
+            events.push((
+                index,
+                AccountEvent {
+                    account_id: account_id.clone(),
+                    operation: operation.clone(),
+                    balance,
+                },
+            ));
+            balance -= ledger::balance_change(operation, account_id);
+        }
+    }
+
+    events.sort_by_key(|(index, _)| *index);
+    events.into_iter().map(|(_, event)| event).collect()
+}
+
+fn touches(operation: &DbAccountOperation, account_id: &CruxId) -> bool {
+    &operation.account_operation___source_account_id == account_id
+        || operation.account_operation___target_account_id.as_ref() == Some(account_id)
+}
+
+/// Events on `account_id` after the operation `last_event_id`, replayed from
+/// its operations. Nothing is replayed for an unknown id.
+pub async fn events_after(
+    client: &CruxClient,
+    account_id: &CruxId,
+    last_event_id: &str,
+) -> Result<Vec<AccountEvent>, DbError> {
+    let mut balance = 0;
+
+    let events = ledger::operations_touching(client, account_id)
+        .await?
+        .into_iter()
+        .map(|operation| {
+            balance += ledger::balance_change(&operation, account_id);
+
+            AccountEvent {
+                account_id: account_id.clone(),
+                operation,
+                balance,
+            }
+        })
+        .collect::<Vec<AccountEvent>>();
+
+    Ok(
+        match events.iter().position(|event| event.id() == last_event_id) {
+            Some(position) => events[position + 1..].to_vec(),
+            None => Vec::new(),
+        },
+    )
+}
+
+pub(crate) struct AccountEventsAfter {
+    pub account_id: String,
+    pub last_event_id: Option<String>,
+}
+
+impl Message for AccountEventsAfter {
+    type Result = Result<Vec<AccountEvent>, DbError>;
+}
+
+impl Handler<AccountEventsAfter> for DbExecutor {
+    type Result = ResponseFuture<Result<Vec<AccountEvent>, DbError>>;
+
+    fn handle(&mut self, msg: AccountEventsAfter, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+
+        Box::pin(async move {
+            let account_id = CruxId::new(&msg.account_id);
+
+            if ledger::cached_account(&client, &account_id)
+                .await?
+                .is_none()
+            {
+                return Err(DbError::NilEntity);
+            }
+
+            match msg.last_event_id {
+                Some(last_event_id) => events_after(&client, &account_id, &last_event_id).await,
+                None => Ok(Vec::new()),
+            }
+        })
+    }
+}
+
+/// What subscribers receive.
+#[derive(Clone, Debug)]
+pub enum Notification {
+    Event(Box<AccountEvent>),
+    Heartbeat,
+}
+
+impl Notification {
+    pub fn to_sse(&self) -> String {
+        match self {
+            Notification::Event(event) => event.to_sse(),
+            Notification::Heartbeat => String::from(": heartbeat\n\n"),
+        }
+    }
+}
+
+/// Fans account events out to the subscribers of each account.
+#[derive(Default)]
+pub struct EventBroker {
+    subscribers: BTreeMap<CruxId, Vec<UnboundedSender<Notification>>>,
+}
+
+impl Actor for EventBroker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |broker, _| broker.heartbeat());
+    }
+}
+
+impl EventBroker {
+    /// Also drops the subscribers that went away.
+    fn heartbeat(&mut self) {
+        for senders in self.subscribers.values_mut() {
+            senders.retain(|sender| sender.unbounded_send(Notification::Heartbeat).is_ok());
+        }
+
+        self.subscribers.retain(|_, senders| !senders.is_empty());
+    }
+}
+
+pub struct Subscribe {
+    pub account_id: CruxId,
+    pub sender: UnboundedSender<Notification>,
+}
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+impl Handler<Subscribe> for EventBroker {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> Self::Result {
+        let Subscribe { account_id, sender } = msg;
+        let senders = self.subscribers.entry(account_id).or_default();
+
+        // A WebSocket shares one sender among its subscriptions and may
+        // subscribe to the same account again.
+        if !senders.iter().any(|other| other.same_receiver(&sender)) {
+            senders.push(sender);
+        }
+    }
+}
+
+pub struct Publish(pub Vec<AccountEvent>);
+
+impl Message for Publish {
+    type Result = ();
+}
+
+impl Handler<Publish> for EventBroker {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Self::Context) -> Self::Result {
+        for event in msg.0 {
+            if let Some(senders) = self.subscribers.get_mut(&event.account_id) {
+                senders.retain(|sender| {
+                    sender
+                        .unbounded_send(Notification::Event(Box::new(event.clone())))
+                        .is_ok()
+                });
+            }
+        }
+
+        self.subscribers.retain(|_, senders| !senders.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OperationType;
+
+    fn event(request_id: &str) -> AccountEvent {
+        AccountEvent {
+            account_id: CruxId::new("alice"),
+            operation: DbAccountOperation {
+                crux__db___id: CruxId::new("operation"),
+                account_operation___type: OperationType::Deposit,
+                account_operation___amount: 10,
+                account_operation___source_account_id: CruxId::new("alice"),
+                account_operation___target_account_id: None,
+                account_operation___triggered_by: None,
+                account_operation___postings: None,
+                account_operation___request_id: Some(request_id.to_string()),
+                account_operation___audit: None,
+                tx___tx_time: Some(String::from("2020-06-01 10:00:00 UTC")),
+            },
+            balance: 10,
+        }
+    }
+
+    /// The data of a single SSE event, joined back as clients do.
+    fn data(sse: &str) -> String {
+        sse.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn single_line_events() {
+        let event = event("abc");
+        let sse = event.to_sse();
+
+        assert!(sse.starts_with("id: operation\nevent: deposit\ndata: {"));
+        assert!(sse.ends_with("}\n\n"));
+        assert_eq!(data(&sse), event.to_edn());
+    }
+
+    #[test]
+    fn every_line_of_the_data_is_a_data_field() {
+        let event = event("first\nsecond\r\nthird\rfourth");
+        let sse = event.to_sse();
+
+        // Only the blank line ending the event.
+        assert_eq!(sse.matches("\n\n").count(), 1);
+        assert!(sse.ends_with("\n\n"));
+        assert!(sse.trim_end().lines().all(|line| line.starts_with("id: ")
+            || line.starts_with("event: ")
+            || line.starts_with("data: ")));
+        assert_eq!(
+            data(&sse),
+            event.to_edn().replace("\r\n", "\n").replace('\r', "\n")
+        );
+    }
+}
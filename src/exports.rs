@@ -0,0 +1,664 @@
+use actix::prelude::*;
+use actix_web::error::ErrorInternalServerError;
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use edn_rs::{Edn, EdnError};
+use futures::{future, stream, Stream, StreamExt};
+use transistor::edn_rs;
+use transistor::types::CruxId;
+
+use crate::config::{self, string_or};
+use crate::crux::CruxClient;
+use crate::ledger::{self, OperationPages};
+use crate::metrics::Timed;
+use crate::{DbAccount, DbAccountOperation, DbError, DbExecutor, OperationType};
+
+/// How exported amounts are written, read from the configuration file:
+///
+/// ```edn
+/// {:currency "EUR" :decimals 2 :bank-id "SMAUGBANK"}
+/// ```
+#[derive(Clone, Debug)]
+pub struct ExportConfig {
+    /// ISO 4217 code of the amounts.
+    pub currency: String,
+    /// Decimal places of the currency, since amounts are kept in its
+    /// smallest unit.
+    pub decimals: usize,
+    /// Identifies the bank to the tools reading OFX files.
+    pub bank_id: String,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            currency: String::from("XXX"),
+            decimals: 0,
+            bank_id: String::from("smaug"),
+        }
+    }
+}
+
+impl ExportConfig {
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        let default = Self::default();
+
+        Ok(Self {
+            currency: string_or(&edn[":currency"], default.currency)?,
+            decimals: config::uint(&edn[":decimals"])?.unwrap_or(default.decimals),
+            bank_id: string_or(&edn[":bank-id"], default.bank_id)?,
+        })
+    }
+
+    /// `amount` in units of the currency, e.g. `-1234` as `-12.34`.
+    pub fn decimal(&self, amount: i64) -> String {
+        let digits = amount.unsigned_abs().to_string();
+        let sign = if amount < 0 { "-" } else { "" };
+
+        if self.decimals == 0 {
+            return format!("{}{}", sign, digits);
+        }
+
+        let digits = format!("{:0>width$}", digits, width = self.decimals + 1);
+        let (units, cents) = digits.split_at(digits.len() - self.decimals);
+
+        format!("{}{}.{}", sign, units, cents)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Ofx,
+    Qif,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "ofx" => Some(Format::Ofx),
+            "qif" => Some(Format::Qif),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Ofx => "application/x-ofx",
+            Format::Qif => "application/qif",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ofx => "ofx",
+            Format::Qif => "qif",
+        }
+    }
+}
+
+/// Start and end of the operations asked for, either of them open.
+pub type Period = (Option<DateTime<FixedOffset>>, Option<DateTime<FixedOffset>>);
+
+/// Reads a `from` or `to` parameter, given as an RFC 3339 time or as a date.
+/// A date starts at midnight UTC, and a `to` date includes the whole day.
+pub fn parse_time(value: &str, end: bool) -> Result<DateTime<FixedOffset>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time);
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("{} is neither a date nor an RFC 3339 time", value))?;
+    let date = if end { date.succ() } else { date };
+
+    Ok(DateTime::<Utc>::from_utc(date.and_hms(0, 0, 0), Utc).into())
+}
+
+/// One operation, as seen from the exported account.
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub id: String,
+    pub time: DateTime<FixedOffset>,
+    pub operation_type: OperationType,
+    /// Positive when the operation added to the balance.
+    pub amount: i64,
+    /// Balance right after the operation.
+    pub balance: i64,
+    /// The other account the money moved from or to.
+    pub counterparty: Option<String>,
+    pub memo: String,
+}
+
+pub fn without_colon(id: &CruxId) -> String {
+    let mut id = edn_rs::to_string(id.clone());
+    id.remove(0);
+    id
+}
+
+impl Line {
+    pub fn new(
+        operation: &DbAccountOperation,
+        account_id: &CruxId,
+        time: DateTime<FixedOffset>,
+        balance: i64,
+    ) -> Self {
+        let amount = ledger::balance_change(operation, account_id);
+
+        let postings = match &operation.account_operation___postings {
+            Some(postings) => postings.clone(),
+            None => ledger::legacy_entry(operation)
+                .map(|entry| entry.into_postings())
+                .unwrap_or_default(),
+        };
+        let counterparty = postings
+            .iter()
+            .map(|posting| &posting.posting___account_id)
+            .find(|id| *id != account_id)
+            .map(without_colon);
+
+        let memo = match operation.account_operation___type {
+            OperationType::Create => String::from("Opening balance"),
+            OperationType::Deposit => String::from("Deposit"),
+            OperationType::Withdraw => String::from("Withdrawal"),
+            OperationType::Transfer => match (&counterparty, amount < 0) {
+                (Some(counterparty), true) => format!("Transfer to {}", counterparty),
+                (Some(counterparty), false) => format!("Transfer from {}", counterparty),
+                (None, _) => String::from("Transfer"),
+            },
+            OperationType::Fee => match &operation.account_operation___triggered_by {
+                Some(operation_id) => format!("Fee for {}", without_colon(operation_id)),
+                None => String::from("Fee"),
+            },
+            OperationType::Interest => String::from("Interest"),
+        };
+
+        Self {
+            id: without_colon(&operation.crux__db___id),
+            time,
+            operation_type: operation.account_operation___type.clone(),
+            amount,
+            balance,
+            counterparty,
+            memo,
+        }
+    }
+}
+
+/// The operations of an account within a period. Only the first page of lines is read up front, the others are read
+/// while the file is sent.
+pub struct Export {
+    pub account_id: String,
+    pub account_type: Option<String>,
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: Option<DateTime<FixedOffset>>,
+    pub first_lines: Vec<Line>,
+    lines: Lines,
+}
+
+/// The lines of an export still to read, a page of operations at a time.
+pub struct Lines {
+    pages: OperationPages,
+    account_id: CruxId,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+    /// Balance after the last operation read.
+    balance: i64,
+    last_time: Option<DateTime<FixedOffset>>,
+    done: bool,
+}
+
+impl Lines {
+    /// The lines of the next operations within the period, none once
+    /// they've all been read. Operations before the period only count
+    /// towards the balance.
+    async fn next_page(&mut self) -> Result<Vec<Line>, DbError> {
+        let mut lines = Vec::new();
+
+        while lines.is_empty() && !self.done {
+            let operations = self.pages.next_page().await?;
+            self.done = operations.is_empty();
+
+            for operation in operations {
+                let time = operation
+                    .tx___tx_time
+                    .as_ref()
+                    .and_then(|time| time.parse::<DateTime<FixedOffset>>().ok());
+                if matches!((time, self.to), (Some(time), Some(to)) if time >= to) {
+                    self.done = true;
+                    break;
+                }
+
+                self.balance += ledger::balance_change(&operation, &self.account_id);
+
+                // Operations whose time can't be read have no line, but
+                // still move the balance of the next ones.
+                let time = match time {
+                    Some(time) => time,
+                    None => continue,
+                };
+
+                if matches!(self.from, Some(from) if time < from) {
+                    continue;
+                }
+
+                self.last_time = Some(time);
+                lines.push(Line::new(&operation, &self.account_id, time, self.balance));
+            }
+        }
+
+        Ok(lines)
+    }
+}
+
+impl Export {
+    /// The file, in pieces rendered as they're taken: the header with the
+    /// first lines, then a piece per page of lines read through `db`, so
+    /// it's never held whole.
+    pub fn render(
+        self,
+        format: Format,
+        config: &ExportConfig,
+        db: Addr<DbExecutor>,
+    ) -> impl Stream<Item = Result<String, actix_web::Error>> + Unpin {
+        let config = config.clone();
+        let header = match format {
+            Format::Csv => String::from("id,time,type,amount,balance,counterparty,memo\n"),
+            Format::Ofx => self.ofx_header(&config),
+            Format::Qif => String::from("!Type:Bank\n"),
+        };
+        let to = self.to;
+
+        let pages = stream::unfold(Some((self.first_lines, self.lines)), move |state| {
+            let db = db.clone();
+            let config = config.clone();
+
+            async move {
+                let (mut page, mut lines) = state?;
+
+                if page.is_empty() && !lines.done {
+                    match db.send(Timed::new(NextLines(lines))).await {
+                        Ok(Ok((next_lines, next_page))) => {
+                            lines = next_lines;
+                            page = next_page;
+                        }
+                        _ => {
+                            let error = ErrorInternalServerError("couldn't read the operations");
+                            return Some((Err(error), None));
+                        }
+                    }
+                }
+
+                if page.is_empty() {
+                    let footer = match format {
+                        Format::Ofx => {
+                            let as_of = to.or(lines.last_time).unwrap_or_else(|| Utc::now().into());
+                            ofx_footer(lines.balance, as_of, &config)
+                        }
+                        Format::Csv | Format::Qif => String::new(),
+                    };
+                    return Some((Ok(footer), None));
+                }
+
+                let piece = page
+                    .iter()
+                    .map(|line| match format {
+                        Format::Csv => csv_line(line, &config),
+                        Format::Ofx => ofx_line(line, &config),
+                        Format::Qif => qif_line(line, &config),
+                    })
+                    .collect::<String>();
+                Some((Ok(piece), Some((Vec::new(), lines))))
+            }
+        });
+
+        Box::pin(stream::once(future::ready(Ok(header))).chain(pages))
+    }
+
+    /// Written before the last lines are read, so the statement ends at
+    /// `to` or now.
+    fn ofx_header(&self, config: &ExportConfig) -> String {
+        let now = Utc::now().into();
+        let start = self
+            .from
+            .or_else(|| self.first_lines.first().map(|line| line.time))
+            .unwrap_or(now);
+        let end = self.to.unwrap_or(now);
+        let account_type = match self.account_type.as_deref() {
+            Some("savings") => "SAVINGS",
+            _ => "CHECKING",
+        };
+
+        format!(
+            "OFXHEADER:100\nDATA:OFXSGML\nVERSION:102\nSECURITY:NONE\nENCODING:USASCII\n\
+             CHARSET:1252\nCOMPRESSION:NONE\nOLDFILEUID:NONE\nNEWFILEUID:NONE\n\n\
+             <OFX>\n<SIGNONMSGSRSV1>\n<SONRS>\n<STATUS>\n<CODE>0\n<SEVERITY>INFO\n</STATUS>\n\
+             <DTSERVER>{}\n<LANGUAGE>ENG\n</SONRS>\n</SIGNONMSGSRSV1>\n\
+             <BANKMSGSRSV1>\n<STMTTRNRS>\n<TRNUID>0\n<STATUS>\n<CODE>0\n<SEVERITY>INFO\n</STATUS>\n\
+             <STMTRS>\n<CURDEF>{}\n<BANKACCTFROM>\n<BANKID>{}\n<ACCTID>{}\n<ACCTTYPE>{}\n\
+             </BANKACCTFROM>\n<BANKTRANLIST>\n<DTSTART>{}\n<DTEND>{}\n",
+            ofx_time(now),
+            ofx_text(&config.currency),
+            ofx_text(&config.bank_id),
+            ofx_text(&self.account_id),
+            account_type,
+            ofx_time(start),
+            ofx_time(end),
+        )
+    }
+}
+
+fn ofx_footer(closing_balance: i64, as_of: DateTime<FixedOffset>, config: &ExportConfig) -> String {
+    format!(
+        "</BANKTRANLIST>\n<LEDGERBAL>\n<BALAMT>{}\n<DTASOF>{}\n</LEDGERBAL>\n\
+         </STMTRS>\n</STMTTRNRS>\n</BANKMSGSRSV1>\n</OFX>\n",
+        config.decimal(closing_balance),
+        ofx_time(as_of),
+    )
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `:operation-type/create` as `create`.
+fn type_name(operation_type: &OperationType) -> String {
+    let keyword = edn_rs::to_string(operation_type.clone());
+
+    keyword.rsplit('/').next().unwrap_or(&keyword).to_string()
+}
+
+fn csv_line(line: &Line, config: &ExportConfig) -> String {
+    format!(
+        "{},{},{},{},{},{},{}\n",
+        line.id,
+        line.time.to_rfc3339(),
+        type_name(&line.operation_type),
+        config.decimal(line.amount),
+        config.decimal(line.balance),
+        csv_field(line.counterparty.as_deref().unwrap_or_default()),
+        csv_field(&line.memo),
+    )
+}
+
+fn ofx_time(time: DateTime<FixedOffset>) -> String {
+    format!("{}[0:GMT]", time.with_timezone(&Utc).format("%Y%m%d%H%M%S"))
+}
+
+fn ofx_text(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn ofx_line(line: &Line, config: &ExportConfig) -> String {
+    let transaction_type = match line.operation_type {
+        OperationType::Create | OperationType::Deposit => "DEP",
+        OperationType::Withdraw => "DEBIT",
+        OperationType::Transfer => "XFER",
+        OperationType::Fee => "FEE",
+        OperationType::Interest => "INT",
+    };
+    let name = line
+        .counterparty
+        .as_ref()
+        .map(|counterparty| {
+            // OFX 1 names are at most 32 characters.
+            format!(
+                "<NAME>{}\n",
+                ofx_text(&counterparty.chars().take(32).collect::<String>())
+            )
+        })
+        .unwrap_or_default();
+
+    format!(
+        "<STMTTRN>\n<TRNTYPE>{}\n<DTPOSTED>{}\n<TRNAMT>{}\n<FITID>{}\n{}<MEMO>{}\n</STMTTRN>\n",
+        transaction_type,
+        ofx_time(line.time),
+        config.decimal(line.amount),
+        line.id,
+        name,
+        ofx_text(&line.memo),
+    )
+}
+
+/// QIF has no escaping, so line breaks are dropped from fields.
+fn qif_text(value: &str) -> String {
+    value.replace(['\n', '\r'], " ")
+}
+
+fn qif_line(line: &Line, config: &ExportConfig) -> String {
+    format!(
+        "D{}\nT{}\nN{}\n{}M{}\n^\n",
+        line.time.with_timezone(&Utc).format("%m/%d/%Y"),
+        config.decimal(line.amount),
+        line.id,
+        line.counterparty
+            .as_ref()
+            .map(|counterparty| format!("P{}\n", qif_text(counterparty)))
+            .unwrap_or_default(),
+        qif_text(&line.memo),
+    )
+}
+
+/// Reads the operations of the account a page at a time, counting those
+/// before `from` towards the balance, up to the first page of lines within
+/// the period.
+pub async fn export(
+    client: &CruxClient,
+    account: &DbAccount,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+) -> Result<Export, DbError> {
+    let account_id = &account.crux__db___id;
+    let mut lines = Lines {
+        pages: OperationPages::new(client, account_id),
+        account_id: account_id.clone(),
+        from,
+        to,
+        balance: 0,
+        last_time: None,
+        done: false,
+    };
+
+    let first_lines = lines.next_page().await?;
+
+    Ok(Export {
+        account_id: without_colon(account_id),
+        account_type: account.account___type.clone(),
+        from,
+        to,
+        first_lines,
+        lines,
+    })
+}
+
+pub(crate) struct ExportOperations {
+    pub account_id: String,
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: Option<DateTime<FixedOffset>>,
+}
+
+impl Message for ExportOperations {
+    type Result = Result<Export, DbError>;
+}
+
+impl Handler<ExportOperations> for DbExecutor {
+    type Result = ResponseFuture<Result<Export, DbError>>;
+
+    fn handle(&mut self, msg: ExportOperations, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+
+        Box::pin(async move {
+            let crux_account = client
+                .entity(edn_rs::to_string(CruxId::new(&msg.account_id)))
+                .await?;
+
+            if crux_account == Edn::Nil {
+                return Err(DbError::NilEntity);
+            }
+
+            let account: DbAccount = edn_rs::from_edn(&crux_account)?;
+
+            export(&client, &account, msg.from, msg.to).await
+        })
+    }
+}
+
+/// Reads the next page of lines of an export being sent.
+pub(crate) struct NextLines(pub Lines);
+
+impl Message for NextLines {
+    type Result = Result<(Lines, Vec<Line>), DbError>;
+}
+
+impl Handler<NextLines> for DbExecutor {
+    type Result = ResponseFuture<Result<(Lines, Vec<Line>), DbError>>;
+
+    fn handle(&mut self, msg: NextLines, _: &mut Self::Context) -> Self::Result {
+        Box::pin(async move {
+            let mut lines = msg.0;
+            let page = lines.next_page().await?;
+
+            Ok((lines, page))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::events::EventBroker;
+    use crate::ledger::{JournalEntry, SystemAccount, OPERATION_PAGE_SIZE};
+    use chrono::TimeZone;
+    use std::sync::Arc;
+    use transistor::types::http::Action;
+
+    fn deposit(index: usize) -> DbAccountOperation {
+        DbAccountOperation {
+            crux__db___id: CruxId::new(&format!("deposit-{}", index)),
+            account_operation___type: OperationType::Deposit,
+            account_operation___amount: 1,
+            account_operation___source_account_id: CruxId::new("alice"),
+            account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(
+                JournalEntry::new()
+                    .debit(&SystemAccount::CashIn.id(), 1)
+                    .credit(&CruxId::new("alice"), 1)
+                    .into_postings(),
+            ),
+            account_operation___request_id: None,
+            account_operation___audit: None,
+            tx___tx_time: Some(Utc.timestamp(1_600_000_000 + index as i64, 0).to_string()),
+        }
+    }
+
+    #[test]
+    fn exports_are_read_a_page_at_a_time() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let count = OPERATION_PAGE_SIZE + 10;
+            client
+                .tx_log(
+                    (0..count)
+                        .map(|index| Action::Put(edn_rs::to_string(deposit(index)), None))
+                        .collect(),
+                )
+                .await
+                .unwrap();
+
+            let account = DbAccount {
+                crux__db___id: CruxId::new("alice"),
+                account___amount: count as i64,
+                account___type: None,
+                account___limits: None,
+                account___interest_rate: None,
+                account___external_id: None,
+            };
+            let from = Utc.timestamp(1_600_000_000 + 5, 0).into();
+            let to = Utc.timestamp(1_600_000_000 + count as i64 - 5, 0).into();
+            let db = DbExecutor(
+                client.clone(),
+                Arc::new(Config::default()),
+                EventBroker::default().start(),
+            )
+            .start();
+            let render = |format| {
+                let client = client.clone();
+                let account = account.clone();
+                let db = db.clone();
+
+                async move {
+                    export(&client, &account, Some(from), Some(to))
+                        .await
+                        .unwrap()
+                        .render(format, &ExportConfig::default(), db)
+                        .map(|piece| piece.unwrap())
+                        .collect::<String>()
+                        .await
+                }
+            };
+
+            let csv = render(Format::Csv).await;
+            let lines = csv.lines().skip(1).collect::<Vec<&str>>();
+            assert_eq!(lines.len(), count - 10);
+            assert!(lines[0].starts_with("deposit-5,"));
+            assert!(lines[0].contains(",deposit,1,6,"));
+            assert!(lines[lines.len() - 1].starts_with(&format!("deposit-{},", count - 6)));
+
+            let ofx = render(Format::Ofx).await;
+            assert_eq!(ofx.matches("<STMTTRN>").count(), count - 10);
+            assert!(ofx.contains(&format!("<BALAMT>{}\n", count - 5)));
+        });
+    }
+
+    #[test]
+    fn operations_with_unreadable_times_still_move_the_balance() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let mut unreadable = deposit(1);
+            unreadable.tx___tx_time = unreadable.tx___tx_time.map(|time| time + " sometime");
+            client
+                .tx_log(
+                    vec![deposit(0), unreadable, deposit(2)]
+                        .into_iter()
+                        .map(|operation| Action::Put(edn_rs::to_string(operation), None))
+                        .collect(),
+                )
+                .await
+                .unwrap();
+
+            let account = DbAccount {
+                crux__db___id: CruxId::new("alice"),
+                account___amount: 3,
+                account___type: None,
+                account___limits: None,
+                account___interest_rate: None,
+                account___external_id: None,
+            };
+            let lines = export(&client, &account, None, None)
+                .await
+                .unwrap()
+                .first_lines
+                .into_iter()
+                .map(|line| (line.id, line.balance))
+                .collect::<Vec<(String, i64)>>();
+
+            assert_eq!(
+                lines,
+                vec![
+                    (String::from("deposit-0"), 1),
+                    (String::from("deposit-2"), 3)
+                ]
+            );
+        });
+    }
+}
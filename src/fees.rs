@@ -0,0 +1,330 @@
+use chrono::{DateTime, Datelike, FixedOffset, TimeZone, Utc};
+use edn_rs::{Edn, EdnError};
+use std::convert::TryFrom;
+use transistor::edn_rs;
+use transistor::types::CruxId;
+
+use crate::audit::Audit;
+use crate::config::{self, Config};
+use crate::crux::CruxClient;
+use crate::ledger::{self, JournalEntry, SystemAccount};
+use crate::{DbAccount, DbAccountOperation, DbError, OperationType};
+
+/// How a fee is computed from the operation amount, e.g.:
+///
+/// ```edn
+/// {:type :flat :amount 100}
+/// {:type :percentage :basis-points 50 :min 10 :max 1000}
+/// {:type :tiered :tiers [{:up-to 10000 :fee {:type :flat :amount 10}}
+///                        {:fee {:type :percentage :basis-points 10}}]}
+/// {:type :free-count :free 3 :fee {:type :flat :amount 50}}
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum FeeRule {
+    Flat(usize),
+    /// In hundredths of a percent, optionally clamped.
+    Percentage {
+        basis_points: usize,
+        min: Option<usize>,
+        max: Option<usize>,
+    },
+    /// The first tier whose `up-to` covers the amount applies; a tier without
+    /// `up-to` covers everything.
+    Tiered(Vec<(Option<usize>, FeeRule)>),
+    /// The first `free` operations of the kind in a calendar month are free.
+    FreeCount {
+        free: usize,
+        fee: Box<FeeRule>,
+    },
+}
+
+impl FeeRule {
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        let required = |key: &str| {
+            config::uint(&edn[key])?.ok_or_else(|| {
+                EdnError::Deserialize(format!("fee rule {} is missing {}", edn, key))
+            })
+        };
+
+        match &edn[":type"] {
+            Edn::Key(k) if k == ":flat" => Ok(FeeRule::Flat(required(":amount")?)),
+            Edn::Key(k) if k == ":percentage" => Ok(FeeRule::Percentage {
+                basis_points: required(":basis-points")?,
+                min: config::uint(&edn[":min"])?,
+                max: config::uint(&edn[":max"])?,
+            }),
+            Edn::Key(k) if k == ":tiered" => Ok(FeeRule::Tiered(
+                edn[":tiers"]
+                    .iter()
+                    .ok_or_else(|| {
+                        EdnError::Deserialize(format!("fee rule {} is missing :tiers", edn))
+                    })?
+                    .map(|tier| {
+                        Ok((
+                            config::uint(&tier[":up-to"])?,
+                            FeeRule::from_settings(&tier[":fee"])?,
+                        ))
+                    })
+                    .collect::<Result<Vec<(Option<usize>, FeeRule)>, EdnError>>()?,
+            )),
+            Edn::Key(k) if k == ":free-count" => Ok(FeeRule::FreeCount {
+                free: required(":free")?,
+                fee: Box::new(FeeRule::from_settings(&edn[":fee"])?),
+            }),
+            _ => Err(EdnError::Deserialize(format!(
+                "couldn't convert {} into a fee rule",
+                edn
+            ))),
+        }
+    }
+
+    fn counts_operations(&self) -> bool {
+        match self {
+            FeeRule::Flat(_) | FeeRule::Percentage { .. } => false,
+            FeeRule::Tiered(tiers) => tiers.iter().any(|(_, fee)| fee.counts_operations()),
+            FeeRule::FreeCount { .. } => true,
+        }
+    }
+
+    /// `monthly_count` is how many operations of the kind the account already
+    /// made this month. Fees too large to count stop at `usize::MAX`, which
+    /// no balance covers.
+    fn evaluate(&self, amount: usize, monthly_count: usize) -> usize {
+        match self {
+            FeeRule::Flat(fee) => *fee,
+            FeeRule::Percentage {
+                basis_points,
+                min,
+                max,
+            } => {
+                // Computed wide, as large amounts overflow once multiplied.
+                let fee = (amount as u128 * *basis_points as u128 + 5_000) / 10_000;
+                let fee = usize::try_from(fee).unwrap_or(usize::MAX);
+                let fee = min.map_or(fee, |min| fee.max(min));
+
+                max.map_or(fee, |max| fee.min(max))
+            }
+            FeeRule::Tiered(tiers) => tiers
+                .iter()
+                .find(|(up_to, _)| match up_to {
+                    Some(up_to) => amount <= *up_to,
+                    None => true,
+                })
+                .map_or(0, |(_, fee)| fee.evaluate(amount, monthly_count)),
+            FeeRule::FreeCount { free, fee } => {
+                if monthly_count < *free {
+                    0
+                } else {
+                    fee.evaluate(amount, monthly_count)
+                }
+            }
+        }
+    }
+}
+
+/// Fee rules per kind of operation, all of them summed when it runs.
+#[derive(Clone, Debug, Default)]
+pub struct FeeSchedule {
+    pub withdraw: Vec<FeeRule>,
+    pub transfer: Vec<FeeRule>,
+}
+
+impl FeeSchedule {
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        let rules = |key: &str| match edn[key].iter() {
+            Some(iter) => iter
+                .map(FeeRule::from_settings)
+                .collect::<Result<Vec<FeeRule>, EdnError>>(),
+            None => Ok(Vec::new()),
+        };
+
+        Ok(Self {
+            withdraw: rules(":withdraw")?,
+            transfer: rules(":transfer")?,
+        })
+    }
+
+    fn rules(&self, operation_type: &OperationType) -> &[FeeRule] {
+        match operation_type {
+            OperationType::Withdraw => &self.withdraw,
+            OperationType::Transfer => &self.transfer,
+            _ => &[],
+        }
+    }
+}
+
+/// The fee `account` would pay right now for an operation of `amount`.
+pub async fn quote(
+    client: &CruxClient,
+    config: &Config,
+    account: &DbAccount,
+    operation_type: &OperationType,
+    amount: usize,
+) -> Result<usize, DbError> {
+    let rules = config.fees.rules(operation_type);
+
+    let monthly_count = if rules.iter().any(FeeRule::counts_operations) {
+        monthly_count(client, account, operation_type).await?
+    } else {
+        0
+    };
+
+    Ok(rules
+        .iter()
+        .map(|rule| rule.evaluate(amount, monthly_count))
+        .fold(0, usize::saturating_add))
+}
+
+async fn monthly_count(
+    client: &CruxClient,
+    account: &DbAccount,
+    operation_type: &OperationType,
+) -> Result<usize, DbError> {
+    let now = Utc::now();
+    let month_start = Utc.ymd(now.year(), now.month(), 1).and_hms(0, 0, 0);
+
+    Ok(ledger::operations_touching(client, &account.crux__db___id)
+        .await?
+        .iter()
+        .filter(|operation| {
+            &operation.account_operation___type == operation_type
+                && operation.account_operation___source_account_id == account.crux__db___id
+        })
+        .filter_map(|operation| {
+            operation
+                .tx___tx_time
+                .as_ref()?
+                .parse::<DateTime<FixedOffset>>()
+                .ok()
+        })
+        .filter(|time| *time >= month_start)
+        .count())
+}
+
+/// Posts `fee` from `account` to fee income as a `Fee` operation linked to
+/// the operation that triggered it and audited like it. Nothing is charged
+/// for a zero fee.
+pub fn charge(
+    account: &mut DbAccount,
+    fee: usize,
+    triggered_by: &CruxId,
+    tx_time: &str,
+    audit: &Audit,
+) -> Result<Option<DbAccountOperation>, DbError> {
+    if fee == 0 {
+        return Ok(None);
+    }
+
+    let fee_income = SystemAccount::FeeIncome.id();
+    let entry = JournalEntry::new()
+        .debit(&account.crux__db___id, fee)
+        .credit(&fee_income, fee);
+    entry.post(&mut [&mut *account])?;
+
+    Ok(Some(DbAccountOperation {
+        crux__db___id: CruxId::new(&uuid::Uuid::new_v4().to_string()),
+        account_operation___type: OperationType::Fee,
+        account_operation___amount: fee,
+        account_operation___source_account_id: account.crux__db___id.clone(),
+        account_operation___target_account_id: Some(fee_income),
+        account_operation___triggered_by: Some(triggered_by.clone()),
+        account_operation___postings: Some(entry.into_postings()),
+        account_operation___request_id: audit.request_id.clone(),
+        account_operation___audit: Some(audit.record.clone()),
+        tx___tx_time: Some(tx_time.to_string()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn percentage(basis_points: usize, min: Option<usize>, max: Option<usize>) -> FeeRule {
+        FeeRule::Percentage {
+            basis_points,
+            min,
+            max,
+        }
+    }
+
+    #[test]
+    fn percentage_rounds_half_up() {
+        assert_eq!(percentage(50, None, None).evaluate(10_000, 0), 50);
+        assert_eq!(percentage(50, None, None).evaluate(100, 0), 1);
+        assert_eq!(percentage(50, None, None).evaluate(99, 0), 0);
+    }
+
+    #[test]
+    fn percentage_is_clamped() {
+        assert_eq!(percentage(50, Some(10), None).evaluate(100, 0), 10);
+        assert_eq!(
+            percentage(50, None, Some(1000)).evaluate(1_000_000, 0),
+            1000
+        );
+    }
+
+    #[test]
+    fn percentage_of_large_amounts_doesnt_overflow() {
+        assert_eq!(
+            percentage(10_000, None, None).evaluate(usize::MAX, 0),
+            usize::MAX
+        );
+        assert_eq!(
+            percentage(20_000, None, None).evaluate(usize::MAX, 0),
+            usize::MAX
+        );
+        assert_eq!(
+            percentage(50, None, Some(1000)).evaluate(usize::MAX, 0),
+            1000
+        );
+    }
+
+    #[test]
+    fn tiered_picks_the_first_tier_covering_the_amount() {
+        let rule = FeeRule::Tiered(vec![
+            (Some(100), FeeRule::Flat(1)),
+            (Some(1000), FeeRule::Flat(5)),
+            (None, percentage(100, None, None)),
+        ]);
+
+        assert_eq!(rule.evaluate(100, 0), 1);
+        assert_eq!(rule.evaluate(101, 0), 5);
+        assert_eq!(rule.evaluate(5000, 0), 50);
+        assert_eq!(
+            FeeRule::Tiered(vec![(Some(10), FeeRule::Flat(1))]).evaluate(11, 0),
+            0
+        );
+    }
+
+    #[test]
+    fn free_count_charges_once_the_free_operations_are_used() {
+        let rule = FeeRule::FreeCount {
+            free: 2,
+            fee: Box::new(FeeRule::Flat(50)),
+        };
+
+        assert_eq!(rule.evaluate(100, 1), 0);
+        assert_eq!(rule.evaluate(100, 2), 50);
+        assert!(rule.counts_operations());
+        assert!(!FeeRule::Flat(1).counts_operations());
+    }
+
+    #[test]
+    fn rules_are_read_from_settings() {
+        let edn = Edn::from_str(
+            "{:type :tiered :tiers [{:up-to 10000 :fee {:type :flat :amount 10}} \
+             {:fee {:type :percentage :basis-points 10 :max 500}}]}",
+        )
+        .unwrap();
+
+        assert_eq!(
+            FeeRule::from_settings(&edn).unwrap(),
+            FeeRule::Tiered(vec![
+                (Some(10000), FeeRule::Flat(10)),
+                (None, percentage(10, None, Some(500))),
+            ])
+        );
+        assert!(FeeRule::from_settings(&Edn::from_str("{:type :flat}").unwrap()).is_err());
+    }
+}
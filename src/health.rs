@@ -0,0 +1,186 @@
+use actix::prelude::*;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::{DbError, DbExecutor};
+
+/// How long Crux may take to answer its status.
+pub const CRUX_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long the readiness check may take, waiting for a free call to Crux
+/// included.
+pub const READY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Asks Crux for its status through the executor's client, so a check that
+/// can't get a call in time tells the client is saturated.
+pub(crate) struct CheckCrux;
+
+impl Message for CheckCrux {
+    type Result = Result<Duration, DbError>;
+}
+
+impl Handler<CheckCrux> for DbExecutor {
+    type Result = ResponseFuture<Result<Duration, DbError>>;
+
+    fn handle(&mut self, _: CheckCrux, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+
+        Box::pin(async move {
+            let start = Instant::now();
+            client.status(CRUX_TIMEOUT).await?;
+
+            Ok(start.elapsed())
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Up,
+    Down,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Up => "up",
+            Status::Down => "down",
+        }
+    }
+}
+
+/// How a dependency answered the readiness check.
+#[derive(Debug)]
+pub struct Check {
+    pub status: Status,
+    pub latency: Duration,
+    pub error: Option<String>,
+}
+
+/// How busy the Crux client is.
+#[derive(Debug)]
+pub struct Pool {
+    pub max_concurrency: usize,
+    /// Calls to Crux being made.
+    pub in_flight: i64,
+}
+
+impl Pool {
+    pub fn current(max_concurrency: usize) -> Self {
+        Self {
+            max_concurrency,
+            in_flight: metrics::crux_in_flight(),
+        }
+    }
+
+    /// Whether new calls have to wait for one to finish.
+    pub fn saturated(&self) -> bool {
+        self.in_flight >= self.max_concurrency as i64
+    }
+}
+
+#[derive(Debug)]
+pub struct Readiness {
+    pub crux: Check,
+    pub pool: Pool,
+}
+
+impl Readiness {
+    /// Ready as long as Crux answers. A saturated client only slows requests
+    /// down, unless Crux can't be reached in time because of it.
+    pub fn ready(&self) -> bool {
+        self.crux.status == Status::Up
+    }
+
+    pub fn to_edn(&self) -> String {
+        format!(
+            "{{:status {}, :checks {{:crux {{:status :{}, :latency-ms {}, :error {}}}, :crux-client {{:status {}, :max-concurrency {}, :in-flight {}}}}}}}",
+            if self.ready() { ":ready" } else { ":not-ready" },
+            self.crux.status.name(),
+            self.crux.latency.as_millis(),
+            self.crux
+                .error
+                .as_ref()
+                .map_or_else(|| String::from("nil"), |error| format!("{:?}", error)),
+            if self.pool.saturated() { ":saturated" } else { ":up" },
+            self.pool.max_concurrency,
+            self.pool.in_flight,
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"status\": \"{}\", \"checks\": {{\"crux\": {{\"status\": \"{}\", \"latency_ms\": {}, \"error\": {}}}, \"crux_client\": {{\"status\": \"{}\", \"max_concurrency\": {}, \"in_flight\": {}}}}}}}",
+            if self.ready() { "ready" } else { "not-ready" },
+            self.crux.status.name(),
+            self.crux.latency.as_millis(),
+            self.crux
+                .error
+                .as_ref()
+                .map_or_else(|| String::from("null"), |error| json_string(error)),
+            if self.pool.saturated() { "saturated" } else { "up" },
+            self.pool.max_concurrency,
+            self.pool.in_flight,
+        )
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readiness(status: Status, error: Option<&str>) -> Readiness {
+        Readiness {
+            crux: Check {
+                status,
+                latency: Duration::from_millis(12),
+                error: error.map(String::from),
+            },
+            pool: Pool {
+                max_concurrency: 4,
+                in_flight: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn ready_bodies() {
+        let readiness = readiness(Status::Up, None);
+
+        assert!(readiness.ready());
+        assert_eq!(
+            readiness.to_json(),
+            "{\"status\": \"ready\", \"checks\": {\"crux\": {\"status\": \"up\", \"latency_ms\": 12, \"error\": null}, \"crux_client\": {\"status\": \"up\", \"max_concurrency\": 4, \"in_flight\": 1}}}"
+        );
+        assert_eq!(
+            readiness.to_edn(),
+            "{:status :ready, :checks {:crux {:status :up, :latency-ms 12, :error nil}, :crux-client {:status :up, :max-concurrency 4, :in-flight 1}}}"
+        );
+    }
+
+    #[test]
+    fn not_ready_bodies_escape_the_error() {
+        let readiness = readiness(Status::Down, Some("said \"no\"\n\tat C:\\crux"));
+
+        assert!(!readiness.ready());
+        assert_eq!(
+            readiness.to_json(),
+            "{\"status\": \"not-ready\", \"checks\": {\"crux\": {\"status\": \"down\", \"latency_ms\": 12, \"error\": \"said \\\"no\\\"\\n\\u0009at C:\\\\crux\"}, \"crux_client\": {\"status\": \"up\", \"max_concurrency\": 4, \"in_flight\": 1}}}"
+        );
+    }
+}
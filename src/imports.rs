@@ -0,0 +1,855 @@
+use actix::prelude::*;
+use chrono::{DateTime, FixedOffset, Utc};
+use edn_derive::Serialize;
+use edn_rs::Edn;
+use std::collections::HashMap;
+use std::str::FromStr;
+use transistor::edn_rs;
+use transistor::types::http::Action;
+use transistor::types::{query::Query, CruxId};
+use uuid::Uuid;
+
+use crate::audit::Audit;
+use crate::config::Config;
+use crate::crux::CruxClient;
+use crate::events::{self, AccountEvent};
+use crate::ledger::{JournalEntry, SystemAccount};
+use crate::metrics;
+use crate::products;
+use crate::webhooks;
+use crate::{DbAccount, DbAccountOperation, DbError, DbExecutor, OperationType};
+
+/// Accounts written per transaction.
+const BATCH_SIZE: usize = 100;
+
+const MAX_EXTERNAL_ID_LENGTH: usize = 64;
+
+/// Namespace of the ids derived from external ids, which mustn't change.
+const IMPORT_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x6f, 0x1c, 0x2e, 0x55, 0x9a, 0x41, 0x4d, 0x0b, 0x8e, 0x37, 0x52, 0xc4, 0x1a, 0xf0, 0x93, 0x6d,
+]);
+
+/// Columns of CSV files, the first two required.
+const COLUMNS: [&str; 4] = ["external_id", "amount", "account_type", "interest_rate"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Edn,
+}
+
+/// An account to open, read from a row of the file.
+#[derive(Clone, Debug)]
+pub struct ImportRow {
+    external_id: String,
+    amount: usize,
+    account_type: Option<String>,
+    interest_rate: Option<usize>,
+}
+
+/// Reads the rows of a file, each of them on its own so one bad row doesn't
+/// keep the others out. Fails only when the file can't be read at all.
+pub fn parse(content: &str, format: Format) -> Result<Vec<Result<ImportRow, String>>, String> {
+    match format {
+        Format::Csv => parse_csv(content),
+        Format::Edn => parse_edn(content),
+    }
+}
+
+/// Reads a CSV file with a header naming its columns, e.g.
+///
+/// ```csv
+/// external_id,amount,account_type,interest_rate
+/// LEG-0001,150000,savings,125
+/// LEG-0002,0,,
+/// ```
+fn parse_csv(content: &str) -> Result<Vec<Result<ImportRow, String>>, String> {
+    let mut records = csv_records(content)?.into_iter();
+
+    let header = records
+        .next()
+        .ok_or_else(|| String::from("the file is empty"))?
+        .into_iter()
+        .map(|column| column.trim().replace('-', "_"))
+        .collect::<Vec<String>>();
+
+    if let Some(column) = header
+        .iter()
+        .find(|column| !COLUMNS.contains(&column.as_str()))
+    {
+        return Err(format!("unknown column {}", column));
+    }
+    for column in &COLUMNS[..2] {
+        if !header.iter().any(|name| name == column) {
+            return Err(format!("missing column {}", column));
+        }
+    }
+
+    Ok(records
+        .map(|record| {
+            if record.len() != header.len() {
+                return Err(format!(
+                    "{} fields for {} columns",
+                    record.len(),
+                    header.len()
+                ));
+            }
+
+            let field = |name: &str| {
+                header
+                    .iter()
+                    .position(|column| column == name)
+                    .map(|index| record[index].trim())
+                    .filter(|value| !value.is_empty())
+            };
+            let number = |name: &str| {
+                field(name)
+                    .map(|value| {
+                        value
+                            .parse::<usize>()
+                            .map_err(|_| format!("{} {} isn't a non-negative integer", name, value))
+                    })
+                    .transpose()
+            };
+
+            Ok(ImportRow {
+                external_id: field("external_id")
+                    .ok_or_else(|| String::from("missing external_id"))?
+                    .to_string(),
+                amount: check_amount(
+                    number("amount")?.ok_or_else(|| String::from("missing amount"))?,
+                )?,
+                account_type: field("account_type").map(String::from),
+                interest_rate: number("interest_rate")?,
+            })
+        })
+        .collect())
+}
+
+/// Records of a CSV file, with fields quoted as in RFC 4180. Blank lines
+/// are skipped.
+fn csv_records(content: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            '\r' if !quoted => (),
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(String::from("unterminated quoted field"));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records.retain(|record| !(record.len() == 1 && record[0].trim().is_empty()));
+    Ok(records)
+}
+
+/// Reads a vector of maps such as
+/// `{:external-id "LEG-0001" :amount 150000 :account-type "savings"}`.
+fn parse_edn(content: &str) -> Result<Vec<Result<ImportRow, String>>, String> {
+    let edn = Edn::from_str(content).map_err(|error| format!("invalid EDN: {:?}", error))?;
+    let rows = match edn {
+        Edn::Vector(_) | Edn::List(_) => edn.iter().into_iter().flatten().collect::<Vec<&Edn>>(),
+        _ => return Err(String::from("expected a vector of accounts")),
+    };
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let string = |key: &str| match &row[key] {
+                Edn::Nil => Ok(None),
+                Edn::Str(value) => Ok(Some(value.clone())),
+                Edn::Key(value) => Ok(Some(value.trim_start_matches(':').to_string())),
+                value => Err(format!("{} {} isn't a string", key, value)),
+            };
+            let number = |key: &str| match &row[key] {
+                Edn::Nil => Ok(None),
+                Edn::UInt(value) => Ok(Some(*value)),
+                value => Err(format!("{} {} isn't a non-negative integer", key, value)),
+            };
+
+            if row.map_iter().is_none() {
+                return Err(format!("{} isn't a map", row));
+            }
+
+            Ok(ImportRow {
+                external_id: string(":external-id")?
+                    .ok_or_else(|| String::from("missing :external-id"))?,
+                amount: check_amount(
+                    number(":amount")?.ok_or_else(|| String::from("missing :amount"))?,
+                )?,
+                account_type: string(":account-type")?,
+                interest_rate: number(":interest-rate")?,
+            })
+        })
+        .collect())
+}
+
+/// Balances are signed, so amounts past the largest of them can't be one.
+fn check_amount(amount: usize) -> Result<usize, String> {
+    if amount > i64::MAX as usize {
+        Err(format!("amount {} is over {}", amount, i64::MAX))
+    } else {
+        Ok(amount)
+    }
+}
+
+fn check_external_id(external_id: &str) -> Result<(), String> {
+    let valid = external_id.len() <= MAX_EXTERNAL_ID_LENGTH
+        && external_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "external id {} should be at most {} letters, digits, -, _ or .",
+            external_id, MAX_EXTERNAL_ID_LENGTH
+        ))
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum RowStatus {
+    /// The account was opened.
+    Imported,
+    /// An account with the external id was already there.
+    Existing,
+    /// The row is invalid and was skipped.
+    Rejected,
+    /// The row is valid but couldn't be written, importing the file again
+    /// retries it.
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RowResult {
+    /// Position of the account in the file, from 1.
+    row: usize,
+    external_id: Option<String>,
+    account_id: Option<String>,
+    status: RowStatus,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ImportReport {
+    imported: usize,
+    existing: usize,
+    rejected: usize,
+    failed: usize,
+    rows: Vec<RowResult>,
+}
+
+impl ImportReport {
+    /// Whether every row is imported or was already.
+    pub fn is_complete(&self) -> bool {
+        self.rejected == 0 && self.failed == 0
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("row,external_id,account_id,status,error\n");
+
+        for result in &self.rows {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                result.row,
+                csv_field(result.external_id.as_deref().unwrap_or_default()),
+                result.account_id.as_deref().unwrap_or_default(),
+                status_name(result.status),
+                csv_field(result.error.as_deref().unwrap_or_default()),
+            ));
+        }
+
+        csv
+    }
+
+    fn push(&mut self, row: usize, external_id: Option<String>, outcome: Result<String, String>) {
+        let (status, account_id, error) = match outcome {
+            Ok(account_id) => (RowStatus::Imported, Some(account_id), None),
+            Err(error) => (RowStatus::Rejected, None, Some(error)),
+        };
+
+        self.rows.push(RowResult {
+            row,
+            external_id,
+            account_id,
+            status,
+            error,
+        });
+    }
+
+    fn count(&mut self) {
+        let rows = &self.rows;
+        let count = |status| rows.iter().filter(|r| r.status == status).count();
+
+        let (imported, existing, rejected, failed) = (
+            count(RowStatus::Imported),
+            count(RowStatus::Existing),
+            count(RowStatus::Rejected),
+            count(RowStatus::Failed),
+        );
+
+        self.imported = imported;
+        self.existing = existing;
+        self.rejected = rejected;
+        self.failed = failed;
+    }
+}
+
+fn status_name(status: RowStatus) -> &'static str {
+    match status {
+        RowStatus::Imported => "imported",
+        RowStatus::Existing => "existing",
+        RowStatus::Rejected => "rejected",
+        RowStatus::Failed => "failed",
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn without_colon(id: &CruxId) -> String {
+    edn_rs::to_string(id.clone())
+        .trim_start_matches(':')
+        .to_string()
+}
+
+/// Id of the account imported with `external_id`, the same on every import
+/// so two imports of a row running at once write the same account.
+fn account_id(external_id: &str) -> CruxId {
+    CruxId::new(&Uuid::new_v5(&IMPORT_NAMESPACE, external_id.as_bytes()).to_string())
+}
+
+/// Id of the `Create` operation of an imported account, for the same reason.
+fn create_operation_id(account_id: &CruxId) -> CruxId {
+    let name = format!("{}/create", without_colon(account_id));
+
+    CruxId::new(&Uuid::new_v5(&IMPORT_NAMESPACE, name.as_bytes()).to_string())
+}
+
+/// Ids of the accounts created with an external id, by external id.
+pub async fn existing_accounts(client: &CruxClient) -> Result<HashMap<String, String>, DbError> {
+    let query = Query::find(vec!["?account", "?external-id"])?
+        .where_clause(vec!["?account :account/external-id ?external-id"])?
+        .build()?;
+
+    Ok(client
+        .query(query)
+        .await?
+        .into_iter()
+        .map(|row| (row[1].clone(), row[0].trim_start_matches(':').to_string()))
+        .collect())
+}
+
+/// Why a row breaks the rules of its product.
+fn describe(db_error: DbError) -> String {
+    match db_error {
+        DbError::ProductRule(violation) => format!(
+            "breaks rule {} of product {}",
+            edn_rs::to_string(violation.rule),
+            violation.product
+        ),
+        db_error => format!("{:?}", db_error),
+    }
+}
+
+/// Opens an account per valid row, with a `Create` operation for its opening
+/// balance, like `POST /accounts` does. Rows whose external id already has
+/// an account are left alone, so a file can be imported again after a
+/// failure. Account ids are derived from external ids and only put if
+/// they're not there yet, so imports of the same row running at once write
+/// one account rather than two, and never reset one opened meanwhile.
+///
+/// Rows are checked first, then written `BATCH_SIZE` accounts per
+/// transaction. When a batch can't be written, its rows and the following
+/// ones are reported as failed.
+pub async fn run(
+    client: &CruxClient,
+    config: &Config,
+    rows: Vec<Result<ImportRow, String>>,
+    audit: &Audit,
+    publish: &dyn Fn(Vec<AccountEvent>),
+) -> Result<ImportReport, DbError> {
+    let existing = existing_accounts(client).await?;
+
+    let mut report = ImportReport::default();
+    let mut rows_by_external_id = HashMap::new();
+    let mut pending = Vec::new();
+
+    for (index, row) in rows.into_iter().enumerate() {
+        let number = index + 1;
+
+        let row = match row {
+            Ok(row) => row,
+            Err(error) => {
+                report.push(number, None, Err(error));
+                continue;
+            }
+        };
+        let external_id = Some(row.external_id.clone());
+
+        if let Err(error) = check_external_id(&row.external_id) {
+            report.push(number, external_id, Err(error));
+            continue;
+        }
+        if let Some(first) = rows_by_external_id.insert(row.external_id.clone(), number) {
+            rows_by_external_id.insert(row.external_id.clone(), first);
+            let error = format!("external id already on row {}", first);
+            report.push(number, external_id, Err(error));
+            continue;
+        }
+        if let Some(account_id) = existing.get(&row.external_id) {
+            report.push(number, external_id, Ok(account_id.clone()));
+            report.rows.last_mut().unwrap().status = RowStatus::Existing;
+            continue;
+        }
+
+        let amount = row.amount;
+        let mut db_account = DbAccount {
+            crux__db___id: account_id(&row.external_id),
+            account___amount: 0,
+            account___type: row.account_type,
+            account___limits: None,
+            account___interest_rate: row.interest_rate,
+            account___external_id: Some(row.external_id),
+        };
+
+        let checked = products::of(&config.products, &db_account).and_then(|product| {
+            product.check_operation(&OperationType::Create, false)?;
+            product.check_balance(0, amount as i64)?;
+            Ok(product.interest_rate)
+        });
+
+        match checked {
+            Ok(interest_rate) => {
+                if db_account.account___interest_rate.is_none() {
+                    db_account.account___interest_rate = interest_rate;
+                }
+                report.push(
+                    number,
+                    external_id,
+                    Ok(without_colon(&db_account.crux__db___id)),
+                );
+                pending.push((report.rows.len() - 1, db_account, amount));
+            }
+            Err(db_error) => report.push(number, external_id, Err(describe(db_error))),
+        }
+    }
+
+    let mut batches = pending.chunks(BATCH_SIZE);
+    while let Some(batch) = batches.next() {
+        if let Err(db_error) = write_new_accounts(client, batch, &mut report, audit, publish).await
+        {
+            db_error.log();
+            let error = match &db_error {
+                DbError::Crux(error) => format!("couldn't write the account: {}", error),
+                db_error => format!("couldn't write the account: {:?}", db_error),
+            };
+
+            for (index, _, _) in std::iter::once(batch).chain(batches).flatten() {
+                let result = &mut report.rows[*index];
+                if result.status == RowStatus::Existing {
+                    continue;
+                }
+                result.status = RowStatus::Failed;
+                result.account_id = None;
+                result.error = Some(error.clone());
+            }
+            break;
+        }
+    }
+
+    report.count();
+    Ok(report)
+}
+
+/// Writes the accounts of `batch`, reporting those another import opened
+/// first as existing. A transaction putting an account that's there
+/// already isn't committed at all, so it's tried again without them.
+async fn write_new_accounts(
+    client: &CruxClient,
+    batch: &[(usize, DbAccount, usize)],
+    report: &mut ImportReport,
+    audit: &Audit,
+    publish: &dyn Fn(Vec<AccountEvent>),
+) -> Result<(), DbError> {
+    let mut batch = batch.to_vec();
+
+    while !batch.is_empty() {
+        if write_batch(client, &batch, audit, publish).await? {
+            return Ok(());
+        }
+
+        let existing = existing_accounts(client).await?;
+        let before = batch.len();
+        batch.retain(|(index, db_account, _)| {
+            let opened = db_account
+                .account___external_id
+                .as_ref()
+                .is_some_and(|external_id| existing.contains_key(external_id));
+            if opened {
+                report.rows[*index].status = RowStatus::Existing;
+            }
+
+            !opened
+        });
+
+        if batch.len() == before {
+            return Err(DbError::StateConflict);
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the accounts of `batch` in a transaction, unless one of them is
+/// there already. Tells whether they were written.
+async fn write_batch(
+    client: &CruxClient,
+    batch: &[(usize, DbAccount, usize)],
+    audit: &Audit,
+    publish: &dyn Fn(Vec<AccountEvent>),
+) -> Result<bool, DbError> {
+    let tx_time = Utc::now().to_string();
+    let mut accounts = Vec::new();
+    let mut operations = Vec::new();
+    let mut actions = Vec::new();
+
+    for (_, db_account, amount) in batch {
+        let mut db_account = db_account.clone();
+
+        let entry = JournalEntry::new()
+            .debit(&SystemAccount::CashIn.id(), *amount)
+            .credit(&db_account.crux__db___id, *amount);
+        entry.post(&mut [&mut db_account])?;
+
+        let account_operation = DbAccountOperation {
+            crux__db___id: create_operation_id(&db_account.crux__db___id),
+            account_operation___type: OperationType::Create,
+            account_operation___amount: *amount,
+            account_operation___source_account_id: db_account.crux__db___id.clone(),
+            account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(entry.into_postings()),
+            account_operation___request_id: audit.request_id.clone(),
+            account_operation___audit: Some(audit.record.clone()),
+            tx___tx_time: Some(tx_time.clone()),
+        };
+
+        actions.push(Action::Match(
+            edn_rs::to_string(db_account.crux__db___id.clone()),
+            String::from("nil"),
+            None,
+        ));
+        actions.push(Action::Put(edn_rs::to_string(db_account.clone()), None));
+        actions.push(Action::Put(
+            edn_rs::to_string(account_operation.clone()),
+            Some(tx_time.parse::<DateTime<FixedOffset>>().unwrap()),
+        ));
+        accounts.push(db_account);
+        operations.push(account_operation);
+    }
+
+    actions.extend(webhooks::enqueue(client, &operations).await?);
+
+    if !client.tx_log_matched(actions).await? {
+        return Ok(false);
+    }
+    metrics::record_operations(&operations);
+    publish(events::account_events(
+        &operations,
+        &accounts.iter().collect::<Vec<&DbAccount>>(),
+    ));
+
+    Ok(true)
+}
+
+pub(crate) struct ImportAccounts {
+    pub rows: Vec<Result<ImportRow, String>>,
+    pub audit: Audit,
+}
+
+impl Message for ImportAccounts {
+    type Result = Result<ImportReport, DbError>;
+}
+
+impl Handler<ImportAccounts> for DbExecutor {
+    type Result = ResponseFuture<Result<ImportReport, DbError>>;
+
+    fn handle(&mut self, msg: ImportAccounts, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+        let config = self.1.clone();
+        let broker = self.2.clone();
+
+        Box::pin(async move {
+            run(&client, &config, msg.rows, &msg.audit, &|events| {
+                broker.do_send(events::Publish(events))
+            })
+            .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Channel;
+
+    fn rows(parsed: Vec<Result<ImportRow, String>>) -> Vec<Result<(String, usize), String>> {
+        parsed
+            .into_iter()
+            .map(|row| row.map(|row| (row.external_id, row.amount)))
+            .collect()
+    }
+
+    #[test]
+    fn csv_rows_are_read_on_their_own() {
+        let content = "external_id,amount,account_type,interest_rate\r\n\
+                       LEG-0001,150000,savings,125\r\n\
+                       \r\n\
+                       \"LEG-0002\",0,,\n\
+                       LEG-0003,-5,,\n\
+                       LEG-0004,1\n\
+                       ,10,,\n";
+        let parsed = parse(content, Format::Csv).unwrap();
+
+        let first = parsed[0].as_ref().unwrap();
+        assert_eq!(first.account_type.as_deref(), Some("savings"));
+        assert_eq!(first.interest_rate, Some(125));
+        assert_eq!(
+            rows(parsed),
+            vec![
+                Ok((String::from("LEG-0001"), 150000)),
+                Ok((String::from("LEG-0002"), 0)),
+                Err(String::from("amount -5 isn't a non-negative integer")),
+                Err(String::from("2 fields for 4 columns")),
+                Err(String::from("missing external_id")),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_fields_may_be_quoted() {
+        let records = csv_records("a,\"b,\"\"c\"\"\nd\"\n,e\n").unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                vec![String::from("a"), String::from("b,\"c\"\nd")],
+                vec![String::new(), String::from("e")],
+            ]
+        );
+        assert!(csv_records("a,\"b").is_err());
+    }
+
+    #[test]
+    fn csv_headers_are_checked() {
+        assert_eq!(
+            parse("external-id,amount\nLEG-0001,1\n", Format::Csv)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            parse("external_id,balance\n", Format::Csv).unwrap_err(),
+            "unknown column balance"
+        );
+        assert_eq!(
+            parse("external_id\n", Format::Csv).unwrap_err(),
+            "missing column amount"
+        );
+        assert_eq!(parse("\n", Format::Csv).unwrap_err(), "the file is empty");
+    }
+
+    #[test]
+    fn edn_rows_are_read_on_their_own() {
+        let content =
+            "[{:external-id \"LEG-0001\" :amount 150000 :account-type :savings :interest-rate 125}
+                        {:external-id \"LEG-0002\" :amount 0}
+                        {:external-id \"LEG-0003\" :amount 1.5}
+                        {:amount 1}
+                        42]";
+        let parsed = parse(content, Format::Edn).unwrap();
+
+        let first = parsed[0].as_ref().unwrap();
+        assert_eq!(first.account_type.as_deref(), Some("savings"));
+        assert_eq!(first.interest_rate, Some(125));
+        assert_eq!(
+            rows(parsed),
+            vec![
+                Ok((String::from("LEG-0001"), 150000)),
+                Ok((String::from("LEG-0002"), 0)),
+                Err(String::from(":amount 1.5 isn't a non-negative integer")),
+                Err(String::from("missing :external-id")),
+                Err(String::from("42 isn't a map")),
+            ]
+        );
+        assert!(parse("{:external-id \"LEG-0001\"}", Format::Edn).is_err());
+    }
+
+    #[test]
+    fn external_ids_are_checked() {
+        assert!(check_external_id("LEG_0001.a-b").is_ok());
+        assert!(check_external_id("LEG 0001").is_err());
+        assert!(check_external_id(&"a".repeat(MAX_EXTERNAL_ID_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn ids_are_derived_from_external_ids() {
+        assert_eq!(account_id("LEG-0001"), account_id("LEG-0001"));
+        assert_ne!(account_id("LEG-0001"), account_id("LEG-0002"));
+        assert_ne!(
+            create_operation_id(&account_id("LEG-0001")),
+            account_id("LEG-0001")
+        );
+    }
+
+    #[test]
+    fn imported_rows_are_left_alone_the_next_time() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let config = Config::default();
+            let audit = Audit::new(Channel::Admin);
+            let content = "external_id,amount\nLEG-0001,100\nLEG-0001,5\n";
+
+            let import = || async {
+                let rows = parse(content, Format::Csv).unwrap();
+                run(&client, &config, rows, &audit, &|_| ()).await.unwrap()
+            };
+
+            let first = import().await;
+            let statuses = |report: &ImportReport| {
+                report
+                    .rows
+                    .iter()
+                    .map(|row| row.status)
+                    .collect::<Vec<RowStatus>>()
+            };
+            assert_eq!(
+                statuses(&first),
+                vec![RowStatus::Imported, RowStatus::Rejected]
+            );
+
+            let second = import().await;
+            assert_eq!(
+                statuses(&second),
+                vec![RowStatus::Existing, RowStatus::Rejected]
+            );
+            assert_eq!(first.rows[0].account_id, second.rows[0].account_id);
+
+            let account: DbAccount = client
+                .entity(edn_rs::to_string(account_id("LEG-0001")))
+                .await
+                .map(|edn| edn_rs::from_edn(&edn).unwrap())
+                .unwrap();
+            assert_eq!(account.account___amount, 100);
+        });
+    }
+
+    #[test]
+    fn amounts_past_the_largest_balance_are_rejected() {
+        let over = i64::MAX as usize + 1;
+        let error = Err(format!("amount {} is over {}", over, i64::MAX));
+
+        let csv = format!(
+            "external_id,amount\nLEG-0001,{}\nLEG-0002,{}\n",
+            over,
+            i64::MAX
+        );
+        assert_eq!(
+            rows(parse(&csv, Format::Csv).unwrap()),
+            vec![
+                error.clone(),
+                Ok((String::from("LEG-0002"), i64::MAX as usize))
+            ]
+        );
+
+        let edn = format!("[{{:external-id \"LEG-0001\" :amount {}}}]", over);
+        assert_eq!(rows(parse(&edn, Format::Edn).unwrap()), vec![error]);
+    }
+
+    #[test]
+    fn accounts_opened_meanwhile_are_left_alone() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let audit = Audit::new(Channel::Admin);
+
+            // Opened by another import, then deposited to.
+            let opened = DbAccount {
+                crux__db___id: account_id("LEG-0001"),
+                account___amount: 150,
+                account___type: None,
+                account___limits: None,
+                account___interest_rate: None,
+                account___external_id: Some(String::from("LEG-0001")),
+            };
+            client
+                .tx_log(vec![Action::Put(edn_rs::to_string(opened.clone()), None)])
+                .await
+                .unwrap();
+
+            let mut report = ImportReport::default();
+            let mut batch = Vec::new();
+            for (index, external_id) in ["LEG-0001", "LEG-0002"].iter().enumerate() {
+                let account_id = account_id(external_id);
+                report.push(index + 1, None, Ok(without_colon(&account_id)));
+                let db_account = DbAccount {
+                    crux__db___id: account_id,
+                    account___amount: 0,
+                    account___external_id: Some(external_id.to_string()),
+                    ..opened.clone()
+                };
+                batch.push((index, db_account, 100));
+            }
+
+            write_new_accounts(&client, &batch, &mut report, &audit, &|_| ())
+                .await
+                .unwrap();
+            assert_eq!(report.rows[0].status, RowStatus::Existing);
+            assert_eq!(report.rows[1].status, RowStatus::Imported);
+
+            let amount = |external_id| {
+                let client = client.clone();
+                async move {
+                    let edn = client
+                        .entity(edn_rs::to_string(account_id(external_id)))
+                        .await
+                        .unwrap();
+                    edn_rs::from_edn::<DbAccount>(&edn)
+                        .unwrap()
+                        .account___amount
+                }
+            };
+            assert_eq!(amount("LEG-0001").await, 150);
+            assert_eq!(amount("LEG-0002").await, 100);
+        });
+    }
+}
@@ -0,0 +1,628 @@
+use actix::prelude::*;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Utc};
+use edn_derive::{Deserialize, Serialize};
+use edn_rs::{Edn, EdnError};
+use std::convert::TryFrom;
+use std::time::Duration;
+use transistor::edn_rs;
+use transistor::types::http::{Action, Order};
+use transistor::types::{query::Query, CruxId};
+
+use crate::audit::{Audit, Channel};
+use crate::config::Config;
+use crate::crux::CruxClient;
+use crate::events::{self, AccountEvent};
+use crate::ledger::{self, JournalEntry, SystemAccount};
+use crate::metrics::{self, Timed};
+use crate::webhooks;
+use crate::{DbAccountOperation, DbError, DbExecutor, OperationType};
+
+/// Accruals are kept in millionths of the account unit and only rounded when
+/// they are paid.
+const MICROS: u128 = 1_000_000;
+
+/// How often the scheduler checks for days left to accrue.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DayCount {
+    /// Actual days over a 365 day year.
+    Act365,
+    /// Actual days over a 360 day year.
+    Act360,
+    /// Actual days over the actual length of the year.
+    ActAct,
+    /// Every month counts as 30 days of a 360 day year.
+    Thirty360,
+}
+
+impl DayCount {
+    /// Fraction of the annual rate earned on `day`, as numerator and denominator.
+    fn day_fraction(self, day: NaiveDate) -> (u128, u128) {
+        match self {
+            DayCount::Act365 => (1, 365),
+            DayCount::Act360 => (1, 360),
+            DayCount::ActAct if is_leap_year(day.year()) => (1, 366),
+            DayCount::ActAct => (1, 365),
+            DayCount::Thirty360 => {
+                let last_of_month = day.succ().month() != day.month();
+
+                if day.day() == 31 {
+                    (0, 360)
+                } else if day.month() == 2 && last_of_month {
+                    (1 + 30 - day.day() as u128, 360)
+                } else {
+                    (1, 360)
+                }
+            }
+        }
+    }
+}
+
+fn is_leap_year(year: i32) -> bool {
+    NaiveDate::from_ymd_opt(year, 2, 29).is_some()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rounding {
+    HalfUp,
+    HalfEven,
+    Down,
+    Up,
+}
+
+impl Rounding {
+    pub fn divide(self, numerator: u128, denominator: u128) -> u128 {
+        let quotient = numerator / denominator;
+        let remainder = numerator % denominator;
+
+        let round_up = match self {
+            Rounding::Down => false,
+            Rounding::Up => remainder > 0,
+            Rounding::HalfUp => remainder * 2 >= denominator,
+            Rounding::HalfEven => {
+                remainder * 2 > denominator || (remainder * 2 == denominator && quotient % 2 == 1)
+            }
+        };
+
+        if round_up {
+            quotient + 1
+        } else {
+            quotient
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterestConfig {
+    pub day_count: DayCount,
+    pub rounding: Rounding,
+}
+
+impl Default for InterestConfig {
+    fn default() -> Self {
+        Self {
+            day_count: DayCount::Act365,
+            rounding: Rounding::HalfEven,
+        }
+    }
+}
+
+impl InterestConfig {
+    /// Interest earned on `day` by `balance` at `rate` basis points a year,
+    /// in millionths of the account unit, saturating at `usize::MAX`.
+    fn accrual_micros(&self, balance: usize, rate: usize, day: NaiveDate) -> usize {
+        let (numerator, denominator) = self.day_count.day_fraction(day);
+
+        usize::try_from(self.rounding.divide(
+            balance as u128 * rate as u128 * numerator * MICROS,
+            10_000 * denominator,
+        ))
+        .unwrap_or(usize::MAX)
+    }
+
+    /// Reads `{:day-count :act-365 :rounding :half-even}`, both optional.
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        let default = Self::default();
+
+        let day_count = match &edn[":day-count"] {
+            Edn::Nil => default.day_count,
+            Edn::Key(k) if k == ":act-365" => DayCount::Act365,
+            Edn::Key(k) if k == ":act-360" => DayCount::Act360,
+            Edn::Key(k) if k == ":act-act" => DayCount::ActAct,
+            Edn::Key(k) if k == ":30-360" => DayCount::Thirty360,
+            other => {
+                return Err(EdnError::Deserialize(format!(
+                    "couldn't convert {} into a day count convention",
+                    other
+                )))
+            }
+        };
+
+        let rounding = match &edn[":rounding"] {
+            Edn::Nil => default.rounding,
+            Edn::Key(k) if k == ":half-up" => Rounding::HalfUp,
+            Edn::Key(k) if k == ":half-even" => Rounding::HalfEven,
+            Edn::Key(k) if k == ":down" => Rounding::Down,
+            Edn::Key(k) if k == ":up" => Rounding::Up,
+            other => {
+                return Err(EdnError::Deserialize(format!(
+                    "couldn't convert {} into a rounding mode",
+                    other
+                )))
+            }
+        };
+
+        Ok(Self {
+            day_count,
+            rounding,
+        })
+    }
+}
+
+/// Interest earned by an account on a single day.
+///
+/// Its id is derived from the account and the day, so accruing the same day
+/// twice writes the same document.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbInterestAccrual {
+    crux__db___id: CruxId,                   // :crux.db/id
+    interest_accrual___account_id: CruxId,   // :interest-accrual/account-id
+    interest_accrual___date: String,         // :interest-accrual/date
+    interest_accrual___balance: usize,       // :interest-accrual/balance
+    interest_accrual___rate: usize,          // :interest-accrual/rate
+    interest_accrual___amount_micros: usize, // :interest-accrual/amount-micros
+}
+
+/// Where the accrual job stopped for an account.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbInterestState {
+    crux__db___id: CruxId,                            // :crux.db/id
+    interest_state___account_id: CruxId,              // :interest-state/account-id
+    interest_state___accrued_through: Option<String>, // :interest-state/accrued-through
+    interest_state___unpaid_micros: usize,            // :interest-state/unpaid-micros
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct InterestRun {
+    accounts: usize,
+    accrued_days: usize,
+    capitalized: usize,
+    failed: usize,
+}
+
+fn state_id(account_id: &CruxId) -> CruxId {
+    CruxId::new(&format!("interest-state-{}", without_colon(account_id)))
+}
+
+fn accrual_id(account_id: &CruxId, day: NaiveDate) -> CruxId {
+    CruxId::new(&format!(
+        "interest-accrual-{}-{}",
+        without_colon(account_id),
+        day
+    ))
+}
+
+fn capitalization_id(account_id: &CruxId, day: NaiveDate) -> CruxId {
+    CruxId::new(&format!(
+        "interest-{}-{}",
+        without_colon(account_id),
+        day.format("%Y-%m")
+    ))
+}
+
+fn without_colon(id: &CruxId) -> String {
+    let mut id_without_colon = edn_rs::to_string(id.clone());
+    id_without_colon.remove(0);
+    id_without_colon
+}
+
+/// Accrues every interest-bearing account for each full day up to `today`
+/// (excluded) that wasn't accrued yet, and capitalizes the months that ended
+/// along the way. `publish` gets the events of the interest paid, and the
+/// operations are stored with `audit`. An account that fails is logged and
+/// counted as failed, and the others are still accrued.
+pub async fn run(
+    client: &CruxClient,
+    config: &Config,
+    today: NaiveDate,
+    audit: &Audit,
+    publish: &dyn Fn(Vec<AccountEvent>),
+) -> Result<InterestRun, DbError> {
+    let query = Query::find(vec!["?account"])?
+        .where_clause(vec!["?account :account/interest-rate ?rate"])?
+        .build()?;
+
+    let mut interest_run = InterestRun::default();
+
+    for row in client.query(query).await? {
+        let account_id = CruxId::new(&row[0]);
+
+        match run_account(client, &config.interest, &account_id, today, audit, publish).await {
+            Ok((accrued_days, capitalized)) => {
+                interest_run.accounts += 1;
+                interest_run.accrued_days += accrued_days;
+                interest_run.capitalized += capitalized;
+            }
+            Err(error) => {
+                tracing::error!(account = %row[0], error = ?error, "interest accrual failed");
+                interest_run.failed += 1;
+            }
+        }
+    }
+
+    Ok(interest_run)
+}
+
+async fn run_account(
+    client: &CruxClient,
+    config: &InterestConfig,
+    account_id: &CruxId,
+    today: NaiveDate,
+    audit: &Audit,
+    publish: &dyn Fn(Vec<AccountEvent>),
+) -> Result<(usize, usize), DbError> {
+    let _locks = ledger::lock(std::slice::from_ref(account_id)).await;
+
+    let mut db_account = ledger::account(client, account_id)
+        .await?
+        .ok_or(DbError::NilEntity)?;
+
+    let rate = match db_account.account___interest_rate {
+        Some(rate) => rate,
+        None => return Ok((0, 0)),
+    };
+
+    let crux_state = client
+        .entity(edn_rs::to_string(state_id(account_id)))
+        .await?;
+    let mut state = if crux_state == Edn::Nil {
+        DbInterestState {
+            crux__db___id: state_id(account_id),
+            interest_state___account_id: account_id.clone(),
+            interest_state___accrued_through: None,
+            interest_state___unpaid_micros: 0,
+        }
+    } else {
+        edn_rs::from_edn(&crux_state)?
+    };
+
+    // Balance of the account after each write, oldest first. Overdrawn
+    // balances don't earn anything.
+    let balances = client
+        .entity_history(edn_rs::to_string(account_id.clone()), Order::Asc, true)
+        .await?
+        .history
+        .into_iter()
+        .map(|h| {
+            let amount = h
+                .db__doc
+                .and_then(|doc| doc[":account/amount"].to_int())
+                .map_or(0, |amount| amount.max(0) as usize);
+
+            (
+                h.db___valid_time.with_timezone(&Utc).naive_utc().date(),
+                amount,
+            )
+        })
+        .collect::<Vec<(NaiveDate, usize)>>();
+
+    let first_day = match &state.interest_state___accrued_through {
+        Some(day) => day
+            .parse::<NaiveDate>()
+            .map_err(|_| DbError::EdnError(EdnError::Deserialize(day.clone())))?
+            .succ(),
+        None => match balances.first() {
+            Some((created, _)) => *created,
+            None => return Ok((0, 0)),
+        },
+    };
+
+    // Everything below is written in a single transaction.
+    let tx_time = Utc::now().to_string();
+    let mut actions = Vec::new();
+    let mut operations = Vec::new();
+    let mut accrued_days = 0;
+    let mut capitalized = 0;
+    // Interest paid by this run isn't in the history yet, but still compounds.
+    let mut paid = 0;
+
+    let mut day = first_day;
+    while day < today {
+        let end_of_day_balance = balances
+            .iter()
+            .take_while(|(date, _)| *date <= day)
+            .last()
+            .map_or(0, |(_, amount)| *amount)
+            + paid;
+
+        let micros = config.accrual_micros(end_of_day_balance, rate, day);
+
+        actions.push(Action::Put(
+            edn_rs::to_string(DbInterestAccrual {
+                crux__db___id: accrual_id(account_id, day),
+                interest_accrual___account_id: account_id.clone(),
+                interest_accrual___date: day.to_string(),
+                interest_accrual___balance: end_of_day_balance,
+                interest_accrual___rate: rate,
+                interest_accrual___amount_micros: micros,
+            }),
+            None,
+        ));
+        state.interest_state___unpaid_micros =
+            state.interest_state___unpaid_micros.saturating_add(micros);
+        accrued_days += 1;
+
+        let last_of_month = day.succ().month() != day.month();
+        if last_of_month {
+            let amount = config
+                .rounding
+                .divide(state.interest_state___unpaid_micros as u128, MICROS)
+                as usize;
+
+            if amount > 0 {
+                let entry = JournalEntry::new()
+                    .debit(&SystemAccount::InterestExpense.id(), amount)
+                    .credit(&db_account.crux__db___id, amount);
+                entry.post(&mut [&mut db_account])?;
+
+                let operation = DbAccountOperation {
+                    crux__db___id: capitalization_id(account_id, day),
+                    account_operation___type: OperationType::Interest,
+                    account_operation___amount: amount,
+                    account_operation___source_account_id: account_id.clone(),
+                    account_operation___target_account_id: None,
+                    account_operation___triggered_by: None,
+                    account_operation___postings: Some(entry.into_postings()),
+                    account_operation___request_id: audit.request_id.clone(),
+                    account_operation___audit: Some(audit.record.clone()),
+                    tx___tx_time: Some(tx_time.clone()),
+                };
+                actions.push(Action::Put(
+                    edn_rs::to_string(operation.clone()),
+                    Some(tx_time.parse::<DateTime<FixedOffset>>().unwrap()),
+                ));
+                operations.push(operation);
+
+                paid += amount;
+                capitalized += 1;
+            }
+
+            state.interest_state___unpaid_micros = 0;
+        }
+
+        state.interest_state___accrued_through = Some(day.to_string());
+        day = day.succ();
+    }
+
+    if accrued_days == 0 {
+        return Ok((0, 0));
+    }
+
+    if capitalized > 0 {
+        actions.push(Action::Put(edn_rs::to_string(db_account.clone()), None));
+    }
+    actions.push(Action::Put(edn_rs::to_string(state), None));
+    actions.extend(webhooks::enqueue(client, &operations).await?);
+
+    client.tx_log(actions).await?;
+    metrics::record_operations(&operations);
+    publish(events::account_events(&operations, &[&db_account]));
+
+    Ok((accrued_days, capitalized))
+}
+
+pub(crate) struct RunInterest {
+    pub today: NaiveDate,
+}
+
+impl Message for RunInterest {
+    type Result = Result<InterestRun, DbError>;
+}
+
+impl Handler<RunInterest> for DbExecutor {
+    type Result = ResponseFuture<Result<InterestRun, DbError>>;
+
+    fn handle(&mut self, msg: RunInterest, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+        let config = self.1.clone();
+        let broker = self.2.clone();
+
+        Box::pin(async move {
+            let audit = Audit::new(Channel::Scheduler);
+
+            run(&client, &config, msg.today, &audit, &|events| {
+                broker.do_send(events::Publish(events))
+            })
+            .await
+        })
+    }
+}
+
+/// Runs the interest job on startup and then every hour, so days missed
+/// while the service was down are caught up.
+pub struct InterestScheduler {
+    pub db: Addr<DbExecutor>,
+}
+
+impl Actor for InterestScheduler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.schedule(ctx);
+        ctx.run_interval(SCHEDULE_INTERVAL, |scheduler, ctx| scheduler.schedule(ctx));
+    }
+}
+
+impl InterestScheduler {
+    fn schedule(&self, ctx: &mut Context<Self>) {
+        let today = Utc::today().naive_utc();
+
+        self.db
+            .send(Timed::new(RunInterest { today }))
+            .into_actor(self)
+            .map(|result, _, _| {
+                if let Ok(Err(error)) = result {
+                    tracing::error!(error = ?error, "interest run failed");
+                }
+            })
+            .wait(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn day(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(year, month, day)
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let cases = [
+            // numerator, denominator, half-up, half-even, down, up
+            (5, 2, 3, 2, 2, 3),
+            (7, 2, 4, 4, 3, 4),
+            (7, 3, 2, 2, 2, 3),
+            (8, 3, 3, 3, 2, 3),
+            (6, 2, 3, 3, 3, 3),
+            (0, 7, 0, 0, 0, 0),
+        ];
+
+        for &(numerator, denominator, half_up, half_even, down, up) in &cases {
+            assert_eq!(Rounding::HalfUp.divide(numerator, denominator), half_up);
+            assert_eq!(Rounding::HalfEven.divide(numerator, denominator), half_even);
+            assert_eq!(Rounding::Down.divide(numerator, denominator), down);
+            assert_eq!(Rounding::Up.divide(numerator, denominator), up);
+        }
+    }
+
+    #[test]
+    fn day_fractions() {
+        assert_eq!(DayCount::Act365.day_fraction(day(2020, 3, 1)), (1, 365));
+        assert_eq!(DayCount::Act360.day_fraction(day(2020, 3, 1)), (1, 360));
+        assert_eq!(DayCount::ActAct.day_fraction(day(2020, 3, 1)), (1, 366));
+        assert_eq!(DayCount::ActAct.day_fraction(day(2021, 3, 1)), (1, 365));
+    }
+
+    #[test]
+    fn thirty_360_counts_thirty_days_a_month() {
+        let days_in = |year, month| {
+            let mut date = day(year, month, 1);
+            let mut numerator = 0;
+            while date.month() == month {
+                numerator += DayCount::Thirty360.day_fraction(date).0;
+                date = date.succ();
+            }
+            numerator
+        };
+
+        assert_eq!(days_in(2021, 1), 30);
+        assert_eq!(days_in(2021, 2), 30);
+        assert_eq!(days_in(2020, 2), 30);
+        assert_eq!(days_in(2021, 4), 30);
+    }
+
+    #[test]
+    fn accruals_are_rounded_to_micros() {
+        let config = InterestConfig {
+            day_count: DayCount::Act365,
+            rounding: Rounding::HalfEven,
+        };
+
+        // 1000 at 5% for a day is 0.136986301... units.
+        assert_eq!(config.accrual_micros(1000, 500, day(2021, 1, 1)), 136_986);
+        assert_eq!(config.accrual_micros(0, 500, day(2021, 1, 1)), 0);
+
+        let config = InterestConfig {
+            rounding: Rounding::Up,
+            ..config
+        };
+        assert_eq!(config.accrual_micros(1000, 500, day(2021, 1, 1)), 136_987);
+    }
+
+    #[test]
+    fn accruals_of_large_balances_dont_overflow() {
+        let config = InterestConfig::default();
+
+        // 100% on 365 units for a day is exactly one unit.
+        assert_eq!(
+            config.accrual_micros(365, 10_000, day(2021, 1, 1)),
+            1_000_000
+        );
+        assert_eq!(
+            config.accrual_micros(usize::MAX / 2, 10_000, day(2021, 1, 1)),
+            usize::MAX
+        );
+    }
+
+    #[test]
+    fn accounts_that_fail_dont_stop_the_run() {
+        use crate::DbAccount;
+
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let mut actions = Vec::new();
+            for id in &["alice", "bob"] {
+                actions.push(Action::Put(
+                    edn_rs::to_string(DbAccount {
+                        crux__db___id: CruxId::new(id),
+                        account___amount: 1000,
+                        account___type: None,
+                        account___limits: None,
+                        account___interest_rate: Some(500),
+                        account___external_id: None,
+                    }),
+                    None,
+                ));
+            }
+            // alice's state can't be read, so only bob is accrued.
+            actions.push(Action::Put(
+                edn_rs::to_string(DbInterestState {
+                    crux__db___id: state_id(&CruxId::new("alice")),
+                    interest_state___account_id: CruxId::new("alice"),
+                    interest_state___accrued_through: Some("yesterday".to_string()),
+                    interest_state___unpaid_micros: 0,
+                }),
+                None,
+            ));
+            client.tx_log(actions).await.unwrap();
+
+            let interest_run = run(
+                &client,
+                &Config::default(),
+                Utc::today().naive_utc(),
+                &Audit::new(Channel::Admin),
+                &|_| (),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(interest_run.accounts, 1);
+            assert_eq!(interest_run.failed, 1);
+        });
+    }
+
+    #[test]
+    fn config_is_read_from_settings() {
+        let config = InterestConfig::from_settings(
+            &Edn::from_str("{:day-count :30-360 :rounding :up}").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(config.day_count, DayCount::Thirty360);
+        assert_eq!(config.rounding, Rounding::Up);
+
+        let config = InterestConfig::from_settings(&Edn::from_str("{}").unwrap()).unwrap();
+        assert_eq!(config.day_count, DayCount::Act365);
+        assert_eq!(config.rounding, Rounding::HalfEven);
+
+        assert!(
+            InterestConfig::from_settings(&Edn::from_str("{:rounding :sideways}").unwrap())
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,779 @@
+use chrono::{DateTime, FixedOffset};
+use edn_derive::{Deserialize, Serialize};
+use edn_rs::Edn;
+use futures::future;
+use lazy_static::lazy_static;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use transistor::edn_rs;
+use transistor::types::{query::Query, CruxId};
+
+use crate::cache::{Lookup, Miss};
+use crate::crux::CruxClient;
+use crate::{DbAccount, DbAccountOperation, DbError, OperationType};
+
+/// Accounts share locks by hash, so there's no lock to keep per account.
+const LOCK_STRIPES: usize = 256;
+
+lazy_static! {
+    static ref ACCOUNT_LOCKS: Vec<Arc<Mutex<()>>> = (0..LOCK_STRIPES)
+        .map(|_| Arc::new(Mutex::new(())))
+        .collect();
+}
+
+/// The locks of the accounts an operation reads and writes, released when
+/// dropped.
+pub struct AccountLocks {
+    _guards: Vec<OwnedMutexGuard<()>>,
+}
+
+/// Waits until no other operation holds any of `account_ids`, then holds
+/// them, so operations on the same accounts take turns reading and writing
+/// balances instead of overwriting each other's. System accounts aren't
+/// locked: no operation writes their balances back, which are the sum of
+/// their postings.
+///
+/// Locks are taken in order, so operations waiting for each other's never
+/// get stuck. They only hold within this process.
+pub async fn lock(account_ids: &[CruxId]) -> AccountLocks {
+    let mut stripes = account_ids
+        .iter()
+        .map(|account_id| {
+            let mut hasher = DefaultHasher::new();
+            edn_rs::to_string(account_id.clone()).hash(&mut hasher);
+            hasher.finish() as usize % LOCK_STRIPES
+        })
+        .collect::<Vec<usize>>();
+    stripes.sort_unstable();
+    stripes.dedup();
+
+    let mut guards = Vec::with_capacity(stripes.len());
+    for stripe in stripes {
+        guards.push(ACCOUNT_LOCKS[stripe].clone().lock_owned().await);
+    }
+
+    AccountLocks { _guards: guards }
+}
+
+/// Accounts owned by the bank itself, used as the other leg of operations
+/// that move money in or out of the ledger.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SystemAccount {
+    CashIn,
+    CashOut,
+    FeeIncome,
+    InterestExpense,
+}
+
+impl SystemAccount {
+    pub fn id(self) -> CruxId {
+        match self {
+            SystemAccount::CashIn => CruxId::new("cash-in"),
+            SystemAccount::CashOut => CruxId::new("cash-out"),
+            SystemAccount::FeeIncome => CruxId::new("fee-income"),
+            SystemAccount::InterestExpense => CruxId::new("interest-expense"),
+        }
+    }
+
+    pub fn all() -> Vec<SystemAccount> {
+        vec![
+            SystemAccount::CashIn,
+            SystemAccount::CashOut,
+            SystemAccount::FeeIncome,
+            SystemAccount::InterestExpense,
+        ]
+    }
+
+    /// Side on which the account balance grows.
+    ///
+    /// Cash received is an asset of the bank and interest paid is an expense
+    /// (debit), everything else, including customer accounts, is owed by it
+    /// or income (credit).
+    fn normal_side(self) -> PostingSide {
+        match self {
+            SystemAccount::CashIn | SystemAccount::InterestExpense => PostingSide::Debit,
+            SystemAccount::CashOut | SystemAccount::FeeIncome => PostingSide::Credit,
+        }
+    }
+
+    pub fn from_id(id: &CruxId) -> Option<SystemAccount> {
+        SystemAccount::all().into_iter().find(|s| &s.id() == id)
+    }
+}
+
+/// Fetches an account to answer with, from the client's cache when it has a
+/// fresh copy. The copy may miss writes other processes made, so operations
+/// changing the account read it with [`account`] instead.
+pub async fn cached_account(
+    client: &CruxClient,
+    id: &CruxId,
+) -> Result<Option<DbAccount>, DbError> {
+    match client.accounts().get(id) {
+        Lookup::Hit(account) => Ok(Some(account)),
+        Lookup::Miss(miss) => fetch_account(client, miss).await,
+    }
+}
+
+/// Fetches the current version of an account from Crux, for operations
+/// writing it back.
+pub async fn account(client: &CruxClient, id: &CruxId) -> Result<Option<DbAccount>, DbError> {
+    fetch_account(client, client.accounts().miss(id)).await
+}
+
+async fn fetch_account(client: &CruxClient, miss: Miss) -> Result<Option<DbAccount>, DbError> {
+    let crux_account = client.entity(edn_rs::to_string(miss.id().clone())).await?;
+
+    if crux_account == Edn::Nil {
+        return Ok(None);
+    }
+
+    let account: DbAccount = edn_rs::from_edn(&crux_account)?;
+    client.accounts().fill(miss, account.clone());
+
+    Ok(Some(account))
+}
+
+/// A system account as it is answered, with the balance its postings sum
+/// to. System accounts have no documents of their own.
+pub fn system_account(system: SystemAccount, balance: i64) -> DbAccount {
+    DbAccount {
+        crux__db___id: system.id(),
+        account___amount: balance,
+        account___type: None,
+        account___limits: None,
+        account___interest_rate: None,
+        account___external_id: None,
+    }
+}
+
+fn normal_side(account_id: &CruxId) -> PostingSide {
+    SystemAccount::from_id(account_id)
+        .map(SystemAccount::normal_side)
+        .unwrap_or(PostingSide::Credit)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PostingSide {
+    Debit,
+    Credit,
+}
+
+documented!(
+    enum PostingSide {
+        Debit,
+        Credit,
+    }
+);
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbPosting {
+    pub posting___account_id: CruxId, // :posting/account-id
+    pub posting___side: PostingSide,  // :posting/side
+    pub posting___amount: usize,      // :posting/amount
+}
+
+impl DbPosting {
+    /// How much this posting changes the account balance, signed. Amounts
+    /// past what a balance holds stop at its bounds.
+    pub fn signed_amount(&self) -> i64 {
+        let amount = i64::try_from(self.posting___amount).unwrap_or(i64::MAX);
+
+        if self.posting___side == normal_side(&self.posting___account_id) {
+            amount
+        } else {
+            -amount
+        }
+    }
+}
+
+/// The postings of a single operation. It can only be posted once debits and
+/// credits sum to the same amount.
+#[derive(Clone, Debug, Default)]
+pub struct JournalEntry {
+    postings: Vec<DbPosting>,
+}
+
+impl JournalEntry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn debit(self, account_id: &CruxId, amount: usize) -> Self {
+        self.push(account_id, PostingSide::Debit, amount)
+    }
+
+    pub fn credit(self, account_id: &CruxId, amount: usize) -> Self {
+        self.push(account_id, PostingSide::Credit, amount)
+    }
+
+    fn push(mut self, account_id: &CruxId, side: PostingSide, amount: usize) -> Self {
+        self.postings.push(DbPosting {
+            posting___account_id: account_id.clone(),
+            posting___side: side,
+            posting___amount: amount,
+        });
+        self
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        let sum = |side| {
+            self.postings
+                .iter()
+                .filter(|p| p.posting___side == side)
+                .map(|p| p.posting___amount as u128)
+                .sum::<u128>()
+        };
+
+        sum(PostingSide::Debit) == sum(PostingSide::Credit)
+    }
+
+    /// Applies every posting to its account, all or nothing.
+    ///
+    /// Postings to system accounts aren't applied to any document: their
+    /// balances are the sum of their postings, so operations don't read and
+    /// write back the accounts they all share. Customer balances are bounded
+    /// by their product, which callers check.
+    pub fn post(&self, accounts: &mut [&mut DbAccount]) -> Result<(), DbError> {
+        if !self.is_balanced() {
+            return Err(DbError::UnbalancedEntry);
+        }
+
+        let mut balances = accounts
+            .iter()
+            .map(|a| a.account___amount)
+            .collect::<Vec<i64>>();
+
+        for posting in &self.postings {
+            if SystemAccount::from_id(&posting.posting___account_id).is_some() {
+                continue;
+            }
+
+            let index = accounts
+                .iter()
+                .position(|a| a.crux__db___id == posting.posting___account_id)
+                .ok_or(DbError::UnbalancedEntry)?;
+
+            balances[index] = balances[index].saturating_add(posting.signed_amount());
+        }
+
+        for (account, balance) in accounts.iter_mut().zip(balances) {
+            account.account___amount = balance;
+        }
+
+        Ok(())
+    }
+
+    pub fn into_postings(self) -> Vec<DbPosting> {
+        self.postings
+    }
+}
+
+/// The entry an operation written before the ledger carried postings stood
+/// for, against the system accounts operations are posted to now. `None`
+/// for a transfer without target.
+pub fn legacy_entry(operation: &DbAccountOperation) -> Option<JournalEntry> {
+    let account_id = &operation.account_operation___source_account_id;
+    let amount = operation.account_operation___amount;
+    let entry = JournalEntry::new();
+
+    Some(match operation.account_operation___type {
+        OperationType::Create | OperationType::Deposit => entry
+            .debit(&SystemAccount::CashIn.id(), amount)
+            .credit(account_id, amount),
+        OperationType::Withdraw => entry
+            .debit(account_id, amount)
+            .credit(&SystemAccount::CashOut.id(), amount),
+        OperationType::Transfer => entry.debit(account_id, amount).credit(
+            operation.account_operation___target_account_id.as_ref()?,
+            amount,
+        ),
+        OperationType::Fee => entry
+            .debit(account_id, amount)
+            .credit(&SystemAccount::FeeIncome.id(), amount),
+        OperationType::Interest => entry
+            .debit(&SystemAccount::InterestExpense.id(), amount)
+            .credit(account_id, amount),
+    })
+}
+
+/// The postings of `operation`, replayed from its type if it was written
+/// before the ledger carried postings.
+pub fn postings(operation: &DbAccountOperation) -> Vec<DbPosting> {
+    match &operation.account_operation___postings {
+        Some(postings) => postings.clone(),
+        None => legacy_entry(operation)
+            .map(JournalEntry::into_postings)
+            .unwrap_or_default(),
+    }
+}
+
+/// How much `operation` moved the balance of `account_id`.
+pub fn balance_change(operation: &DbAccountOperation, account_id: &CruxId) -> i64 {
+    if operation.account_operation___postings.is_none()
+        && operation.account_operation___type == OperationType::Transfer
+        && operation.account_operation___target_account_id.is_none()
+    {
+        // A transfer without target only ever left its source.
+        return if &operation.account_operation___source_account_id == account_id {
+            -(operation.account_operation___amount as i64)
+        } else {
+            0
+        };
+    }
+
+    postings(operation)
+        .iter()
+        .filter(|p| &p.posting___account_id == account_id)
+        .map(|p| p.signed_amount())
+        .sum()
+}
+
+/// Every operation where `account_id` is either the source or the target,
+/// oldest first.
+pub async fn operations_touching(
+    client: &CruxClient,
+    account_id: &CruxId,
+) -> Result<Vec<DbAccountOperation>, DbError> {
+    let mut account_id_without_colon = edn_rs::to_string(account_id.clone());
+    account_id_without_colon.remove(0);
+
+    let mut operations = Vec::new();
+
+    for attribute in &["source-account-id", "target-account-id"] {
+        let query = Query::find(vec!["?account-operation"])?
+            .where_clause(vec![&format!(
+                "?account-operation :account-operation/{} ?account-id",
+                attribute
+            )])?
+            .args(vec![&format!("?account-id :{}", account_id_without_colon)])?
+            .build()?;
+
+        let rows = client.query(query).await?;
+        let crux_operations = future::try_join_all(
+            rows.iter()
+                .map(|row| client.entity(edn_rs::to_string(CruxId::new(&row[0])))),
+        )
+        .await?;
+
+        for crux_operation in crux_operations {
+            let operation: DbAccountOperation = edn_rs::from_edn(&crux_operation)?;
+
+            operations.push(operation);
+        }
+    }
+
+    operations.sort_by_key(|operation| {
+        operation
+            .tx___tx_time
+            .as_ref()
+            .and_then(|time| time.parse::<DateTime<FixedOffset>>().ok())
+    });
+    operations.dedup_by(|a, b| a.crux__db___id == b.crux__db___id);
+
+    Ok(operations)
+}
+
+/// Operations read per query when paging through those of an account.
+pub const OPERATION_PAGE_SIZE: usize = 500;
+
+/// The operations where an account is either the source or the target,
+/// oldest first unless made with `newest_first`, read from Crux a page at a
+/// time so they're never held all at once. Operations without a transaction
+/// time are left out.
+pub struct OperationPages {
+    client: CruxClient,
+    account_id: CruxId,
+    descending: bool,
+    /// Where the account is the source, and where it's the target.
+    sides: [Side; 2],
+}
+
+/// The operations of one attribute, read ahead up to a page.
+struct Side {
+    attribute: &'static str,
+    descending: bool,
+    offset: usize,
+    /// Transaction times and ids of the operations read but not taken.
+    pending: VecDeque<(String, String)>,
+    exhausted: bool,
+}
+
+impl Side {
+    fn new(attribute: &'static str, descending: bool) -> Self {
+        Self {
+            attribute,
+            descending,
+            offset: 0,
+            pending: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// The next operation, reading the next page when the last one is used
+    /// up.
+    async fn peek(
+        &mut self,
+        client: &CruxClient,
+        account_id: &CruxId,
+    ) -> Result<Option<(String, String)>, DbError> {
+        if self.pending.is_empty() && !self.exhausted {
+            let mut account_id_without_colon = edn_rs::to_string(account_id.clone());
+            account_id_without_colon.remove(0);
+
+            // Transaction times are written in UTC, so they sort as they're
+            // written.
+            let query = Query::find(vec!["?tx-time", "?account-operation"])?
+                .where_clause(vec![
+                    &format!(
+                        "?account-operation :account-operation/{} ?account-id",
+                        self.attribute
+                    ),
+                    "?account-operation :tx/tx-time ?tx-time",
+                ])?
+                .args(vec![&format!("?account-id :{}", account_id_without_colon)])?
+                .order_by(if self.descending {
+                    vec!["?tx-time :desc", "?account-operation :desc"]
+                } else {
+                    vec!["?tx-time :asc", "?account-operation :asc"]
+                })?
+                .offset(self.offset)
+                .limit(OPERATION_PAGE_SIZE)
+                .build()?;
+
+            let rows = client.query(query).await?;
+            self.offset += rows.len();
+            self.exhausted = rows.len() < OPERATION_PAGE_SIZE;
+            // The page comes back as a set, sorted oldest first whatever
+            // order it was taken in.
+            let mut rows = rows.into_iter().collect::<Vec<Vec<String>>>();
+            if self.descending {
+                rows.reverse();
+            }
+            self.pending = rows
+                .into_iter()
+                .map(|mut row| {
+                    let id = row.pop().unwrap_or_default();
+                    (row.pop().unwrap_or_default(), id)
+                })
+                .collect();
+        }
+
+        Ok(self.pending.front().cloned())
+    }
+}
+
+impl OperationPages {
+    pub fn new(client: &CruxClient, account_id: &CruxId) -> Self {
+        Self::ordered(client, account_id, false)
+    }
+
+    /// Pages of the latest operations first, to read only as far back as
+    /// needed.
+    pub fn newest_first(client: &CruxClient, account_id: &CruxId) -> Self {
+        Self::ordered(client, account_id, true)
+    }
+
+    fn ordered(client: &CruxClient, account_id: &CruxId, descending: bool) -> Self {
+        Self {
+            client: client.clone(),
+            account_id: account_id.clone(),
+            descending,
+            sides: [
+                Side::new("source-account-id", descending),
+                Side::new("target-account-id", descending),
+            ],
+        }
+    }
+
+    /// The next operations, up to `OPERATION_PAGE_SIZE` of them, none once
+    /// they've all been read.
+    pub async fn next_page(&mut self) -> Result<Vec<DbAccountOperation>, DbError> {
+        let [source, target] = &mut self.sides;
+        let mut ids = Vec::new();
+
+        while ids.len() < OPERATION_PAGE_SIZE {
+            let next = match (
+                source.peek(&self.client, &self.account_id).await?,
+                target.peek(&self.client, &self.account_id).await?,
+            ) {
+                (None, None) => break,
+                (Some(from), Some(to)) if from == to => {
+                    // A transfer between the account and itself.
+                    target.pending.pop_front();
+                    source.pending.pop_front()
+                }
+                (Some(from), Some(to)) if (from < to) != self.descending => {
+                    source.pending.pop_front()
+                }
+                (Some(_), Some(_)) | (None, Some(_)) => target.pending.pop_front(),
+                (Some(_), None) => source.pending.pop_front(),
+            };
+
+            ids.extend(next.map(|(_, id)| id));
+        }
+
+        let crux_operations = future::try_join_all(
+            ids.iter()
+                .map(|id| self.client.entity(edn_rs::to_string(CruxId::new(id)))),
+        )
+        .await?;
+
+        crux_operations
+            .iter()
+            .map(|crux_operation| Ok(edn_rs::from_edn(crux_operation)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn customer(id: &str, amount: i64) -> DbAccount {
+        DbAccount {
+            crux__db___id: CruxId::new(id),
+            account___amount: amount,
+            account___type: None,
+            account___limits: None,
+            account___interest_rate: None,
+            account___external_id: None,
+        }
+    }
+
+    fn operation(
+        operation_type: OperationType,
+        amount: usize,
+        target: Option<&str>,
+        postings: Option<Vec<DbPosting>>,
+    ) -> DbAccountOperation {
+        DbAccountOperation {
+            crux__db___id: CruxId::new("operation"),
+            account_operation___type: operation_type,
+            account_operation___amount: amount,
+            account_operation___source_account_id: CruxId::new("alice"),
+            account_operation___target_account_id: target.map(CruxId::new),
+            account_operation___triggered_by: None,
+            account_operation___postings: postings,
+            account_operation___request_id: None,
+            account_operation___audit: None,
+            tx___tx_time: None,
+        }
+    }
+
+    #[test]
+    fn post_moves_customer_balances() {
+        let mut alice = customer("alice", 100);
+        let mut bob = customer("bob", 5);
+
+        JournalEntry::new()
+            .debit(&alice.crux__db___id.clone(), 30)
+            .credit(&bob.crux__db___id.clone(), 30)
+            .post(&mut [&mut alice, &mut bob])
+            .unwrap();
+
+        assert_eq!(alice.account___amount, 70);
+        assert_eq!(bob.account___amount, 35);
+    }
+
+    #[test]
+    fn post_leaves_system_accounts_to_their_postings() {
+        let mut alice = customer("alice", 0);
+
+        JournalEntry::new()
+            .debit(&SystemAccount::CashIn.id(), 40)
+            .credit(&alice.crux__db___id.clone(), 40)
+            .post(&mut [&mut alice])
+            .unwrap();
+
+        assert_eq!(alice.account___amount, 40);
+    }
+
+    #[test]
+    fn post_rejects_unbalanced_entries_without_changing_anything() {
+        let mut alice = customer("alice", 100);
+        let mut bob = customer("bob", 0);
+
+        let result = JournalEntry::new()
+            .debit(&alice.crux__db___id.clone(), 30)
+            .credit(&bob.crux__db___id.clone(), 20)
+            .post(&mut [&mut alice, &mut bob]);
+
+        assert!(matches!(result, Err(DbError::UnbalancedEntry)));
+        assert_eq!(alice.account___amount, 100);
+        assert_eq!(bob.account___amount, 0);
+    }
+
+    #[test]
+    fn post_rejects_postings_to_accounts_not_passed() {
+        let mut alice = customer("alice", 100);
+
+        let result = JournalEntry::new()
+            .debit(&alice.crux__db___id.clone(), 30)
+            .credit(&CruxId::new("bob"), 30)
+            .post(&mut [&mut alice]);
+
+        assert!(matches!(result, Err(DbError::UnbalancedEntry)));
+        assert_eq!(alice.account___amount, 100);
+    }
+
+    #[test]
+    fn post_keeps_huge_amounts_within_the_balance() {
+        let mut alice = customer("alice", 100);
+
+        JournalEntry::new()
+            .debit(&alice.crux__db___id.clone(), usize::MAX)
+            .credit(&SystemAccount::FeeIncome.id(), usize::MAX)
+            .post(&mut [&mut alice])
+            .unwrap();
+
+        assert_eq!(alice.account___amount, 100 - i64::MAX);
+    }
+
+    #[test]
+    fn is_balanced_sums_each_side() {
+        let entry = JournalEntry::new()
+            .debit(&CruxId::new("alice"), 30)
+            .credit(&CruxId::new("bob"), 10)
+            .credit(&SystemAccount::FeeIncome.id(), 20);
+
+        assert!(entry.is_balanced());
+        assert!(!entry.debit(&CruxId::new("alice"), 1).is_balanced());
+    }
+
+    #[test]
+    fn balance_change_follows_postings() {
+        let postings = JournalEntry::new()
+            .debit(&CruxId::new("alice"), 25)
+            .credit(&SystemAccount::CashOut.id(), 25)
+            .into_postings();
+        let withdrawal = operation(OperationType::Withdraw, 25, None, Some(postings));
+
+        assert_eq!(balance_change(&withdrawal, &CruxId::new("alice")), -25);
+        assert_eq!(
+            balance_change(&withdrawal, &SystemAccount::CashOut.id()),
+            25
+        );
+        assert_eq!(balance_change(&withdrawal, &CruxId::new("bob")), 0);
+    }
+
+    #[test]
+    fn balance_change_replays_legacy_operations() {
+        let deposit = operation(OperationType::Deposit, 10, None, None);
+        assert_eq!(balance_change(&deposit, &CruxId::new("alice")), 10);
+        assert_eq!(balance_change(&deposit, &SystemAccount::CashIn.id()), 10);
+
+        let withdrawal = operation(OperationType::Withdraw, 10, None, None);
+        assert_eq!(balance_change(&withdrawal, &CruxId::new("alice")), -10);
+        assert_eq!(
+            balance_change(&withdrawal, &SystemAccount::CashOut.id()),
+            10
+        );
+
+        let transfer = operation(OperationType::Transfer, 10, Some("bob"), None);
+        assert_eq!(balance_change(&transfer, &CruxId::new("alice")), -10);
+        assert_eq!(balance_change(&transfer, &CruxId::new("bob")), 10);
+
+        let interest = operation(OperationType::Interest, 10, None, None);
+        assert_eq!(balance_change(&interest, &CruxId::new("alice")), 10);
+        assert_eq!(
+            balance_change(&interest, &SystemAccount::InterestExpense.id()),
+            10
+        );
+    }
+
+    #[test]
+    fn balance_change_of_a_transfer_without_target_only_leaves_its_source() {
+        let transfer = operation(OperationType::Transfer, 10, None, None);
+
+        assert_eq!(balance_change(&transfer, &CruxId::new("alice")), -10);
+        assert_eq!(balance_change(&transfer, &CruxId::new("bob")), 0);
+    }
+
+    #[test]
+    fn operation_pages_take_both_sides_in_time_order() {
+        use chrono::{TimeZone, Utc};
+        use transistor::types::http::Action;
+
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let count = OPERATION_PAGE_SIZE + 10;
+
+            let actions = (0..count)
+                .map(|index| {
+                    let (source, target) = match index % 3 {
+                        0 => ("alice", "bob"),
+                        1 => ("bob", "alice"),
+                        _ => ("alice", "alice"),
+                    };
+                    let mut transfer = operation(OperationType::Transfer, 1, Some(target), None);
+                    transfer.crux__db___id = CruxId::new(&format!("operation-{}", index));
+                    transfer.account_operation___source_account_id = CruxId::new(source);
+                    transfer.tx___tx_time =
+                        Some(Utc.timestamp(1_600_000_000 + index as i64, 0).to_string());
+
+                    Action::Put(edn_rs::to_string(transfer), None)
+                })
+                // Written newest first, so the order comes from the times.
+                .rev()
+                .collect();
+            client.tx_log(actions).await.unwrap();
+
+            let oldest_first = (0..count)
+                .map(|index| CruxId::new(&format!("operation-{}", index)))
+                .collect::<Vec<CruxId>>();
+            let newest_first = oldest_first.iter().rev().cloned().collect();
+            type Pages = fn(&CruxClient, &CruxId) -> OperationPages;
+            let orders: [(Pages, Vec<CruxId>); 2] = [
+                (OperationPages::new, oldest_first),
+                (OperationPages::newest_first, newest_first),
+            ];
+
+            for (pages, expected) in orders.iter() {
+                let mut pages = pages(&client, &CruxId::new("alice"));
+                let mut ids = Vec::new();
+                loop {
+                    let page = pages.next_page().await.unwrap();
+                    if page.is_empty() {
+                        break;
+                    }
+                    assert!(page.len() <= OPERATION_PAGE_SIZE);
+                    ids.extend(page.into_iter().map(|operation| operation.crux__db___id));
+                }
+
+                assert_eq!(&ids, expected);
+            }
+        });
+    }
+
+    #[test]
+    fn operations_read_accounts_past_the_cache() {
+        use crate::cache::AccountWrites;
+        use transistor::types::http::Action;
+
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let alice = customer("alice", 10);
+            client
+                .tx_log(vec![Action::Put(edn_rs::to_string(alice.clone()), None)])
+                .await
+                .unwrap();
+
+            // As if another process had cached what it last saw of alice.
+            let stale = customer("alice", 30);
+            let writes = AccountWrites::of(&[Action::Put(edn_rs::to_string(stale), None)]);
+            client.accounts().written(writes, usize::MAX);
+
+            let amount = |account: Option<DbAccount>| account.unwrap().account___amount;
+            let id = alice.crux__db___id;
+            assert_eq!(amount(cached_account(&client, &id).await.unwrap()), 30);
+            assert_eq!(amount(account(&client, &id).await.unwrap()), 10);
+            // The account read is cached again.
+            assert_eq!(amount(cached_account(&client, &id).await.unwrap()), 10);
+        });
+    }
+}
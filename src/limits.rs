@@ -0,0 +1,285 @@
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use edn_derive::{Deserialize, Serialize};
+use edn_rs::{Edn, EdnError};
+use transistor::edn_rs;
+use transistor::types::CruxId;
+
+use crate::config::{self, Config, DEFAULT_ACCOUNT_TYPE};
+use crate::crux::CruxClient;
+use crate::ledger;
+use crate::{DbAccount, DbError, OperationType};
+
+/// Limits on money leaving an account. A `None` limit isn't enforced.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DbLimits {
+    pub limits___max_per_operation: Option<usize>, // :limits/max-per-operation
+    pub limits___max_daily_total: Option<usize>,   // :limits/max-daily-total
+    pub limits___max_daily_count: Option<usize>,   // :limits/max-daily-count
+}
+
+impl DbLimits {
+    /// Reads limits written without namespace, as in the configuration file
+    /// and in `PUT /accounts/{id}/limits`.
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        Ok(Self {
+            limits___max_per_operation: config::uint(&edn[":max-per-operation"])?,
+            limits___max_daily_total: config::uint(&edn[":max-daily-total"])?,
+            limits___max_daily_count: config::uint(&edn[":max-daily-count"])?,
+        })
+    }
+
+    /// Fills the limits not set here with the ones from `fallback`.
+    pub fn or(self, fallback: DbLimits) -> Self {
+        Self {
+            limits___max_per_operation: self
+                .limits___max_per_operation
+                .or(fallback.limits___max_per_operation),
+            limits___max_daily_total: self
+                .limits___max_daily_total
+                .or(fallback.limits___max_daily_total),
+            limits___max_daily_count: self
+                .limits___max_daily_count
+                .or(fallback.limits___max_daily_count),
+        }
+    }
+}
+
+/// Account limits overridden on the account itself, falling back to the
+/// defaults of its type.
+pub fn effective_limits(config: &Config, account: &DbAccount) -> DbLimits {
+    let account_type = account
+        .account___type
+        .as_deref()
+        .unwrap_or(DEFAULT_ACCOUNT_TYPE);
+
+    account
+        .account___limits
+        .clone()
+        .unwrap_or_default()
+        .or(config.default_limits(account_type))
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    PerOperation,
+    DailyTotal,
+    DailyCount,
+}
+
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    pub max: usize,
+    /// When the operation would fit the limit again, if waiting helps at all.
+    pub resets_at: Option<DateTime<Utc>>,
+}
+
+impl LimitExceeded {
+    pub fn to_edn(&self) -> String {
+        format!(
+            "{{:error :limit/exceeded, :limit {}, :max {}, :resets-at {}}}",
+            edn_rs::to_string(self.limit),
+            self.max,
+            edn_rs::to_string(self.resets_at.map(|time| time.to_string())),
+        )
+    }
+}
+
+/// Checks that `amount` can leave `account` right now, looking at the
+/// operations that took money out of it in the last 24 hours.
+pub async fn check_outflow(
+    client: &CruxClient,
+    config: &Config,
+    account: &DbAccount,
+    amount: usize,
+) -> Result<(), DbError> {
+    let limits = effective_limits(config, account);
+    let now = Utc::now();
+
+    let outflows =
+        if limits.limits___max_daily_total.is_none() && limits.limits___max_daily_count.is_none() {
+            Vec::new()
+        } else {
+            outflows_since(client, &account.crux__db___id, now - Duration::hours(24)).await?
+        };
+
+    check(&limits, &outflows, amount).map_err(DbError::LimitExceeded)
+}
+
+/// The amounts that left `account_id` after `window_start`, oldest first.
+/// Only the operations after it are read, newest first.
+async fn outflows_since(
+    client: &CruxClient,
+    account_id: &CruxId,
+    window_start: DateTime<Utc>,
+) -> Result<Vec<(DateTime<Utc>, usize)>, DbError> {
+    let mut pages = ledger::OperationPages::newest_first(client, account_id);
+    let mut outflows = Vec::new();
+
+    loop {
+        let page = pages.next_page().await?;
+        if page.is_empty() {
+            break;
+        }
+
+        for operation in &page {
+            let time = match operation
+                .tx___tx_time
+                .as_ref()
+                .and_then(|time| time.parse::<DateTime<FixedOffset>>().ok())
+            {
+                Some(time) => time.with_timezone(&Utc),
+                None => continue,
+            };
+            if time <= window_start {
+                outflows.reverse();
+                return Ok(outflows);
+            }
+
+            // Fees don't count towards the limits of the operation that
+            // caused them.
+            let change = ledger::balance_change(operation, account_id);
+            if operation.account_operation___type != OperationType::Fee && change < 0 {
+                outflows.push((time, change.unsigned_abs() as usize));
+            }
+        }
+    }
+
+    outflows.reverse();
+    Ok(outflows)
+}
+
+/// Checks `amount` against `limits`, after the `outflows` of the last 24
+/// hours, oldest first so dropping from the front is what happens as time
+/// passes.
+fn check(
+    limits: &DbLimits,
+    outflows: &[(DateTime<Utc>, usize)],
+    amount: usize,
+) -> Result<(), LimitExceeded> {
+    if let Some(max) = limits.limits___max_per_operation {
+        if amount > max {
+            return Err(LimitExceeded {
+                limit: Limit::PerOperation,
+                max,
+                resets_at: None,
+            });
+        }
+    }
+
+    if let Some(max) = limits.limits___max_daily_total {
+        // Summed wider than the amounts, so no total wraps around below
+        // the limit.
+        let max_total = max as u128;
+        let mut total = outflows.iter().map(|(_, a)| *a as u128).sum::<u128>() + amount as u128;
+
+        if total > max_total {
+            let resets_at = outflows.iter().find_map(|(time, outflow)| {
+                total -= *outflow as u128;
+                if total <= max_total {
+                    Some(*time + Duration::hours(24))
+                } else {
+                    None
+                }
+            });
+
+            return Err(LimitExceeded {
+                limit: Limit::DailyTotal,
+                max,
+                resets_at,
+            });
+        }
+    }
+
+    if let Some(max) = limits.limits___max_daily_count {
+        if outflows.len() + 1 > max {
+            let resets_at = outflows
+                .get(outflows.len() - max)
+                .map(|(time, _)| *time + Duration::hours(24));
+
+            return Err(LimitExceeded {
+                limit: Limit::DailyCount,
+                max,
+                resets_at,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn limits(
+        max_per_operation: Option<usize>,
+        max_daily_total: Option<usize>,
+        max_daily_count: Option<usize>,
+    ) -> DbLimits {
+        DbLimits {
+            limits___max_per_operation: max_per_operation,
+            limits___max_daily_total: max_daily_total,
+            limits___max_daily_count: max_daily_count,
+        }
+    }
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.ymd(2020, 9, 1).and_hms(hour, 0, 0)
+    }
+
+    #[test]
+    fn operations_over_the_max_per_operation_never_fit() {
+        let limits = limits(Some(100), None, None);
+
+        assert!(check(&limits, &[], 100).is_ok());
+        let exceeded = check(&limits, &[], 101).unwrap_err();
+        assert_eq!(exceeded.limit, Limit::PerOperation);
+        assert_eq!(exceeded.max, 100);
+        assert_eq!(exceeded.resets_at, None);
+    }
+
+    #[test]
+    fn daily_totals_reset_when_enough_outflows_are_a_day_old() {
+        let limits = limits(None, Some(100), None);
+        let outflows = [(at(1), 40), (at(2), 30), (at(3), 20)];
+
+        assert!(check(&limits, &outflows, 10).is_ok());
+        let exceeded = check(&limits, &outflows, 50).unwrap_err();
+        assert_eq!(exceeded.limit, Limit::DailyTotal);
+        assert_eq!(exceeded.max, 100);
+        // 140 until the first outflow is a day old, 100 after that.
+        assert_eq!(exceeded.resets_at, Some(at(1) + Duration::hours(24)));
+
+        let exceeded = check(&limits, &outflows, 90).unwrap_err();
+        assert_eq!(exceeded.resets_at, Some(at(3) + Duration::hours(24)));
+        // Over the total on its own, it never fits.
+        let exceeded = check(&limits, &outflows, 101).unwrap_err();
+        assert_eq!(exceeded.resets_at, None);
+    }
+
+    #[test]
+    fn daily_counts_reset_when_enough_outflows_are_a_day_old() {
+        let exceeded = check(&limits(None, None, Some(0)), &[], 1).unwrap_err();
+        assert_eq!(exceeded.resets_at, None);
+
+        let limits = limits(None, None, Some(2));
+        assert!(check(&limits, &[(at(1), 1)], 1).is_ok());
+        let exceeded = check(&limits, &[(at(1), 1), (at(2), 1), (at(3), 1)], 1).unwrap_err();
+        assert_eq!(exceeded.limit, Limit::DailyCount);
+        assert_eq!(exceeded.max, 2);
+        assert_eq!(exceeded.resets_at, Some(at(2) + Duration::hours(24)));
+    }
+
+    #[test]
+    fn huge_amounts_dont_wrap_around_the_daily_total() {
+        let limits = limits(None, Some(100), None);
+
+        let exceeded = check(&limits, &[(at(1), 10)], usize::MAX).unwrap_err();
+        assert_eq!(exceeded.limit, Limit::DailyTotal);
+        assert_eq!(exceeded.resets_at, None);
+        assert!(check(&limits, &[(at(1), usize::MAX)], usize::MAX).is_err());
+    }
+}
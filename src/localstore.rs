@@ -0,0 +1,1019 @@
+use chrono::{DateTime, FixedOffset, Utc};
+use edn_rs::{Edn, EdnError};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use transistor::edn_rs;
+use transistor::edn_rs::Vector;
+use transistor::types::error::CruxError;
+use transistor::types::http::{Action, Order};
+use transistor::types::response::{EntityHistoryElement, EntityHistoryResponse, TxLogResponse};
+
+use crate::crux::Error;
+
+lazy_static! {
+    /// Stores opened by path, so every client in the process shares them.
+    static ref STORES: Mutex<HashMap<String, Arc<LocalStore>>> = Mutex::new(HashMap::new());
+}
+
+/// Opens the store kept in the file at `path`, replaying its transactions,
+/// or returns it when it's already open. A missing file is an empty store.
+///
+/// The file is locked for as long as the process runs, as two processes
+/// appending to it would give out the same transaction ids. Opening a
+/// store another process has open fails.
+pub fn open(path: &str) -> Result<Arc<LocalStore>, Error> {
+    let mut stores = STORES.lock().unwrap();
+    if let Some(store) = stores.get(path) {
+        return Ok(store.clone());
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .and_then(|file| lock(file, path))
+        .map_err(Error::Store)?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content).map_err(Error::Store)?;
+
+    let mut log = Log::default();
+    read_log(&content)?
+        .into_iter()
+        .for_each(|transaction| log.apply(transaction));
+
+    let store = Arc::new(LocalStore {
+        file,
+        log: Mutex::new(log),
+    });
+    stores.insert(path.to_string(), store.clone());
+
+    Ok(store)
+}
+
+/// Takes an exclusive lock on `file`, released when the process exits.
+#[cfg(unix)]
+fn lock(file: File, path: &str) -> std::io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safe: the descriptor is open for as long as `file` lives.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let error = std::io::Error::last_os_error();
+        return Err(match error.kind() {
+            std::io::ErrorKind::WouldBlock => {
+                std::io::Error::new(error.kind(), format!("{} is open in another process", path))
+            }
+            _ => error,
+        });
+    }
+
+    Ok(file)
+}
+
+/// Files aren't locked elsewhere: a store must only be opened by one
+/// process at a time.
+#[cfg(not(unix))]
+fn lock(file: File, _: &str) -> std::io::Result<File> {
+    Ok(file)
+}
+
+/// Reads transactions written by `Transaction::to_edn`, either one after the
+/// other as in a store file or in a vector as Crux answers them.
+pub fn read_log(content: &str) -> Result<Vec<Transaction>, Error> {
+    // Tagged literals aren't supported by the parser, their values are
+    // enough here.
+    let content = content.replace("#crux/id", "").replace("#inst", "");
+    let edn = if content.trim_start().starts_with('[') {
+        Edn::from_str(&content)?
+    } else {
+        Edn::from_str(&format!("[{}]", content))?
+    };
+
+    edn.iter()
+        .into_iter()
+        .flatten()
+        .map(Transaction::from_edn)
+        .collect()
+}
+
+fn time(edn: &Edn) -> Result<DateTime<FixedOffset>, EdnError> {
+    let time: String = edn_rs::from_edn(edn)?;
+
+    time.parse()
+        .map_err(|_| EdnError::Deserialize(format!("couldn't convert {} into a time", edn)))
+}
+
+fn unsupported(what: String) -> Error {
+    Error::Fatal(CruxError::QueryFormatError(format!(
+        "not supported by the in-process store: {}",
+        what
+    )))
+}
+
+/// A document written or deleted, from its valid time on, or the document
+/// an entity must be for its transaction to be committed, `nil` when it
+/// mustn't be there.
+#[derive(Clone, Debug)]
+pub enum Op {
+    Put(Edn, DateTime<FixedOffset>),
+    Delete(Edn, DateTime<FixedOffset>),
+    Match(Edn, Edn),
+}
+
+impl Op {
+    fn from_action(action: Action, now: DateTime<FixedOffset>) -> Result<Self, Error> {
+        match action {
+            Action::Put(doc, valid_time) => {
+                Ok(Op::Put(Edn::from_str(&doc)?, valid_time.unwrap_or(now)))
+            }
+            Action::Delete(id, valid_time) => {
+                Ok(Op::Delete(Edn::from_str(&id)?, valid_time.unwrap_or(now)))
+            }
+            Action::Match(id, doc, None) => {
+                Ok(Op::Match(Edn::from_str(&id)?, Edn::from_str(&doc)?))
+            }
+            action => Err(unsupported(format!("{:?}", action))),
+        }
+    }
+
+    /// Reads `[:crux.tx/put doc valid-time]`, the valid time defaulting to
+    /// `tx_time`.
+    fn from_edn(op: &Edn, tx_time: DateTime<FixedOffset>) -> Result<Self, Error> {
+        let valid_time = match &op[2] {
+            Edn::Nil => tx_time,
+            valid_time => time(valid_time)?,
+        };
+
+        match &op[0] {
+            Edn::Key(key) if key == ":crux.tx/put" => Ok(Op::Put(op[1].clone(), valid_time)),
+            Edn::Key(key) if key == ":crux.tx/delete" => Ok(Op::Delete(op[1].clone(), valid_time)),
+            Edn::Key(key) if key == ":crux.tx/match" && op[3] == Edn::Nil => {
+                Ok(Op::Match(op[1].clone(), op[2].clone()))
+            }
+            _ => Err(unsupported(op.to_string())),
+        }
+    }
+
+    pub fn id(&self) -> String {
+        match self {
+            Op::Put(doc, _) => doc[":crux.db/id"].to_string(),
+            Op::Delete(id, _) | Op::Match(id, _) => id.to_string(),
+        }
+    }
+
+    pub fn into_action(self) -> Action {
+        match self {
+            Op::Put(doc, valid_time) => Action::Put(to_edn(&doc), Some(valid_time)),
+            Op::Delete(id, valid_time) => Action::Delete(to_edn(&id), Some(valid_time)),
+            Op::Match(id, doc) => Action::Match(to_edn(&id), to_edn(&doc), None),
+        }
+    }
+
+    fn to_edn(&self) -> String {
+        match self {
+            Op::Put(doc, valid_time) => format!(
+                "[:crux.tx/put {} {:?}]",
+                to_edn(doc),
+                valid_time.to_rfc3339()
+            ),
+            Op::Delete(id, valid_time) => format!(
+                "[:crux.tx/delete {} {:?}]",
+                to_edn(id),
+                valid_time.to_rfc3339()
+            ),
+            Op::Match(id, doc) => format!("[:crux.tx/match {} {}]", to_edn(id), to_edn(doc)),
+        }
+    }
+}
+
+/// A transaction of the log, with its operations.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    pub tx_id: usize,
+    pub tx_time: DateTime<FixedOffset>,
+    pub ops: Vec<Op>,
+}
+
+impl Transaction {
+    fn from_edn(edn: &Edn) -> Result<Self, Error> {
+        let tx_id = edn[":crux.tx/tx-id"]
+            .to_uint()
+            .ok_or_else(|| EdnError::Deserialize(format!("transaction without id: {}", edn)))?;
+        let tx_time = time(&edn[":crux.tx/tx-time"])?;
+        let ops = edn[":crux.api/tx-ops"]
+            .iter()
+            .into_iter()
+            .flatten()
+            .map(|op| Op::from_edn(op, tx_time))
+            .collect::<Result<Vec<Op>, Error>>()?;
+
+        Ok(Self {
+            tx_id,
+            tx_time,
+            ops,
+        })
+    }
+
+    /// The transaction on a single line, as found in store files and
+    /// exports.
+    pub fn to_edn(&self) -> String {
+        format!(
+            "{{:crux.tx/tx-id {}, :crux.tx/tx-time {:?}, :crux.api/tx-ops [{}]}}",
+            self.tx_id,
+            self.tx_time.to_rfc3339(),
+            self.ops
+                .iter()
+                .map(Op::to_edn)
+                .collect::<Vec<String>>()
+                .join(" ")
+        )
+    }
+}
+
+/// Prints `edn` so it reads back the same, unlike its `Display`, which
+/// leaves trailing commas in collections.
+pub fn to_edn(edn: &Edn) -> String {
+    let join = |items: Vec<String>, separator| items.join(separator);
+
+    match edn {
+        Edn::Map(_) => format!(
+            "{{{}}}",
+            join(
+                edn.map_iter()
+                    .into_iter()
+                    .flatten()
+                    .map(|(key, value)| format!("{} {}", key, to_edn(value)))
+                    .collect(),
+                ", "
+            )
+        ),
+        Edn::Vector(_) => format!(
+            "[{}]",
+            join(edn.iter().into_iter().flatten().map(to_edn).collect(), " ")
+        ),
+        Edn::List(_) => format!(
+            "({})",
+            join(edn.iter().into_iter().flatten().map(to_edn).collect(), " ")
+        ),
+        Edn::Set(_) => format!(
+            "#{{{}}}",
+            join(
+                edn.set_iter().into_iter().flatten().map(to_edn).collect(),
+                " "
+            )
+        ),
+        _ => edn.to_string(),
+    }
+}
+
+/// A version of a document, `None` once deleted.
+#[derive(Debug)]
+struct Version {
+    tx_id: usize,
+    tx_time: DateTime<FixedOffset>,
+    valid_time: DateTime<FixedOffset>,
+    doc: Option<Edn>,
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    /// Versions of every document by id, in transaction order.
+    entities: HashMap<String, Vec<Version>>,
+    transactions: Vec<Transaction>,
+    /// Transactions left out as one of their matches failed.
+    failed: BTreeSet<usize>,
+}
+
+impl Log {
+    fn next_tx_id(&self) -> usize {
+        self.transactions
+            .last()
+            .map_or(0, |transaction| transaction.tx_id + 1)
+    }
+
+    /// Applies the transaction, unless one of its matches fails. It's kept
+    /// in the log either way, as Crux keeps it.
+    fn apply(&mut self, transaction: Transaction) {
+        let matched = transaction.ops.iter().all(|op| match op {
+            Op::Match(_, doc) => {
+                self.current(&op.id(), transaction.tx_time)
+                    .unwrap_or(&Edn::Nil)
+                    == doc
+            }
+            Op::Put(..) | Op::Delete(..) => true,
+        });
+        if !matched {
+            self.failed.insert(transaction.tx_id);
+            self.transactions.push(transaction);
+            return;
+        }
+
+        for op in &transaction.ops {
+            let (doc, valid_time) = match op {
+                Op::Put(doc, valid_time) => (Some(doc.clone()), *valid_time),
+                Op::Delete(_, valid_time) => (None, *valid_time),
+                Op::Match(..) => continue,
+            };
+
+            self.entities.entry(op.id()).or_default().push(Version {
+                tx_id: transaction.tx_id,
+                tx_time: transaction.tx_time,
+                valid_time,
+                doc,
+            });
+        }
+
+        self.transactions.push(transaction);
+    }
+
+    /// The document as of now: the version with the latest valid time that
+    /// isn't in the future, and the latest transaction among those.
+    fn current(&self, id: &str, now: DateTime<FixedOffset>) -> Option<&Edn> {
+        self.entities
+            .get(id)?
+            .iter()
+            .filter(|version| version.valid_time <= now)
+            .max_by_key(|version| (version.valid_time, version.tx_id))?
+            .doc
+            .as_ref()
+    }
+}
+
+/// Documents kept in the process and appended to a file, answering the
+/// calls smaug makes to Crux the way Crux does. Meant for development and
+/// admin tasks on exported data, it holds everything in memory.
+#[derive(Debug)]
+pub struct LocalStore {
+    /// The store file, open for appending and locked.
+    file: File,
+    log: Mutex<Log>,
+}
+
+impl LocalStore {
+    pub fn entity(&self, id: &str) -> Result<Edn, Error> {
+        let log = self.log.lock().unwrap();
+
+        Ok(log.current(id, now()).cloned().unwrap_or(Edn::Nil))
+    }
+
+    /// Every valid time of the document, with its latest version.
+    pub fn entity_history(
+        &self,
+        id: &str,
+        order: Order,
+        with_docs: bool,
+    ) -> Result<EntityHistoryResponse, Error> {
+        let log = self.log.lock().unwrap();
+
+        let mut versions = log
+            .entities
+            .get(id)
+            .map(|versions| versions.iter().collect::<Vec<&Version>>())
+            .unwrap_or_default();
+        versions.sort_by_key(|version| (version.valid_time, version.tx_id));
+        versions.reverse();
+        versions.dedup_by_key(|version| version.valid_time);
+        if order == Order::Asc {
+            versions.reverse();
+        }
+
+        Ok(EntityHistoryResponse {
+            history: versions
+                .into_iter()
+                .map(|version| EntityHistoryElement {
+                    db___valid_time: version.valid_time,
+                    tx___tx_id: version.tx_id,
+                    tx___tx_time: version.tx_time,
+                    db___content_hash: version.doc.as_ref().map_or_else(
+                        || "0".repeat(64),
+                        |doc| format!("{:x}", Sha256::digest(to_edn(doc).as_bytes())),
+                    ),
+                    db__doc: version.doc.clone().filter(|_| with_docs),
+                })
+                .collect(),
+        })
+    }
+
+    /// Appends the transaction to the file, then applies it.
+    pub fn tx_log(&self, actions: Vec<Action>) -> Result<TxLogResponse, Error> {
+        let mut log = self.log.lock().unwrap();
+
+        let tx_time = now();
+        let transaction = Transaction {
+            tx_id: log.next_tx_id(),
+            tx_time,
+            ops: actions
+                .into_iter()
+                .map(|action| Op::from_action(action, tx_time))
+                .collect::<Result<Vec<Op>, Error>>()?,
+        };
+
+        writeln!(&self.file, "{}", transaction.to_edn()).map_err(Error::Store)?;
+
+        let response = TxLogResponse {
+            tx___tx_id: transaction.tx_id,
+            tx___tx_time: transaction.tx_time,
+            tx__event___tx_events: None,
+        };
+        log.apply(transaction);
+
+        Ok(response)
+    }
+
+    /// Whether transaction `tx_id` was applied, which it wasn't if one of its
+    /// matches failed.
+    pub fn tx_committed(&self, tx_id: usize) -> Result<bool, Error> {
+        let log = self.log.lock().unwrap();
+
+        if log.next_tx_id() <= tx_id {
+            return Err(unsupported(format!("transaction {} isn't known", tx_id)));
+        }
+
+        Ok(!log.failed.contains(&tx_id))
+    }
+
+    /// The transactions after `after_tx_id`, as Crux lists them.
+    pub fn tx_log_tail(&self, after_tx_id: Option<usize>, limit: usize) -> Result<String, Error> {
+        let log = self.log.lock().unwrap();
+
+        Ok(format!(
+            "[{}]",
+            log.transactions
+                .iter()
+                .filter(|transaction| after_tx_id.is_none_or(|after| transaction.tx_id > after))
+                .take(limit)
+                .map(Transaction::to_edn)
+                .collect::<Vec<String>>()
+                .join("\n")
+        ))
+    }
+
+    /// Answers the queries smaug makes: `:where` clauses of a single
+    /// attribute, joined on their variables, with optional `:args`,
+    /// `:order-by`, `:offset` and `:limit`. Values are ordered as they're
+    /// written.
+    pub fn query(&self, query: &str) -> Result<BTreeSet<Vec<String>>, Error> {
+        let query = LocalQuery::parse(query)?;
+        let log = self.log.lock().unwrap();
+
+        let now = now();
+        let docs = log
+            .entities
+            .keys()
+            .filter_map(|id| log.current(id, now))
+            .collect::<Vec<&Edn>>();
+
+        let mut rows = query.args.clone();
+        for (entity, attribute, value) in &query.clauses {
+            rows = rows
+                .iter()
+                .flat_map(|row| {
+                    docs.iter().flat_map(move |doc| {
+                        let values = match &doc[attribute.as_str()] {
+                            Edn::Nil => vec![],
+                            many @ Edn::Vector(_) | many @ Edn::Set(_) | many @ Edn::List(_) => {
+                                many.iter()
+                                    .into_iter()
+                                    .flatten()
+                                    .chain(many.set_iter().into_iter().flatten())
+                                    .collect()
+                            }
+                            one => vec![one],
+                        };
+
+                        values.into_iter().filter_map(move |found| {
+                            let row = entity.unify(row.clone(), &doc[":crux.db/id"])?;
+                            value.unify(row, found)
+                        })
+                    })
+                })
+                .collect();
+        }
+
+        let results = rows
+            .into_iter()
+            .map(|row| {
+                let values = query
+                    .find
+                    .iter()
+                    .map(|variable| {
+                        row.get(variable).cloned().ok_or_else(|| {
+                            unsupported(format!("{} isn't bound by the :where clauses", variable))
+                        })
+                    })
+                    .collect::<Result<Vec<Edn>, Error>>()?;
+
+                Ok(Edn::Vector(Vector::new(values))
+                    .to_vec()
+                    .unwrap_or_default())
+            })
+            .collect::<Result<BTreeSet<Vec<String>>, Error>>()?;
+
+        let mut results = results.into_iter().collect::<Vec<Vec<String>>>();
+        results.sort_by(|a, b| {
+            query
+                .order_by
+                .iter()
+                .map(|&(index, descending)| {
+                    let order = a[index].cmp(&b[index]);
+                    if descending {
+                        order.reverse()
+                    } else {
+                        order
+                    }
+                })
+                .find(|order| *order != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        Ok(results
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+}
+
+fn now() -> DateTime<FixedOffset> {
+    Utc::now().into()
+}
+
+/// A variable or a value in a `:where` clause.
+#[derive(Debug)]
+enum Term {
+    Variable(String),
+    Value(Edn),
+}
+
+type Row = HashMap<String, Edn>;
+
+impl Term {
+    fn parse(term: &str) -> Result<Self, Error> {
+        if term.starts_with('?') {
+            Ok(Term::Variable(term.to_string()))
+        } else {
+            Ok(Term::Value(Edn::from_str(term)?))
+        }
+    }
+
+    /// `row` with this term bound to `value`, unless it's bound to
+    /// something else.
+    fn unify(&self, mut row: Row, value: &Edn) -> Option<Row> {
+        match self {
+            Term::Value(expected) => Some(row).filter(|_| expected == value),
+            Term::Variable(variable) => match row.get(variable) {
+                Some(bound) if bound != value => None,
+                Some(_) => Some(row),
+                None => {
+                    row.insert(variable.clone(), value.clone());
+                    Some(row)
+                }
+            },
+        }
+    }
+}
+
+/// A query as transistor serializes it, which the EDN parser can't read as
+/// it doesn't support symbols.
+#[derive(Debug)]
+struct LocalQuery {
+    find: Vec<String>,
+    clauses: Vec<(Term, String, Term)>,
+    args: Vec<Row>,
+    /// Positions in `find` to sort by, and whether it's descending.
+    order_by: Vec<(usize, bool)>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// A value of a query, read far enough to find its parts: a collection
+/// with its opening delimiter, or anything else as written.
+#[derive(Debug, PartialEq)]
+enum Form {
+    Atom(String),
+    Coll(char, Vec<Form>),
+}
+
+impl Form {
+    /// Reads every form of `text`, whatever the whitespace and commas
+    /// between them, keeping strings whole.
+    fn read_all(text: &str) -> Result<Vec<Self>, Error> {
+        let mut chars = text.chars().peekable();
+        let mut stack: Vec<(char, Vec<Form>)> = vec![(' ', Vec::new())];
+
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_whitespace() || c == ',' => (),
+                '[' | '{' | '(' => stack.push((c, Vec::new())),
+                ']' | '}' | ')' => {
+                    let (open, forms) = stack
+                        .pop()
+                        .filter(|(open, _)| {
+                            matches!((open, c), ('[', ']') | ('{', '}') | ('(', ')'))
+                        })
+                        .ok_or_else(|| unsupported(format!("unbalanced {} in {}", c, text)))?;
+                    let parent = stack
+                        .last_mut()
+                        .ok_or_else(|| unsupported(format!("unbalanced {} in {}", c, text)))?;
+                    parent.1.push(Form::Coll(open, forms));
+                }
+                '"' => {
+                    let mut atom = String::from('"');
+                    let mut escaped = false;
+                    loop {
+                        let c = chars.next().ok_or_else(|| {
+                            unsupported(format!("unterminated string in {}", text))
+                        })?;
+                        atom.push(c);
+                        match c {
+                            '"' if !escaped => break,
+                            '\\' => escaped = !escaped,
+                            _ => escaped = false,
+                        }
+                    }
+                    stack.last_mut().unwrap().1.push(Form::Atom(atom));
+                }
+                c => {
+                    let mut atom = c.to_string();
+                    while let Some(&next) = chars.peek() {
+                        if next.is_whitespace() || ",[]{}()\"".contains(next) {
+                            break;
+                        }
+                        atom.push(next);
+                        chars.next();
+                    }
+                    stack.last_mut().unwrap().1.push(Form::Atom(atom));
+                }
+            }
+        }
+
+        match stack.pop() {
+            Some((' ', forms)) if stack.is_empty() => Ok(forms),
+            _ => Err(unsupported(format!("unbalanced query {}", text))),
+        }
+    }
+
+    fn atom(&self) -> Option<&str> {
+        match self {
+            Form::Atom(atom) => Some(atom),
+            Form::Coll(..) => None,
+        }
+    }
+
+    /// The forms of a collection opened by `open`.
+    fn items(&self, open: char) -> Option<&[Form]> {
+        match self {
+            Form::Coll(delimiter, items) if *delimiter == open => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl LocalQuery {
+    /// Reads `{:query {:find [...] :where [[...] ...] :args [{...} ...]}}`,
+    /// or the inner map alone.
+    fn parse(query: &str) -> Result<Self, Error> {
+        let malformed = || unsupported(query.to_string());
+
+        let forms = Form::read_all(query)?;
+        let mut map = match &forms[..] {
+            [map] => map.items('{').ok_or_else(malformed)?,
+            _ => return Err(malformed()),
+        };
+        if let [key, inner] = map {
+            if key.atom() == Some(":query") {
+                map = inner.items('{').ok_or_else(malformed)?;
+            }
+        }
+
+        let mut find = None;
+        let mut clauses = None;
+        let mut args = vec![Row::new()];
+        let mut order_by = Vec::new();
+        let mut offset = 0;
+        let mut limit = None;
+
+        for pair in map.chunks(2) {
+            let (key, value) = match pair {
+                [key, value] => (key.atom().ok_or_else(malformed)?, value),
+                _ => return Err(malformed()),
+            };
+
+            match key {
+                ":find" => {
+                    find = Some(
+                        value
+                            .items('[')
+                            .ok_or_else(malformed)?
+                            .iter()
+                            .map(|variable| variable.atom().map(String::from).ok_or_else(malformed))
+                            .collect::<Result<Vec<String>, Error>>()?,
+                    )
+                }
+                ":where" => {
+                    clauses = Some(
+                        value
+                            .items('[')
+                            .ok_or_else(malformed)?
+                            .iter()
+                            .map(|clause| match clause.items('[').ok_or_else(malformed)? {
+                                [Form::Atom(entity), Form::Atom(attribute), Form::Atom(value)]
+                                    if attribute.starts_with(':') =>
+                                {
+                                    Ok((
+                                        Term::parse(entity)?,
+                                        attribute.clone(),
+                                        Term::parse(value)?,
+                                    ))
+                                }
+                                _ => Err(unsupported(format!("{:?}", clause))),
+                            })
+                            .collect::<Result<Vec<(Term, String, Term)>, Error>>()?,
+                    )
+                }
+                ":args" => {
+                    args = value
+                        .items('[')
+                        .ok_or_else(malformed)?
+                        .iter()
+                        .map(|arg| {
+                            arg.items('{')
+                                .ok_or_else(malformed)?
+                                .chunks(2)
+                                .map(|pair| match pair {
+                                    [Form::Atom(variable), Form::Atom(value)]
+                                        if variable.starts_with('?') =>
+                                    {
+                                        Ok((variable.clone(), Edn::from_str(value)?))
+                                    }
+                                    _ => Err(unsupported(format!("{:?}", arg))),
+                                })
+                                .collect::<Result<Row, Error>>()
+                        })
+                        .collect::<Result<Vec<Row>, Error>>()?
+                }
+                ":order-by" => {
+                    order_by = value
+                        .items('[')
+                        .ok_or_else(malformed)?
+                        .iter()
+                        .map(|clause| match clause.items('[').ok_or_else(malformed)? {
+                            [Form::Atom(variable), Form::Atom(direction)]
+                                if direction == ":asc" || direction == ":desc" =>
+                            {
+                                Ok((variable.clone(), direction == ":desc"))
+                            }
+                            _ => Err(unsupported(format!("{:?}", clause))),
+                        })
+                        .collect::<Result<Vec<(String, bool)>, Error>>()?
+                }
+                ":offset" => {
+                    offset = value
+                        .atom()
+                        .and_then(|offset| offset.parse().ok())
+                        .ok_or_else(malformed)?
+                }
+                ":limit" => {
+                    limit = Some(
+                        value
+                            .atom()
+                            .and_then(|limit| limit.parse().ok())
+                            .ok_or_else(malformed)?,
+                    )
+                }
+                option => return Err(unsupported(option.to_string())),
+            }
+        }
+
+        let find = find.ok_or_else(malformed)?;
+        let order_by = order_by
+            .into_iter()
+            .map(|(variable, descending)| {
+                find.iter()
+                    .position(|found| *found == variable)
+                    .map(|index| (index, descending))
+                    .ok_or_else(|| unsupported(format!("{} isn't found", variable)))
+            })
+            .collect::<Result<Vec<(usize, bool)>, Error>>()?;
+
+        Ok(Self {
+            find,
+            clauses: clauses.ok_or_else(malformed)?,
+            args,
+            order_by,
+            offset,
+            limit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use transistor::types::query::Query;
+    use uuid::Uuid;
+
+    fn temp_path() -> String {
+        std::env::temp_dir()
+            .join(format!("smaug-test-{}.edn", Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn queries_are_read_as_transistor_writes_them() {
+        let query = Query::find(vec!["?account", "?amount"])
+            .unwrap()
+            .where_clause(vec![
+                "?account :account/amount ?amount",
+                "?account :account/type \"joint savings\"",
+                "?account :account/external-id ?external-id",
+            ])
+            .unwrap()
+            .args(vec!["?external-id \"A-1\""])
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let query = LocalQuery::parse(&edn_rs::to_string(query)).unwrap();
+
+        assert_eq!(query.find, vec!["?account", "?amount"]);
+        assert_eq!(query.clauses.len(), 3);
+        assert_eq!(query.clauses[1].1, ":account/type");
+        assert!(matches!(
+            &query.clauses[1].2,
+            Term::Value(Edn::Str(account_type)) if account_type == "joint savings"
+        ));
+        assert_eq!(
+            query.args[0].get("?external-id"),
+            Some(&Edn::Str(String::from("A-1")))
+        );
+    }
+
+    #[test]
+    fn queries_are_read_whatever_their_layout() {
+        let query = LocalQuery::parse(
+            "{:find [?id] , :where [ [?id :webhook/url \"http://a.example/x y\"] ], \
+             :args [{?id :hook, }]}",
+        )
+        .unwrap();
+
+        assert_eq!(query.find, vec!["?id"]);
+        assert!(matches!(
+            &query.clauses[0].2,
+            Term::Value(Edn::Str(url)) if url == "http://a.example/x y"
+        ));
+        assert_eq!(
+            query.args[0].get("?id"),
+            Some(&Edn::Key(String::from(":hook")))
+        );
+    }
+
+    #[test]
+    fn unsupported_queries_are_refused() {
+        for query in &[
+            "{:query {:find [?a] :where [[?a :b ?c]] :full-results? true}}",
+            "{:query {:find [?a] :where [[?a :b ?c]] :order-by [[?c :asc]]}}",
+            "{:query {:find [?a] :where [[?a ?b ?c]]}}",
+            "{:query {:find [?a] :where [[?a :b \"unterminated]]}}",
+            "{:query {:find [?a] :where [[?a :b ?c]]}",
+        ] {
+            assert!(LocalQuery::parse(query).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn strings_with_spaces_match() {
+        let path = temp_path();
+        let store = open(&path).unwrap();
+        store
+            .tx_log(vec![Action::Put(
+                String::from(
+                    "{:crux.db/id :alice, :account/amount 3, :account/type \"joint savings\"}",
+                ),
+                None,
+            )])
+            .unwrap();
+
+        let in_clause = Query::find(vec!["?account"])
+            .unwrap()
+            .where_clause(vec!["?account :account/type \"joint savings\""])
+            .unwrap()
+            .build()
+            .unwrap();
+        let in_args = "{:query {:find [?account] \
+                       :where [[?account :account/type ?type]] \
+                       :args [{?type \"joint savings\"}]}}";
+
+        for query in &[edn_rs::to_string(in_clause), in_args.to_string()] {
+            let rows = store.query(query).unwrap();
+            assert_eq!(
+                rows.into_iter().collect::<Vec<Vec<String>>>(),
+                vec![vec![String::from(":alice")]]
+            );
+        }
+    }
+
+    #[test]
+    fn results_are_paged_in_order() {
+        let path = temp_path();
+        let store = open(&path).unwrap();
+        store
+            .tx_log(
+                ["c", "a", "d", "b"]
+                    .iter()
+                    .map(|id| {
+                        Action::Put(format!("{{:crux.db/id :{} :account/amount 1}}", id), None)
+                    })
+                    .collect(),
+            )
+            .unwrap();
+
+        let page = |offset| {
+            let query = Query::find(vec!["?account", "?amount"])
+                .unwrap()
+                .where_clause(vec!["?account :account/amount ?amount"])
+                .unwrap()
+                .order_by(vec!["?amount :asc", "?account :desc"])
+                .unwrap()
+                .offset(offset)
+                .limit(3)
+                .build()
+                .unwrap();
+
+            store
+                .query(&edn_rs::to_string(query))
+                .unwrap()
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect::<BTreeSet<String>>()
+        };
+
+        assert_eq!(
+            page(0),
+            [":b", ":c", ":d"].iter().map(|id| id.to_string()).collect()
+        );
+        assert_eq!(page(3), [":a"].iter().map(|id| id.to_string()).collect());
+    }
+
+    #[test]
+    fn store_files_are_locked_while_open() {
+        let path = temp_path();
+        let _store = open(&path).unwrap();
+
+        // As another process would, bypassing the stores already open.
+        let other = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let error = lock(other, &path).unwrap_err();
+        assert!(error.to_string().contains("another process"));
+    }
+
+    #[test]
+    fn transactions_whose_matches_fail_write_nothing() {
+        let path = temp_path();
+        let store = open(&path).unwrap();
+        let put = |amount: usize| {
+            Action::Put(
+                format!("{{:crux.db/id :alice, :account/amount {}}}", amount),
+                None,
+            )
+        };
+        let absent = || Action::Match(String::from(":alice"), String::from("nil"), None);
+
+        let first = store.tx_log(vec![absent(), put(3)]).unwrap();
+        let second = store.tx_log(vec![absent(), put(5)]).unwrap();
+        assert!(store.tx_committed(first.tx___tx_id).unwrap());
+        assert!(!store.tx_committed(second.tx___tx_id).unwrap());
+        assert!(store.tx_committed(second.tx___tx_id + 1).is_err());
+        assert_eq!(
+            store.entity(":alice").unwrap()[":account/amount"],
+            Edn::UInt(3)
+        );
+
+        // Replayed from the file, the same match fails again.
+        let mut log = Log::default();
+        let mut content = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        read_log(&content)
+            .unwrap()
+            .into_iter()
+            .for_each(|transaction| log.apply(transaction));
+        assert!(log.failed.contains(&second.tx___tx_id));
+        assert_eq!(
+            log.current(":alice", now()).unwrap()[":account/amount"],
+            Edn::UInt(3)
+        );
+    }
+}
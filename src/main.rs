@@ -1,33 +1,124 @@
-use actix_web::{middleware::DefaultHeaders, web, App, HttpResponse, HttpServer};
+use actix_web::{
+    guard,
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::DefaultHeaders,
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use bytes::Bytes;
 use chrono::{DateTime, FixedOffset, Utc};
 use edn_derive::{Deserialize, Serialize};
 use edn_rs::{Edn, EdnError};
+use futures::channel::mpsc;
+use futures::{future, stream, FutureExt, StreamExt};
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
-use transistor::client::Crux;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use tracing_futures::Instrument;
 use transistor::edn_rs;
 use transistor::types::http::{Action, Order};
-use transistor::types::{error::CruxError, query::Query, response::EntityHistoryElement, CruxId};
+use transistor::types::{error::CruxError, response::EntityHistoryElement, CruxId};
 use uuid::Uuid;
 
 use actix::prelude::*;
-
-struct DbExecutor(Crux);
+use actix_web::dev::Service;
+use actix_web_actors::ws;
+
+#[macro_use]
+mod apidocs;
+mod admin;
+mod audit;
+mod bench;
+mod cache;
+mod config;
+mod crux;
+mod events;
+mod exports;
+mod fees;
+mod health;
+mod imports;
+mod interest;
+mod ledger;
+mod limits;
+mod localstore;
+#[macro_use]
+mod metrics;
+mod payments;
+mod products;
+mod projector;
+mod ratelimit;
+mod reconcile;
+mod socket;
+mod statements;
+mod telemetry;
+mod webhooks;
+
+use audit::{Audit, AuditFilter, Channel, DbAudit, ResponseAudit};
+use config::Config;
+use crux::CruxClient;
+use events::{EventBroker, Notification, Publish};
+use ledger::{DbPosting, JournalEntry, PostingSide, SystemAccount};
+use limits::{DbLimits, LimitExceeded};
+use metrics::Timed;
+use products::{Product, RuleViolation};
+use projector::{OperationsIndex, RunningTotals};
+use telemetry::RequestId;
+use webhooks::{DbDelivery, DbDeliveryAttempt, DbWebhook, DeliveryStatus};
+
+/// Where the HTTP server listens.
+const ADDRESS: &str = "127.0.0.1:8000";
+
+/// Largest file `POST /imports` and `POST /payment-initiations` take.
+const IMPORT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Handles every message as a future of its own, so they wait for Crux
+/// together instead of in turns. How many calls to Crux are made at once is
+/// up to the client.
+#[derive(Clone)]
+struct DbExecutor(CruxClient, Arc<Config>, Addr<EventBroker>);
 
 impl Actor for DbExecutor {
-    type Context = SyncContext<Self>;
+    type Context = Context<Self>;
 }
 
 #[derive(Debug)]
 enum DbError {
     NilEntity,
     StateConflict,
-    CruxError(CruxError),
+    UnbalancedEntry,
+    LimitExceeded(LimitExceeded),
+    ProductRule(RuleViolation),
+    Crux(crux::Error),
     EdnError(EdnError),
 }
 
+impl DbError {
+    /// Logs the error, as a warning when it's the request's fault.
+    fn log(&self) {
+        match self {
+            DbError::Crux(error @ crux::Error::CircuitOpen { .. }) => {
+                tracing::warn!(error = %error, "Crux unavailable")
+            }
+            DbError::Crux(error) => tracing::error!(error = %error, "Crux failed"),
+            DbError::EdnError(error) => tracing::error!(error = ?error, "invalid EDN from Crux"),
+            DbError::UnbalancedEntry => tracing::error!("unbalanced journal entry"),
+            DbError::NilEntity
+            | DbError::StateConflict
+            | DbError::LimitExceeded(_)
+            | DbError::ProductRule(_) => tracing::warn!(error = ?self, "request rejected"),
+        }
+    }
+}
+
+impl From<crux::Error> for DbError {
+    fn from(crux_error: crux::Error) -> Self {
+        DbError::Crux(crux_error)
+    }
+}
+
 impl From<CruxError> for DbError {
     fn from(crux_error: CruxError) -> Self {
-        DbError::CruxError(crux_error)
+        DbError::Crux(crux_error.into())
     }
 }
 
@@ -39,6 +130,7 @@ impl From<EdnError> for DbError {
 
 struct CreateAccount {
     account: DbAccount,
+    audit: Audit,
 }
 
 impl Message for CreateAccount {
@@ -46,29 +138,62 @@ impl Message for CreateAccount {
 }
 
 impl Handler<CreateAccount> for DbExecutor {
-    type Result = Result<DbAccount, DbError>;
+    type Result = ResponseFuture<Result<DbAccount, DbError>>;
 
     fn handle(&mut self, msg: CreateAccount, _: &mut Self::Context) -> Self::Result {
-        let db_account = msg.account;
+        let executor = self.clone();
+
+        Box::pin(async move { executor.create_account(msg).await })
+    }
+}
+
+impl DbExecutor {
+    async fn create_account(&self, msg: CreateAccount) -> Result<DbAccount, DbError> {
+        let mut db_account = msg.account;
+        let amount = db_account.account___amount;
+        db_account.account___amount = 0;
+
+        let product = products::of(&self.1.products, &db_account)?;
+        product.check_operation(&OperationType::Create, false)?;
+        product.check_balance(0, amount)?;
+        if db_account.account___interest_rate.is_none() {
+            db_account.account___interest_rate = product.interest_rate;
+        }
+
+        let client = &self.0;
+        let entry = JournalEntry::new()
+            .debit(&SystemAccount::CashIn.id(), amount as usize)
+            .credit(&db_account.crux__db___id, amount as usize);
+        entry.post(&mut [&mut db_account])?;
 
-        let client = self.0.http_client();
         let action1 = Action::Put(edn_rs::to_string(db_account.clone()), None);
 
         let tx_time = Utc::now().to_string();
         let account_operation = DbAccountOperation {
             crux__db___id: CruxId::new(&Uuid::new_v4().to_string()),
             account_operation___type: OperationType::Create,
-            account_operation___amount: db_account.account___amount,
+            account_operation___amount: amount as usize,
             account_operation___source_account_id: db_account.crux__db___id.clone(),
             account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(entry.into_postings()),
+            account_operation___request_id: msg.audit.request_id.clone(),
+            account_operation___audit: Some(msg.audit.record.clone()),
             tx___tx_time: Some(tx_time.clone()),
         };
         let action2 = Action::Put(
-            edn_rs::to_string(account_operation),
+            edn_rs::to_string(account_operation.clone()),
             Some(tx_time.parse::<DateTime<FixedOffset>>().unwrap()),
         );
 
-        client.tx_log(vec![action1, action2])?;
+        let operations = vec![account_operation];
+        let mut actions = vec![action1, action2];
+        actions.extend(webhooks::enqueue(client, &operations).await?);
+
+        client.tx_log(actions).await?;
+        metrics::record_operations(&operations);
+        self.2
+            .do_send(Publish(events::account_events(&operations, &[&db_account])));
 
         Ok(db_account)
     }
@@ -83,23 +208,23 @@ impl Message for GetAccount {
 }
 
 impl Handler<GetAccount> for DbExecutor {
-    type Result = Result<DbAccount, DbError>;
+    type Result = ResponseFuture<Result<DbAccount, DbError>>;
 
     fn handle(&mut self, msg: GetAccount, _: &mut Self::Context) -> Self::Result {
-        let client = self.0.http_client();
-        let crux_account = client.entity(edn_rs::to_string(CruxId::new(&msg.account_id)))?;
-
-        if crux_account == Edn::Nil {
-            return Err(DbError::NilEntity);
-        }
+        let client = self.0.clone();
 
-        Ok(edn_rs::from_edn(&crux_account)?)
+        Box::pin(async move {
+            ledger::cached_account(&client, &CruxId::new(&msg.account_id))
+                .await?
+                .ok_or(DbError::NilEntity)
+        })
     }
 }
 
 struct AccountDeposit {
     account_id: String,
     amount: usize,
+    audit: Audit,
 }
 
 impl Message for AccountDeposit {
@@ -107,19 +232,34 @@ impl Message for AccountDeposit {
 }
 
 impl Handler<AccountDeposit> for DbExecutor {
-    type Result = Result<DbAccount, DbError>;
+    type Result = ResponseFuture<Result<DbAccount, DbError>>;
 
     fn handle(&mut self, msg: AccountDeposit, _: &mut Self::Context) -> Self::Result {
-        let client = self.0.http_client();
-        let crux_account = client.entity(edn_rs::to_string(CruxId::new(&msg.account_id)))?;
+        let executor = self.clone();
 
-        if crux_account == Edn::Nil {
-            return Err(DbError::NilEntity);
-        }
+        Box::pin(async move { executor.deposit(msg).await })
+    }
+}
+
+impl DbExecutor {
+    async fn deposit(&self, msg: AccountDeposit) -> Result<DbAccount, DbError> {
+        let client = &self.0;
+        let account_id = CruxId::new(&msg.account_id);
+        let _locks = ledger::lock(std::slice::from_ref(&account_id)).await;
+
+        let mut db_account = ledger::account(client, &account_id)
+            .await?
+            .ok_or(DbError::NilEntity)?;
 
-        let mut db_account: DbAccount = edn_rs::from_edn(&crux_account)?;
+        let product = products::of(&self.1.products, &db_account)?;
+        product.check_operation(&OperationType::Deposit, false)?;
+        let balance_before = db_account.account___amount;
 
-        db_account.account___amount += msg.amount;
+        let entry = JournalEntry::new()
+            .debit(&SystemAccount::CashIn.id(), msg.amount)
+            .credit(&db_account.crux__db___id, msg.amount);
+        entry.post(&mut [&mut db_account])?;
+        product.check_balance(balance_before, db_account.account___amount)?;
 
         let action1 = Action::Put(edn_rs::to_string(db_account.clone()), None);
 
@@ -130,14 +270,25 @@ impl Handler<AccountDeposit> for DbExecutor {
             account_operation___amount: msg.amount,
             account_operation___source_account_id: db_account.crux__db___id.clone(),
             account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(entry.into_postings()),
+            account_operation___request_id: msg.audit.request_id.clone(),
+            account_operation___audit: Some(msg.audit.record.clone()),
             tx___tx_time: Some(tx_time.clone()),
         };
         let action2 = Action::Put(
-            edn_rs::to_string(account_operation),
+            edn_rs::to_string(account_operation.clone()),
             Some(tx_time.parse::<DateTime<FixedOffset>>().unwrap()),
         );
 
-        client.tx_log(vec![action1, action2])?;
+        let operations = vec![account_operation];
+        let mut actions = vec![action1, action2];
+        actions.extend(webhooks::enqueue(client, &operations).await?);
+
+        client.tx_log(actions).await?;
+        metrics::record_operations(&operations);
+        self.2
+            .do_send(Publish(events::account_events(&operations, &[&db_account])));
 
         Ok(db_account)
     }
@@ -146,6 +297,7 @@ impl Handler<AccountDeposit> for DbExecutor {
 struct AccountWithdraw {
     account_id: String,
     amount: usize,
+    audit: Audit,
 }
 
 impl Message for AccountWithdraw {
@@ -153,36 +305,87 @@ impl Message for AccountWithdraw {
 }
 
 impl Handler<AccountWithdraw> for DbExecutor {
-    type Result = Result<DbAccount, DbError>;
+    type Result = ResponseFuture<Result<DbAccount, DbError>>;
 
     fn handle(&mut self, msg: AccountWithdraw, _: &mut Self::Context) -> Self::Result {
-        let client = self.0.http_client();
-        let crux_account = client.entity(edn_rs::to_string(CruxId::new(&msg.account_id)))?;
+        let executor = self.clone();
 
-        if crux_account == Edn::Nil {
-            return Err(DbError::NilEntity);
-        }
+        Box::pin(async move { executor.withdraw(msg).await })
+    }
+}
 
-        let mut db_account: DbAccount = edn_rs::from_edn(&crux_account)?;
+impl DbExecutor {
+    async fn withdraw(&self, msg: AccountWithdraw) -> Result<DbAccount, DbError> {
+        let client = &self.0;
+        let account_id = CruxId::new(&msg.account_id);
+        let _locks = ledger::lock(std::slice::from_ref(&account_id)).await;
 
-        db_account.account___amount -= msg.amount;
+        let mut db_account = ledger::account(client, &account_id)
+            .await?
+            .ok_or(DbError::NilEntity)?;
 
-        let action1 = Action::Put(edn_rs::to_string(db_account.clone()), None);
+        let product = products::of(&self.1.products, &db_account)?;
+        product.check_operation(&OperationType::Withdraw, true)?;
+        let balance_before = db_account.account___amount;
+
+        limits::check_outflow(client, &self.1, &db_account, msg.amount).await?;
+
+        let fee = fees::quote(
+            client,
+            &self.1,
+            &db_account,
+            &OperationType::Withdraw,
+            msg.amount,
+        )
+        .await?;
+
+        let entry = JournalEntry::new()
+            .debit(&db_account.crux__db___id, msg.amount)
+            .credit(&SystemAccount::CashOut.id(), msg.amount);
+        entry.post(&mut [&mut db_account])?;
 
         let tx_time = Utc::now().to_string();
+        let valid_time = tx_time.parse::<DateTime<FixedOffset>>().unwrap();
+        let operation_id = CruxId::new(&Uuid::new_v4().to_string());
+
+        let fee_operation =
+            fees::charge(&mut db_account, fee, &operation_id, &tx_time, &msg.audit)?;
+        product.check_balance(balance_before, db_account.account___amount)?;
+
+        let mut actions = vec![Action::Put(edn_rs::to_string(db_account.clone()), None)];
+
         let account_operation = DbAccountOperation {
-            crux__db___id: CruxId::new(&Uuid::new_v4().to_string()),
+            crux__db___id: operation_id,
             account_operation___type: OperationType::Withdraw,
             account_operation___amount: msg.amount,
             account_operation___source_account_id: db_account.crux__db___id.clone(),
             account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(entry.into_postings()),
+            account_operation___request_id: msg.audit.request_id.clone(),
+            account_operation___audit: Some(msg.audit.record.clone()),
             tx___tx_time: Some(tx_time.clone()),
         };
-        let action2 = Action::Put(
-            edn_rs::to_string(account_operation),
-            Some(tx_time.parse::<DateTime<FixedOffset>>().unwrap()),
-        );
-        client.tx_log(vec![action1, action2])?;
+        actions.push(Action::Put(
+            edn_rs::to_string(account_operation.clone()),
+            Some(valid_time),
+        ));
+
+        if let Some(fee_operation) = &fee_operation {
+            actions.push(Action::Put(
+                edn_rs::to_string(fee_operation.clone()),
+                Some(valid_time),
+            ));
+        }
+
+        let mut operations = vec![account_operation];
+        operations.extend(fee_operation);
+        actions.extend(webhooks::enqueue(client, &operations).await?);
+
+        client.tx_log(actions).await?;
+        metrics::record_operations(&operations);
+        self.2
+            .do_send(Publish(events::account_events(&operations, &[&db_account])));
 
         Ok(db_account)
     }
@@ -192,6 +395,10 @@ struct AccountTransfer {
     source_account_id: String,
     amount: usize,
     target_account_id: String,
+    audit: Audit,
+    /// Written in the same transaction, like the record of a payment
+    /// instruction executed by the transfer.
+    also_write: Vec<Action>,
 }
 
 impl Message for AccountTransfer {
@@ -199,52 +406,109 @@ impl Message for AccountTransfer {
 }
 
 impl Handler<AccountTransfer> for DbExecutor {
-    type Result = Result<DbAccount, DbError>;
+    type Result = ResponseFuture<Result<DbAccount, DbError>>;
 
     fn handle(&mut self, msg: AccountTransfer, _: &mut Self::Context) -> Self::Result {
-        let client = self.0.http_client();
-        let crux_source_account =
-            client.entity(edn_rs::to_string(CruxId::new(&msg.source_account_id)))?;
-
-        if crux_source_account == Edn::Nil {
-            return Err(DbError::NilEntity);
-        }
-
-        let mut db_source_account: DbAccount = edn_rs::from_edn(&crux_source_account)?;
-
-        if db_source_account.account___amount < msg.amount {
-            return Err(DbError::StateConflict);
-        }
-
-        let crux_target_account =
-            client.entity(edn_rs::to_string(CruxId::new(&msg.target_account_id)))?;
+        let executor = self.clone();
 
-        if crux_target_account == Edn::Nil {
-            return Err(DbError::NilEntity);
-        }
+        Box::pin(async move { executor.transfer(msg).await })
+    }
+}
 
-        let mut db_target_account: DbAccount = edn_rs::from_edn(&crux_target_account)?;
+impl DbExecutor {
+    /// Also how payment initiations move money, one instruction at a time.
+    async fn transfer(&self, msg: AccountTransfer) -> Result<DbAccount, DbError> {
+        let client = &self.0;
+        let source_account_id = CruxId::new(&msg.source_account_id);
+        let target_account_id = CruxId::new(&msg.target_account_id);
+        let _locks = ledger::lock(&[source_account_id.clone(), target_account_id.clone()]).await;
+
+        let mut db_source_account = ledger::account(client, &source_account_id)
+            .await?
+            .ok_or(DbError::NilEntity)?;
+
+        let source_product = products::of(&self.1.products, &db_source_account)?;
+        source_product.check_operation(&OperationType::Transfer, true)?;
+        let source_balance_before = db_source_account.account___amount;
+
+        limits::check_outflow(client, &self.1, &db_source_account, msg.amount).await?;
+
+        let mut db_target_account = ledger::account(client, &target_account_id)
+            .await?
+            .ok_or(DbError::NilEntity)?;
+
+        let target_product = products::of(&self.1.products, &db_target_account)?;
+        target_product.check_operation(&OperationType::Transfer, false)?;
+        let target_balance_before = db_target_account.account___amount;
+
+        let fee = fees::quote(
+            client,
+            &self.1,
+            &db_source_account,
+            &OperationType::Transfer,
+            msg.amount,
+        )
+        .await?;
+
+        let entry = JournalEntry::new()
+            .debit(&db_source_account.crux__db___id, msg.amount)
+            .credit(&db_target_account.crux__db___id, msg.amount);
+        entry.post(&mut [&mut db_source_account, &mut db_target_account])?;
 
-        db_source_account.account___amount -= msg.amount;
-        db_target_account.account___amount += msg.amount;
+        let tx_time = Utc::now().to_string();
+        let valid_time = tx_time.parse::<DateTime<FixedOffset>>().unwrap();
+        let operation_id = CruxId::new(&Uuid::new_v4().to_string());
+
+        let fee_operation = fees::charge(
+            &mut db_source_account,
+            fee,
+            &operation_id,
+            &tx_time,
+            &msg.audit,
+        )?;
+        source_product.check_balance(source_balance_before, db_source_account.account___amount)?;
+        target_product.check_balance(target_balance_before, db_target_account.account___amount)?;
 
-        let action1 = Action::Put(edn_rs::to_string(db_source_account.clone()), None);
-        let action2 = Action::Put(edn_rs::to_string(db_target_account.clone()), None);
+        let mut actions = vec![
+            Action::Put(edn_rs::to_string(db_source_account.clone()), None),
+            Action::Put(edn_rs::to_string(db_target_account.clone()), None),
+        ];
 
-        let tx_time = Utc::now().to_string();
         let account_operation = DbAccountOperation {
-            crux__db___id: CruxId::new(&Uuid::new_v4().to_string()),
+            crux__db___id: operation_id,
             account_operation___type: OperationType::Transfer,
             account_operation___amount: msg.amount,
             account_operation___source_account_id: db_source_account.crux__db___id.clone(),
             account_operation___target_account_id: Some(db_target_account.crux__db___id.clone()),
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(entry.into_postings()),
+            account_operation___request_id: msg.audit.request_id.clone(),
+            account_operation___audit: Some(msg.audit.record.clone()),
             tx___tx_time: Some(tx_time.clone()),
         };
-        let action3 = Action::Put(
-            edn_rs::to_string(account_operation),
-            Some(tx_time.parse::<DateTime<FixedOffset>>().unwrap()),
-        );
-        client.tx_log(vec![action1, action2, action3])?;
+        actions.push(Action::Put(
+            edn_rs::to_string(account_operation.clone()),
+            Some(valid_time),
+        ));
+
+        if let Some(fee_operation) = &fee_operation {
+            actions.push(Action::Put(
+                edn_rs::to_string(fee_operation.clone()),
+                Some(valid_time),
+            ));
+        }
+
+        let mut operations = vec![account_operation];
+        operations.extend(fee_operation);
+        actions.extend(webhooks::enqueue(client, &operations).await?);
+        actions.extend(msg.also_write);
+
+        client.tx_log(actions).await?;
+        metrics::record_operations(&operations);
+        self.2.do_send(Publish(events::account_events(
+            &operations,
+            &[&db_source_account, &db_target_account],
+        )));
 
         Ok(db_source_account)
     }
@@ -259,25 +523,30 @@ impl Message for AccountHistory {
 }
 
 impl Handler<AccountHistory> for DbExecutor {
-    type Result = Result<Vec<ResponseAccountHistoryElement>, DbError>;
+    type Result = ResponseFuture<Result<Vec<ResponseAccountHistoryElement>, DbError>>;
 
     fn handle(&mut self, msg: AccountHistory, _: &mut Self::Context) -> Self::Result {
-        let client = self.0.http_client();
-        let response = client.entity_history(
-            edn_rs::to_string(CruxId::new(&msg.account_id)),
-            Order::Desc,
-            true,
-        )?;
-
-        if response.history.is_empty() {
-            return Err(DbError::NilEntity);
-        }
-
-        Ok(response
-            .history
-            .into_iter()
-            .map(ResponseAccountHistoryElement::from)
-            .collect::<Vec<ResponseAccountHistoryElement>>())
+        let client = self.0.clone();
+
+        Box::pin(async move {
+            let response = client
+                .entity_history(
+                    edn_rs::to_string(CruxId::new(&msg.account_id)),
+                    Order::Desc,
+                    true,
+                )
+                .await?;
+
+            if response.history.is_empty() {
+                return Err(DbError::NilEntity);
+            }
+
+            Ok(response
+                .history
+                .into_iter()
+                .map(ResponseAccountHistoryElement::from)
+                .collect::<Vec<ResponseAccountHistoryElement>>())
+        })
     }
 }
 
@@ -290,57 +559,227 @@ impl Message for AccountOperations {
 }
 
 impl Handler<AccountOperations> for DbExecutor {
-    type Result = Result<Vec<DbAccountOperation>, DbError>;
+    type Result = ResponseFuture<Result<Vec<DbAccountOperation>, DbError>>;
 
     fn handle(&mut self, msg: AccountOperations, _: &mut Self::Context) -> Self::Result {
-        let client = self.0.http_client();
-        let response = client.entity(edn_rs::to_string(CruxId::new(&msg.account_id)))?;
+        let executor = self.clone();
+
+        Box::pin(async move { executor.account_operations(msg).await })
+    }
+}
+
+impl DbExecutor {
+    async fn account_operations(
+        &self,
+        msg: AccountOperations,
+    ) -> Result<Vec<DbAccountOperation>, DbError> {
+        let client = &self.0;
+        let response = client
+            .entity(edn_rs::to_string(CruxId::new(&msg.account_id)))
+            .await?;
 
         if response == Edn::Nil {
             return Err(DbError::NilEntity);
         }
 
-        let query = Query::find(vec![
-            "?account-operation",
-            // "?tx-time",
-        ])?
-        .where_clause(vec![
-            "?account-operation :account-operation/source-account-id ?account-id",
-            // "?account-operation :tx/tx-time ?tx-time",
-        ])?
-        // ORDER TIME IDIOT
-        // .order_by(vec!["?tx-time :asc"])?
-        .args(vec![&format!("?account-id :{}", msg.account_id)])?
-        .build()?;
-
-        let operations = client.query(query)?;
-
-        Ok(operations
-            .iter()
-            .map(|a| {
-                let id = edn_rs::to_string(CruxId::new(&a[0]));
-
-                let edn_body = client.entity(id).unwrap();
-                edn_body
-            })
-            .map(|edn| edn_rs::from_edn(&edn))
-            .collect::<Result<Vec<DbAccountOperation>, EdnError>>()?)
+        // Transfers credited to the account are listed too, all in time
+        // order.
+        let mut pages = ledger::OperationPages::new(client, &CruxId::new(&msg.account_id));
+        let mut operations = Vec::new();
+        loop {
+            let page = pages.next_page().await?;
+            if page.is_empty() {
+                return Ok(operations);
+            }
+            operations.extend(page);
+        }
+    }
+}
+
+struct SetAccountLimits {
+    account_id: String,
+    limits: DbLimits,
+}
+
+impl Message for SetAccountLimits {
+    type Result = Result<DbLimits, DbError>;
+}
+
+impl Handler<SetAccountLimits> for DbExecutor {
+    type Result = ResponseFuture<Result<DbLimits, DbError>>;
+
+    fn handle(&mut self, msg: SetAccountLimits, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+        let config = self.1.clone();
+
+        Box::pin(async move {
+            let account_id = CruxId::new(&msg.account_id);
+            let _locks = ledger::lock(std::slice::from_ref(&account_id)).await;
+
+            let mut db_account = ledger::account(&client, &account_id)
+                .await?
+                .ok_or(DbError::NilEntity)?;
+            db_account.account___limits = Some(msg.limits);
+
+            client
+                .tx_log(vec![Action::Put(
+                    edn_rs::to_string(db_account.clone()),
+                    None,
+                )])
+                .await?;
+
+            Ok(limits::effective_limits(&config, &db_account))
+        })
+    }
+}
+
+struct GetAccountLimits {
+    account_id: String,
+}
+
+impl Message for GetAccountLimits {
+    type Result = Result<DbLimits, DbError>;
+}
+
+impl Handler<GetAccountLimits> for DbExecutor {
+    type Result = ResponseFuture<Result<DbLimits, DbError>>;
+
+    fn handle(&mut self, msg: GetAccountLimits, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+        let config = self.1.clone();
+
+        Box::pin(async move {
+            let db_account = ledger::cached_account(&client, &CruxId::new(&msg.account_id))
+                .await?
+                .ok_or(DbError::NilEntity)?;
+
+            Ok(limits::effective_limits(&config, &db_account))
+        })
+    }
+}
+
+struct FeeQuote {
+    account_id: String,
+    operation_type: OperationType,
+    amount: usize,
+}
+
+impl Message for FeeQuote {
+    type Result = Result<usize, DbError>;
+}
+
+impl Handler<FeeQuote> for DbExecutor {
+    type Result = ResponseFuture<Result<usize, DbError>>;
+
+    fn handle(&mut self, msg: FeeQuote, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+        let config = self.1.clone();
+
+        Box::pin(async move {
+            let db_account = ledger::cached_account(&client, &CruxId::new(&msg.account_id))
+                .await?
+                .ok_or(DbError::NilEntity)?;
+
+            fees::quote(
+                &client,
+                &config,
+                &db_account,
+                &msg.operation_type,
+                msg.amount,
+            )
+            .await
+        })
+    }
+}
+
+struct Reconcile;
+
+impl Message for Reconcile {
+    type Result = Result<Vec<reconcile::Mismatch>, DbError>;
+}
+
+impl Handler<Reconcile> for DbExecutor {
+    type Result = ResponseFuture<Result<Vec<reconcile::Mismatch>, DbError>>;
+
+    fn handle(&mut self, _: Reconcile, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+
+        Box::pin(async move { reconcile::run(&client).await })
     }
 }
 
+timed_handlers!(
+    CreateAccount,
+    GetAccount,
+    AccountDeposit,
+    AccountWithdraw,
+    AccountTransfer,
+    AccountHistory,
+    AccountOperations,
+    SetAccountLimits,
+    GetAccountLimits,
+    FeeQuote,
+    Reconcile,
+    events::AccountEventsAfter,
+    health::CheckCrux,
+    exports::ExportOperations,
+    exports::NextLines,
+    imports::ImportAccounts,
+    interest::RunInterest,
+    payments::InitiatePayments,
+    statements::AccountStatement,
+    webhooks::CreateWebhook,
+    webhooks::WebhookDeliveries,
+);
+
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct DbAccount {
-    crux__db___id: CruxId,   // :crux.db/id
-    account___amount: usize, // :account/amount
+    crux__db___id: CruxId,                  // :crux.db/id
+    account___amount: i64,                  // :account/amount
+    account___type: Option<String>,         // :account/type
+    account___limits: Option<DbLimits>,     // :account/limits
+    account___interest_rate: Option<usize>, // :account/interest-rate
+    account___external_id: Option<String>,  // :account/external-id
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 enum OperationType {
     Create,
     Deposit,
     Withdraw,
     Transfer,
+    Fee,
+    Interest,
+}
+
+documented!(
+    enum OperationType {
+        Create,
+        Deposit,
+        Withdraw,
+        Transfer,
+        Fee,
+        Interest,
+    }
+);
+
+impl OperationType {
+    /// Reads an operation type written as a plain keyword, e.g. `:deposit`.
+    fn from_keyword(edn: &Edn) -> Result<Self, EdnError> {
+        match edn {
+            Edn::Key(k) if k == ":create" => Ok(OperationType::Create),
+            Edn::Key(k) if k == ":deposit" => Ok(OperationType::Deposit),
+            Edn::Key(k) if k == ":withdraw" => Ok(OperationType::Withdraw),
+            Edn::Key(k) if k == ":transfer" => Ok(OperationType::Transfer),
+            Edn::Key(k) if k == ":fee" => Ok(OperationType::Fee),
+            Edn::Key(k) if k == ":interest" => Ok(OperationType::Interest),
+            _ => Err(EdnError::Deserialize(format!(
+                "couldn't convert {} into an operation type",
+                edn
+            ))),
+        }
+    }
 }
 
 #[allow(non_snake_case)]
@@ -351,19 +790,39 @@ struct DbAccountOperation {
     account_operation___amount: usize,                     // :account-operation/amount
     account_operation___source_account_id: CruxId,         // :account-operation/source-account-id
     account_operation___target_account_id: Option<CruxId>, // :account-operation/target-account-id
+    account_operation___triggered_by: Option<CruxId>,      // :account-operation/triggered-by
+    account_operation___postings: Option<Vec<DbPosting>>,  // :account-operation/postings
+    account_operation___request_id: Option<String>,        // :account-operation/request-id
+    account_operation___audit: Option<DbAudit>,            // :account-operation/audit
     tx___tx_time: Option<String>,                          // :tx/tx-time
 }
 
 struct State {
     db: Addr<DbExecutor>,
+    broker: Addr<EventBroker>,
+    config: Arc<Config>,
+    running_totals: Arc<RwLock<RunningTotals>>,
+    operations_index: Arc<RwLock<OperationsIndex>>,
+    rate_limiter: Arc<ratelimit::RateLimiter>,
 }
 
 #[derive(Serialize)]
 struct ResponseAccount {
     id: String,
-    amount: usize,
+    amount: i64,
+    account_type: Option<String>,
+    interest_rate: Option<usize>,
+    external_id: Option<String>,
 }
 
+documented!(ResponseAccount {
+    id: String,
+    amount: i64,
+    account_type: Option<String>,
+    interest_rate: Option<usize>,
+    external_id: Option<String>,
+});
+
 impl From<DbAccount> for ResponseAccount {
     fn from(db_account: DbAccount) -> Self {
         let mut uuid_without_colon = edn_rs::to_string(db_account.crux__db___id);
@@ -372,6 +831,9 @@ impl From<DbAccount> for ResponseAccount {
         Self {
             id: uuid_without_colon,
             amount: db_account.account___amount,
+            account_type: db_account.account___type,
+            interest_rate: db_account.account___interest_rate,
+            external_id: db_account.account___external_id,
         }
     }
 }
@@ -379,17 +841,23 @@ impl From<DbAccount> for ResponseAccount {
 #[derive(Serialize)]
 struct ResponseAccountHistoryElement {
     id: String,
-    amount: usize,
+    amount: i64,
     time: String,
 }
 
+documented!(ResponseAccountHistoryElement {
+    id: String,
+    amount: i64,
+    time: String,
+});
+
 impl From<EntityHistoryElement> for ResponseAccountHistoryElement {
     fn from(history_element: EntityHistoryElement) -> Self {
         let edn_document = history_element.db__doc.unwrap();
 
         Self {
             id: edn_document[":crux.db/id"].to_string(),
-            amount: edn_document[":account/amount"].to_uint().unwrap_or(0),
+            amount: edn_document[":account/amount"].to_int().unwrap_or(0) as i64,
             time: history_element.tx___tx_time.to_string(),
         }
     }
@@ -402,7 +870,51 @@ struct ResponseAccountOperation {
     amount: usize,
     source_account_id: String,
     target_account_id: Option<String>,
+    triggered_by: Option<String>,
+    postings: Vec<ResponsePosting>,
+    request_id: Option<String>,
+    /// Only shown to admins.
+    audit: Option<ResponseAudit>,
+    time: String,
+}
+
+documented!(ResponseAccountOperation {
+    id: String,
+    operation_type: OperationType,
+    amount: usize,
+    source_account_id: String,
+    target_account_id: Option<String>,
+    triggered_by: Option<String>,
+    postings: Vec<ResponsePosting>,
+    request_id: Option<String>,
+    audit: Option<ResponseAudit>,
     time: String,
+});
+
+#[derive(Serialize)]
+struct ResponsePosting {
+    account_id: String,
+    side: PostingSide,
+    amount: usize,
+}
+
+documented!(ResponsePosting {
+    account_id: String,
+    side: PostingSide,
+    amount: usize,
+});
+
+impl From<DbPosting> for ResponsePosting {
+    fn from(db_posting: DbPosting) -> Self {
+        let mut account_id_without_colon = edn_rs::to_string(db_posting.posting___account_id);
+        account_id_without_colon.remove(0);
+
+        Self {
+            account_id: account_id_without_colon,
+            side: db_posting.posting___side,
+            amount: db_posting.posting___amount,
+        }
+    }
 }
 
 impl From<DbAccountOperation> for ResponseAccountOperation {
@@ -428,21 +940,224 @@ impl From<DbAccountOperation> for ResponseAccountOperation {
 
                     target_id_without_colon
                 }),
+            triggered_by: db_account_operation.account_operation___triggered_by.map(
+                |operation_id| {
+                    let mut operation_id_without_colon = edn_rs::to_string(operation_id);
+
+                    operation_id_without_colon.remove(0);
+
+                    operation_id_without_colon
+                },
+            ),
+            postings: db_account_operation
+                .account_operation___postings
+                .unwrap_or_default()
+                .into_iter()
+                .map(ResponsePosting::from)
+                .collect(),
+            request_id: db_account_operation.account_operation___request_id,
+            audit: None,
             time: db_account_operation.tx___tx_time.unwrap(),
         }
     }
 }
 
+/// The largest amount an account can be opened with or an operation can
+/// move, as balances are signed.
+const MAX_AMOUNT: usize = i64::MAX as usize;
+
 #[derive(Deserialize)]
 struct RequestAccount {
     amount: usize,
+    account_type: Option<String>,
+    /// Annual rate, in basis points.
+    interest_rate: Option<usize>,
 }
 
+documented!(RequestAccount {
+    amount: usize,
+    account_type: Option<String>,
+    interest_rate: Option<usize>,
+});
+
 impl From<RequestAccount> for DbAccount {
     fn from(req_account: RequestAccount) -> Self {
         Self {
             crux__db___id: CruxId::new(&Uuid::new_v4().to_string()),
-            account___amount: req_account.amount,
+            account___amount: req_account.amount as i64,
+            account___type: req_account.account_type,
+            account___limits: None,
+            account___interest_rate: req_account.interest_rate,
+            account___external_id: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseAccountSummary {
+    id: String,
+    balance: i64,
+    inflow: usize,
+    outflow: usize,
+    operations: usize,
+    operation_ids: Vec<String>,
+}
+
+documented!(ResponseAccountSummary {
+    id: String,
+    balance: i64,
+    inflow: usize,
+    outflow: usize,
+    operations: usize,
+    operation_ids: Vec<String>,
+});
+
+#[derive(Serialize)]
+struct ResponseWebhook {
+    id: String,
+    url: String,
+    events: Option<Vec<OperationType>>,
+    account_id: Option<String>,
+}
+
+documented!(ResponseWebhook {
+    id: String,
+    url: String,
+    events: Option<Vec<OperationType>>,
+    account_id: Option<String>,
+});
+
+impl From<DbWebhook> for ResponseWebhook {
+    fn from(db_webhook: DbWebhook) -> Self {
+        let mut id_without_colon = edn_rs::to_string(db_webhook.crux__db___id);
+        id_without_colon.remove(0);
+
+        Self {
+            id: id_without_colon,
+            url: db_webhook.webhook___url,
+            events: db_webhook.webhook___events,
+            account_id: db_webhook.webhook___account_id.map(|account_id| {
+                let mut account_id_without_colon = edn_rs::to_string(account_id);
+
+                account_id_without_colon.remove(0);
+
+                account_id_without_colon
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseDelivery {
+    id: String,
+    operation_id: String,
+    status: DeliveryStatus,
+    created_at: String,
+    next_attempt_at: Option<String>,
+    attempts: Vec<ResponseDeliveryAttempt>,
+}
+
+#[derive(Serialize)]
+struct ResponseDeliveryAttempt {
+    time: String,
+    status_code: Option<usize>,
+    error: Option<String>,
+}
+
+impl From<DbDeliveryAttempt> for ResponseDeliveryAttempt {
+    fn from(db_attempt: DbDeliveryAttempt) -> Self {
+        Self {
+            time: db_attempt.attempt___time,
+            status_code: db_attempt.attempt___status_code,
+            error: db_attempt.attempt___error,
+        }
+    }
+}
+
+impl From<DbDelivery> for ResponseDelivery {
+    fn from(db_delivery: DbDelivery) -> Self {
+        let mut id_without_colon = edn_rs::to_string(db_delivery.crux__db___id);
+        id_without_colon.remove(0);
+
+        let mut operation_id_without_colon = edn_rs::to_string(db_delivery.delivery___operation_id);
+        operation_id_without_colon.remove(0);
+
+        Self {
+            id: id_without_colon,
+            operation_id: operation_id_without_colon,
+            status: db_delivery.delivery___status,
+            created_at: db_delivery.delivery___created_at,
+            next_attempt_at: db_delivery.delivery___next_attempt_at,
+            attempts: db_delivery
+                .delivery___attempts
+                .into_iter()
+                .map(ResponseDeliveryAttempt::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseProduct {
+    name: String,
+    description: Option<String>,
+    operations: Vec<OperationType>,
+    withdrawals: bool,
+    min_balance: usize,
+    overdraft: usize,
+    max_balance: Option<usize>,
+    interest_rate: Option<usize>,
+}
+
+impl From<Product> for ResponseProduct {
+    fn from(product: Product) -> Self {
+        Self {
+            name: product.name,
+            description: product.description,
+            operations: product.operations,
+            withdrawals: product.withdrawals,
+            min_balance: product.min_balance,
+            overdraft: product.overdraft,
+            max_balance: product.max_balance,
+            interest_rate: product.interest_rate,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ResponseFeeQuote {
+    operation_type: OperationType,
+    amount: usize,
+    fee: usize,
+    total: usize,
+}
+
+documented!(ResponseFeeQuote {
+    operation_type: OperationType,
+    amount: usize,
+    fee: usize,
+    total: usize,
+});
+
+#[derive(Serialize)]
+struct ResponseLimits {
+    max_per_operation: Option<usize>,
+    max_daily_total: Option<usize>,
+    max_daily_count: Option<usize>,
+}
+
+documented!(ResponseLimits {
+    max_per_operation: Option<usize>,
+    max_daily_total: Option<usize>,
+    max_daily_count: Option<usize>,
+});
+
+impl From<DbLimits> for ResponseLimits {
+    fn from(db_limits: DbLimits) -> Self {
+        Self {
+            max_per_operation: db_limits.limits___max_per_operation,
+            max_daily_total: db_limits.limits___max_daily_total,
+            max_daily_count: db_limits.limits___max_daily_count,
         }
     }
 }
@@ -450,40 +1165,68 @@ impl From<RequestAccount> for DbAccount {
 async fn create_account(
     data: web::Data<State>,
     body: String,
+    audit: Audit,
 ) -> Result<HttpResponse, HttpResponse> {
     let req_account: RequestAccount =
         edn_rs::from_str(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
+    if req_account.amount > MAX_AMOUNT {
+        return Err(HttpResponse::BadRequest().finish());
+    }
 
     let response = data
         .db
-        .send(CreateAccount {
+        .send(Timed::new(CreateAccount {
             account: req_account.into(),
-        })
+            audit,
+        }))
         .await;
     let db_account = response
         .map_err(|_| HttpResponse::InternalServerError().finish())?
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+        .map_err(|db_error| match db_error {
+            DbError::StateConflict => HttpResponse::UnprocessableEntity().finish(),
+            DbError::ProductRule(violation) => HttpResponse::UnprocessableEntity()
+                .content_type("application/edn")
+                .body(violation.to_edn()),
+            db_error => server_error(db_error),
+        })?;
 
     Ok(HttpResponse::Created()
         .content_type("application/edn")
         .body(edn_rs::to_string(ResponseAccount::from(db_account))))
 }
 
+/// System accounts are served from the projections, as their balances are
+/// the sum of their postings.
 async fn get_account(
     data: web::Data<State>,
     account_id: web::Path<String>,
 ) -> Result<HttpResponse, HttpResponse> {
+    if let Some(system) = SystemAccount::from_id(&CruxId::new(&account_id)) {
+        let balance = data
+            .running_totals
+            .read()
+            .unwrap()
+            .get(&system.id())
+            .map_or(0, |totals| totals.balance);
+
+        return Ok(HttpResponse::Ok()
+            .content_type("application/edn")
+            .body(edn_rs::to_string(ResponseAccount::from(
+                ledger::system_account(system, balance),
+            ))));
+    }
+
     let response = data
         .db
-        .send(GetAccount {
+        .send(Timed::new(GetAccount {
             account_id: account_id.to_string(),
-        })
+        }))
         .await;
     let db_account = response
         .map_err(|_| HttpResponse::InternalServerError().finish())?
         .map_err(|db_error| match db_error {
             DbError::NilEntity => HttpResponse::NotFound().finish(),
-            _ => HttpResponse::InternalServerError().finish(),
+            db_error => server_error(db_error),
         })?;
 
     Ok(HttpResponse::Ok()
@@ -491,84 +1234,357 @@ async fn get_account(
         .body(edn_rs::to_string(ResponseAccount::from(db_account))))
 }
 
-async fn account_deposit(
-    data: web::Data<State>,
-    account_id: web::Path<String>,
-    body: String,
-) -> Result<HttpResponse, HttpResponse> {
-    let edn_body = Edn::from_str(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
+/// Status and body answered to a deposit, withdrawal or transfer, shared
+/// by the HTTP routes and the WebSocket.
+fn operation_outcome(
+    response: Result<Result<DbAccount, DbError>, MailboxError>,
+) -> (StatusCode, Option<String>) {
+    match response {
+        Ok(Ok(db_account)) => (
+            StatusCode::OK,
+            Some(edn_rs::to_string(ResponseAccount::from(db_account))),
+        ),
+        Ok(Err(DbError::NilEntity)) => (StatusCode::NOT_FOUND, None),
+        Ok(Err(DbError::StateConflict)) => (StatusCode::CONFLICT, None),
+        Ok(Err(DbError::LimitExceeded(limit_exceeded))) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Some(limit_exceeded.to_edn()),
+        ),
+        Ok(Err(DbError::ProductRule(violation))) => {
+            (StatusCode::UNPROCESSABLE_ENTITY, Some(violation.to_edn()))
+        }
+        Ok(Err(DbError::Crux(error))) if error.is_unknown() => (StatusCode::GATEWAY_TIMEOUT, None),
+        Ok(Err(DbError::Crux(error))) if error.is_retryable() => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            error
+                .retry_after_seconds()
+                .map(|seconds| format!("{{:retry-after {}}}", seconds)),
+        ),
+        Ok(Err(_)) | Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, None),
+    }
+}
 
-    let account_id = account_id.to_string();
-    let amount = edn_body[":amount"].to_uint().unwrap_or(0);
+/// Answer to a deposit, withdrawal or transfer over HTTP, telling when to
+/// retry in a header.
+fn operation_response(response: Result<Result<DbAccount, DbError>, MailboxError>) -> HttpResponse {
+    match response {
+        Ok(Err(db_error @ DbError::Crux(_))) => server_error(db_error),
+        response => edn_response(operation_outcome(response)),
+    }
+}
 
-    let response = data.db.send(AccountDeposit { account_id, amount }).await;
-    let db_account = response
-        .map_err(|_| HttpResponse::InternalServerError().finish())?
-        .map_err(|db_error| match db_error {
-            DbError::NilEntity => HttpResponse::NotFound().finish(),
-            _ => HttpResponse::InternalServerError().finish(),
-        })?;
+/// Answer to errors the client can't fix: `503` while Crux can't be
+/// reached, with `Retry-After` when the circuit breaker is open, `504`
+/// without any when a write may have happened anyway, `500` otherwise.
+fn server_error(db_error: DbError) -> HttpResponse {
+    match db_error {
+        DbError::Crux(error) if error.is_unknown() => HttpResponse::GatewayTimeout().finish(),
+        DbError::Crux(error) if error.is_retryable() => {
+            let mut response = HttpResponse::ServiceUnavailable();
+            if let Some(seconds) = error.retry_after_seconds() {
+                response.header("Retry-After", seconds.to_string());
+            }
+            response.finish()
+        }
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/edn")
-        .body(edn_rs::to_string(ResponseAccount::from(db_account))))
+fn edn_response((status, body): (StatusCode, Option<String>)) -> HttpResponse {
+    match body {
+        Some(body) => HttpResponse::build(status)
+            .content_type("application/edn")
+            .body(body),
+        None => HttpResponse::build(status).finish(),
+    }
 }
 
-async fn account_withdraw(
+/// The `:amount` of an operation request, `0` when left out. Amounts past
+/// the largest balance are refused, as they'd wrap into negative ones.
+fn request_amount(edn_body: &Edn) -> Option<usize> {
+    Some(edn_body[":amount"].to_uint().unwrap_or(0)).filter(|amount| *amount <= MAX_AMOUNT)
+}
+
+async fn account_deposit(
     data: web::Data<State>,
     account_id: web::Path<String>,
     body: String,
+    audit: Audit,
 ) -> Result<HttpResponse, HttpResponse> {
     let edn_body = Edn::from_str(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
 
     let account_id = account_id.to_string();
-    let amount = edn_body[":amount"].to_uint().unwrap_or(0);
+    let amount = request_amount(&edn_body).ok_or_else(|| HttpResponse::BadRequest().finish())?;
 
-    let response = data.db.send(AccountWithdraw { account_id, amount }).await;
-    let db_account = response
-        .map_err(|_| HttpResponse::InternalServerError().finish())?
-        .map_err(|db_error| match db_error {
-            DbError::NilEntity => HttpResponse::NotFound().finish(),
-            _ => HttpResponse::InternalServerError().finish(),
-        })?;
+    let response = data
+        .db
+        .send(Timed::new(AccountDeposit {
+            account_id,
+            amount,
+            audit,
+        }))
+        .await;
+    Ok(operation_response(response))
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/edn")
-        .body(edn_rs::to_string(ResponseAccount::from(db_account))))
+async fn account_withdraw(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+    body: String,
+    audit: Audit,
+) -> Result<HttpResponse, HttpResponse> {
+    let edn_body = Edn::from_str(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
+
+    let account_id = account_id.to_string();
+    let amount = request_amount(&edn_body).ok_or_else(|| HttpResponse::BadRequest().finish())?;
+
+    let response = data
+        .db
+        .send(Timed::new(AccountWithdraw {
+            account_id,
+            amount,
+            audit,
+        }))
+        .await;
+    Ok(operation_response(response))
 }
 
 async fn account_transfer(
     data: web::Data<State>,
     source_account_id: web::Path<String>,
     body: String,
+    audit: Audit,
 ) -> Result<HttpResponse, HttpResponse> {
     let edn_body = Edn::from_str(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
 
     let source_account_id = source_account_id.to_string();
-    let amount = edn_body[":amount"].to_uint().unwrap_or(0);
+    let amount = request_amount(&edn_body).ok_or_else(|| HttpResponse::BadRequest().finish())?;
     let target_account_id: String = edn_rs::from_edn(&edn_body[":target-account-id"])
         .map_err(|_| HttpResponse::BadRequest().finish())?;
 
     let response = data
         .db
-        .send(AccountTransfer {
+        .send(Timed::new(AccountTransfer {
             source_account_id,
             amount,
             target_account_id,
+            audit,
+            also_write: Vec::new(),
+        }))
+        .await;
+    Ok(operation_response(response))
+}
+
+async fn set_account_limits(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+    body: String,
+) -> Result<HttpResponse, HttpResponse> {
+    let edn_body = Edn::from_str(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
+    let limits =
+        DbLimits::from_settings(&edn_body).map_err(|_| HttpResponse::BadRequest().finish())?;
+
+    let response = data
+        .db
+        .send(Timed::new(SetAccountLimits {
+            account_id: account_id.to_string(),
+            limits,
+        }))
+        .await;
+    let db_limits = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(|db_error| match db_error {
+            DbError::NilEntity => HttpResponse::NotFound().finish(),
+            db_error => server_error(db_error),
+        })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/edn")
+        .body(edn_rs::to_string(ResponseLimits::from(db_limits))))
+}
+
+async fn get_account_limits(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+) -> Result<HttpResponse, HttpResponse> {
+    let response = data
+        .db
+        .send(Timed::new(GetAccountLimits {
+            account_id: account_id.to_string(),
+        }))
+        .await;
+    let db_limits = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(|db_error| match db_error {
+            DbError::NilEntity => HttpResponse::NotFound().finish(),
+            db_error => server_error(db_error),
+        })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/edn")
+        .body(edn_rs::to_string(ResponseLimits::from(db_limits))))
+}
+
+async fn fee_quote(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, HttpResponse> {
+    let operation_type = match query.get("operation").map(String::as_str) {
+        Some("withdraw") => OperationType::Withdraw,
+        Some("transfer") => OperationType::Transfer,
+        _ => return Err(HttpResponse::BadRequest().finish()),
+    };
+    let amount = query
+        .get("amount")
+        .and_then(|amount| amount.parse::<usize>().ok())
+        .ok_or_else(|| HttpResponse::BadRequest().finish())?;
+
+    let response = data
+        .db
+        .send(Timed::new(FeeQuote {
+            account_id: account_id.to_string(),
+            operation_type: operation_type.clone(),
+            amount,
+        }))
+        .await;
+    let fee = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(|db_error| match db_error {
+            DbError::NilEntity => HttpResponse::NotFound().finish(),
+            db_error => server_error(db_error),
+        })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/edn")
+        .body(edn_rs::to_string(ResponseFeeQuote {
+            operation_type,
+            amount,
+            fee,
+            total: amount.saturating_add(fee),
+        })))
+}
+
+async fn account_events(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+    request: HttpRequest,
+) -> Result<HttpResponse, HttpResponse> {
+    let last_event_id = request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|header| header.to_str().ok())
+        .map(String::from);
+
+    // Subscribing before replaying means nothing written in between is lost,
+    // only possibly sent twice, which the filter below prevents.
+    let (sender, receiver) = mpsc::unbounded();
+    data.broker
+        .send(events::Subscribe {
+            account_id: CruxId::new(&account_id),
+            sender,
         })
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    let response = data
+        .db
+        .send(Timed::new(events::AccountEventsAfter {
+            account_id: account_id.to_string(),
+            last_event_id,
+        }))
         .await;
-    let db_account = response
+    let replayed = response
         .map_err(|_| HttpResponse::InternalServerError().finish())?
         .map_err(|db_error| match db_error {
             DbError::NilEntity => HttpResponse::NotFound().finish(),
-            DbError::StateConflict => HttpResponse::Conflict().finish(),
-            DbError::CruxError(_) => HttpResponse::InternalServerError().finish(),
-            DbError::EdnError(_) => HttpResponse::InternalServerError().finish(),
+            db_error => server_error(db_error),
         })?;
 
+    let replayed_ids = replayed
+        .iter()
+        .map(|event| event.id())
+        .collect::<HashSet<String>>();
+    let live = receiver.filter(move |notification| {
+        future::ready(match notification {
+            Notification::Event(event) => !replayed_ids.contains(&event.id()),
+            Notification::Heartbeat => true,
+        })
+    });
+
+    let body = stream::iter(
+        replayed
+            .into_iter()
+            .map(|event| Notification::Event(Box::new(event))),
+    )
+    .chain(live)
+    .map(|notification| Ok::<Bytes, actix_web::Error>(Bytes::from(notification.to_sse())));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(body))
+}
+
+async fn account_socket(
+    data: web::Data<State>,
+    request: HttpRequest,
+    stream: web::Payload,
+    audit: Audit,
+) -> Result<HttpResponse, actix_web::Error> {
+    let client = data.rate_limiter.client(&request);
+
+    ws::start(
+        socket::AccountSocket::new(
+            data.db.clone(),
+            data.broker.clone(),
+            audit,
+            data.rate_limiter.clone(),
+            client,
+        ),
+        &request,
+        stream,
+    )
+}
+
+/// Served from the projections instead of Crux, so it may lag a moment
+/// behind writes.
+async fn account_summary(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+) -> Result<HttpResponse, HttpResponse> {
+    let crux_id = CruxId::new(&account_id);
+
+    let totals = data
+        .running_totals
+        .read()
+        .unwrap()
+        .get(&crux_id)
+        .cloned()
+        .ok_or_else(|| HttpResponse::NotFound().finish())?;
+    let operation_ids = data
+        .operations_index
+        .read()
+        .unwrap()
+        .get(&crux_id)
+        .iter()
+        .map(|operation_id| {
+            let mut operation_id_without_colon = edn_rs::to_string(operation_id.clone());
+            operation_id_without_colon.remove(0);
+            operation_id_without_colon
+        })
+        .collect::<Vec<String>>();
+
     Ok(HttpResponse::Ok()
         .content_type("application/edn")
-        .body(edn_rs::to_string(ResponseAccount::from(db_account))))
+        .body(edn_rs::to_string(ResponseAccountSummary {
+            id: account_id.to_string(),
+            balance: totals.balance,
+            inflow: totals.inflow,
+            outflow: totals.outflow,
+            operations: totals.operations,
+            operation_ids,
+        })))
 }
 
 async fn account_history(
@@ -577,15 +1593,15 @@ async fn account_history(
 ) -> Result<HttpResponse, HttpResponse> {
     let response = data
         .db
-        .send(AccountHistory {
+        .send(Timed::new(AccountHistory {
             account_id: account_id.to_string(),
-        })
+        }))
         .await;
     let response_history = response
         .map_err(|_| HttpResponse::InternalServerError().finish())?
         .map_err(|db_error| match db_error {
             DbError::NilEntity => HttpResponse::NotFound().finish(),
-            _ => HttpResponse::InternalServerError().finish(),
+            db_error => server_error(db_error),
         })?;
 
     Ok(HttpResponse::Ok()
@@ -593,26 +1609,45 @@ async fn account_history(
         .body(edn_rs::to_string(response_history)))
 }
 
+/// Admins also get each operation's audit metadata, and may filter by it
+/// with `principal`, `client-id`, `source-ip` and `channel`.
 async fn account_operations(
     data: web::Data<State>,
     account_id: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    audit: Audit,
 ) -> Result<HttpResponse, HttpResponse> {
+    let admin = audit.is_admin(&data.config);
+    let filter =
+        AuditFilter::from_query(&query).map_err(|error| HttpResponse::BadRequest().body(error))?;
+    if !admin && !filter.is_empty() {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+
     let response = data
         .db
-        .send(AccountOperations {
+        .send(Timed::new(AccountOperations {
             account_id: account_id.to_string(),
-        })
+        }))
         .await;
     let db_account_operations = response
         .map_err(|_| HttpResponse::InternalServerError().finish())?
         .map_err(|db_error| match db_error {
             DbError::NilEntity => HttpResponse::NotFound().finish(),
-            _ => HttpResponse::InternalServerError().finish(),
+            db_error => server_error(db_error),
         })?;
 
     let response_operations = db_account_operations
         .into_iter()
-        .map(ResponseAccountOperation::from)
+        .filter(|operation| filter.matches(operation.account_operation___audit.as_ref()))
+        .map(|operation| {
+            let audit = operation.account_operation___audit.clone();
+            let mut response_operation = ResponseAccountOperation::from(operation);
+            if admin {
+                response_operation.audit = audit.map(ResponseAudit::from);
+            }
+            response_operation
+        })
         .collect::<Vec<ResponseAccountOperation>>();
 
     Ok(HttpResponse::Ok()
@@ -620,46 +1655,1074 @@ async fn account_operations(
         .body(edn_rs::to_string(response_operations)))
 }
 
+/// The `from` and `to` parameters of exports and statements.
+fn period(query: &HashMap<String, String>) -> Result<exports::Period, HttpResponse> {
+    let time = |name: &str, end| {
+        query
+            .get(name)
+            .map(|value| exports::parse_time(value, end))
+            .transpose()
+            .map_err(|error| HttpResponse::BadRequest().body(error))
+    };
+
+    Ok((time("from", false)?, time("to", true)?))
+}
+
+/// Streamed as it's rendered, so long exports aren't held whole. Lines past
+/// the first page are read while the file is sent.
+async fn export_operations(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, HttpResponse> {
+    let format = match query.get("format").map(String::as_str) {
+        Some(name) => exports::Format::from_name(name)
+            .ok_or_else(|| HttpResponse::BadRequest().body(format!("unknown format {}", name)))?,
+        None => exports::Format::Csv,
+    };
+    let (from, to) = period(&query)?;
+
+    let response = data
+        .db
+        .send(Timed::new(exports::ExportOperations {
+            account_id: account_id.to_string(),
+            from,
+            to,
+        }))
+        .await;
+    let export = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(|db_error| match db_error {
+            DbError::NilEntity => HttpResponse::NotFound().finish(),
+            db_error => server_error(db_error),
+        })?;
+
+    let file_name = format!("{}.{}", account_id, format.extension());
+    let body = export
+        .render(format, &data.config.exports, data.db.clone())
+        .map(|piece| piece.map(Bytes::from));
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .streaming(body))
+}
+
+async fn account_statement(
+    data: web::Data<State>,
+    account_id: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, HttpResponse> {
+    let format = match query.get("format").map(String::as_str) {
+        Some(name) => statements::Format::from_name(name)
+            .ok_or_else(|| HttpResponse::BadRequest().body(format!("unknown format {}", name)))?,
+        None => statements::Format::Camt053,
+    };
+    let (from, to) = period(&query)?;
+
+    let response = data
+        .db
+        .send(Timed::new(statements::AccountStatement {
+            account_id: account_id.to_string(),
+            from,
+            to,
+        }))
+        .await;
+    let statement = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(|db_error| match db_error {
+            DbError::NilEntity => HttpResponse::NotFound().finish(),
+            db_error => server_error(db_error),
+        })?;
+
+    let file_name = format!("{}.{}", account_id, format.extension());
+    let body = stream::iter(
+        statement
+            .render(format, &data.config.exports)
+            .map(|piece| Ok::<Bytes, actix_web::Error>(Bytes::from(piece))),
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", file_name),
+        )
+        .streaming(body))
+}
+
+async fn list_products(data: web::Data<State>) -> HttpResponse {
+    let mut products = data
+        .config
+        .products
+        .values()
+        .cloned()
+        .map(ResponseProduct::from)
+        .collect::<Vec<ResponseProduct>>();
+    products.sort_by(|a, b| a.name.cmp(&b.name));
+
+    HttpResponse::Ok()
+        .content_type("application/edn")
+        .body(edn_rs::to_string(products))
+}
+
+async fn create_webhook(
+    data: web::Data<State>,
+    body: String,
+    audit: Audit,
+) -> Result<HttpResponse, HttpResponse> {
+    // Webhooks see the operations of every account and make smaug call
+    // their URLs.
+    if !audit.is_admin(&data.config) {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+
+    let edn_body = Edn::from_str(&body).map_err(|_| HttpResponse::BadRequest().finish())?;
+
+    let url: String =
+        edn_rs::from_edn(&edn_body[":url"]).map_err(|_| HttpResponse::BadRequest().finish())?;
+    let secret: String =
+        edn_rs::from_edn(&edn_body[":secret"]).map_err(|_| HttpResponse::BadRequest().finish())?;
+    if !(url.starts_with("http://") || url.starts_with("https://")) || secret.is_empty() {
+        return Err(HttpResponse::BadRequest().finish());
+    }
+
+    let events = match edn_body[":events"].iter() {
+        Some(iter) => Some(
+            iter.map(OperationType::from_keyword)
+                .collect::<Result<Vec<OperationType>, EdnError>>()
+                .map_err(|_| HttpResponse::BadRequest().finish())?,
+        ),
+        None => None,
+    };
+    let account_id = match &edn_body[":account-id"] {
+        Edn::Nil => None,
+        account_id => Some(CruxId::new(
+            &edn_rs::from_edn::<String>(account_id)
+                .map_err(|_| HttpResponse::BadRequest().finish())?,
+        )),
+    };
+
+    let response = data
+        .db
+        .send(Timed::new(webhooks::CreateWebhook {
+            webhook: DbWebhook {
+                crux__db___id: CruxId::new(&Uuid::new_v4().to_string()),
+                webhook___url: url,
+                webhook___events: events,
+                webhook___account_id: account_id,
+                webhook___secret: secret,
+            },
+        }))
+        .await;
+    let db_webhook = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(server_error)?;
+
+    Ok(HttpResponse::Created()
+        .content_type("application/edn")
+        .body(edn_rs::to_string(ResponseWebhook::from(db_webhook))))
+}
+
+async fn webhook_deliveries(
+    data: web::Data<State>,
+    webhook_id: web::Path<String>,
+    audit: Audit,
+) -> Result<HttpResponse, HttpResponse> {
+    if !audit.is_admin(&data.config) {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+
+    let response = data
+        .db
+        .send(Timed::new(webhooks::WebhookDeliveries {
+            webhook_id: webhook_id.to_string(),
+        }))
+        .await;
+    let db_deliveries = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(|db_error| match db_error {
+            DbError::NilEntity => HttpResponse::NotFound().finish(),
+            db_error => server_error(db_error),
+        })?;
+
+    let response_deliveries = db_deliveries
+        .into_iter()
+        .map(ResponseDelivery::from)
+        .collect::<Vec<ResponseDelivery>>();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/edn")
+        .body(edn_rs::to_string(response_deliveries)))
+}
+
+async fn reconciliation(
+    data: web::Data<State>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, HttpResponse> {
+    let response = data.db.send(Timed::new(Reconcile)).await;
+    let mismatches = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(server_error)?;
+
+    match query.get("format").map(String::as_str) {
+        Some("csv") => Ok(HttpResponse::Ok()
+            .content_type("text/csv")
+            .body(reconcile::to_csv(&mismatches))),
+        Some("edn") | None => Ok(HttpResponse::Ok()
+            .content_type("application/edn")
+            .body(edn_rs::to_string(mismatches))),
+        Some(_) => Err(HttpResponse::BadRequest().finish()),
+    }
+}
+
+async fn import_accounts(
+    data: web::Data<State>,
+    request: HttpRequest,
+    query: web::Query<HashMap<String, String>>,
+    body: String,
+    audit: Audit,
+) -> Result<HttpResponse, HttpResponse> {
+    if !audit.is_admin(&data.config) {
+        return Err(HttpResponse::Forbidden().finish());
+    }
+
+    let header = |name| audit::header(request.headers(), name).unwrap_or_default();
+    let format = match query.get("format").map(String::as_str) {
+        Some("csv") => imports::Format::Csv,
+        Some("edn") => imports::Format::Edn,
+        Some(_) => return Err(HttpResponse::BadRequest().finish()),
+        None if header("Content-Type").starts_with("text/csv") => imports::Format::Csv,
+        None => imports::Format::Edn,
+    };
+
+    let rows =
+        imports::parse(&body, format).map_err(|error| HttpResponse::BadRequest().body(error))?;
+
+    let response = data
+        .db
+        .send(Timed::new(imports::ImportAccounts { rows, audit }))
+        .await;
+    let report = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(server_error)?;
+
+    if header("Accept").contains("text/csv") {
+        return Ok(HttpResponse::Ok()
+            .content_type("text/csv")
+            .body(report.to_csv()));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/edn")
+        .body(edn_rs::to_string(report)))
+}
+
+/// Answers a pain.002 report even when every instruction was rejected:
+/// only a body that isn't a pain.001 file gets a `400`.
+async fn initiate_payments(
+    data: web::Data<State>,
+    body: String,
+    audit: Audit,
+) -> Result<HttpResponse, HttpResponse> {
+    let initiation = payments::parse(&body, &data.config.exports)
+        .map_err(|error| HttpResponse::BadRequest().body(error))?;
+
+    let response = data
+        .db
+        .send(Timed::new(payments::InitiatePayments { initiation, audit }))
+        .await;
+    let report = response
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .map_err(|db_error| match db_error {
+            DbError::StateConflict => {
+                HttpResponse::Conflict().body("the file is already being executed")
+            }
+            db_error => server_error(db_error),
+        })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(report.to_pain002()))
+}
+
+/// The API description, as EDN, as OpenAPI 3 for `Accept:
+/// application/json`, or as a page for browsers.
+async fn api_docs(request: HttpRequest) -> HttpResponse {
+    let accept = request
+        .headers()
+        .get("Accept")
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or("");
+
+    if accept.contains("application/json") {
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .body(apidocs::to_openapi())
+    } else if accept.contains("text/html") {
+        HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(apidocs::to_html())
+    } else {
+        HttpResponse::Ok()
+            .content_type("application/edn")
+            .body(apidocs::to_edn())
+    }
+}
+
+async fn health_live() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/edn")
+        .body("{:status :up}")
+}
+
+async fn health_ready(data: web::Data<State>, request: HttpRequest) -> HttpResponse {
+    // Taken before sending the check, which would count itself.
+    let pool = health::Pool::current(data.config.crux_client.max_concurrency);
+
+    let start = Instant::now();
+    let response = data
+        .db
+        .send(Timed::new(health::CheckCrux))
+        .timeout(health::READY_TIMEOUT)
+        .await;
+
+    let (status, latency, error) = match response {
+        Ok(Ok(latency)) => (health::Status::Up, latency, None),
+        Ok(Err(error)) => (
+            health::Status::Down,
+            start.elapsed(),
+            Some(format!("{:?}", error)),
+        ),
+        Err(MailboxError::Timeout) => (
+            health::Status::Down,
+            start.elapsed(),
+            Some(String::from("timed out waiting for Crux")),
+        ),
+        Err(MailboxError::Closed) => (
+            health::Status::Down,
+            start.elapsed(),
+            Some(String::from("executor stopped")),
+        ),
+    };
+
+    let readiness = health::Readiness {
+        crux: health::Check {
+            status,
+            latency,
+            error,
+        },
+        pool,
+    };
+
+    let mut builder = if readiness.ready() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+
+    let json = request
+        .headers()
+        .get("Accept")
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+
+    if json {
+        builder
+            .content_type("application/json")
+            .body(readiness.to_json())
+    } else {
+        builder
+            .content_type("application/edn")
+            .body(readiness.to_edn())
+    }
+}
+
+async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+const USAGE: &str = "usage: smaug [--store <file>] <command>
+
+commands:
+  serve                         serve the API (the default)
+  reconcile [--format edn|csv]  check balances against operations
+  accrue-interest               accrue and pay interest up to yesterday
+  export [<file>]               write every transaction to a file or stdout
+  import [--force] <file>       write exported transactions
+  import-accounts [--format csv|edn] <file>
+                                open the accounts listed in a file
+  migrate [--dry-run]           update documents written by older versions
+  rebuild-projections           replay the log into new projection checkpoints
+  check-config [<file>]         check the configuration file
+  bench-storage [--requests <n>] [--latency-ms <n>]
+                                compare the blocking and async executors
+                                against a local Crux stand-in";
+
 fn main() {
+    telemetry::init();
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    // Runs against the in-process store in the file instead of Crux.
+    let store = match args.as_slice() {
+        [flag, path, ..] if flag == "--store" => {
+            let path = path.clone();
+            args.drain(..2);
+            Some(path)
+        }
+        _ => None,
+    };
+
+    if args.first().map(String::as_str) == Some("check-config") {
+        return run_check_config(store, &args[1..]);
+    }
+
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("invalid configuration: {}", error);
+            std::process::exit(2);
+        }
+    };
+
+    if store.is_some() {
+        config.crux_store = store;
+    }
+    if let Some(path) = &config.crux_store {
+        if let Err(error) = localstore::open(path) {
+            eprintln!("couldn't read the store {}: {}", path, error);
+            std::process::exit(2);
+        }
+    }
+
+    match args.first().map(String::as_str) {
+        None | Some("serve") => serve(config),
+        Some("reconcile") => run_reconcile(config, &args[1..]),
+        Some("accrue-interest") => run_accrue_interest(config),
+        Some("export") => run_export(config, &args[1..]),
+        Some("import") => run_import(config, &args[1..]),
+        Some("import-accounts") => run_import_accounts(config, &args[1..]),
+        Some("migrate") => run_migrate(config, &args[1..]),
+        Some("rebuild-projections") => run_rebuild_projections(config),
+        Some("bench-storage") => run_bench_storage(config, &args[1..]),
+        Some(command) => {
+            eprintln!("unknown command: {}", command);
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Runs the work of a command on a system of its own, which the Crux
+/// client needs to make its calls.
+fn block_on<F: future::Future + 'static>(work: F) -> F::Output {
+    actix::System::new("smaug").block_on(work)
+}
+
+/// Prints the reconciliation report, exiting with 1 if any account diverged.
+fn run_reconcile(config: Config, args: &[String]) {
+    let csv = match args {
+        [] => false,
+        [flag, format] if flag == "--format" && format == "edn" => false,
+        [flag, format] if flag == "--format" && format == "csv" => true,
+        _ => {
+            eprintln!("usage: smaug reconcile [--format edn|csv]");
+            std::process::exit(2);
+        }
+    };
+
+    let client = CruxClient::new(&config);
+
+    let mismatches = match block_on(async move { reconcile::run(&client).await }) {
+        Ok(mismatches) => mismatches,
+        Err(error) => {
+            eprintln!("reconciliation failed: {:?}", error);
+            std::process::exit(2);
+        }
+    };
+    let has_mismatches = !mismatches.is_empty();
+
+    if csv {
+        print!("{}", reconcile::to_csv(&mismatches));
+    } else {
+        println!("{}", edn_rs::to_string(mismatches));
+    }
+
+    if has_mismatches {
+        std::process::exit(1);
+    }
+}
+
+/// Accrues and capitalizes interest up to yesterday, like the scheduler does.
+fn run_accrue_interest(config: Config) {
+    let client = CruxClient::new(&config);
+
+    match block_on(async move {
+        interest::run(
+            &client,
+            &config,
+            Utc::today().naive_utc(),
+            &Audit::new(Channel::Admin),
+            &|_| (),
+        )
+        .await
+    }) {
+        Ok(interest_run) => println!("{}", edn_rs::to_string(interest_run)),
+        Err(error) => {
+            eprintln!("interest run failed: {:?}", error);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Writes every transaction of the log, one per line, in the format of
+/// store files.
+fn run_export(config: Config, args: &[String]) {
+    let mut out: Box<dyn std::io::Write> = match args {
+        [] => Box::new(std::io::stdout()),
+        [path] => match std::fs::File::create(path) {
+            Ok(file) => Box::new(std::io::BufWriter::new(file)),
+            Err(error) => {
+                eprintln!("couldn't create {}: {}", path, error);
+                std::process::exit(2);
+            }
+        },
+        _ => {
+            eprintln!("usage: smaug export [<file>]");
+            std::process::exit(2);
+        }
+    };
+
+    let client = CruxClient::new(&config);
+
+    let transactions = match block_on(async move { admin::export(&client).await }) {
+        Ok(transactions) => transactions,
+        Err(error) => {
+            eprintln!("export failed: {:?}", error);
+            std::process::exit(2);
+        }
+    };
+
+    let written = transactions
+        .iter()
+        .try_for_each(|transaction| writeln!(out, "{}", transaction.to_edn()))
+        .and_then(|_| out.flush());
+    if let Err(error) = written {
+        eprintln!("export failed: {}", error);
+        std::process::exit(2);
+    }
+
+    eprintln!("exported {} transactions", transactions.len());
+}
+
+/// Writes the transactions of an export, refusing to unless the store has
+/// no account yet or `--force` is given.
+fn run_import(config: Config, args: &[String]) {
+    let (force, path) = match args {
+        [path] if path != "--force" => (false, path),
+        [flag, path] if flag == "--force" => (true, path),
+        _ => {
+            eprintln!("usage: smaug import [--force] <file>");
+            std::process::exit(2);
+        }
+    };
+
+    let transactions = match std::fs::read_to_string(path)
+        .map_err(crux::Error::Store)
+        .and_then(|content| localstore::read_log(&content))
+    {
+        Ok(transactions) => transactions,
+        Err(error) => {
+            eprintln!("couldn't read {}: {}", path, error);
+            std::process::exit(2);
+        }
+    };
+
+    let client = CruxClient::new(&config);
+
+    let imported = block_on(async move {
+        match admin::is_empty(&client).await {
+            Ok(true) => (),
+            Ok(false) if force => (),
+            Ok(false) => {
+                eprintln!("the store already has accounts, import with --force to add to them");
+                std::process::exit(1);
+            }
+            Err(error) => return Err(error),
+        }
+
+        admin::import(&client, transactions).await
+    });
+
+    match imported {
+        Ok(import_run) => println!("{}", edn_rs::to_string(import_run)),
+        Err(error) => {
+            eprintln!("import failed: {:?}", error);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Opens the accounts listed in a CSV or EDN file, printing the report and
+/// exiting with 1 if any row wasn't imported.
+fn run_import_accounts(config: Config, args: &[String]) {
+    let (format, path) = match args {
+        [path] if path.ends_with(".csv") => (imports::Format::Csv, path),
+        [path] => (imports::Format::Edn, path),
+        [flag, format, path] if flag == "--format" && format == "csv" => {
+            (imports::Format::Csv, path)
+        }
+        [flag, format, path] if flag == "--format" && format == "edn" => {
+            (imports::Format::Edn, path)
+        }
+        _ => {
+            eprintln!("usage: smaug import-accounts [--format csv|edn] <file>");
+            std::process::exit(2);
+        }
+    };
+
+    let rows = match std::fs::read_to_string(path)
+        .map_err(|error| error.to_string())
+        .and_then(|content| imports::parse(&content, format))
+    {
+        Ok(rows) => rows,
+        Err(error) => {
+            eprintln!("couldn't read {}: {}", path, error);
+            std::process::exit(2);
+        }
+    };
+
+    let client = CruxClient::new(&config);
+
+    match block_on(async move {
+        imports::run(&client, &config, rows, &Audit::new(Channel::Admin), &|_| ()).await
+    }) {
+        Ok(report) => {
+            println!("{}", edn_rs::to_string(report.clone()));
+            if !report.is_complete() {
+                std::process::exit(1);
+            }
+        }
+        Err(error) => {
+            eprintln!("import failed: {:?}", error);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Runs the migrations the store didn't have yet, printing them.
+fn run_migrate(config: Config, args: &[String]) {
+    let dry_run = match args {
+        [] => false,
+        [flag] if flag == "--dry-run" => true,
+        _ => {
+            eprintln!("usage: smaug migrate [--dry-run]");
+            std::process::exit(2);
+        }
+    };
+
+    let client = CruxClient::new(&config);
+
+    match block_on(async move { admin::migrate(&client, dry_run).await }) {
+        Ok(runs) => println!("{}", edn_rs::to_string(runs)),
+        Err(error) => {
+            eprintln!("migration failed: {:?}", error);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Replays the whole log into the projections, replacing their checkpoints.
+/// A running server keeps its own until it's restarted.
+fn run_rebuild_projections(config: Config) {
+    let projections: Vec<projector::SharedProjection> = vec![
+        Arc::new(RwLock::new(RunningTotals::default())),
+        Arc::new(RwLock::new(OperationsIndex::default())),
+    ];
+
+    let mut projector = projector::Projector::new(CruxClient::new(&config), projections);
+
+    match block_on(async move { projector.rebuild().await }) {
+        Ok(rebuild) => println!("{}", edn_rs::to_string(rebuild)),
+        Err(error) => {
+            eprintln!("rebuilding projections failed: {:?}", error);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Prints the requests per second the blocking and the async executors
+/// manage reading accounts from a local Crux stand-in.
+fn run_bench_storage(config: Config, args: &[String]) {
+    let mut requests = 500;
+    let mut latency_ms = 20;
+    for pair in args.chunks(2) {
+        let value = pair.get(1).and_then(|value| value.parse().ok());
+        match (pair[0].as_str(), value) {
+            ("--requests", Some(value)) => requests = value,
+            ("--latency-ms", Some(value)) => latency_ms = value,
+            _ => {
+                eprintln!("usage: smaug bench-storage [--requests <n>] [--latency-ms <n>]");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let latency = std::time::Duration::from_millis(latency_ms);
+    match block_on(async move { bench::run(config, requests as usize, latency).await }) {
+        Ok(report) => println!("{}", edn_rs::to_string(report)),
+        Err(error) => {
+            eprintln!("benchmark failed: {}", error);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Reads the configuration file, or `path`, and prints what it sets up,
+/// exiting with 1 if it has problems.
+fn run_check_config(store: Option<String>, args: &[String]) {
+    let config = match args {
+        [] => Config::load(),
+        [path] => Config::from_file(path),
+        _ => {
+            eprintln!("usage: smaug check-config [<file>]");
+            std::process::exit(2);
+        }
+    };
+
+    let mut config = match config {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("invalid configuration: {}", error);
+            std::process::exit(1);
+        }
+    };
+    if store.is_some() {
+        config.crux_store = store;
+    }
+
+    let check = admin::check_config(&config);
+    let is_valid = check.is_valid();
+    println!("{}", edn_rs::to_string(check));
+
+    if !is_valid {
+        std::process::exit(1);
+    }
+}
+
+/// Registers every route of the API from one table, which also lists them
+/// for the test holding the API description to it.
+macro_rules! api_routes {
+    ($($method:ident $path:literal => $handler:ident $(, max_bytes $max_bytes:expr)?;)*) => {
+        fn api_routes(config: &mut web::ServiceConfig) {
+            $(
+                config.service(
+                    web::resource($path)
+                        $(.app_data(web::PayloadConfig::new($max_bytes)))?
+                        .guard(guard::$method())
+                        .route(web::route().to($handler)),
+                );
+            )*
+        }
+
+        #[cfg(test)]
+        const API_ROUTES: &[(&str, &str)] = &[$((stringify!($method), $path)),*];
+    };
+}
+
+api_routes! {
+    Post "/accounts" => create_account;
+    Get "/accounts/{account_id}" => get_account;
+    Post "/accounts/{account_id}/deposit" => account_deposit;
+    Post "/accounts/{account_id}/withdraw" => account_withdraw;
+    Post "/accounts/{account_id}/transfer" => account_transfer;
+    Get "/accounts/{account_id}/events" => account_events;
+    Get "/accounts/{account_id}/summary" => account_summary;
+    Get "/accounts/{account_id}/history" => account_history;
+    Get "/accounts/{account_id}/operations" => account_operations;
+    Get "/accounts/{account_id}/operations/export" => export_operations;
+    Get "/accounts/{account_id}/statement" => account_statement;
+    Get "/accounts/{account_id}/limits" => get_account_limits;
+    Put "/accounts/{account_id}/limits" => set_account_limits;
+    Get "/accounts/{account_id}/fee-quote" => fee_quote;
+    Get "/products" => list_products;
+    Get "/ws" => account_socket;
+    Post "/webhooks" => create_webhook;
+    Get "/webhooks/{webhook_id}/deliveries" => webhook_deliveries;
+    Post "/imports" => import_accounts, max_bytes IMPORT_MAX_BYTES;
+    Post "/payment-initiations" => initiate_payments, max_bytes IMPORT_MAX_BYTES;
+    Get "/reconciliation" => reconciliation;
+    Get "/api-docs" => api_docs;
+    Get "/metrics" => metrics;
+    Get "/health/live" => health_live;
+    Get "/health/ready" => health_ready;
+}
+
+fn serve(config: Config) {
     let sys = actix::System::new("app");
 
-    let addr = SyncArbiter::start(3, || DbExecutor(Crux::new("localhost", "3000")));
+    let config = Arc::new(config);
+    let broker = EventBroker::default().start();
+    // Shared by everything below, so they all draw from the same
+    // connections and concurrency.
+    let client = CruxClient::new(&config);
+
+    let addr = DbExecutor(client.clone(), config.clone(), broker.clone()).start();
+
+    interest::InterestScheduler { db: addr.clone() }.start();
+
+    webhooks::WebhookDispatcher {
+        client: client.clone(),
+        config: config.clone(),
+        http: reqwest::Client::new(),
+    }
+    .start();
+
+    let running_totals = Arc::new(RwLock::new(RunningTotals::default()));
+    let operations_index = Arc::new(RwLock::new(OperationsIndex::default()));
+    let projections: Vec<projector::SharedProjection> =
+        vec![running_totals.clone(), operations_index.clone()];
+    let projector = projector::Projector::new(client, projections);
+    projector::ProjectorScheduler {
+        projector: Arc::new(tokio::sync::Mutex::new(projector)),
+    }
+    .start();
+
+    let rate_limiter = Arc::new(ratelimit::RateLimiter::new(
+        &config,
+        Arc::new(ratelimit::MemoryStore::default()),
+    ));
 
     HttpServer::new(move || {
+        let rate_limiter = rate_limiter.clone();
+
         App::new()
-            .data(State { db: addr.clone() })
+            .data(State {
+                db: addr.clone(),
+                broker: broker.clone(),
+                config: config.clone(),
+                running_totals: running_totals.clone(),
+                operations_index: operations_index.clone(),
+                rate_limiter: rate_limiter.clone(),
+            })
+            .wrap_fn(move |req, srv| match rate_limiter.check(&req) {
+                Some((scope, decision)) if !decision.allowed => future::Either::Left(future::ok(
+                    req.into_response(ratelimit::too_many_requests(scope, &decision)),
+                )),
+                decision => future::Either::Right(srv.call(req).map(move |response| {
+                    let mut response = response?;
+                    if let Some((_, decision)) = decision {
+                        ratelimit::add_headers(response.headers_mut(), &decision);
+                    }
+                    Ok(response)
+                })),
+            })
+            .wrap_fn(|req, srv| {
+                let start = Instant::now();
+
+                srv.call(req).map(move |response| {
+                    if let Ok(response) = &response {
+                        metrics::observe_request(response, start.elapsed());
+                    }
+                    response
+                })
+            })
             .wrap(
                 DefaultHeaders::new()
                     .header("Content-Type", "application/edn")
                     .header("Accept", "application/edn"),
             )
-            .route("/accounts", web::post().to(create_account))
-            .route("/accounts/{account_id}", web::get().to(get_account))
-            .route(
-                "/accounts/{account_id}/deposit",
-                web::post().to(account_deposit),
-            )
-            .route(
-                "/accounts/{account_id}/withdraw",
-                web::post().to(account_withdraw),
-            )
-            .route(
-                "/accounts/{account_id}/transfer",
-                web::post().to(account_transfer),
-            )
-            .route(
-                "/accounts/{account_id}/history",
-                web::get().to(account_history),
-            )
-            .route(
-                "/accounts/{account_id}/operations",
-                web::get().to(account_operations),
-            )
+            .wrap_fn(|req, srv| {
+                let request_id = RequestId::from_headers(req.headers());
+                request_id.attach(&req);
+
+                let span = tracing::info_span!(
+                    "request",
+                    request_id = %request_id.0,
+                    method = %req.method(),
+                    path = %req.path(),
+                );
+                let start = Instant::now();
+
+                span.in_scope(|| srv.call(req))
+                    .map(move |response| {
+                        let mut response = response?;
+                        response.headers_mut().insert(
+                            HeaderName::from_static("x-request-id"),
+                            HeaderValue::from_str(&request_id.0).unwrap(),
+                        );
+
+                        let status = response.status().as_u16();
+                        let elapsed_ms = start.elapsed().as_millis() as u64;
+                        if response.status().is_server_error() {
+                            tracing::error!(status, elapsed_ms, "request failed");
+                        } else {
+                            tracing::info!(status, elapsed_ms, "request handled");
+                        }
+
+                        Ok(response)
+                    })
+                    .instrument(span)
+            })
+            .configure(api_routes)
     })
-    .bind("127.0.0.1:8000")
+    .bind(ADDRESS)
     .unwrap()
     .run();
 
-    println!("Started HTTP server: 127.0.0.1:8080");
+    tracing::info!(address = ADDRESS, "HTTP server started");
     let _ = sys.run();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::Method;
+    use actix_web::test;
+
+    #[test]
+    fn routes_are_told_apart_by_method() {
+        actix::System::new("test").block_on(async {
+            let mut app = test::init_service(App::new().configure(api_routes)).await;
+
+            let request = |method: Method| {
+                test::TestRequest::with_uri("/accounts/alice/limits")
+                    .method(method)
+                    .to_request()
+            };
+
+            // Without the app state the handlers fail, but they're found.
+            let get = test::call_service(&mut app, request(Method::GET)).await;
+            assert_ne!(get.status(), StatusCode::NOT_FOUND);
+            let put = test::call_service(&mut app, request(Method::PUT)).await;
+            assert_ne!(put.status(), StatusCode::NOT_FOUND);
+
+            let delete = test::call_service(&mut app, request(Method::DELETE)).await;
+            assert_eq!(delete.status(), StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn amounts_past_the_largest_balance_are_refused() {
+        let amount = |body: &str| request_amount(&Edn::from_str(body).unwrap());
+
+        assert_eq!(amount("{:amount 5}"), Some(5));
+        assert_eq!(amount("{}"), Some(0));
+        assert_eq!(
+            amount(&format!("{{:amount {}}}", i64::MAX)),
+            Some(i64::MAX as usize)
+        );
+        assert_eq!(
+            amount(&format!("{{:amount {}}}", i64::MAX as u64 + 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn operations_credited_to_an_account_are_listed_in_time_order() {
+        actix::System::new("test").block_on(async {
+            let db = DbExecutor(
+                CruxClient::in_process(),
+                Arc::new(Config::default()),
+                EventBroker::default().start(),
+            )
+            .start();
+            let audit = || Audit::new(Channel::Admin);
+
+            for id in &["alice", "bob"] {
+                let account = DbAccount {
+                    crux__db___id: CruxId::new(id),
+                    account___amount: 10,
+                    account___type: None,
+                    account___limits: None,
+                    account___interest_rate: None,
+                    account___external_id: None,
+                };
+                db.send(CreateAccount {
+                    account,
+                    audit: audit(),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            }
+            db.send(AccountTransfer {
+                source_account_id: String::from("bob"),
+                amount: 3,
+                target_account_id: String::from("alice"),
+                audit: audit(),
+                also_write: Vec::new(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+            let operations = db
+                .send(AccountOperations {
+                    account_id: String::from("alice"),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            let types = operations
+                .iter()
+                .map(|operation| operation.account_operation___type.clone())
+                .collect::<Vec<OperationType>>();
+            assert_eq!(types, vec![OperationType::Create, OperationType::Transfer]);
+        });
+    }
+
+    #[test]
+    fn only_admins_use_webhooks() {
+        actix::System::new("test").block_on(async {
+            let config = Arc::new(Config {
+                admins: vec![String::from("ops@bank.example")].into_iter().collect(),
+                gateway_secret: Some(String::from("s3cret")),
+                ..Config::default()
+            });
+            let broker = EventBroker::default().start();
+            let db = DbExecutor(CruxClient::in_process(), config.clone(), broker.clone()).start();
+            let rate_limiter = Arc::new(ratelimit::RateLimiter::new(
+                &config,
+                Arc::new(ratelimit::MemoryStore::default()),
+            ));
+            let mut app = test::init_service(
+                App::new()
+                    .data(State {
+                        db,
+                        broker,
+                        config,
+                        running_totals: Arc::new(RwLock::new(RunningTotals::default())),
+                        operations_index: Arc::new(RwLock::new(OperationsIndex::default())),
+                        rate_limiter,
+                    })
+                    .configure(api_routes),
+            )
+            .await;
+
+            let create = |principal: &str| {
+                test::TestRequest::post()
+                    .uri("/webhooks")
+                    .header("X-Authenticated-Principal", principal)
+                    .header("X-Gateway-Secret", "s3cret")
+                    .set_payload("{:url \"https://hooks.example/smaug\" :secret \"shh\"}")
+                    .to_request()
+            };
+            let created = test::call_service(&mut app, create("someone@bank.example")).await;
+            assert_eq!(created.status(), StatusCode::FORBIDDEN);
+            let created = test::call_service(&mut app, create("ops@bank.example")).await;
+            assert_eq!(created.status(), StatusCode::CREATED);
+
+            let deliveries = test::TestRequest::get()
+                .uri("/webhooks/any/deliveries")
+                .to_request();
+            let deliveries = test::call_service(&mut app, deliveries).await;
+            assert_eq!(deliveries.status(), StatusCode::FORBIDDEN);
+        });
+    }
+}
@@ -0,0 +1,304 @@
+use actix::prelude::*;
+use actix_web::dev::ServiceResponse;
+use futures::Future;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use std::time::{Duration, Instant};
+use tracing_futures::Instrument;
+
+use crate::{DbAccountOperation, OperationType};
+
+lazy_static! {
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "smaug_http_request_duration_seconds",
+        "Time spent answering HTTP requests, by route and status.",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref CRUX_CALL_DURATION: HistogramVec = register_histogram_vec!(
+        "smaug_crux_call_duration_seconds",
+        "Time spent in calls to Crux, by call type.",
+        &["call"]
+    )
+    .unwrap();
+    static ref CRUX_CALL_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "smaug_crux_call_errors_total",
+        "Calls to Crux that failed, by call type.",
+        &["call"]
+    )
+    .unwrap();
+    static ref CRUX_RETRIES: IntCounterVec = register_int_counter_vec!(
+        "smaug_crux_retries_total",
+        "Calls to Crux retried after a failure, by call type.",
+        &["call"]
+    )
+    .unwrap();
+    static ref CRUX_CIRCUIT_OPEN: IntGauge = register_int_gauge!(
+        "smaug_crux_circuit_open",
+        "Whether the Crux circuit breaker is open, failing calls without making them."
+    )
+    .unwrap();
+    static ref CRUX_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "smaug_crux_in_flight",
+        "Calls to Crux being made, out of the client's maximum concurrency."
+    )
+    .unwrap();
+    static ref ACCOUNT_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "smaug_account_cache_lookups_total",
+        "Accounts looked up in the cache, by whether they were found.",
+        &["result"]
+    )
+    .unwrap();
+    static ref DB_MAILBOX_WAIT: HistogramVec = register_histogram_vec!(
+        "smaug_db_mailbox_wait_seconds",
+        "Time messages waited in the DbExecutor mailbox before being handled.",
+        &["message"]
+    )
+    .unwrap();
+    static ref DB_IN_FLIGHT: IntGauge = register_int_gauge!(
+        "smaug_db_in_flight",
+        "Messages sent to the DbExecutor and not handled yet."
+    )
+    .unwrap();
+    static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "smaug_rate_limited_total",
+        "Requests refused for going over a rate limit, by limit scope.",
+        &["scope"]
+    )
+    .unwrap();
+    static ref OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "smaug_operations_total",
+        "Account operations written, by type.",
+        &["type"]
+    )
+    .unwrap();
+    static ref AMOUNT_MOVED: IntCounterVec = register_int_counter_vec!(
+        "smaug_amount_moved_total",
+        "Amount moved by account operations, by type.",
+        &["type"]
+    )
+    .unwrap();
+}
+
+/// Every metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+
+    String::from_utf8(buffer).unwrap()
+}
+
+pub fn observe_request<B>(response: &ServiceResponse<B>, elapsed: Duration) {
+    let request = response.request();
+
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[
+            request.method().as_str(),
+            &route(request),
+            response.status().as_str(),
+        ])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Pattern of the route that answered, e.g. `/accounts/{account_id}`, so
+/// requests aren't counted per account. Paths no route matches are grouped
+/// together.
+fn route(request: &actix_web::HttpRequest) -> String {
+    let path = request.path();
+
+    if !request.resource_map().has_resource(path) {
+        return String::from("unmatched");
+    }
+
+    let params = request.match_info();
+
+    path.split('/')
+        .map(|segment| {
+            params
+                .iter()
+                .find(|(_, value)| !segment.is_empty() && *value == segment)
+                .map_or_else(|| segment.to_string(), |(name, _)| format!("{{{}}}", name))
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+pub fn observe_crux_call<T, E>(call: &str, start: Instant, result: &Result<T, E>) {
+    CRUX_CALL_DURATION
+        .with_label_values(&[call])
+        .observe(start.elapsed().as_secs_f64());
+
+    if result.is_err() {
+        CRUX_CALL_ERRORS.with_label_values(&[call]).inc();
+    }
+}
+
+pub fn record_crux_retry(call: &str) {
+    CRUX_RETRIES.with_label_values(&[call]).inc();
+}
+
+pub fn record_account_cache_lookup(hit: bool) {
+    ACCOUNT_CACHE_LOOKUPS
+        .with_label_values(&[if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+pub fn set_crux_circuit_open(open: bool) {
+    CRUX_CIRCUIT_OPEN.set(i64::from(open));
+}
+
+/// Calls to Crux being made, not counting those waiting for their turn.
+pub fn crux_in_flight() -> i64 {
+    CRUX_IN_FLIGHT.get()
+}
+
+/// Counts a call to Crux as being made until dropped.
+pub struct CruxInFlight(());
+
+impl CruxInFlight {
+    pub fn new() -> Self {
+        CRUX_IN_FLIGHT.inc();
+        CruxInFlight(())
+    }
+}
+
+impl Drop for CruxInFlight {
+    fn drop(&mut self) {
+        CRUX_IN_FLIGHT.dec();
+    }
+}
+
+pub fn record_rate_limited(scope: &str) {
+    RATE_LIMITED.with_label_values(&[scope]).inc();
+}
+
+/// Counts operations once their transaction was submitted.
+pub fn record_operations(operations: &[DbAccountOperation]) {
+    for operation in operations {
+        let operation_type = operation_type_label(&operation.account_operation___type);
+
+        OPERATIONS.with_label_values(&[operation_type]).inc();
+        AMOUNT_MOVED
+            .with_label_values(&[operation_type])
+            .inc_by(operation.account_operation___amount as i64);
+    }
+}
+
+fn operation_type_label(operation_type: &OperationType) -> &'static str {
+    match operation_type {
+        OperationType::Create => "create",
+        OperationType::Deposit => "deposit",
+        OperationType::Withdraw => "withdraw",
+        OperationType::Transfer => "transfer",
+        OperationType::Fee => "fee",
+        OperationType::Interest => "interest",
+    }
+}
+
+/// Counts a message as in flight until dropped.
+pub struct InFlight(());
+
+impl InFlight {
+    fn new() -> Self {
+        DB_IN_FLIGHT.inc();
+        InFlight(())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        DB_IN_FLIGHT.dec();
+    }
+}
+
+/// A message for the `DbExecutor`, stamped when sent so the time it waited
+/// in the mailbox is measured, and handled in a span under the one it was
+/// sent from.
+pub struct Timed<M> {
+    msg: M,
+    sent_at: Instant,
+    span: tracing::Span,
+    in_flight: InFlight,
+}
+
+impl<M> Timed<M> {
+    pub fn new(msg: M) -> Self {
+        Self {
+            msg,
+            sent_at: Instant::now(),
+            span: tracing::Span::current(),
+            in_flight: InFlight::new(),
+        }
+    }
+
+    /// Records how long the message waited, then handles it, counted as in
+    /// flight until the future answering it is done.
+    pub fn handle<F: Future + 'static>(
+        self,
+        handle: impl FnOnce(M) -> F,
+    ) -> ResponseFuture<F::Output> {
+        let type_name = std::any::type_name::<M>();
+        let message = type_name.rsplit("::").next().unwrap_or(type_name);
+        let waited = self.sent_at.elapsed();
+
+        DB_MAILBOX_WAIT
+            .with_label_values(&[message])
+            .observe(waited.as_secs_f64());
+
+        let span = tracing::info_span!(parent: &self.span, "db_executor", message);
+        let msg = self.msg;
+        let in_flight = self.in_flight;
+        let future = span.in_scope(|| {
+            tracing::debug!(waited_ms = waited.as_millis() as u64, "message received");
+            handle(msg)
+        });
+
+        Box::pin(
+            async move {
+                let _in_flight = in_flight;
+                future.await
+            }
+            .instrument(span),
+        )
+    }
+}
+
+impl<M: Message> Message for Timed<M> {
+    type Result = M::Result;
+}
+
+/// Lets the `DbExecutor` handle `Timed` versions of `messages`, which all
+/// answer with a `ResponseFuture` of a `Result<_, DbError>`. Errors are
+/// logged.
+macro_rules! timed_handlers {
+    ($($message:ty),* $(,)?) => {
+        $(
+            impl Handler<$crate::metrics::Timed<$message>> for DbExecutor {
+                type Result = <DbExecutor as Handler<$message>>::Result;
+
+                fn handle(
+                    &mut self,
+                    timed: $crate::metrics::Timed<$message>,
+                    ctx: &mut Self::Context,
+                ) -> Self::Result {
+                    timed.handle(|msg| {
+                        let future = <DbExecutor as Handler<$message>>::handle(self, msg, ctx);
+
+                        async move {
+                            let result = future.await;
+                            if let Err(error) = &result {
+                                error.log();
+                            }
+                            result
+                        }
+                    })
+                }
+            }
+        )*
+    };
+}
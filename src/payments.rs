@@ -0,0 +1,971 @@
+use actix::prelude::*;
+use chrono::{SecondsFormat, Utc};
+use edn_derive::{Deserialize, Serialize};
+use edn_rs::Edn;
+use lazy_static::lazy_static;
+use roxmltree::{Document, Node};
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::sync::Mutex;
+use transistor::edn_rs;
+use transistor::types::http::Action;
+use transistor::types::CruxId;
+use uuid::Uuid;
+
+use crate::audit::Audit;
+use crate::exports::ExportConfig;
+use crate::imports;
+use crate::ledger::SystemAccount;
+use crate::statements::xml_text;
+use crate::{AccountTransfer, DbError, DbExecutor};
+
+/// Namespace of the ids of the documents recording what was executed,
+/// which mustn't change.
+const PAYMENT_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x2b, 0x8d, 0x51, 0xe4, 0x07, 0x6a, 0x4f, 0x93, 0xa1, 0x5c, 0xd8, 0x3e, 0x64, 0x90, 0x1f, 0xb7,
+]);
+
+/// Reason codes of stored statuses, read back as the codes they were.
+const REASON_CODES: [&str; 10] = [
+    "AC01", "AG01", "AM01", "AM03", "AM04", "AM05", "AM12", "AM14", "DUPL", "MS03",
+];
+
+lazy_static! {
+    /// Message ids of the files being executed by this process.
+    static ref EXECUTING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Why an instruction was rejected, as an ISO 20022 status reason code and
+/// a description.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rejection {
+    pub code: &'static str,
+    pub reason: String,
+}
+
+impl Rejection {
+    fn new(code: &'static str, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// A credit transfer of a pain.001 file, with the debtor account of its
+/// payment information.
+#[derive(Clone, Debug)]
+pub struct Instruction {
+    pub payment_information_id: String,
+    pub instruction_id: Option<String>,
+    pub end_to_end_id: String,
+    pub debtor: Option<String>,
+    pub creditor: Option<String>,
+    /// In the smallest unit of the currency, or why it can't be paid.
+    pub amount: Result<usize, Rejection>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Initiation {
+    pub message_id: String,
+    pub instructions: Vec<Instruction>,
+}
+
+/// Children are found by local name, so any version of pain.001 reads the
+/// same.
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+fn text(node: Node, path: &[&str]) -> Option<String> {
+    let mut node = node;
+    for name in path {
+        node = child(node, name)?;
+    }
+
+    node.text()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(String::from)
+}
+
+/// The IBAN or other id of an account, e.g. of `<DbtrAcct>`.
+fn account(node: Node, name: &str) -> Option<String> {
+    let id = child(child(node, name)?, "Id")?;
+
+    text(id, &["IBAN"]).or_else(|| text(id, &["Othr", "Id"]))
+}
+
+/// `10.50` as `1050` for a currency with 2 decimals. Amounts with more
+/// decimals than the currency has aren't paid.
+fn parse_amount(value: &str, decimals: usize) -> Option<usize> {
+    let (units, fraction) = match value.find('.') {
+        Some(index) => (&value[..index], &value[index + 1..]),
+        None => (value, ""),
+    };
+    let fraction = fraction.trim_end_matches('0');
+
+    if units.is_empty()
+        || fraction.len() > decimals
+        || !units.chars().all(|c| c.is_ascii_digit())
+        || !fraction.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    format!("{}{:0<width$}", units, fraction, width = decimals)
+        .parse()
+        .ok()
+}
+
+fn amount(transfer: Node, config: &ExportConfig) -> Result<usize, Rejection> {
+    let instructed = child(transfer, "Amt")
+        .and_then(|amount| child(amount, "InstdAmt"))
+        .ok_or_else(|| Rejection::new("AM12", "no instructed amount"))?;
+
+    let currency = instructed.attribute("Ccy").unwrap_or_default();
+    if currency != config.currency {
+        return Err(Rejection::new(
+            "AM03",
+            format!("accounts are in {}, not {}", config.currency, currency),
+        ));
+    }
+
+    let value = instructed.text().unwrap_or_default().trim();
+    match parse_amount(value, config.decimals) {
+        Some(0) => Err(Rejection::new("AM01", "the amount is zero")),
+        Some(amount) => Ok(amount),
+        None => Err(Rejection::new(
+            "AM12",
+            format!("{} isn't an amount in {}", value, config.currency),
+        )),
+    }
+}
+
+/// Reads the credit transfers of a pain.001 file. Only a file that isn't a
+/// pain.001 at all fails: instructions that can't be paid are rejected one
+/// by one.
+pub fn parse(content: &str, config: &ExportConfig) -> Result<Initiation, String> {
+    let document = Document::parse(content).map_err(|error| format!("invalid XML: {}", error))?;
+    let initiation = child(document.root_element(), "CstmrCdtTrfInitn")
+        .ok_or_else(|| String::from("not a pain.001 customer credit transfer initiation"))?;
+    let message_id = text(initiation, &["GrpHdr", "MsgId"])
+        .ok_or_else(|| String::from("the group header has no MsgId"))?;
+
+    let mut instructions = Vec::new();
+    for payment_information in children(initiation, "PmtInf") {
+        let payment_information_id = text(payment_information, &["PmtInfId"]).unwrap_or_default();
+        let debtor = account(payment_information, "DbtrAcct");
+
+        for transfer in children(payment_information, "CdtTrfTxInf") {
+            instructions.push(Instruction {
+                payment_information_id: payment_information_id.clone(),
+                instruction_id: text(transfer, &["PmtId", "InstrId"]),
+                end_to_end_id: text(transfer, &["PmtId", "EndToEndId"])
+                    .unwrap_or_else(|| String::from("NOTPROVIDED")),
+                debtor: debtor.clone(),
+                creditor: account(transfer, "CdtrAcct"),
+                amount: amount(transfer, config),
+            });
+        }
+    }
+
+    Ok(Initiation {
+        message_id,
+        instructions,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstructionStatus {
+    pub payment_information_id: String,
+    pub instruction_id: Option<String>,
+    pub end_to_end_id: String,
+    /// `None` once the transfer was written.
+    pub rejection: Option<Rejection>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StatusReport {
+    pub message_id: String,
+    pub statuses: Vec<InstructionStatus>,
+}
+
+/// ISO 20022 status of a group of instructions: settled, rejected, or
+/// partly both.
+fn group_status<'a>(statuses: impl Iterator<Item = &'a InstructionStatus>) -> &'static str {
+    let (mut settled, mut rejected) = (false, false);
+    for status in statuses {
+        if status.rejection.is_some() {
+            rejected = true;
+        } else {
+            settled = true;
+        }
+    }
+
+    match (settled, rejected) {
+        (_, false) => "ACSC",
+        (false, true) => "RJCT",
+        (true, true) => "PART",
+    }
+}
+
+impl StatusReport {
+    /// A `pain.002.001.03` customer payment status report.
+    pub fn to_pain002(&self) -> String {
+        let mut payment_information_ids = Vec::new();
+        for status in &self.statuses {
+            if !payment_information_ids.contains(&&status.payment_information_id) {
+                payment_information_ids.push(&status.payment_information_id);
+            }
+        }
+
+        let payment_information = payment_information_ids
+            .iter()
+            .map(|id| {
+                let statuses = self
+                    .statuses
+                    .iter()
+                    .filter(|status| &&status.payment_information_id == id)
+                    .collect::<Vec<&InstructionStatus>>();
+                let transactions = statuses
+                    .iter()
+                    .map(|status| transaction_status(status))
+                    .collect::<String>();
+
+                format!(
+                    "<OrgnlPmtInfAndSts>\n<OrgnlPmtInfId>{}</OrgnlPmtInfId>\n\
+                     <PmtInfSts>{}</PmtInfSts>\n{}</OrgnlPmtInfAndSts>\n",
+                    xml_text(id),
+                    group_status(statuses.iter().copied()),
+                    transactions,
+                )
+            })
+            .collect::<String>();
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:pain.002.001.03\">\n\
+             <CstmrPmtStsRpt>\n<GrpHdr>\n<MsgId>{}</MsgId>\n<CreDtTm>{}</CreDtTm>\n</GrpHdr>\n\
+             <OrgnlGrpInfAndSts>\n<OrgnlMsgId>{}</OrgnlMsgId>\n\
+             <OrgnlMsgNmId>pain.001.001.03</OrgnlMsgNmId>\n<OrgnlNbOfTxs>{}</OrgnlNbOfTxs>\n\
+             <GrpSts>{}</GrpSts>\n</OrgnlGrpInfAndSts>\n{}</CstmrPmtStsRpt>\n</Document>\n",
+            Uuid::new_v4().to_simple(),
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            xml_text(&self.message_id),
+            self.statuses.len(),
+            group_status(self.statuses.iter()),
+            payment_information,
+        )
+    }
+}
+
+fn transaction_status(status: &InstructionStatus) -> String {
+    let instruction_id = status
+        .instruction_id
+        .as_ref()
+        .map(|id| format!("<OrgnlInstrId>{}</OrgnlInstrId>\n", xml_text(id)))
+        .unwrap_or_default();
+    let (transaction_status, reason) = match &status.rejection {
+        None => ("ACSC", String::new()),
+        Some(rejection) => (
+            "RJCT",
+            format!(
+                "<StsRsnInf><Rsn><Cd>{}</Cd></Rsn><AddtlInf>{}</AddtlInf></StsRsnInf>\n",
+                rejection.code,
+                xml_text(&rejection.reason.chars().take(105).collect::<String>()),
+            ),
+        ),
+    };
+
+    format!(
+        "<TxInfAndSts>\n{}<OrgnlEndToEndId>{}</OrgnlEndToEndId>\n<TxSts>{}</TxSts>\n{}</TxInfAndSts>\n",
+        instruction_id,
+        xml_text(&status.end_to_end_id),
+        transaction_status,
+        reason,
+    )
+}
+
+/// `3f2a...` as `3f2a....-....-....-....-............`, since statements
+/// write account ids without their hyphens.
+fn hyphenated(id: &str) -> Option<String> {
+    if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &id[..8],
+        &id[8..12],
+        &id[12..16],
+        &id[16..20],
+        &id[20..]
+    ))
+}
+
+/// The id of the customer account `identifier` stands for: its id, with or
+/// without hyphens, or its external id.
+async fn resolve(
+    executor: &DbExecutor,
+    aliases: &HashMap<String, String>,
+    identifier: &str,
+) -> Result<Option<String>, DbError> {
+    let system_ids = SystemAccount::all()
+        .into_iter()
+        .map(SystemAccount::id)
+        .collect::<Vec<CruxId>>();
+
+    let candidates = iter::once(identifier.to_string()).chain(hyphenated(identifier));
+    for candidate in candidates {
+        let id = CruxId::new(&candidate);
+        if system_ids.contains(&id) {
+            continue;
+        }
+        if executor.0.entity(edn_rs::to_string(id)).await? != Edn::Nil {
+            return Ok(Some(candidate));
+        }
+    }
+
+    Ok(aliases.get(identifier).cloned())
+}
+
+/// Why a transfer was refused, as a status reason.
+fn rejection(db_error: DbError) -> Rejection {
+    match db_error {
+        DbError::NilEntity => Rejection::new("AC01", "no such account"),
+        DbError::StateConflict => Rejection::new("AM04", "insufficient funds"),
+        DbError::LimitExceeded(limit_exceeded) => Rejection::new("AM14", limit_exceeded.to_edn()),
+        DbError::ProductRule(violation) => Rejection::new(
+            "AG01",
+            format!(
+                "breaks rule {} of product {}",
+                edn_rs::to_string(violation.rule),
+                violation.product
+            ),
+        ),
+        db_error => Rejection::new("MS03", format!("{:?}", db_error)),
+    }
+}
+
+/// A pain.001 file executed, with the status of each of its instructions
+/// to report them again when the file is posted again.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbPaymentMessage {
+    pub crux__db___id: CruxId,                            // :crux.db/id
+    pub payment_message___message_id: String,             // :payment-message/message-id
+    pub payment_message___statuses: Vec<DbPaymentStatus>, // :payment-message/statuses
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbPaymentStatus {
+    pub payment_status___payment_information_id: String, // :payment-status/payment-information-id
+    pub payment_status___instruction_id: Option<String>, // :payment-status/instruction-id
+    pub payment_status___end_to_end_id: String,          // :payment-status/end-to-end-id
+    pub payment_status___code: Option<String>,           // :payment-status/code
+    pub payment_status___reason: Option<String>,         // :payment-status/reason
+}
+
+/// A payment information or an instruction that was executed, with the
+/// message it came in.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbPaymentReference {
+    pub crux__db___id: CruxId,                  // :crux.db/id
+    pub payment_reference___message_id: String, // :payment-reference/message-id
+}
+
+/// Text as it can be stored: the EDN reader ends a string at its first
+/// quote and doesn't undo escapes.
+fn storable(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '"' => '\'',
+            '\\' => '/',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+impl From<&InstructionStatus> for DbPaymentStatus {
+    fn from(status: &InstructionStatus) -> Self {
+        Self {
+            payment_status___payment_information_id: storable(&status.payment_information_id),
+            payment_status___instruction_id: status.instruction_id.as_deref().map(storable),
+            payment_status___end_to_end_id: storable(&status.end_to_end_id),
+            payment_status___code: status
+                .rejection
+                .as_ref()
+                .map(|rejection| rejection.code.to_string()),
+            payment_status___reason: status
+                .rejection
+                .as_ref()
+                .map(|rejection| storable(&rejection.reason)),
+        }
+    }
+}
+
+impl From<DbPaymentStatus> for InstructionStatus {
+    fn from(status: DbPaymentStatus) -> Self {
+        let reason = status.payment_status___reason.unwrap_or_default();
+        let rejection = status.payment_status___code.map(|code| Rejection {
+            code: REASON_CODES
+                .iter()
+                .find(|known| **known == code)
+                .copied()
+                .unwrap_or("MS03"),
+            reason,
+        });
+
+        Self {
+            payment_information_id: status.payment_status___payment_information_id,
+            instruction_id: status.payment_status___instruction_id,
+            end_to_end_id: status.payment_status___end_to_end_id,
+            rejection,
+        }
+    }
+}
+
+/// Id of the document recording what `name` stands for, e.g.
+/// `message/<MsgId>`.
+fn record_id(name: &str) -> CruxId {
+    CruxId::new(&Uuid::new_v5(&PAYMENT_NAMESPACE, name.as_bytes()).to_string())
+}
+
+fn message_record_id(message_id: &str) -> CruxId {
+    record_id(&format!("message/{}", message_id))
+}
+
+fn payment_information_record_id(payment_information_id: &str) -> CruxId {
+    record_id(&format!("payment-information/{}", payment_information_id))
+}
+
+/// EndToEndIds are the debtor's, so two debtors may use the same.
+fn end_to_end_record_id(source_account_id: &str, end_to_end_id: &str) -> CruxId {
+    record_id(&format!(
+        "end-to-end/{}/{}",
+        source_account_id, end_to_end_id
+    ))
+}
+
+/// The message a payment information or an instruction was executed in.
+async fn executed_in(executor: &DbExecutor, id: &CruxId) -> Result<Option<String>, DbError> {
+    let crux_reference = executor.0.entity(edn_rs::to_string(id.clone())).await?;
+    if crux_reference == Edn::Nil {
+        return Ok(None);
+    }
+
+    let reference: DbPaymentReference = edn_rs::from_edn(&crux_reference)?;
+    Ok(Some(reference.payment_reference___message_id))
+}
+
+/// A message id being executed by this process, until dropped.
+struct Executing(String);
+
+impl Executing {
+    /// `None` when the message is already being executed.
+    fn start(message_id: &str) -> Option<Self> {
+        let mut executing = EXECUTING.lock().unwrap();
+
+        if executing.insert(message_id.to_string()) {
+            Some(Self(message_id.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Executing {
+    fn drop(&mut self) {
+        EXECUTING.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub(crate) struct InitiatePayments {
+    pub initiation: Initiation,
+    pub audit: Audit,
+}
+
+impl Message for InitiatePayments {
+    type Result = Result<StatusReport, DbError>;
+}
+
+impl Handler<InitiatePayments> for DbExecutor {
+    type Result = ResponseFuture<Result<StatusReport, DbError>>;
+
+    /// Executes the instructions in order, each as a transfer of its own,
+    /// so one being refused doesn't stop the others. A message executed
+    /// before is answered with its report instead, and payment informations
+    /// and instructions executed before are rejected.
+    fn handle(&mut self, msg: InitiatePayments, _: &mut Self::Context) -> Self::Result {
+        let executor = self.clone();
+
+        Box::pin(async move {
+            let message_id = msg.initiation.message_id;
+            let _executing = Executing::start(&message_id).ok_or(DbError::StateConflict)?;
+
+            let crux_message = executor
+                .0
+                .entity(edn_rs::to_string(message_record_id(&message_id)))
+                .await?;
+            if crux_message != Edn::Nil {
+                let message: DbPaymentMessage = edn_rs::from_edn(&crux_message)?;
+
+                return Ok(StatusReport {
+                    message_id,
+                    statuses: message
+                        .payment_message___statuses
+                        .into_iter()
+                        .map(InstructionStatus::from)
+                        .collect(),
+                });
+            }
+
+            let aliases = imports::existing_accounts(&executor.0).await?;
+            let mut payment_informations = HashMap::new();
+            let mut end_to_end_ids = HashSet::new();
+            let mut statuses = Vec::new();
+
+            for instruction in msg.initiation.instructions {
+                let payment_information_id = instruction.payment_information_id.clone();
+                let earlier = match payment_informations.get(&payment_information_id) {
+                    Some(earlier) => Ok(Option::clone(earlier)),
+                    None => {
+                        let id = payment_information_record_id(&payment_information_id);
+                        let earlier = executed_in(&executor, &id).await;
+                        if let Ok(earlier) = &earlier {
+                            payment_informations
+                                .insert(payment_information_id.clone(), earlier.clone());
+                        }
+                        earlier
+                    }
+                };
+
+                let outcome = match earlier {
+                    Ok(Some(earlier)) => Err(Rejection::new(
+                        "DUPL",
+                        format!("payment information executed in message {}", earlier),
+                    )),
+                    Ok(None) => {
+                        executor
+                            .execute(
+                                &instruction,
+                                &aliases,
+                                &message_id,
+                                &mut end_to_end_ids,
+                                &msg.audit,
+                            )
+                            .await
+                    }
+                    Err(db_error) => Err(rejection(db_error)),
+                };
+
+                statuses.push(InstructionStatus {
+                    payment_information_id,
+                    instruction_id: instruction.instruction_id,
+                    end_to_end_id: instruction.end_to_end_id,
+                    rejection: outcome.err(),
+                });
+            }
+
+            let report = StatusReport {
+                message_id,
+                statuses,
+            };
+            // Without the record, the file can be posted again, and its
+            // executed instructions are still refused by their EndToEndId.
+            if let Err(db_error) = record(&executor, &report, &payment_informations).await {
+                db_error.log();
+            }
+
+            Ok(report)
+        })
+    }
+}
+
+/// Records the message with its report, and its payment informations not
+/// executed before.
+async fn record(
+    executor: &DbExecutor,
+    report: &StatusReport,
+    payment_informations: &HashMap<String, Option<String>>,
+) -> Result<(), DbError> {
+    let message = DbPaymentMessage {
+        crux__db___id: message_record_id(&report.message_id),
+        payment_message___message_id: storable(&report.message_id),
+        payment_message___statuses: report.statuses.iter().map(DbPaymentStatus::from).collect(),
+    };
+
+    let mut actions = vec![Action::Put(edn_rs::to_string(message), None)];
+    for (payment_information_id, earlier) in payment_informations {
+        if earlier.is_none() {
+            let reference = DbPaymentReference {
+                crux__db___id: payment_information_record_id(payment_information_id),
+                payment_reference___message_id: storable(&report.message_id),
+            };
+            actions.push(Action::Put(edn_rs::to_string(reference), None));
+        }
+    }
+
+    executor.0.tx_log(actions).await?;
+    Ok(())
+}
+
+impl DbExecutor {
+    /// Transfers the amount of the instruction unless its EndToEndId was
+    /// used before by the same debtor, in this file or an earlier one. The
+    /// EndToEndId is recorded in the transaction of the transfer.
+    async fn execute(
+        &self,
+        instruction: &Instruction,
+        aliases: &HashMap<String, String>,
+        message_id: &str,
+        end_to_end_ids: &mut HashSet<(String, String)>,
+        audit: &Audit,
+    ) -> Result<(), Rejection> {
+        let amount = instruction.amount.clone()?;
+        let source_account_id = self
+            .party_account(aliases, &instruction.debtor, "debtor")
+            .await?;
+        let target_account_id = self
+            .party_account(aliases, &instruction.creditor, "creditor")
+            .await?;
+
+        let mut also_write = Vec::new();
+        let end_to_end_id = &instruction.end_to_end_id;
+        if end_to_end_id != "NOTPROVIDED" {
+            if !end_to_end_ids.insert((source_account_id.clone(), end_to_end_id.clone())) {
+                return Err(Rejection::new("AM05", "repeats an earlier EndToEndId"));
+            }
+
+            let id = end_to_end_record_id(&source_account_id, end_to_end_id);
+            if let Some(earlier) = executed_in(self, &id).await.map_err(rejection)? {
+                return Err(Rejection::new(
+                    "AM05",
+                    format!("EndToEndId executed in message {}", earlier),
+                ));
+            }
+
+            let reference = DbPaymentReference {
+                crux__db___id: id,
+                payment_reference___message_id: storable(message_id),
+            };
+            also_write.push(Action::Put(edn_rs::to_string(reference), None));
+        }
+
+        let transfer = AccountTransfer {
+            source_account_id,
+            amount,
+            target_account_id,
+            audit: audit.clone(),
+            also_write,
+        };
+
+        self.transfer(transfer).await.map(|_| ()).map_err(rejection)
+    }
+    async fn party_account(
+        &self,
+        aliases: &HashMap<String, String>,
+        identifier: &Option<String>,
+        party: &str,
+    ) -> Result<String, Rejection> {
+        let identifier = identifier
+            .as_ref()
+            .ok_or_else(|| Rejection::new("AC01", format!("no {} account", party)))?;
+
+        resolve(self, aliases, identifier)
+            .await
+            .map_err(rejection)?
+            .ok_or_else(|| Rejection::new("AC01", format!("no {} account {}", party, identifier)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Channel;
+    use crate::config::Config;
+    use crate::crux::CruxClient;
+    use crate::events::EventBroker;
+    use crate::DbAccount;
+    use std::sync::Arc;
+
+    fn config() -> ExportConfig {
+        ExportConfig {
+            currency: String::from("EUR"),
+            decimals: 2,
+            bank_id: String::from("SMAUGBANK"),
+        }
+    }
+
+    /// `(end-to-end id, creditor, amount)`.
+    type Transfer<'a> = (&'a str, &'a str, &'a str);
+
+    /// A pain.001 file of payment informations, each with its debtor and
+    /// transfers.
+    fn pain001(message_id: &str, payments: &[(&str, &str, &[Transfer])]) -> String {
+        let payments = payments
+            .iter()
+            .map(|(payment_information_id, debtor, transfers)| {
+                let transfers = transfers
+                    .iter()
+                    .map(|(end_to_end_id, creditor, amount)| {
+                        format!(
+                            "<CdtTrfTxInf><PmtId><EndToEndId>{}</EndToEndId></PmtId>\
+                             <Amt><InstdAmt Ccy=\"EUR\">{}</InstdAmt></Amt>\
+                             <CdtrAcct><Id><Othr><Id>{}</Id></Othr></Id></CdtrAcct>\
+                             </CdtTrfTxInf>",
+                            end_to_end_id, amount, creditor
+                        )
+                    })
+                    .collect::<String>();
+
+                format!(
+                    "<PmtInf><PmtInfId>{}</PmtInfId>\
+                     <DbtrAcct><Id><Othr><Id>{}</Id></Othr></Id></DbtrAcct>{}</PmtInf>",
+                    payment_information_id, debtor, transfers
+                )
+            })
+            .collect::<String>();
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:pain.001.001.03\">\
+             <CstmrCdtTrfInitn><GrpHdr><MsgId>{}</MsgId></GrpHdr>{}</CstmrCdtTrfInitn>\
+             </Document>",
+            message_id, payments
+        )
+    }
+
+    #[test]
+    fn instructions_are_read_with_their_payment_information() {
+        let content = "<?xml version=\"1.0\"?>\
+            <Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:pain.001.001.09\">\
+            <CstmrCdtTrfInitn><GrpHdr><MsgId> MSG-1 </MsgId></GrpHdr>\
+            <PmtInf><PmtInfId>PMT-1</PmtInfId>\
+            <DbtrAcct><Id><IBAN>DE89370400440532013000</IBAN></Id></DbtrAcct>\
+            <CdtTrfTxInf><PmtId><InstrId>I-1</InstrId><EndToEndId>E-1</EndToEndId></PmtId>\
+            <Amt><InstdAmt Ccy=\"EUR\">10.5</InstdAmt></Amt>\
+            <CdtrAcct><Id><Othr><Id>bob</Id></Othr></Id></CdtrAcct></CdtTrfTxInf>\
+            <CdtTrfTxInf><Amt><InstdAmt Ccy=\"EUR\">1.000</InstdAmt></Amt></CdtTrfTxInf>\
+            </PmtInf>\
+            <PmtInf><CdtTrfTxInf><PmtId><EndToEndId>E-2</EndToEndId></PmtId>\
+            <Amt><InstdAmt Ccy=\"USD\">1</InstdAmt></Amt></CdtTrfTxInf></PmtInf>\
+            </CstmrCdtTrfInitn></Document>";
+        let initiation = parse(content, &config()).unwrap();
+
+        assert_eq!(initiation.message_id, "MSG-1");
+        let first = &initiation.instructions[0];
+        assert_eq!(first.payment_information_id, "PMT-1");
+        assert_eq!(first.instruction_id.as_deref(), Some("I-1"));
+        assert_eq!(first.end_to_end_id, "E-1");
+        assert_eq!(first.debtor.as_deref(), Some("DE89370400440532013000"));
+        assert_eq!(first.creditor.as_deref(), Some("bob"));
+        assert_eq!(first.amount, Ok(1050));
+
+        let second = &initiation.instructions[1];
+        assert_eq!(second.end_to_end_id, "NOTPROVIDED");
+        assert_eq!(second.debtor.as_deref(), Some("DE89370400440532013000"));
+        assert_eq!(second.creditor, None);
+        assert_eq!(second.amount, Ok(100));
+
+        let third = &initiation.instructions[2];
+        assert_eq!(third.payment_information_id, "");
+        assert_eq!(third.debtor, None);
+        assert_eq!(third.amount.as_ref().unwrap_err().code, "AM03");
+    }
+
+    #[test]
+    fn amounts_are_read_in_the_decimals_of_the_currency() {
+        assert_eq!(parse_amount("10", 2), Some(1000));
+        assert_eq!(parse_amount("10.5", 2), Some(1050));
+        assert_eq!(parse_amount("0.01", 2), Some(1));
+        assert_eq!(parse_amount("7", 0), Some(7));
+        assert_eq!(parse_amount("10.001", 2), None);
+        assert_eq!(parse_amount(".5", 2), None);
+        assert_eq!(parse_amount("-1", 2), None);
+        assert_eq!(parse_amount("1e3", 2), None);
+
+        let rejection = |amount: &str| {
+            let content = pain001("MSG", &[("PMT", "alice", &[("E", "bob", amount)])]);
+            parse(&content, &config()).unwrap().instructions[0]
+                .amount
+                .clone()
+                .unwrap_err()
+                .code
+        };
+        assert_eq!(rejection("0.00"), "AM01");
+        assert_eq!(rejection("1.234"), "AM12");
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        assert!(parse("<Document", &config()).is_err());
+        assert!(parse("<Document><CstmrPmtStsRpt/></Document>", &config()).is_err());
+        assert!(parse(
+            "<Document><CstmrCdtTrfInitn><GrpHdr/></CstmrCdtTrfInitn></Document>",
+            &config()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn account_ids_are_hyphenated_back() {
+        assert_eq!(
+            hyphenated("7c9e6679742540de944be07fc1f90ae7").as_deref(),
+            Some("7c9e6679-7425-40de-944b-e07fc1f90ae7")
+        );
+        assert_eq!(hyphenated("alice"), None);
+    }
+
+    #[test]
+    fn statuses_are_stored_as_they_can_be_read() {
+        let status = InstructionStatus {
+            payment_information_id: String::from("PMT \"1\""),
+            instruction_id: None,
+            end_to_end_id: String::from("E\\1"),
+            rejection: Some(Rejection::new("AM14", "{:limit \"daily\"}\n")),
+        };
+
+        let edn = edn_rs::to_string(DbPaymentStatus::from(&status));
+        let stored: DbPaymentStatus = edn_rs::from_str(&edn).unwrap();
+        assert_eq!(
+            InstructionStatus::from(stored),
+            InstructionStatus {
+                payment_information_id: String::from("PMT '1'"),
+                instruction_id: None,
+                end_to_end_id: String::from("E/1"),
+                rejection: Some(Rejection::new("AM14", "{:limit 'daily'} ")),
+            }
+        );
+    }
+
+    #[test]
+    fn a_message_is_executed_once_at_a_time() {
+        let executing = Executing::start("MSG-LOCK").unwrap();
+        assert!(Executing::start("MSG-LOCK").is_none());
+        assert!(Executing::start("MSG-OTHER").is_some());
+
+        drop(executing);
+        assert!(Executing::start("MSG-LOCK").is_some());
+    }
+
+    #[test]
+    fn repeated_files_and_ids_are_not_executed_again() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let accounts = ["alice", "bob"]
+                .iter()
+                .map(|id| {
+                    let account = DbAccount {
+                        crux__db___id: CruxId::new(id),
+                        account___amount: 10000,
+                        account___type: None,
+                        account___limits: None,
+                        account___interest_rate: None,
+                        account___external_id: None,
+                    };
+                    Action::Put(edn_rs::to_string(account), None)
+                })
+                .collect();
+            client.tx_log(accounts).await.unwrap();
+
+            let db = DbExecutor(
+                client.clone(),
+                Arc::new(Config::default()),
+                EventBroker::default().start(),
+            )
+            .start();
+            let initiate = |content: String| {
+                let db = db.clone();
+
+                async move {
+                    let initiation = parse(&content, &config()).unwrap();
+                    let audit = Audit::new(Channel::Api);
+
+                    db.send(InitiatePayments { initiation, audit })
+                        .await
+                        .unwrap()
+                        .unwrap()
+                }
+            };
+            let codes = |report: &StatusReport| {
+                report
+                    .statuses
+                    .iter()
+                    .map(|status| status.rejection.as_ref().map(|rejection| rejection.code))
+                    .collect::<Vec<Option<&str>>>()
+            };
+            let balance = |id: &'static str| {
+                let client = client.clone();
+
+                async move {
+                    let account: DbAccount =
+                        edn_rs::from_edn(&client.entity(format!(":{}", id)).await.unwrap())
+                            .unwrap();
+                    account.account___amount
+                }
+            };
+
+            let first = pain001(
+                "MSG-1",
+                &[(
+                    "PMT-1",
+                    "alice",
+                    &[
+                        ("E-1", "bob", "1.00"),
+                        ("E-1", "bob", "1.00"),
+                        ("E-2", "bob", "2.00"),
+                    ],
+                )],
+            );
+            let report = initiate(first.clone()).await;
+            assert_eq!(codes(&report), vec![None, Some("AM05"), None]);
+            assert_eq!(balance("alice").await, 9700);
+
+            // Posted again, e.g. after a timeout.
+            let again = initiate(first).await;
+            assert_eq!(again.statuses, report.statuses);
+            assert_eq!(balance("alice").await, 9700);
+
+            let repeated_payment_information = initiate(pain001(
+                "MSG-2",
+                &[("PMT-1", "alice", &[("E-3", "bob", "1.00")])],
+            ))
+            .await;
+            assert_eq!(codes(&repeated_payment_information), vec![Some("DUPL")]);
+
+            // Bob may use the EndToEndIds Alice used.
+            let repeated_end_to_end_ids = initiate(pain001(
+                "MSG-3",
+                &[
+                    (
+                        "PMT-2",
+                        "alice",
+                        &[("E-2", "bob", "1.00"), ("E-3", "bob", "1.00")],
+                    ),
+                    ("PMT-3", "bob", &[("E-1", "alice", "0.50")]),
+                ],
+            ))
+            .await;
+            assert_eq!(
+                codes(&repeated_end_to_end_ids),
+                vec![Some("AM05"), None, None]
+            );
+            assert_eq!(balance("alice").await, 9650);
+            assert_eq!(balance("bob").await, 10350);
+        });
+    }
+}
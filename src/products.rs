@@ -0,0 +1,277 @@
+use edn_derive::Serialize;
+use edn_rs::{Edn, EdnError};
+use std::collections::HashMap;
+use transistor::edn_rs;
+
+use crate::config::{self, DEFAULT_ACCOUNT_TYPE};
+use crate::{DbAccount, DbError, OperationType};
+
+/// Product of the bank's own accounts, which customers can't open.
+pub const INTERNAL_PRODUCT: &str = "internal";
+
+/// Kind of account a customer can open and the rules its accounts follow,
+/// read from the configuration file:
+///
+/// ```edn
+/// {:description "Savings account"
+///  :operations [:create :deposit :withdraw :transfer]
+///  :withdrawals true
+///  :min-balance 1000
+///  :overdraft 0
+///  :max-balance 10000000
+///  :interest-rate 150}
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Product {
+    pub name: String,
+    pub description: Option<String>,
+    /// Operations customers may start on the account, as source or target.
+    pub operations: Vec<OperationType>,
+    /// Whether customers may take money out, by withdrawing or transferring.
+    pub withdrawals: bool,
+    pub min_balance: usize,
+    /// How far below `min_balance` the account may go.
+    pub overdraft: usize,
+    pub max_balance: Option<usize>,
+    /// Annual rate in basis points for accounts created without one.
+    pub interest_rate: Option<usize>,
+}
+
+impl Product {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: None,
+            operations: vec![
+                OperationType::Create,
+                OperationType::Deposit,
+                OperationType::Withdraw,
+                OperationType::Transfer,
+            ],
+            withdrawals: true,
+            min_balance: 0,
+            overdraft: 0,
+            max_balance: None,
+            interest_rate: None,
+        }
+    }
+
+    /// Products available without configuration: `default`, which keeps the
+    /// behavior accounts had before products existed, and `internal`.
+    pub fn builtin() -> HashMap<String, Product> {
+        let mut internal = Product::new(INTERNAL_PRODUCT);
+        internal.description = Some(String::from("Accounts owned by the bank"));
+        internal.operations = Vec::new();
+        internal.withdrawals = false;
+
+        let mut products = HashMap::new();
+        products.insert(
+            DEFAULT_ACCOUNT_TYPE.to_string(),
+            Product::new(DEFAULT_ACCOUNT_TYPE),
+        );
+        products.insert(INTERNAL_PRODUCT.to_string(), internal);
+
+        products
+    }
+
+    pub fn from_settings(name: &str, edn: &Edn) -> Result<Self, EdnError> {
+        let default = Product::new(name);
+
+        let operations = match edn[":operations"].iter() {
+            Some(iter) => iter
+                .map(operation_type)
+                .collect::<Result<Vec<OperationType>, EdnError>>()?,
+            None => default.operations,
+        };
+
+        let withdrawals = match &edn[":withdrawals"] {
+            Edn::Nil => default.withdrawals,
+            Edn::Bool(withdrawals) => *withdrawals,
+            other => {
+                return Err(EdnError::Deserialize(format!(
+                    "couldn't convert {} into a boolean setting",
+                    other
+                )))
+            }
+        };
+
+        let description = match &edn[":description"] {
+            Edn::Nil => None,
+            description => Some(edn_rs::from_edn(description)?),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            description,
+            operations,
+            withdrawals,
+            min_balance: config::uint(&edn[":min-balance"])?.unwrap_or(default.min_balance),
+            overdraft: config::uint(&edn[":overdraft"])?.unwrap_or(default.overdraft),
+            max_balance: config::uint(&edn[":max-balance"])?,
+            interest_rate: config::uint(&edn[":interest-rate"])?,
+        })
+    }
+
+    /// Lowest balance the account may be left with.
+    pub fn floor(&self) -> i64 {
+        self.min_balance as i64 - self.overdraft as i64
+    }
+
+    /// Checks that customers may start `operation_type` on an account of
+    /// this product, `outflow` telling whether money would leave it.
+    pub fn check_operation(
+        &self,
+        operation_type: &OperationType,
+        outflow: bool,
+    ) -> Result<(), DbError> {
+        if !self.operations.contains(operation_type) {
+            return Err(self.violation(Rule::OperationNotAllowed, None));
+        }
+
+        if outflow && !self.withdrawals {
+            return Err(self.violation(Rule::WithdrawalsNotAllowed, None));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the balance an operation left the account with.
+    ///
+    /// Only the bound the balance moved towards is checked, so an account
+    /// already outside them, e.g. after its product changed, can still be
+    /// brought back. Going below the floor is a `StateConflict`, as for
+    /// insufficient funds.
+    pub fn check_balance(&self, before: i64, after: i64) -> Result<(), DbError> {
+        if after < before && after < self.floor() {
+            return Err(DbError::StateConflict);
+        }
+
+        if let Some(max) = self.max_balance {
+            if after > before && after > max as i64 {
+                return Err(self.violation(Rule::MaxBalance, Some(max)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn violation(&self, rule: Rule, limit: Option<usize>) -> DbError {
+        DbError::ProductRule(RuleViolation {
+            product: self.name.clone(),
+            rule,
+            limit,
+        })
+    }
+}
+
+/// Fees and interest are posted by the bank, not started by customers.
+fn operation_type(edn: &Edn) -> Result<OperationType, EdnError> {
+    match OperationType::from_keyword(edn)? {
+        OperationType::Fee | OperationType::Interest => Err(EdnError::Deserialize(format!(
+            "{} isn't a customer operation",
+            edn
+        ))),
+        operation_type => Ok(operation_type),
+    }
+}
+
+/// Product of `account`, failing if its type isn't a known product.
+pub fn of<'a>(
+    products: &'a HashMap<String, Product>,
+    account: &DbAccount,
+) -> Result<&'a Product, DbError> {
+    let name = account
+        .account___type
+        .as_deref()
+        .unwrap_or(DEFAULT_ACCOUNT_TYPE);
+
+    products.get(name).ok_or_else(|| {
+        DbError::ProductRule(RuleViolation {
+            product: name.to_string(),
+            rule: Rule::UnknownProduct,
+            limit: None,
+        })
+    })
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Rule {
+    UnknownProduct,
+    OperationNotAllowed,
+    WithdrawalsNotAllowed,
+    MaxBalance,
+}
+
+#[derive(Debug)]
+pub struct RuleViolation {
+    pub product: String,
+    pub rule: Rule,
+    pub limit: Option<usize>,
+}
+
+impl RuleViolation {
+    pub fn to_edn(&self) -> String {
+        format!(
+            "{{:error :product/rule-violated, :product {}, :rule {}, :limit {}}}",
+            edn_rs::to_string(self.product.clone()),
+            edn_rs::to_string(self.rule),
+            edn_rs::to_string(self.limit),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use transistor::types::CruxId;
+
+    #[test]
+    fn overdrafts_go_below_zero() {
+        let product = Product::from_settings(
+            "current",
+            &Edn::from_str("{:min-balance 0 :overdraft 500}").unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(product.floor(), -500);
+        assert!(product.check_balance(100, -500).is_ok());
+        assert!(matches!(
+            product.check_balance(100, -501),
+            Err(DbError::StateConflict)
+        ));
+        // Already overdrawn past the floor, it can still be brought back.
+        assert!(product.check_balance(-900, -800).is_ok());
+    }
+
+    #[test]
+    fn max_balance_only_bounds_inflows() {
+        let mut product = Product::new("savings");
+        product.max_balance = Some(1000);
+
+        assert!(product.check_balance(900, 1000).is_ok());
+        assert!(matches!(
+            product.check_balance(900, 1001),
+            Err(DbError::ProductRule(_))
+        ));
+        assert!(product.check_balance(2000, 1500).is_ok());
+    }
+
+    #[test]
+    fn amounts_are_signed_and_read_from_older_documents() {
+        let older: DbAccount = edn_rs::from_str(
+            "{:crux.db/id :alice :account/amount 100 :account/type nil \
+             :account/limits nil :account/interest-rate nil :account/external-id nil}",
+        )
+        .unwrap();
+        assert_eq!(older.account___amount, 100);
+
+        let overdrawn = DbAccount {
+            crux__db___id: CruxId::new("alice"),
+            account___amount: -250,
+            ..older
+        };
+        let written: DbAccount = edn_rs::from_str(&edn_rs::to_string(overdrawn)).unwrap();
+        assert_eq!(written.account___amount, -250);
+    }
+}
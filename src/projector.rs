@@ -0,0 +1,744 @@
+use actix::prelude::*;
+use edn_derive::Serialize;
+use edn_rs::{Edn, EdnError};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use transistor::edn_rs;
+use transistor::types::http::Action;
+use transistor::types::{query::Query, CruxId};
+
+use crate::admin;
+use crate::crux::{CruxClient, TX_LOG_PAGE_SIZE};
+use crate::ledger::{self, SystemAccount};
+use crate::{DbAccount, DbAccountOperation, DbError};
+
+/// How often the transaction log is polled for new transactions.
+const TAIL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A document written to Crux that projections care about.
+#[derive(Clone, Debug)]
+pub enum Event {
+    Account(DbAccount),
+    Operation(DbAccountOperation),
+}
+
+/// A read model built from the transaction log, one event at a time, keyed
+/// by account.
+///
+/// The state of each key is saved with the checkpoint, in a document of its
+/// own so only the keys that changed are written again. After a restart it
+/// is restored and only fed the transactions it hadn't seen.
+pub trait Projection: Send + Sync {
+    /// Unique name, used for the checkpoint ids.
+    fn name(&self) -> &'static str;
+
+    /// Applies `event`, returning the keys whose state it changed.
+    fn apply(&mut self, event: &Event) -> Vec<CruxId>;
+
+    /// The state of `key` as EDN, for `restore`.
+    fn state(&self, key: &CruxId) -> String;
+
+    fn restore(&mut self, key: CruxId, state: &Edn) -> Result<(), EdnError>;
+}
+
+pub type SharedProjection = Arc<RwLock<dyn Projection>>;
+
+/// Balance and flows of every account, as of the last transaction projected.
+/// System account balances are the sum of their postings.
+#[derive(Clone, Debug, Default)]
+pub struct RunningTotals {
+    totals: BTreeMap<CruxId, Totals>,
+    /// Ids of the operations added to the totals of every account.
+    applied: BTreeMap<CruxId, BTreeSet<CruxId>>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Totals {
+    pub balance: i64,
+    pub inflow: usize,
+    pub outflow: usize,
+    pub operations: usize,
+}
+
+impl RunningTotals {
+    pub fn get(&self, account_id: &CruxId) -> Option<&Totals> {
+        self.totals.get(account_id)
+    }
+}
+
+impl Projection for RunningTotals {
+    fn name(&self) -> &'static str {
+        "running-totals"
+    }
+
+    fn apply(&mut self, event: &Event) -> Vec<CruxId> {
+        match event {
+            Event::Account(account) => {
+                // System account documents written before their balances
+                // were summed from postings are stale.
+                if SystemAccount::from_id(&account.crux__db___id).is_some() {
+                    return Vec::new();
+                }
+
+                self.totals
+                    .entry(account.crux__db___id.clone())
+                    .or_default()
+                    .balance = account.account___amount;
+
+                vec![account.crux__db___id.clone()]
+            }
+            Event::Operation(operation) => {
+                let mut accounts = touched(operation);
+                accounts.extend(
+                    ledger::postings(operation)
+                        .into_iter()
+                        .map(|p| p.posting___account_id),
+                );
+                accounts.sort();
+                accounts.dedup();
+
+                // Rewriting an operation, like the monthly interest, doesn't
+                // add it again.
+                accounts.retain(|account_id| {
+                    self.applied
+                        .entry(account_id.clone())
+                        .or_default()
+                        .insert(operation.crux__db___id.clone())
+                });
+
+                for account_id in &accounts {
+                    let change = ledger::balance_change(operation, account_id);
+                    let system = SystemAccount::from_id(account_id).is_some();
+                    let totals = self.totals.entry(account_id.clone()).or_default();
+
+                    if system {
+                        totals.balance += change;
+                    }
+
+                    if change < 0 {
+                        totals.outflow += (-change) as usize;
+                    } else {
+                        totals.inflow += change as usize;
+                    }
+                    totals.operations += 1;
+                }
+
+                accounts
+            }
+        }
+    }
+
+    fn state(&self, account_id: &CruxId) -> String {
+        let totals = self.totals.get(account_id).cloned().unwrap_or_default();
+
+        let applied = self
+            .applied
+            .get(account_id)
+            .into_iter()
+            .flatten()
+            .map(|id| edn_rs::to_string(id.clone()))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        format!(
+            "{{:balance {} :inflow {} :outflow {} :operations {} :applied [{}]}}",
+            totals.balance, totals.inflow, totals.outflow, totals.operations, applied,
+        )
+    }
+
+    fn restore(&mut self, account_id: CruxId, totals: &Edn) -> Result<(), EdnError> {
+        // Checkpoints written before operations were tracked have none.
+        let applied = totals[":applied"]
+            .iter()
+            .into_iter()
+            .flatten()
+            .map(|id| CruxId::new(id.to_string().trim_start_matches(':')))
+            .collect();
+        self.applied.insert(account_id.clone(), applied);
+        self.totals.insert(
+            account_id,
+            Totals {
+                balance: edn_rs::from_edn(&totals[":balance"])?,
+                inflow: edn_rs::from_edn(&totals[":inflow"])?,
+                outflow: edn_rs::from_edn(&totals[":outflow"])?,
+                operations: edn_rs::from_edn(&totals[":operations"])?,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+/// Ids of the operations touching every account, oldest first.
+#[derive(Clone, Debug, Default)]
+pub struct OperationsIndex {
+    operations: BTreeMap<CruxId, Vec<CruxId>>,
+}
+
+impl OperationsIndex {
+    pub fn get(&self, account_id: &CruxId) -> &[CruxId] {
+        self.operations
+            .get(account_id)
+            .map_or(&[], |operations| operations.as_slice())
+    }
+}
+
+impl Projection for OperationsIndex {
+    fn name(&self) -> &'static str {
+        "operations-index"
+    }
+
+    fn apply(&mut self, event: &Event) -> Vec<CruxId> {
+        let mut changed = Vec::new();
+
+        if let Event::Operation(operation) = event {
+            for account_id in touched(operation) {
+                let operations = self.operations.entry(account_id.clone()).or_default();
+
+                // Rewriting an operation, like the monthly interest, keeps
+                // its place.
+                if !operations.contains(&operation.crux__db___id) {
+                    operations.push(operation.crux__db___id.clone());
+                    changed.push(account_id);
+                }
+            }
+        }
+
+        changed
+    }
+
+    fn state(&self, account_id: &CruxId) -> String {
+        format!(
+            "[{}]",
+            self.get(account_id)
+                .iter()
+                .map(|id| edn_rs::to_string(id.clone()))
+                .collect::<Vec<String>>()
+                .join(" ")
+        )
+    }
+
+    fn restore(&mut self, account_id: CruxId, operations: &Edn) -> Result<(), EdnError> {
+        let operations = operations
+            .iter()
+            .into_iter()
+            .flatten()
+            .map(|id| CruxId::new(id.to_string().trim_start_matches(':')))
+            .collect();
+        self.operations.insert(account_id, operations);
+
+        Ok(())
+    }
+}
+
+fn touched(operation: &DbAccountOperation) -> Vec<CruxId> {
+    let mut accounts = vec![operation.account_operation___source_account_id.clone()];
+    accounts.extend(operation.account_operation___target_account_id.clone());
+    accounts
+}
+
+/// A projection and the last transaction it was fed.
+struct Checkpointed {
+    projection: SharedProjection,
+    tx_id: Option<usize>,
+    /// Whether the head of the checkpoint is behind. Checkpoints are
+    /// transactions too, so writing one every round would never settle.
+    dirty: bool,
+    /// Keys whose state changed since the checkpoint was last written.
+    dirty_keys: BTreeSet<CruxId>,
+}
+
+/// Projections rebuilt and the last transaction fed to them.
+#[derive(Serialize, Clone, Debug)]
+pub struct Rebuild {
+    projections: Vec<String>,
+    tx_id: Option<usize>,
+}
+
+/// Tails the Crux transaction log, feeding every projection the accounts and
+/// operations written after its checkpoint.
+///
+/// A checkpoint is a head document, `:projection-<name>`, with the last
+/// transaction fed, and a document per key, `:projection-<name>-<key>`, with
+/// its state as an EDN string.
+pub struct Projector {
+    client: CruxClient,
+    projections: Vec<Checkpointed>,
+    loaded: bool,
+}
+
+impl Projector {
+    pub fn new(client: CruxClient, projections: Vec<SharedProjection>) -> Self {
+        Self {
+            client,
+            projections: projections
+                .into_iter()
+                .map(|projection| Checkpointed {
+                    projection,
+                    tx_id: None,
+                    dirty: false,
+                    dirty_keys: BTreeSet::new(),
+                })
+                .collect(),
+            loaded: false,
+        }
+    }
+
+    /// Restores every projection from its checkpoint, if it has one.
+    async fn load(&mut self) -> Result<(), DbError> {
+        for checkpointed in &mut self.projections {
+            let name = checkpointed.projection.read().unwrap().name();
+            let head = self
+                .client
+                .entity(edn_rs::to_string(checkpoint_id(name)))
+                .await?;
+            if head == Edn::Nil {
+                continue;
+            }
+
+            let mut projection = checkpointed.projection.write().unwrap();
+
+            // Checkpoints used to keep the whole state in the head, they're
+            // split on the next write.
+            if let Some(iter) = head[":projection/state"].map_iter() {
+                for (key, state) in iter {
+                    let key = CruxId::new(key.trim_start_matches(':'));
+                    projection.restore(key.clone(), state)?;
+                    checkpointed.dirty_keys.insert(key);
+                }
+                checkpointed.dirty = true;
+            } else {
+                drop(projection);
+                let query = Query::find(vec!["?key", "?state"])?
+                    .where_clause(vec![
+                        &format!("?checkpoint :projection/of :{}", name),
+                        "?checkpoint :projection/key ?key",
+                        "?checkpoint :projection/state ?state",
+                    ])?
+                    .build()?;
+                let rows = self.client.query(query).await?;
+
+                let mut projection = checkpointed.projection.write().unwrap();
+                for row in rows {
+                    let key = CruxId::new(row[0].trim_start_matches(':'));
+                    projection.restore(key, &Edn::from_str(&row[1])?)?;
+                }
+            }
+
+            checkpointed.tx_id = head[":projection/tx-id"].to_uint();
+        }
+
+        self.loaded = true;
+        Ok(())
+    }
+
+    /// Feeds the whole log to the projections, which must be empty, and
+    /// replaces their checkpoints.
+    pub async fn rebuild(&mut self) -> Result<Rebuild, DbError> {
+        self.loaded = true;
+        for checkpointed in &mut self.projections {
+            checkpointed.tx_id = None;
+            checkpointed.dirty = true;
+        }
+
+        self.tail().await?;
+
+        Ok(Rebuild {
+            projections: self
+                .projections
+                .iter()
+                .map(|checkpointed| checkpointed.projection.read().unwrap().name().to_string())
+                .collect(),
+            tx_id: self
+                .projections
+                .iter()
+                .filter_map(|checkpointed| checkpointed.tx_id)
+                .max(),
+        })
+    }
+
+    /// Feeds the transactions written since the last round, a page at a
+    /// time, writing the checkpoints of the projections that changed after
+    /// each page.
+    pub async fn tail(&mut self) -> Result<(), DbError> {
+        if !self.loaded {
+            self.load().await?;
+        }
+
+        loop {
+            // A projection without checkpoint starts from the beginning.
+            let after_tx_id = self
+                .projections
+                .iter()
+                .map(|checkpointed| checkpointed.tx_id)
+                .min()
+                .flatten();
+
+            let page = self.transactions(after_tx_id).await?;
+            let done = page.len() < TX_LOG_PAGE_SIZE;
+
+            for (tx_id, events) in page {
+                for checkpointed in &mut self.projections {
+                    if matches!(checkpointed.tx_id, Some(fed) if fed >= tx_id) {
+                        continue;
+                    }
+
+                    if !events.is_empty() {
+                        let mut projection = checkpointed.projection.write().unwrap();
+                        for event in &events {
+                            checkpointed.dirty_keys.extend(projection.apply(event));
+                        }
+                        checkpointed.dirty = true;
+                    }
+                    checkpointed.tx_id = Some(tx_id);
+                }
+            }
+
+            self.checkpoint().await?;
+
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes the head of every checkpoint that is behind, with the state
+    /// of the keys that changed, in one transaction.
+    async fn checkpoint(&mut self) -> Result<(), DbError> {
+        let mut actions = Vec::new();
+
+        for checkpointed in self
+            .projections
+            .iter()
+            .filter(|checkpointed| checkpointed.dirty)
+        {
+            let projection = checkpointed.projection.read().unwrap();
+            let name = projection.name();
+
+            actions.push(Action::Put(
+                format!(
+                    "{{:crux.db/id {}, :projection/tx-id {}}}",
+                    edn_rs::to_string(checkpoint_id(name)),
+                    edn_rs::to_string(checkpointed.tx_id),
+                ),
+                None,
+            ));
+            actions.extend(checkpointed.dirty_keys.iter().map(|key| {
+                Action::Put(
+                    format!(
+                        "{{:crux.db/id {}, :projection/of :{}, :projection/key {}, :projection/state {}}}",
+                        edn_rs::to_string(key_checkpoint_id(name, key)),
+                        name,
+                        edn_rs::to_string(key.clone()),
+                        edn_rs::to_string(projection.state(key)),
+                    ),
+                    None,
+                )
+            }));
+        }
+
+        if !actions.is_empty() {
+            self.client.tx_log(actions).await?;
+            for checkpointed in &mut self.projections {
+                checkpointed.dirty = false;
+                checkpointed.dirty_keys.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// At most a page of transactions after `after_tx_id`, with the
+    /// accounts and operations they put.
+    async fn transactions(
+        &self,
+        after_tx_id: Option<usize>,
+    ) -> Result<Vec<(usize, Vec<Event>)>, DbError> {
+        let body = self
+            .client
+            .tx_log_tail(after_tx_id, TX_LOG_PAGE_SIZE)
+            .await?;
+
+        // Tagged literals aren't supported by the parser, their values are
+        // enough here.
+        let edn = Edn::from_str(&body.replace("#crux/id", "").replace("#inst", ""))?;
+
+        let schema_id = Edn::Key(edn_rs::to_string(admin::schema_id()));
+        let mut transactions = Vec::new();
+
+        for tx in edn.iter().into_iter().flatten() {
+            let tx_id = tx[":crux.tx/tx-id"]
+                .to_uint()
+                .ok_or_else(|| EdnError::Deserialize(format!("transaction without id: {}", tx)))?;
+
+            let mut events = Vec::new();
+            let ops = tx[":crux.api/tx-ops"]
+                .iter()
+                .into_iter()
+                .flatten()
+                .collect::<Vec<&Edn>>();
+
+            // Migrations rewrite documents without moving money.
+            if ops.iter().any(|op| op[1][":crux.db/id"] == schema_id) {
+                transactions.push((tx_id, events));
+                continue;
+            }
+
+            // A transaction whose matches failed wrote nothing.
+            let matches = ops
+                .iter()
+                .any(|op| op[0] == Edn::Key(String::from(":crux.tx/match")));
+            if matches && !self.client.tx_committed(tx_id).await? {
+                transactions.push((tx_id, events));
+                continue;
+            }
+
+            for op in ops {
+                if op[0] != Edn::Key(String::from(":crux.tx/put")) {
+                    continue;
+                }
+
+                let doc = &op[1];
+                if doc[":account-operation/type"] != Edn::Nil {
+                    events.push(Event::Operation(edn_rs::from_edn(doc)?));
+                } else if doc[":account/amount"] != Edn::Nil {
+                    events.push(Event::Account(edn_rs::from_edn(doc)?));
+                }
+            }
+
+            transactions.push((tx_id, events));
+        }
+
+        Ok(transactions)
+    }
+}
+
+fn checkpoint_id(name: &str) -> CruxId {
+    CruxId::new(&format!("projection-{}", name))
+}
+
+fn key_checkpoint_id(name: &str, key: &CruxId) -> CruxId {
+    CruxId::new(&format!(
+        "projection-{}-{}",
+        name,
+        edn_rs::to_string(key.clone()).trim_start_matches(':')
+    ))
+}
+
+/// Has the projector catch up with the log every second, waiting for each
+/// round to finish before the next.
+pub struct ProjectorScheduler {
+    pub projector: Arc<Mutex<Projector>>,
+}
+
+impl Actor for ProjectorScheduler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(TAIL_INTERVAL, |scheduler, ctx| scheduler.schedule(ctx));
+    }
+}
+
+impl ProjectorScheduler {
+    fn schedule(&self, ctx: &mut Context<Self>) {
+        let projector = self.projector.clone();
+
+        async move { projector.lock().await.tail().await }
+            .into_actor(self)
+            .map(|result, _, _| {
+                if let Err(error) = result {
+                    tracing::error!(error = ?error, "projector failed");
+                }
+            })
+            .wait(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::JournalEntry;
+    use crate::OperationType;
+    use transistor::types::http::Order;
+
+    fn account(id: &CruxId, amount: i64) -> Event {
+        Event::Account(DbAccount {
+            crux__db___id: id.clone(),
+            account___amount: amount,
+            account___type: None,
+            account___limits: None,
+            account___interest_rate: None,
+            account___external_id: None,
+        })
+    }
+
+    fn deposit(id: &str, account_id: &CruxId, amount: usize) -> Event {
+        let postings = JournalEntry::new()
+            .debit(&SystemAccount::CashIn.id(), amount)
+            .credit(account_id, amount)
+            .into_postings();
+
+        Event::Operation(DbAccountOperation {
+            crux__db___id: CruxId::new(id),
+            account_operation___type: OperationType::Deposit,
+            account_operation___amount: amount,
+            account_operation___source_account_id: account_id.clone(),
+            account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(postings),
+            account_operation___request_id: None,
+            account_operation___audit: None,
+            tx___tx_time: None,
+        })
+    }
+
+    #[test]
+    fn running_totals_sum_system_balances_from_postings() {
+        let alice = CruxId::new("alice");
+        let cash_in = SystemAccount::CashIn.id();
+        let mut totals = RunningTotals::default();
+
+        totals.apply(&deposit("first", &alice, 30));
+        totals.apply(&account(&alice, 30));
+        // Left by an older version, and ignored.
+        totals.apply(&account(&cash_in, 1000));
+        totals.apply(&deposit("second", &alice, 12));
+        totals.apply(&account(&alice, 42));
+
+        let alice_totals = totals.get(&alice).unwrap();
+        assert_eq!(alice_totals.balance, 42);
+        assert_eq!(alice_totals.inflow, 42);
+        assert_eq!(alice_totals.operations, 2);
+
+        let cash_in_totals = totals.get(&cash_in).unwrap();
+        assert_eq!(cash_in_totals.balance, 42);
+        assert_eq!(cash_in_totals.inflow, 42);
+        assert_eq!(cash_in_totals.operations, 2);
+    }
+
+    #[test]
+    fn running_totals_add_operations_written_again_once() {
+        let alice = CruxId::new("alice");
+        let mut totals = RunningTotals::default();
+
+        assert_eq!(
+            totals.apply(&deposit("interest", &alice, 30)),
+            vec![alice.clone(), SystemAccount::CashIn.id()]
+        );
+        assert!(totals.apply(&deposit("interest", &alice, 30)).is_empty());
+
+        // Restored from a checkpoint, the operation is still known.
+        let mut restored = RunningTotals::default();
+        for account_id in &[alice.clone(), SystemAccount::CashIn.id()] {
+            let state = Edn::from_str(&totals.state(account_id)).unwrap();
+            restored.restore(account_id.clone(), &state).unwrap();
+        }
+        assert!(restored.apply(&deposit("interest", &alice, 30)).is_empty());
+
+        for totals in &[totals, restored] {
+            assert_eq!(totals.get(&alice).unwrap().inflow, 30);
+            assert_eq!(totals.get(&alice).unwrap().operations, 1);
+            assert_eq!(totals.get(&SystemAccount::CashIn.id()).unwrap().balance, 30);
+        }
+    }
+
+    fn put(event: Event) -> Action {
+        match event {
+            Event::Account(account) => Action::Put(edn_rs::to_string(account), None),
+            Event::Operation(operation) => Action::Put(edn_rs::to_string(operation), None),
+        }
+    }
+
+    fn projector(client: &CruxClient) -> (Arc<RwLock<RunningTotals>>, Projector) {
+        let totals = Arc::new(RwLock::new(RunningTotals::default()));
+        let projections: Vec<SharedProjection> = vec![totals.clone()];
+
+        (totals, Projector::new(client.clone(), projections))
+    }
+
+    #[test]
+    fn checkpoints_are_written_per_key_and_restored() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let alice = CruxId::new("alice");
+            let bob = CruxId::new("bob");
+            client
+                .tx_log(vec![
+                    put(deposit("deposit", &alice, 30)),
+                    put(account(&alice, 30)),
+                ])
+                .await
+                .unwrap();
+            client.tx_log(vec![put(account(&bob, 5))]).await.unwrap();
+
+            let (_, mut first) = projector(&client);
+            first.tail().await.unwrap();
+
+            let checkpoint = client
+                .entity(String::from(":projection-running-totals-alice"))
+                .await
+                .unwrap();
+            assert_eq!(
+                checkpoint[":projection/key"],
+                Edn::Key(String::from(":alice"))
+            );
+            let head = client
+                .entity(String::from(":projection-running-totals"))
+                .await
+                .unwrap();
+            assert_eq!(head[":projection/state"], Edn::Nil);
+
+            // Only bob changes, and only his checkpoint is written again.
+            client.tx_log(vec![put(account(&bob, 7))]).await.unwrap();
+            first.tail().await.unwrap();
+            let alice_history = client
+                .entity_history(
+                    String::from(":projection-running-totals-alice"),
+                    Order::Desc,
+                    false,
+                )
+                .await
+                .unwrap();
+            assert_eq!(alice_history.history.len(), 1);
+
+            let (totals, mut second) = projector(&client);
+            second.tail().await.unwrap();
+
+            let totals = totals.read().unwrap();
+            assert_eq!(totals.get(&alice).unwrap().balance, 30);
+            assert_eq!(totals.get(&alice).unwrap().inflow, 30);
+            assert_eq!(totals.get(&bob).unwrap().balance, 7);
+            assert_eq!(totals.get(&SystemAccount::CashIn.id()).unwrap().balance, 30);
+        });
+    }
+
+    #[test]
+    fn whole_state_checkpoints_are_split() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            client
+                .tx_log(vec![Action::Put(
+                    String::from(
+                        "{:crux.db/id :projection-running-totals, :projection/tx-id 0, \
+                         :projection/state {:alice {:balance 3 :inflow 3 :outflow 0 :operations 1}}}",
+                    ),
+                    None,
+                )])
+                .await
+                .unwrap();
+
+            let (totals, mut projector) = projector(&client);
+            projector.tail().await.unwrap();
+            assert_eq!(totals.read().unwrap().get(&CruxId::new("alice")).unwrap().balance, 3);
+
+            let checkpoint = client
+                .entity(String::from(":projection-running-totals-alice"))
+                .await
+                .unwrap();
+            assert_eq!(checkpoint[":projection/of"], Edn::Key(String::from(":running-totals")));
+        });
+    }
+}
@@ -0,0 +1,571 @@
+use actix_web::dev::{Path, ResourceDef, ServiceRequest};
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue, Method};
+use actix_web::{HttpRequest, HttpResponse};
+use edn_rs::{Edn, EdnError};
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use transistor::edn_rs;
+
+use crate::audit;
+use crate::config::{self, Config};
+use crate::metrics;
+
+/// Buckets kept by the `MemoryStore`, the least recently used ones being
+/// dropped past it.
+const MAX_BUCKETS: usize = 100_000;
+
+lazy_static! {
+    /// Every route under an account, to find the account a request is for.
+    static ref ACCOUNT_ROUTES: ResourceDef = ResourceDef::prefix("/accounts/{account_id}");
+}
+
+/// A token bucket: up to `burst` requests at once, refilled with
+/// `per_second` requests every second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub burst: usize,
+    pub per_second: f64,
+}
+
+impl Limit {
+    /// Reads `{:burst 10 :per-second 2}`, or `:per-minute` for slower
+    /// refills. Missing settings mean no limit.
+    fn from_settings(edn: &Edn) -> Result<Option<Self>, EdnError> {
+        if *edn == Edn::Nil {
+            return Ok(None);
+        }
+
+        let per_second = match (
+            config::uint(&edn[":per-second"])?,
+            config::uint(&edn[":per-minute"])?,
+        ) {
+            (Some(per_second), _) => per_second as f64,
+            (None, Some(per_minute)) => per_minute as f64 / 60.0,
+            (None, None) => {
+                return Err(EdnError::Deserialize(format!(
+                    "rate limit without :per-second or :per-minute: {}",
+                    edn
+                )))
+            }
+        };
+        let burst = config::uint(&edn[":burst"])?.unwrap_or_else(|| per_second.ceil() as usize);
+
+        if burst == 0 || per_second <= 0.0 {
+            return Err(EdnError::Deserialize(format!(
+                "rate limit letting no request through: {}",
+                edn
+            )));
+        }
+
+        Ok(Some(Self { burst, per_second }))
+    }
+}
+
+/// Limits of a route, per API client and per account.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteLimits {
+    pub client: Option<Limit>,
+    pub account: Option<Limit>,
+}
+
+impl RouteLimits {
+    fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        Ok(Self {
+            client: Limit::from_settings(&edn[":client"])?,
+            account: Limit::from_settings(&edn[":account"])?,
+        })
+    }
+}
+
+/// Limits of the routes matching `path`, for `method` or any method when
+/// it's `None`.
+#[derive(Clone, Debug)]
+pub struct RouteRule {
+    pub method: Option<Method>,
+    pub path: String,
+    pub limits: RouteLimits,
+}
+
+/// Rate limits, read from the configuration file:
+///
+/// ```edn
+/// {:default {:client {:burst 100 :per-second 20}}
+///  :routes [{:method :post :path "/accounts/{account_id}/transfer"
+///            :client {:burst 10 :per-second 2}
+///            :account {:burst 5 :per-minute 30}}]}
+/// ```
+///
+/// A route matching a rule only gets that rule's limits, the others get
+/// the default ones.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    pub default: RouteLimits,
+    pub routes: Vec<RouteRule>,
+}
+
+impl RateLimitConfig {
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        let routes = edn[":routes"]
+            .iter()
+            .into_iter()
+            .flatten()
+            .map(|rule| {
+                let method = match &rule[":method"] {
+                    Edn::Nil => None,
+                    Edn::Key(method) => Some(
+                        Method::from_str(&method.trim_start_matches(':').to_uppercase()).map_err(
+                            |_| EdnError::Deserialize(format!("unknown method {}", method)),
+                        )?,
+                    ),
+                    method => {
+                        return Err(EdnError::Deserialize(format!(
+                            "couldn't convert {} into a method",
+                            method
+                        )))
+                    }
+                };
+
+                Ok(RouteRule {
+                    method,
+                    path: edn_rs::from_edn(&rule[":path"])?,
+                    limits: RouteLimits::from_settings(rule)?,
+                })
+            })
+            .collect::<Result<Vec<RouteRule>, EdnError>>()?;
+
+        Ok(Self {
+            default: RouteLimits::from_settings(&edn[":default"])?,
+            routes,
+        })
+    }
+}
+
+/// Outcome of taking a token from a bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: usize,
+    /// Tokens left in the bucket.
+    pub remaining: usize,
+    /// Wait until the next token, when none is left.
+    pub retry_after: Duration,
+    /// Wait until the bucket is full again.
+    pub reset: Duration,
+}
+
+/// The decision clients should follow out of two buckets, with its scope:
+/// a denial, or the one with the fewest tokens left.
+fn most_restrictive(
+    a: (&'static str, Decision),
+    b: (&'static str, Decision),
+) -> (&'static str, Decision) {
+    match (a.1.allowed, b.1.allowed) {
+        (true, false) => b,
+        (false, false) if b.1.retry_after > a.1.retry_after => b,
+        (true, true) if b.1.remaining < a.1.remaining => b,
+        _ => a,
+    }
+}
+
+/// Where buckets are kept. The in-process `MemoryStore` limits every
+/// instance on its own, a store shared between instances makes the limits
+/// hold for all of them.
+pub trait Store: Send + Sync {
+    /// Takes a token from the bucket `key`, which starts full, unless it's
+    /// empty.
+    fn take(&self, key: &str, limit: &Limit) -> Decision;
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Position in `Buckets::recency`.
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    /// Keys by when their bucket was last used, oldest first.
+    recency: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+/// Buckets kept in the process, up to a number after which the least
+/// recently used one is dropped for every new one. A dropped bucket starts
+/// full again, which only lets a client through sooner if it wasn't heard
+/// of while as many others were.
+#[derive(Debug)]
+pub struct MemoryStore {
+    max_buckets: usize,
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(MAX_BUCKETS)
+    }
+}
+
+impl MemoryStore {
+    pub fn new(max_buckets: usize) -> Self {
+        Self {
+            max_buckets: max_buckets.max(1),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    fn take_at(&self, key: &str, limit: &Limit, now: Instant) -> Decision {
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets {
+            by_key,
+            recency,
+            uses,
+        } = &mut *buckets;
+
+        *uses += 1;
+        let bucket = match by_key.get_mut(key) {
+            Some(bucket) => {
+                recency.remove(&bucket.used);
+                bucket
+            }
+            None => {
+                while by_key.len() >= self.max_buckets {
+                    match recency.keys().next().copied() {
+                        Some(oldest) => {
+                            if let Some(oldest) = recency.remove(&oldest) {
+                                by_key.remove(&oldest);
+                            }
+                        }
+                        None => break,
+                    }
+                }
+
+                by_key.entry(key.to_string()).or_insert(Bucket {
+                    tokens: limit.burst as f64,
+                    updated: now,
+                    used: *uses,
+                })
+            }
+        };
+        bucket.used = *uses;
+        recency.insert(*uses, key.to_string());
+
+        let refilled =
+            now.saturating_duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(limit.burst as f64);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            limit: limit.burst,
+            remaining: bucket.tokens.floor() as usize,
+            retry_after: if allowed {
+                Duration::from_secs(0)
+            } else {
+                Duration::from_secs_f64((1.0 - bucket.tokens) / limit.per_second)
+            },
+            reset: Duration::from_secs_f64((limit.burst as f64 - bucket.tokens) / limit.per_second),
+        }
+    }
+}
+
+impl Store for MemoryStore {
+    fn take(&self, key: &str, limit: &Limit) -> Decision {
+        self.take_at(key, limit, Instant::now())
+    }
+}
+
+/// Limits requests per API client and per account with token buckets.
+pub struct RateLimiter {
+    default: RouteLimits,
+    routes: Vec<(Option<Method>, ResourceDef, String, RouteLimits)>,
+    gateway_secret: Option<String>,
+    store: Arc<dyn Store>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config, store: Arc<dyn Store>) -> Self {
+        Self {
+            default: config.rate_limits.default.clone(),
+            routes: config
+                .rate_limits
+                .routes
+                .iter()
+                .map(|rule| {
+                    let name = match &rule.method {
+                        Some(method) => format!("{} {}", method, rule.path),
+                        None => rule.path.clone(),
+                    };
+
+                    (
+                        rule.method.clone(),
+                        ResourceDef::new(rule.path.as_str()),
+                        name,
+                        rule.limits.clone(),
+                    )
+                })
+                .collect(),
+            gateway_secret: config.gateway_secret.clone(),
+            store,
+        }
+    }
+
+    /// Takes a token from each bucket the request falls in. `None` when no
+    /// limit applies to it. Probes and metrics scrapes are never limited.
+    pub fn check(&self, request: &ServiceRequest) -> Option<(&'static str, Decision)> {
+        let path = request.path();
+        if path == "/metrics" || path.starts_with("/health/") {
+            return None;
+        }
+
+        let client = client_key(request, self.gateway_secret.as_deref());
+        self.check_route(request.method(), path, client.as_deref())
+    }
+
+    /// The API client making `request`, to charge what it sends later, like
+    /// the commands of a WebSocket, to the buckets of its requests.
+    pub fn client(&self, request: &HttpRequest) -> Option<String> {
+        key_of(
+            request.headers(),
+            request.connection_info().remote(),
+            request.peer_addr(),
+            self.gateway_secret.as_deref(),
+        )
+    }
+
+    /// Takes a token from each bucket a request of `client` to `method` and
+    /// `path` falls in, as `check` does.
+    pub fn check_route(
+        &self,
+        method: &Method,
+        path: &str,
+        client: Option<&str>,
+    ) -> Option<(&'static str, Decision)> {
+        let (route, limits) = self
+            .routes
+            .iter()
+            .find(|(rule, resource, _, _)| {
+                rule.as_ref().is_none_or(|rule| rule == method) && resource.is_match(path)
+            })
+            .map_or(("default", &self.default), |(_, _, name, limits)| {
+                (name.as_str(), limits)
+            });
+
+        let client = limits.client.and_then(|limit| {
+            client.map(|client| {
+                let key = format!("{}|client|{}", route, client);
+                ("client", self.store.take(&key, &limit))
+            })
+        });
+        let account = limits.account.and_then(|limit| {
+            let mut path = Path::new(path);
+            if !ACCOUNT_ROUTES.match_path(&mut path) {
+                return None;
+            }
+
+            path.get("account_id").map(|account_id| {
+                let key = format!("{}|account|{}", route, account_id);
+                ("account", self.store.take(&key, &limit))
+            })
+        });
+
+        let decision = match (client, account) {
+            (Some(client), Some(account)) => Some(most_restrictive(client, account)),
+            (decision, None) | (None, decision) => decision,
+        };
+
+        if let Some((scope, Decision { allowed: false, .. })) = decision {
+            metrics::record_rate_limited(scope);
+        }
+
+        decision
+    }
+}
+
+/// The API client making a request: its principal, or else its address.
+/// Neither `X-Client-Id` nor forwarded addresses are trusted unless the
+/// request came through the gateway, as any client could make them up to
+/// get buckets of their own.
+fn client_key(request: &ServiceRequest, gateway_secret: Option<&str>) -> Option<String> {
+    key_of(
+        request.headers(),
+        request.connection_info().remote(),
+        request.peer_addr(),
+        gateway_secret,
+    )
+}
+
+fn key_of(
+    headers: &HeaderMap,
+    forwarded: Option<&str>,
+    peer: Option<SocketAddr>,
+    gateway_secret: Option<&str>,
+) -> Option<String> {
+    audit::principal(headers, gateway_secret)
+        .map(|principal| format!("principal|{}", principal))
+        .or_else(|| {
+            audit::client_ip(headers, forwarded, peer, gateway_secret)
+                .map(|ip| format!("ip|{}", ip))
+        })
+}
+
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Adds the `X-RateLimit-*` headers telling clients how much they have
+/// left.
+pub fn add_headers(headers: &mut HeaderMap, decision: &Decision) {
+    let values = [
+        ("x-ratelimit-limit", decision.limit as u64),
+        ("x-ratelimit-remaining", decision.remaining as u64),
+        ("x-ratelimit-reset", seconds(decision.reset)),
+    ];
+
+    for (name, value) in values.iter() {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(*value));
+    }
+}
+
+fn retry_after(decision: &Decision) -> u64 {
+    seconds(decision.retry_after).max(1)
+}
+
+/// Body of the answer to a request over its limit.
+pub fn exceeded(scope: &str, decision: &Decision) -> String {
+    format!(
+        "{{:error :rate-limit/exceeded, :scope :{}, :retry-after {}}}",
+        scope,
+        retry_after(decision)
+    )
+}
+
+/// `429` answered to a request over its limit.
+pub fn too_many_requests(scope: &str, decision: &Decision) -> HttpResponse {
+    let mut response = HttpResponse::TooManyRequests()
+        .content_type("application/edn")
+        .header("Retry-After", retry_after(decision).to_string())
+        .body(exceeded(scope, decision));
+
+    add_headers(response.headers_mut(), decision);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const LIMIT: Limit = Limit {
+        burst: 2,
+        per_second: 4.0,
+    };
+
+    #[test]
+    fn buckets_allow_the_burst_then_refill() {
+        let store = MemoryStore::default();
+        let start = Instant::now();
+
+        let first = store.take_at("key", &LIMIT, start);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert_eq!(first.reset, Duration::from_millis(250));
+
+        assert!(store.take_at("key", &LIMIT, start).allowed);
+
+        let denied = store.take_at("key", &LIMIT, start);
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Duration::from_millis(250));
+        assert_eq!(denied.reset, Duration::from_millis(500));
+
+        // A token comes back every quarter of a second.
+        let refilled = store.take_at("key", &LIMIT, start + Duration::from_millis(250));
+        assert!(refilled.allowed);
+        assert_eq!(refilled.remaining, 0);
+
+        // And no more than the burst is ever held.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(store.take_at("key", &LIMIT, later).remaining, 1);
+    }
+
+    #[test]
+    fn buckets_are_kept_apart_by_key() {
+        let store = MemoryStore::default();
+        let now = Instant::now();
+
+        store.take_at("a", &LIMIT, now);
+        store.take_at("a", &LIMIT, now);
+
+        assert!(!store.take_at("a", &LIMIT, now).allowed);
+        assert!(store.take_at("b", &LIMIT, now).allowed);
+    }
+
+    #[test]
+    fn least_recently_used_buckets_are_dropped_past_the_bound() {
+        let store = MemoryStore::new(2);
+        let now = Instant::now();
+
+        store.take_at("a", &LIMIT, now);
+        store.take_at("b", &LIMIT, now);
+        store.take_at("a", &LIMIT, now);
+        // Drops "b", the least recently used, though it isn't full.
+        store.take_at("c", &LIMIT, now);
+
+        let buckets = store.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), 2);
+        assert_eq!(buckets.recency.len(), 2);
+        assert!(buckets.by_key.contains_key("a"));
+        assert!(buckets.by_key.contains_key("c"));
+        drop(buckets);
+
+        // "a" was kept with its tokens taken.
+        assert!(!store.take_at("a", &LIMIT, now).allowed);
+    }
+
+    #[test]
+    fn clients_are_their_peer_address_unless_from_the_gateway() {
+        let peer = "10.0.0.7:4321".parse().unwrap();
+        let forged = TestRequest::default()
+            .peer_addr(peer)
+            .header("X-Client-Id", "someone-else")
+            .header("X-Forwarded-For", "192.0.2.1")
+            .header("X-Authenticated-Principal", "support@bank.example")
+            .to_srv_request();
+        assert_eq!(
+            client_key(&forged, Some("s3cret")),
+            Some(String::from("ip|10.0.0.7"))
+        );
+
+        let gateway = TestRequest::default()
+            .peer_addr(peer)
+            .header("X-Gateway-Secret", "s3cret")
+            .header("X-Authenticated-Principal", "support@bank.example")
+            .to_srv_request();
+        assert_eq!(
+            client_key(&gateway, Some("s3cret")),
+            Some(String::from("principal|support@bank.example"))
+        );
+
+        let anonymous = TestRequest::default()
+            .peer_addr(peer)
+            .header("X-Gateway-Secret", "s3cret")
+            .header("X-Forwarded-For", "192.0.2.1")
+            .to_srv_request();
+        assert_eq!(
+            client_key(&anonymous, Some("s3cret")),
+            Some(String::from("ip|192.0.2.1"))
+        );
+    }
+}
@@ -0,0 +1,465 @@
+use edn_derive::Serialize;
+use edn_rs::Edn;
+use std::collections::{BTreeMap, BTreeSet};
+use transistor::edn_rs;
+use transistor::types::http::Order;
+use transistor::types::CruxId;
+
+use crate::admin;
+use crate::crux::{CruxClient, TX_LOG_PAGE_SIZE};
+use crate::ledger::{self, SystemAccount};
+use crate::localstore::{self, Op, Transaction};
+use crate::{DbAccount, DbAccountOperation, DbError};
+
+/// An account whose stored balance disagrees with its operations.
+#[derive(Serialize, Clone, Debug)]
+pub struct Mismatch {
+    account_id: String,
+    stored_amount: i64,
+    computed_amount: i64,
+    history_amount: Option<i64>,
+    first_divergent_operation_id: Option<String>,
+    first_divergent_operation_time: Option<String>,
+}
+
+documented!(Mismatch {
+    account_id: String,
+    stored_amount: i64,
+    computed_amount: i64,
+    history_amount: Option<i64>,
+    first_divergent_operation_id: Option<String>,
+    first_divergent_operation_time: Option<String>,
+});
+
+impl Mismatch {
+    pub fn to_csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.account_id,
+            self.stored_amount,
+            self.computed_amount,
+            self.history_amount
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+            self.first_divergent_operation_id
+                .clone()
+                .unwrap_or_default(),
+            self.first_divergent_operation_time
+                .clone()
+                .unwrap_or_default(),
+        )
+    }
+}
+
+pub fn to_csv(mismatches: &[Mismatch]) -> String {
+    let mut csv = String::from(
+        "account_id,stored_amount,computed_amount,history_amount,first_divergent_operation_id,first_divergent_operation_time\n",
+    );
+
+    for mismatch in mismatches {
+        csv.push_str(&mismatch.to_csv_row());
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// Replays the operations of every customer account and reports the ones
+/// whose stored `:account/amount`, any version written before it, or the
+/// latest version of its history disagrees with them.
+pub async fn run(client: &CruxClient) -> Result<Vec<Mismatch>, DbError> {
+    let mut reconciliation = Reconciliation::default();
+    let mut after_tx_id = None;
+
+    loop {
+        let page = client.tx_log_tail(after_tx_id, TX_LOG_PAGE_SIZE).await?;
+        let transactions = localstore::read_log(&page)?;
+
+        for transaction in &transactions {
+            // A transaction whose matches failed wrote nothing.
+            let matches = transaction.ops.iter().any(|op| matches!(op, Op::Match(..)));
+            if matches && !client.tx_committed(transaction.tx_id).await? {
+                continue;
+            }
+
+            reconciliation.feed(transaction)?;
+        }
+
+        match transactions.last() {
+            Some(last) if transactions.len() == TX_LOG_PAGE_SIZE => after_tx_id = Some(last.tx_id),
+            _ => break,
+        }
+    }
+
+    reconciliation.read_histories(client).await?;
+
+    Ok(reconciliation.finish())
+}
+
+/// An account as replayed so far.
+#[derive(Default)]
+struct Replay {
+    computed_amount: i64,
+    stored_amount: Option<i64>,
+    /// Balance of the latest version in the account's history, which differs
+    /// from the last one written when versions were written in the past.
+    history_amount: Option<i64>,
+    /// Id and time of the first operation after which the stored balance
+    /// disagreed, or only the time of the transaction when it had none.
+    first_divergent: Option<(Option<CruxId>, Option<String>)>,
+}
+
+/// Walks the log in order, adding up the operations of every transaction
+/// and checking each account version against them. Versions are matched to
+/// operations by the transaction that wrote both, so versions written
+/// without operation, like new limits, don't shift anything.
+///
+/// Transactions written by migrations don't move money and are skipped.
+/// System accounts have no documents to check.
+#[derive(Default)]
+struct Reconciliation {
+    accounts: BTreeMap<CruxId, Replay>,
+    seen_operations: BTreeSet<CruxId>,
+}
+
+impl Reconciliation {
+    fn feed(&mut self, transaction: &Transaction) -> Result<(), DbError> {
+        let schema_id = Edn::Key(edn_rs::to_string(admin::schema_id()));
+        let puts = transaction
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::Put(doc, _) => Some(doc),
+                Op::Delete(..) | Op::Match(..) => None,
+            })
+            .collect::<Vec<&Edn>>();
+
+        if puts.iter().any(|doc| doc[":crux.db/id"] == schema_id) {
+            return Ok(());
+        }
+
+        let mut operations = BTreeMap::<CruxId, DbAccountOperation>::new();
+        for doc in &puts {
+            if doc[":account-operation/type"] == Edn::Nil {
+                continue;
+            }
+
+            let operation: DbAccountOperation = edn_rs::from_edn(doc)?;
+            if !self.seen_operations.insert(operation.crux__db___id.clone()) {
+                continue;
+            }
+
+            let mut touched = ledger::postings(&operation)
+                .into_iter()
+                .map(|p| p.posting___account_id)
+                .collect::<Vec<CruxId>>();
+            touched.push(operation.account_operation___source_account_id.clone());
+            touched.extend(operation.account_operation___target_account_id.clone());
+            touched.sort();
+            touched.dedup();
+
+            for account_id in touched {
+                if SystemAccount::from_id(&account_id).is_some() {
+                    continue;
+                }
+
+                self.accounts
+                    .entry(account_id.clone())
+                    .or_default()
+                    .computed_amount += ledger::balance_change(&operation, &account_id);
+                operations
+                    .entry(account_id)
+                    .or_insert_with(|| operation.clone());
+            }
+        }
+
+        for doc in &puts {
+            if doc[":account/amount"] == Edn::Nil {
+                continue;
+            }
+
+            let db_account: DbAccount = edn_rs::from_edn(doc)?;
+            if SystemAccount::from_id(&db_account.crux__db___id).is_some() {
+                continue;
+            }
+
+            let operation = operations.get(&db_account.crux__db___id);
+            let replay = self.accounts.entry(db_account.crux__db___id).or_default();
+            replay.stored_amount = Some(db_account.account___amount);
+
+            if replay.first_divergent.is_none()
+                && replay.computed_amount != db_account.account___amount
+            {
+                replay.first_divergent = Some(match operation {
+                    Some(operation) => (
+                        Some(operation.crux__db___id.clone()),
+                        operation.tx___tx_time.clone(),
+                    ),
+                    None => (None, Some(transaction.tx_time.to_string())),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the latest history version of every stored account.
+    async fn read_histories(&mut self, client: &CruxClient) -> Result<(), DbError> {
+        for (account_id, replay) in self.accounts.iter_mut() {
+            if replay.stored_amount.is_none() {
+                continue;
+            }
+
+            replay.history_amount = client
+                .entity_history(edn_rs::to_string(account_id.clone()), Order::Desc, true)
+                .await?
+                .history
+                .into_iter()
+                .next()
+                .and_then(|version| version.db__doc)
+                .and_then(|doc| doc[":account/amount"].to_int())
+                .map(|amount| amount as i64);
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Vec<Mismatch> {
+        self.accounts
+            .into_iter()
+            .filter_map(|(account_id, replay)| {
+                // Accounts only ever named by operations aren't stored.
+                let stored_amount = replay.stored_amount?;
+
+                let history_agrees = replay
+                    .history_amount
+                    .is_none_or(|amount| amount == replay.computed_amount);
+                if replay.first_divergent.is_none()
+                    && replay.computed_amount == stored_amount
+                    && history_agrees
+                {
+                    return None;
+                }
+
+                let (operation_id, operation_time) = replay.first_divergent.unwrap_or((None, None));
+
+                Some(Mismatch {
+                    account_id: without_colon(account_id),
+                    stored_amount,
+                    computed_amount: replay.computed_amount,
+                    history_amount: replay.history_amount,
+                    first_divergent_operation_id: operation_id.map(without_colon),
+                    first_divergent_operation_time: operation_time,
+                })
+            })
+            .collect()
+    }
+}
+
+fn without_colon(id: CruxId) -> String {
+    let mut id_without_colon = edn_rs::to_string(id);
+    id_without_colon.remove(0);
+    id_without_colon
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::JournalEntry;
+    use crate::limits::DbLimits;
+    use crate::OperationType;
+    use chrono::{DateTime, FixedOffset};
+    use std::str::FromStr;
+
+    fn time() -> DateTime<FixedOffset> {
+        "2020-06-01T10:00:00+00:00".parse().unwrap()
+    }
+
+    fn put(doc: String) -> Op {
+        Op::Put(Edn::from_str(&doc).unwrap(), time())
+    }
+
+    fn account(amount: i64, limits: Option<DbLimits>) -> Op {
+        put(edn_rs::to_string(DbAccount {
+            crux__db___id: CruxId::new("alice"),
+            account___amount: amount,
+            account___type: None,
+            account___limits: limits,
+            account___interest_rate: None,
+            account___external_id: None,
+        }))
+    }
+
+    fn operation(id: &str, operation_type: OperationType, entry: JournalEntry) -> Op {
+        put(edn_rs::to_string(DbAccountOperation {
+            crux__db___id: CruxId::new(id),
+            account_operation___type: operation_type,
+            account_operation___amount: 0,
+            account_operation___source_account_id: CruxId::new("alice"),
+            account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: Some(entry.into_postings()),
+            account_operation___request_id: None,
+            account_operation___audit: None,
+            tx___tx_time: Some(time().to_string()),
+        }))
+    }
+
+    fn deposit(id: &str, amount: usize) -> Op {
+        operation(
+            id,
+            OperationType::Deposit,
+            JournalEntry::new()
+                .debit(&SystemAccount::CashIn.id(), amount)
+                .credit(&CruxId::new("alice"), amount),
+        )
+    }
+
+    fn withdrawal(id: &str, amount: usize) -> Op {
+        operation(
+            id,
+            OperationType::Withdraw,
+            JournalEntry::new()
+                .debit(&CruxId::new("alice"), amount)
+                .credit(&SystemAccount::CashOut.id(), amount),
+        )
+    }
+
+    fn fee(id: &str, amount: usize) -> Op {
+        operation(
+            id,
+            OperationType::Fee,
+            JournalEntry::new()
+                .debit(&CruxId::new("alice"), amount)
+                .credit(&SystemAccount::FeeIncome.id(), amount),
+        )
+    }
+
+    fn reconcile(transactions: &[Transaction]) -> Result<Vec<Mismatch>, DbError> {
+        let mut reconciliation = Reconciliation::default();
+        for transaction in transactions {
+            reconciliation.feed(transaction)?;
+        }
+
+        Ok(reconciliation.finish())
+    }
+
+    fn log(transactions: Vec<Vec<Op>>) -> Vec<Transaction> {
+        transactions
+            .into_iter()
+            .enumerate()
+            .map(|(tx_id, ops)| Transaction {
+                tx_id,
+                tx_time: time(),
+                ops,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn versions_without_operation_dont_shift_anything() {
+        let limits = DbLimits {
+            limits___max_per_operation: Some(10),
+            limits___max_daily_total: None,
+            limits___max_daily_count: None,
+        };
+
+        let mismatches = reconcile(&log(vec![
+            vec![account(50, None), deposit("create", 50)],
+            vec![account(50, Some(limits.clone()))],
+            vec![
+                account(40, Some(limits)),
+                withdrawal("withdraw", 9),
+                fee("fee", 1),
+            ],
+            vec![deposit("deposit", 5), account(45, None)],
+        ]))
+        .unwrap();
+
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+    }
+
+    #[test]
+    fn reports_the_first_operation_the_stored_balance_disagrees_with() {
+        let mismatches = reconcile(&log(vec![
+            vec![account(50, None), deposit("create", 50)],
+            vec![account(50, None)],
+            vec![account(70, None), deposit("deposit", 10)],
+            vec![account(75, None), deposit("other", 5)],
+        ]))
+        .unwrap();
+
+        assert_eq!(mismatches.len(), 1);
+        let mismatch = &mismatches[0];
+        assert_eq!(mismatch.account_id, "alice");
+        assert_eq!(mismatch.stored_amount, 75);
+        assert_eq!(mismatch.computed_amount, 65);
+        assert_eq!(
+            mismatch.first_divergent_operation_id.as_deref(),
+            Some("deposit")
+        );
+    }
+
+    #[test]
+    fn reports_versions_written_without_operation_that_change_the_balance() {
+        let mismatches = reconcile(&log(vec![
+            vec![account(50, None), deposit("create", 50)],
+            vec![account(80, None)],
+        ]))
+        .unwrap();
+
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].first_divergent_operation_id, None);
+        assert_eq!(
+            mismatches[0].first_divergent_operation_time,
+            Some(time().to_string())
+        );
+    }
+
+    #[test]
+    fn skips_migrations_and_operations_written_again() {
+        let schema = put(format!(
+            "{{:crux.db/id {}, :schema/version 2}}",
+            edn_rs::to_string(admin::schema_id())
+        ));
+
+        let mismatches = reconcile(&log(vec![
+            vec![account(50, None), deposit("create", 50)],
+            vec![account(50, None), deposit("create", 50), schema],
+            vec![account(50, None), deposit("create", 50)],
+        ]))
+        .unwrap();
+
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+    }
+
+    #[test]
+    fn reports_histories_disagreeing_with_the_operations() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let earlier = "2020-05-01T10:00:00+00:00".parse().unwrap();
+            let past_version = match account(90, None) {
+                Op::Put(doc, _) => Op::Put(doc, earlier),
+                op => op,
+            };
+            for ops in [
+                vec![account(50, None), deposit("create", 50)],
+                // The last version written is valid before the first one, so
+                // the history still ends with the first.
+                vec![past_version],
+            ] {
+                client
+                    .tx_log(ops.into_iter().map(Op::into_action).collect())
+                    .await
+                    .unwrap();
+            }
+
+            let mismatches = run(&client).await.unwrap();
+
+            assert_eq!(mismatches.len(), 1);
+            assert_eq!(mismatches[0].stored_amount, 90);
+            assert_eq!(mismatches[0].computed_amount, 50);
+            assert_eq!(mismatches[0].history_amount, Some(50));
+        });
+    }
+}
@@ -0,0 +1,335 @@
+use actix::prelude::*;
+use actix_web::http::{Method, StatusCode};
+use actix_web_actors::ws;
+use edn_rs::Edn;
+use futures::channel::mpsc::{self, UnboundedSender};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use transistor::edn_rs;
+use transistor::types::CruxId;
+
+use crate::audit::Audit;
+use crate::events::{EventBroker, Notification, Subscribe};
+use crate::metrics::Timed;
+use crate::ratelimit::{self, RateLimiter};
+use crate::{
+    operation_outcome, request_amount, AccountDeposit, AccountTransfer, AccountWithdraw, DbAccount,
+    DbError, DbExecutor, GetAccount, ResponseAccount,
+};
+
+/// How often the client is pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long the client may go without answering before it is dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// A WebSocket connection. Clients send EDN commands, each with a
+/// `:correlation-id` echoed in its result:
+///
+/// ```edn
+/// {:type :subscribe :account-id "..." :correlation-id "1"}
+/// {:type :unsubscribe :account-id "..." :correlation-id "2"}
+/// {:type :deposit :account-id "..." :amount 100 :correlation-id "3"}
+/// {:type :withdraw :account-id "..." :amount 100 :correlation-id "4"}
+/// {:type :transfer :account-id "..." :target-account-id "..." :amount 100 :correlation-id "5"}
+/// ```
+///
+/// and get back `{:type :result :correlation-id ... :status ... :body ...}`,
+/// with the status and body the HTTP routes answer with, plus
+/// `{:type :event :event ...}` for operations on subscribed accounts.
+/// Operations take from the rate limit buckets of their HTTP routes, and
+/// get `429` with the body of their `429` once those are empty.
+pub struct AccountSocket {
+    db: Addr<DbExecutor>,
+    broker: Addr<EventBroker>,
+    sender: Option<UnboundedSender<Notification>>,
+    subscriptions: BTreeSet<CruxId>,
+    last_heard: Instant,
+    /// Audit of the upgrade request, stored on the operations submitted.
+    audit: Audit,
+    rate_limiter: Arc<RateLimiter>,
+    /// The API client of the upgrade request, whose buckets operations
+    /// take from.
+    client: Option<String>,
+}
+
+impl AccountSocket {
+    pub fn new(
+        db: Addr<DbExecutor>,
+        broker: Addr<EventBroker>,
+        audit: Audit,
+        rate_limiter: Arc<RateLimiter>,
+        client: Option<String>,
+    ) -> Self {
+        Self {
+            db,
+            broker,
+            sender: None,
+            subscriptions: BTreeSet::new(),
+            last_heard: Instant::now(),
+            audit,
+            rate_limiter,
+            client,
+        }
+    }
+
+    /// The `429` body of the HTTP route of an operation when the client or
+    /// account is over its limit there.
+    fn rate_limited(&self, operation: &str, account_id: &str) -> Option<String> {
+        match self.rate_limiter.check_route(
+            &Method::POST,
+            &format!("/accounts/{}/{}", account_id, operation),
+            self.client.as_deref(),
+        ) {
+            Some((scope, decision)) if !decision.allowed => {
+                Some(ratelimit::exceeded(scope, &decision))
+            }
+            _ => None,
+        }
+    }
+
+    fn command(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let edn = match Edn::from_str(text) {
+            Ok(edn) => edn,
+            Err(_) => return ctx.text(result(&Edn::Nil, StatusCode::BAD_REQUEST, None)),
+        };
+        let correlation_id = edn[":correlation-id"].clone();
+
+        let account_id = match edn_rs::from_edn::<String>(&edn[":account-id"]) {
+            Ok(account_id) => account_id,
+            Err(_) => return ctx.text(result(&correlation_id, StatusCode::BAD_REQUEST, None)),
+        };
+        let amount = match request_amount(&edn) {
+            Some(amount) => amount,
+            None => return ctx.text(result(&correlation_id, StatusCode::BAD_REQUEST, None)),
+        };
+        let audit = self.audit.clone();
+
+        if let Edn::Key(operation) = &edn[":type"] {
+            let operation = operation.trim_start_matches(':');
+            if ["deposit", "withdraw", "transfer"].contains(&operation) {
+                if let Some(body) = self.rate_limited(operation, &account_id) {
+                    return ctx.text(result(
+                        &correlation_id,
+                        StatusCode::TOO_MANY_REQUESTS,
+                        Some(body),
+                    ));
+                }
+            }
+        }
+
+        match &edn[":type"] {
+            Edn::Key(k) if k == ":subscribe" => self.subscribe(account_id, correlation_id, ctx),
+            Edn::Key(k) if k == ":unsubscribe" => {
+                self.subscriptions.remove(&CruxId::new(&account_id));
+                ctx.text(result(&correlation_id, StatusCode::OK, None));
+            }
+            Edn::Key(k) if k == ":deposit" => {
+                let response = self.db.send(Timed::new(AccountDeposit {
+                    account_id,
+                    amount,
+                    audit,
+                }));
+                self.respond(response, correlation_id, ctx);
+            }
+            Edn::Key(k) if k == ":withdraw" => {
+                let response = self.db.send(Timed::new(AccountWithdraw {
+                    account_id,
+                    amount,
+                    audit,
+                }));
+                self.respond(response, correlation_id, ctx);
+            }
+            Edn::Key(k) if k == ":transfer" => {
+                let target_account_id = match edn_rs::from_edn::<String>(&edn[":target-account-id"])
+                {
+                    Ok(target_account_id) => target_account_id,
+                    Err(_) => {
+                        return ctx.text(result(&correlation_id, StatusCode::BAD_REQUEST, None))
+                    }
+                };
+
+                let response = self.db.send(Timed::new(AccountTransfer {
+                    source_account_id: account_id,
+                    amount,
+                    target_account_id,
+                    audit,
+                    also_write: Vec::new(),
+                }));
+                self.respond(response, correlation_id, ctx);
+            }
+            _ => ctx.text(result(&correlation_id, StatusCode::BAD_REQUEST, None)),
+        }
+    }
+
+    /// Answers with the account as `GET /accounts/{id}` would, subscribing
+    /// before reading it so no operation falls in between.
+    fn subscribe(
+        &mut self,
+        account_id: String,
+        correlation_id: Edn,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let sender = match &self.sender {
+            Some(sender) => sender.clone(),
+            None => {
+                let (sender, receiver) = mpsc::unbounded();
+                ctx.add_stream(receiver);
+                self.sender = Some(sender.clone());
+                sender
+            }
+        };
+
+        let crux_id = CruxId::new(&account_id);
+        self.broker.do_send(Subscribe {
+            account_id: crux_id.clone(),
+            sender,
+        });
+
+        self.db
+            .send(Timed::new(GetAccount { account_id }))
+            .into_actor(self)
+            .map(move |response, socket, ctx| {
+                let (status, body) = match response {
+                    Ok(Ok(db_account)) => {
+                        socket.subscriptions.insert(crux_id);
+                        (
+                            StatusCode::OK,
+                            Some(edn_rs::to_string(ResponseAccount::from(db_account))),
+                        )
+                    }
+                    response => operation_outcome(response),
+                };
+
+                ctx.text(result(&correlation_id, status, body));
+            })
+            .spawn(ctx);
+    }
+
+    fn respond(
+        &self,
+        response: impl Future<Output = Result<Result<DbAccount, DbError>, MailboxError>> + 'static,
+        correlation_id: Edn,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        response
+            .into_actor(self)
+            .map(move |response, _, ctx| {
+                let (status, body) = operation_outcome(response);
+                ctx.text(result(&correlation_id, status, body));
+            })
+            .spawn(ctx);
+    }
+}
+
+fn result(correlation_id: &Edn, status: StatusCode, body: Option<String>) -> String {
+    format!(
+        "{{:type :result, :correlation-id {}, :status {}, :body {}}}",
+        correlation_id,
+        status.as_u16(),
+        body.unwrap_or_else(|| String::from("nil")),
+    )
+}
+
+impl Actor for AccountSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(PING_INTERVAL, |socket, ctx| {
+            if Instant::now().duration_since(socket.last_heard) > CLIENT_TIMEOUT {
+                ctx.stop();
+            } else {
+                ctx.ping(b"");
+            }
+        });
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for AccountSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        self.last_heard = Instant::now();
+
+        match msg {
+            Ok(ws::Message::Text(text)) => self.command(&text, ctx),
+            Ok(ws::Message::Ping(message)) => ctx.pong(&message),
+            Ok(ws::Message::Pong(_)) => (),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => ctx.text(result(&Edn::Nil, StatusCode::BAD_REQUEST, None)),
+            Err(_) => ctx.stop(),
+        }
+    }
+}
+
+impl StreamHandler<Notification> for AccountSocket {
+    fn handle(&mut self, notification: Notification, ctx: &mut Self::Context) {
+        if let Notification::Event(event) = notification {
+            if self.subscriptions.contains(&event.account_id) {
+                ctx.text(format!("{{:type :event, :event {}}}", event.to_edn()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Channel;
+    use crate::config::Config;
+    use crate::crux::CruxClient;
+    use crate::ratelimit::{Limit, MemoryStore, RateLimitConfig, RouteLimits, RouteRule};
+
+    #[test]
+    fn operations_take_from_the_buckets_of_their_routes() {
+        actix::System::new("test").block_on(async {
+            let config = Arc::new(Config {
+                rate_limits: RateLimitConfig {
+                    default: RouteLimits::default(),
+                    routes: vec![RouteRule {
+                        method: Some(Method::POST),
+                        path: String::from("/accounts/{account_id}/deposit"),
+                        limits: RouteLimits {
+                            client: None,
+                            account: Some(Limit {
+                                burst: 1,
+                                per_second: 0.001,
+                            }),
+                        },
+                    }],
+                },
+                ..Config::default()
+            });
+            let rate_limiter =
+                Arc::new(RateLimiter::new(&config, Arc::new(MemoryStore::default())));
+            let broker = EventBroker::default().start();
+            let db = DbExecutor(CruxClient::in_process(), config, broker.clone()).start();
+            let socket = AccountSocket::new(
+                db,
+                broker,
+                Audit::new(Channel::Api),
+                rate_limiter.clone(),
+                Some(String::from("ip|10.0.0.7")),
+            );
+
+            assert_eq!(socket.rate_limited("deposit", "alice"), None);
+
+            // The HTTP route has nothing left for alice either.
+            let (scope, decision) = rate_limiter
+                .check_route(&Method::POST, "/accounts/alice/deposit", None)
+                .unwrap();
+            assert_eq!(scope, "account");
+            assert!(!decision.allowed);
+
+            assert!(socket
+                .rate_limited("deposit", "alice")
+                .unwrap()
+                .starts_with("{:error :rate-limit/exceeded, :scope :account"));
+            assert_eq!(socket.rate_limited("deposit", "bob"), None);
+            assert_eq!(socket.rate_limited("withdraw", "alice"), None);
+        });
+    }
+}
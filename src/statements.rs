@@ -0,0 +1,821 @@
+use actix::prelude::*;
+use chrono::{DateTime, Duration, FixedOffset, SecondsFormat, Utc};
+use edn_rs::Edn;
+use futures::future;
+use std::iter;
+use transistor::edn_rs;
+use transistor::types::http::Order;
+use transistor::types::CruxId;
+use uuid::Uuid;
+
+use crate::crux::CruxClient;
+use crate::exports::{self, ExportConfig, Line};
+use crate::ledger::{self, OperationPages};
+use crate::{DbAccount, DbError, DbExecutor, OperationType};
+
+/// Characters of a line of an MT940 `:86:` field.
+const MT940_LINE_LENGTH: usize = 65;
+
+/// Lines of an MT940 `:86:` field.
+const MT940_LINES: usize = 6;
+
+/// How long after the time it stores an operation may have been written, and
+/// become valid.
+fn write_delay() -> Duration {
+    Duration::minutes(1)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// ISO 20022 `camt.053.001.02` XML.
+    Camt053,
+    /// SWIFT MT940, without the message blocks around its fields.
+    Mt940,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "camt053" | "camt.053" => Some(Format::Camt053),
+            "mt940" => Some(Format::Mt940),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Camt053 => "application/xml",
+            Format::Mt940 => "text/plain",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Camt053 => "xml",
+            Format::Mt940 => "sta",
+        }
+    }
+}
+
+/// The balance of an account at some valid time.
+#[derive(Clone, Copy, Debug)]
+pub struct Balance {
+    pub amount: i64,
+    pub time: DateTime<FixedOffset>,
+}
+
+/// An operation of the statement, booked when it was written and valued at
+/// its valid time.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub line: Line,
+    pub booked: DateTime<FixedOffset>,
+    pub value: DateTime<FixedOffset>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Statement {
+    /// Random, identifying the statement to the client's systems.
+    pub id: String,
+    pub account_id: String,
+    pub opening: Balance,
+    pub closing: Balance,
+    pub entries: Vec<Entry>,
+}
+
+/// Ids as their fields allow: UUIDs don't fit in the 35 characters most
+/// of them take with their hyphens.
+fn reference(id: &str, length: usize) -> String {
+    id.replace('-', "").chars().take(length).collect()
+}
+
+pub fn xml_text(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn xml_time(time: DateTime<FixedOffset>) -> String {
+    time.with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Characters MT940 fields can hold, others being replaced by spaces.
+fn swift_text(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "/-?:().,'+ ".contains(c) {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect()
+}
+
+/// Amounts written with a decimal comma, which MT940 requires even when
+/// there are no decimals.
+fn swift_amount(config: &ExportConfig, amount: i64) -> String {
+    let amount = config.decimal(amount.abs()).replace('.', ",");
+
+    if amount.contains(',') {
+        amount
+    } else {
+        format!("{},", amount)
+    }
+}
+
+fn camt_indicator(amount: i64) -> &'static str {
+    if amount < 0 {
+        "DBIT"
+    } else {
+        "CRDT"
+    }
+}
+
+fn swift_indicator(amount: i64) -> &'static str {
+    if amount < 0 {
+        "D"
+    } else {
+        "C"
+    }
+}
+
+impl Statement {
+    /// The file, in pieces rendered as they're taken, like exports.
+    pub fn render(
+        self,
+        format: Format,
+        config: &ExportConfig,
+    ) -> impl Iterator<Item = String> + Unpin {
+        let config = config.clone();
+        let (header, footer) = match format {
+            Format::Camt053 => (
+                self.camt_header(&config),
+                String::from("</Stmt>\n</BkToCstmrStmt>\n</Document>\n"),
+            ),
+            Format::Mt940 => (self.mt940_header(&config), self.mt940_footer(&config)),
+        };
+
+        iter::once(header)
+            .chain(self.entries.into_iter().map(move |entry| match format {
+                Format::Camt053 => camt_entry(&entry, &config),
+                Format::Mt940 => mt940_entry(&entry, &config),
+            }))
+            .chain(iter::once(footer))
+    }
+
+    fn camt_header(&self, config: &ExportConfig) -> String {
+        let now = xml_time(Utc::now().into());
+        let balance = |code, balance: &Balance| {
+            format!(
+                "<Bal>\n<Tp><CdOrPrtry><Cd>{}</Cd></CdOrPrtry></Tp>\n\
+                 <Amt Ccy=\"{}\">{}</Amt>\n<CdtDbtInd>{}</CdtDbtInd>\n\
+                 <Dt><DtTm>{}</DtTm></Dt>\n</Bal>\n",
+                code,
+                xml_text(&config.currency),
+                config.decimal(balance.amount.abs()),
+                camt_indicator(balance.amount),
+                xml_time(balance.time),
+            )
+        };
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <Document xmlns=\"urn:iso:std:iso:20022:tech:xsd:camt.053.001.02\">\n\
+             <BkToCstmrStmt>\n<GrpHdr>\n<MsgId>{id}</MsgId>\n<CreDtTm>{now}</CreDtTm>\n</GrpHdr>\n\
+             <Stmt>\n<Id>{id}</Id>\n<CreDtTm>{now}</CreDtTm>\n\
+             <FrToDt><FrDtTm>{from}</FrDtTm><ToDtTm>{to}</ToDtTm></FrToDt>\n\
+             <Acct><Id><Othr><Id>{account}</Id></Othr></Id><Ccy>{currency}</Ccy></Acct>\n\
+             {opening}{closing}",
+            id = self.id,
+            now = now,
+            from = xml_time(self.opening.time),
+            to = xml_time(self.closing.time),
+            account = xml_text(&reference(&self.account_id, 34)),
+            currency = xml_text(&config.currency),
+            opening = balance("OPBD", &self.opening),
+            closing = balance("CLBD", &self.closing),
+        )
+    }
+
+    fn mt940_header(&self, config: &ExportConfig) -> String {
+        format!(
+            ":20:{}\r\n:25:{}\r\n:28C:1\r\n:60F:{}{}{}{}\r\n",
+            reference(&self.id, 16),
+            swift_text(&reference(&self.account_id, 35)),
+            swift_indicator(self.opening.amount),
+            self.opening.time.with_timezone(&Utc).format("%y%m%d"),
+            swift_text(&config.currency),
+            swift_amount(config, self.opening.amount),
+        )
+    }
+
+    fn mt940_footer(&self, config: &ExportConfig) -> String {
+        // The closing time is the first moment after the statement.
+        let closing_date = (self.closing.time - Duration::seconds(1)).with_timezone(&Utc);
+
+        format!(
+            ":62F:{}{}{}{}\r\n",
+            swift_indicator(self.closing.amount),
+            closing_date.format("%y%m%d"),
+            swift_text(&config.currency),
+            swift_amount(config, self.closing.amount),
+        )
+    }
+}
+
+/// Proprietary transaction code of camt.053 entries, e.g. `TRANSFER`.
+fn camt_code(operation_type: &OperationType) -> &'static str {
+    match operation_type {
+        OperationType::Create => "CREATE",
+        OperationType::Deposit => "DEPOSIT",
+        OperationType::Withdraw => "WITHDRAW",
+        OperationType::Transfer => "TRANSFER",
+        OperationType::Fee => "FEE",
+        OperationType::Interest => "INTEREST",
+    }
+}
+
+fn camt_entry(entry: &Entry, config: &ExportConfig) -> String {
+    let line = &entry.line;
+    let reference = reference(&line.id, 35);
+    // The other party paid a credit and was paid a debit.
+    let related_parties = line
+        .counterparty
+        .as_ref()
+        .map(|counterparty| {
+            let (party, account) = if line.amount < 0 {
+                ("Cdtr", "CdtrAcct")
+            } else {
+                ("Dbtr", "DbtrAcct")
+            };
+
+            format!(
+                "<RltdPties><{party}><Nm>{name}</Nm></{party}>\
+                 <{account}><Id><Othr><Id>{id}</Id></Othr></Id></{account}></RltdPties>\n",
+                party = party,
+                account = account,
+                name = xml_text(&counterparty.chars().take(140).collect::<String>()),
+                id = xml_text(&self::reference(counterparty, 34)),
+            )
+        })
+        .unwrap_or_default();
+
+    format!(
+        "<Ntry>\n<NtryRef>{reference}</NtryRef>\n<Amt Ccy=\"{currency}\">{amount}</Amt>\n\
+         <CdtDbtInd>{indicator}</CdtDbtInd>\n<Sts>BOOK</Sts>\n\
+         <BookgDt><DtTm>{booked}</DtTm></BookgDt>\n<ValDt><DtTm>{value}</DtTm></ValDt>\n\
+         <AcctSvcrRef>{reference}</AcctSvcrRef>\n\
+         <BkTxCd><Prtry><Cd>{code}</Cd><Issr>{issuer}</Issr></Prtry></BkTxCd>\n\
+         <NtryDtls><TxDtls>\n<Refs><AcctSvcrRef>{reference}</AcctSvcrRef></Refs>\n\
+         {related_parties}<RmtInf><Ustrd>{memo}</Ustrd></RmtInf>\n</TxDtls></NtryDtls>\n</Ntry>\n",
+        reference = reference,
+        currency = xml_text(&config.currency),
+        amount = config.decimal(line.amount.abs()),
+        indicator = camt_indicator(line.amount),
+        booked = xml_time(entry.booked),
+        value = xml_time(entry.value),
+        code = camt_code(&line.operation_type),
+        issuer = xml_text(&config.bank_id.chars().take(35).collect::<String>()),
+        related_parties = related_parties,
+        memo = xml_text(&line.memo.chars().take(140).collect::<String>()),
+    )
+}
+
+/// SWIFT transaction type of MT940 entries.
+fn swift_code(operation_type: &OperationType) -> &'static str {
+    match operation_type {
+        OperationType::Transfer => "NTRF",
+        OperationType::Fee => "NCHG",
+        OperationType::Interest => "NINT",
+        OperationType::Create | OperationType::Deposit | OperationType::Withdraw => "NMSC",
+    }
+}
+
+fn mt940_entry(entry: &Entry, config: &ExportConfig) -> String {
+    let line = &entry.line;
+    let mut details = vec![line.memo.clone(), format!("Operation {}", line.id)];
+    if let Some(counterparty) = &line.counterparty {
+        details.push(format!("Counterparty {}", counterparty));
+    }
+    let details = details
+        .iter()
+        .take(MT940_LINES)
+        .map(|detail| swift_text(&detail.chars().take(MT940_LINE_LENGTH).collect::<String>()))
+        .collect::<Vec<String>>()
+        .join("\r\n");
+
+    format!(
+        ":61:{}{}{}{}{}NONREF//{}\r\n:86:{}\r\n",
+        entry.value.with_timezone(&Utc).format("%y%m%d"),
+        entry.booked.with_timezone(&Utc).format("%m%d"),
+        swift_indicator(line.amount),
+        swift_amount(config, line.amount),
+        swift_code(&line.operation_type),
+        reference(&line.id, 16),
+        details,
+    )
+}
+
+/// The operations of the account valued within the period, with the
+/// balances the account history recorded at its start and end.
+pub async fn statement(
+    client: &CruxClient,
+    account: &DbAccount,
+    from: Option<DateTime<FixedOffset>>,
+    to: Option<DateTime<FixedOffset>>,
+) -> Result<Statement, DbError> {
+    let account_id = &account.crux__db___id;
+    let now = Utc::now().into();
+
+    let history = client
+        .entity_history(edn_rs::to_string(account_id.clone()), Order::Asc, true)
+        .await?
+        .history;
+    // The balance before `time`, or the current one.
+    let balance_at = |time: Option<DateTime<FixedOffset>>| {
+        history
+            .iter()
+            .take_while(|version| match time {
+                Some(time) => version.db___valid_time < time,
+                None => true,
+            })
+            .last()
+            .and_then(|version| version.db__doc.as_ref()?[":account/amount"].to_int())
+            .unwrap_or(0) as i64
+    };
+
+    let opening_time = from
+        .or_else(|| history.first().map(|version| version.db___valid_time))
+        .unwrap_or(now);
+    let closing_time = to.unwrap_or(now);
+    let opening = Balance {
+        amount: balance_at(Some(opening_time)),
+        time: opening_time,
+    };
+    let closing = Balance {
+        amount: balance_at(to),
+        time: closing_time,
+    };
+
+    // Operations are paged in the order of the times they store, so only
+    // those that may be valued within the period are read, up to `to`.
+    let mut pages = OperationPages::new(client, account_id);
+    let mut touching = Vec::new();
+    'pages: loop {
+        let page = pages.next_page().await?;
+        if page.is_empty() {
+            break;
+        }
+
+        for operation in page {
+            let written = operation
+                .tx___tx_time
+                .as_ref()
+                .and_then(|time| time.parse::<DateTime<FixedOffset>>().ok());
+            match written {
+                Some(written) if written >= closing_time => break 'pages,
+                Some(written) if written < opening_time - write_delay() => {}
+                _ => touching.push(operation),
+            }
+        }
+    }
+
+    // The first version of an operation is the one written with it.
+    let histories = future::try_join_all(touching.iter().map(|operation| {
+        client.entity_history(
+            edn_rs::to_string(operation.crux__db___id.clone()),
+            Order::Asc,
+            false,
+        )
+    }))
+    .await?;
+
+    let mut operations = Vec::new();
+    for (operation, history) in touching.into_iter().zip(histories) {
+        if let Some(version) = history.history.into_iter().next() {
+            let value = version.db___valid_time;
+            if value >= opening_time && value < closing_time {
+                operations.push((value, version.tx___tx_time, operation));
+            }
+        }
+    }
+    operations.sort_by_key(|(value, booked, _)| (*value, *booked));
+
+    let mut balance = opening.amount;
+    let entries = operations
+        .into_iter()
+        .map(|(value, booked, operation)| {
+            balance += ledger::balance_change(&operation, account_id);
+
+            Entry {
+                line: Line::new(&operation, account_id, booked, balance),
+                booked,
+                value,
+            }
+        })
+        .collect();
+
+    Ok(Statement {
+        id: Uuid::new_v4().to_simple().to_string(),
+        account_id: exports::without_colon(account_id),
+        opening,
+        closing,
+        entries,
+    })
+}
+
+pub(crate) struct AccountStatement {
+    pub account_id: String,
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: Option<DateTime<FixedOffset>>,
+}
+
+impl Message for AccountStatement {
+    type Result = Result<Statement, DbError>;
+}
+
+impl Handler<AccountStatement> for DbExecutor {
+    type Result = ResponseFuture<Result<Statement, DbError>>;
+
+    fn handle(&mut self, msg: AccountStatement, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+
+        Box::pin(async move {
+            let crux_account = client
+                .entity(edn_rs::to_string(CruxId::new(&msg.account_id)))
+                .await?;
+
+            if crux_account == Edn::Nil {
+                return Err(DbError::NilEntity);
+            }
+
+            let account: DbAccount = edn_rs::from_edn(&crux_account)?;
+
+            statement(&client, &account, msg.from, msg.to).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    const SCHEMA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/schemas/camt.053.001.02.xsd");
+
+    fn time(value: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(value).unwrap()
+    }
+
+    fn entry(
+        id: &str,
+        operation_type: OperationType,
+        amount: i64,
+        balance: i64,
+        counterparty: Option<&str>,
+        memo: &str,
+        (booked, value): (&str, &str),
+    ) -> Entry {
+        Entry {
+            line: Line {
+                id: id.to_string(),
+                time: time(booked),
+                operation_type,
+                amount,
+                balance,
+                counterparty: counterparty.map(String::from),
+                memo: memo.to_string(),
+            },
+            booked: time(booked),
+            value: time(value),
+        }
+    }
+
+    /// A statement with a credit, debits past zero and text MT940 can't
+    /// hold.
+    fn statement() -> Statement {
+        Statement {
+            id: Uuid::new_v4().to_simple().to_string(),
+            account_id: String::from("7c9e6679-7425-40de-944b-e07fc1f90ae7"),
+            opening: Balance {
+                amount: 1000,
+                time: time("2020-06-01T00:00:00Z"),
+            },
+            closing: Balance {
+                amount: -250,
+                time: time("2020-07-01T00:00:00Z"),
+            },
+            entries: vec![
+                entry(
+                    "0b7c3e1a-0d4f-4cbb-9d0e-1f6a3b2c4d5e",
+                    OperationType::Deposit,
+                    500,
+                    1500,
+                    Some("cash-in"),
+                    "Deposit",
+                    ("2020-06-02T10:30:00+02:00", "2020-06-01T23:00:00Z"),
+                ),
+                entry(
+                    "5f0e4b2c-9a8d-4e7f-b6c5-d4e3f2a1b0c9",
+                    OperationType::Transfer,
+                    -1500,
+                    0,
+                    Some("Ärger & <Söhne> GmbH, a name much longer than thirty-five characters"),
+                    "Transfer to Ärger & <Söhne>",
+                    ("2020-06-15T12:00:00Z", "2020-06-15T12:00:00Z"),
+                ),
+                entry(
+                    "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d",
+                    OperationType::Fee,
+                    -250,
+                    -250,
+                    Some("fee-income"),
+                    "Fee for 5f0e4b2c-9a8d-4e7f-b6c5-d4e3f2a1b0c9",
+                    ("2020-06-30T23:59:59Z", "2020-06-30T23:59:59Z"),
+                ),
+            ],
+        }
+    }
+
+    fn config() -> ExportConfig {
+        ExportConfig {
+            currency: String::from("EUR"),
+            decimals: 2,
+            bank_id: String::from("SMAUGBANK"),
+        }
+    }
+
+    #[test]
+    fn camt053_statements_match_the_schema() {
+        let xml = statement()
+            .render(Format::Camt053, &config())
+            .collect::<String>();
+        let path = std::env::temp_dir().join(format!("smaug-test-{}.xml", Uuid::new_v4()));
+        std::fs::write(&path, &xml).unwrap();
+
+        let output = Command::new("xmllint")
+            .args(["--noout", "--schema", SCHEMA])
+            .arg(&path)
+            .output()
+            .expect("statements are validated with xmllint, from libxml2");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            output.status.success(),
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stderr),
+            xml
+        );
+    }
+
+    #[test]
+    fn camt053_entries_are_booked_and_valued_apart() {
+        let xml = statement()
+            .render(Format::Camt053, &config())
+            .collect::<String>();
+        let document = roxmltree::Document::parse(&xml).unwrap();
+        let texts = |name: &str| {
+            document
+                .descendants()
+                .filter(|node| node.has_tag_name(name))
+                .map(|node| {
+                    node.descendants()
+                        .find(|node| node.has_tag_name("DtTm"))
+                        .and_then(|node| node.text())
+                        .unwrap_or_default()
+                        .to_string()
+                })
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            texts("BookgDt"),
+            vec![
+                "2020-06-02T08:30:00Z",
+                "2020-06-15T12:00:00Z",
+                "2020-06-30T23:59:59Z"
+            ]
+        );
+        assert_eq!(
+            texts("ValDt"),
+            vec![
+                "2020-06-01T23:00:00Z",
+                "2020-06-15T12:00:00Z",
+                "2020-06-30T23:59:59Z"
+            ]
+        );
+        assert!(xml.contains("<Amt Ccy=\"EUR\">2.50</Amt>\n<CdtDbtInd>DBIT</CdtDbtInd>"));
+    }
+
+    /// Characters of the SWIFT `x` character set.
+    fn is_x(value: &str) -> bool {
+        value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/-?:().,'+ ".contains(c))
+    }
+
+    /// A `15d` amount: digits with a decimal comma, at most 15 characters.
+    fn is_amount(value: &str) -> bool {
+        let (units, decimals) = value.split_once(',').unwrap_or(("", "x"));
+
+        value.len() <= 15
+            && !units.is_empty()
+            && units.chars().all(|c| c.is_ascii_digit())
+            && decimals.chars().all(|c| c.is_ascii_digit())
+    }
+
+    /// A `1!a6!n3!a15d` balance.
+    fn is_balance(value: &str) -> bool {
+        let (mark, rest) = value.split_at(1);
+        let (date, rest) = rest.split_at(6);
+        let (currency, amount) = rest.split_at(3);
+
+        (mark == "C" || mark == "D")
+            && date.chars().all(|c| c.is_ascii_digit())
+            && currency.chars().all(|c| c.is_ascii_uppercase())
+            && is_amount(amount)
+    }
+
+    /// Fields of an MT940 file by tag, each with its lines.
+    fn mt940_fields(text: &str) -> Vec<(String, Vec<String>)> {
+        assert!(text.ends_with("\r\n"));
+        let mut fields: Vec<(String, Vec<String>)> = Vec::new();
+
+        for line in text.trim_end_matches("\r\n").split("\r\n") {
+            assert!(!line.contains('\n'), "{:?}", line);
+
+            if let Some(field) = line.strip_prefix(':') {
+                let (tag, value) = field.split_once(':').unwrap();
+                fields.push((tag.to_string(), vec![value.to_string()]));
+            } else {
+                // Lines of a field can't start like a tag or a block end.
+                assert!(!line.starts_with('-'), "{:?}", line);
+                fields.last_mut().unwrap().1.push(line.to_string());
+            }
+        }
+
+        fields
+    }
+
+    #[test]
+    fn mt940_fields_keep_their_formats() {
+        let statement = statement();
+        let text = statement
+            .clone()
+            .render(Format::Mt940, &config())
+            .collect::<String>();
+        let fields = mt940_fields(&text);
+
+        let tags = fields
+            .iter()
+            .map(|(tag, _)| tag.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            tags,
+            vec!["20", "25", "28C", "60F", "61", "86", "61", "86", "61", "86", "62F"]
+        );
+
+        for (tag, lines) in &fields {
+            assert!(lines.iter().all(|line| is_x(line)), "{}: {:?}", tag, lines);
+
+            let value = &lines[0];
+            match tag.as_str() {
+                "20" => assert!(lines.len() == 1 && value.len() <= 16, "{:?}", value),
+                "25" => assert!(lines.len() == 1 && value.len() <= 35, "{:?}", value),
+                "28C" => assert!(value.len() <= 5 && value.chars().all(|c| c.is_ascii_digit())),
+                "60F" | "62F" => assert!(is_balance(value), "{}: {:?}", tag, value),
+                "61" => {
+                    // 6!n[4!n]2a[1!a]15d1!a3!c16x[//16x]
+                    let (value_date, rest) = value.split_at(6);
+                    let (entry_date, rest) = rest.split_at(4);
+                    let (mark, rest) = rest.split_at(1);
+                    let amount_length = rest
+                        .find(|c: char| !c.is_ascii_digit() && c != ',')
+                        .unwrap();
+                    let (amount, rest) = rest.split_at(amount_length);
+                    let (code, rest) = rest.split_at(4);
+                    let (customer_reference, bank_reference) = rest.split_once("//").unwrap();
+
+                    assert!(value_date.chars().all(|c| c.is_ascii_digit()));
+                    assert!(entry_date.chars().all(|c| c.is_ascii_digit()));
+                    assert!(mark == "C" || mark == "D");
+                    assert!(is_amount(amount), "{:?}", amount);
+                    assert!(
+                        code.starts_with('N')
+                            && code[1..].chars().all(|c| c.is_ascii_alphanumeric())
+                    );
+                    assert!(!customer_reference.is_empty() && customer_reference.len() <= 16);
+                    assert!(!bank_reference.is_empty() && bank_reference.len() <= 16);
+                }
+                "86" => assert!(
+                    lines.len() <= MT940_LINES
+                        && lines.iter().all(|line| line.len() <= MT940_LINE_LENGTH),
+                    "{:?}",
+                    lines
+                ),
+                tag => panic!("unexpected field {}", tag),
+            }
+        }
+
+        assert_eq!(fields[3].1[0], "C200601EUR10,00");
+        assert_eq!(fields[10].1[0], "D200630EUR2,50");
+    }
+
+    #[test]
+    fn mt940_entries_are_valued_and_booked_apart() {
+        let text = statement()
+            .render(Format::Mt940, &config())
+            .collect::<String>();
+        let entries = mt940_fields(&text)
+            .into_iter()
+            .filter(|(tag, _)| tag == "61")
+            .map(|(_, lines)| lines[0].split('N').next().unwrap().to_string())
+            .collect::<Vec<String>>();
+
+        // Value date, booking month and day, mark and amount.
+        assert_eq!(
+            entries,
+            vec!["2006010602C5,00", "2006150615D15,00", "2006300630D2,50"]
+        );
+    }
+
+    #[test]
+    fn statements_take_the_operations_valued_within_the_period() {
+        use crate::ledger::{JournalEntry, SystemAccount};
+        use crate::DbAccountOperation;
+        use transistor::types::http::Action;
+
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let alice = CruxId::new("alice");
+            let account = |amount| DbAccount {
+                crux__db___id: alice.clone(),
+                account___amount: amount,
+                account___type: None,
+                account___limits: None,
+                account___interest_rate: None,
+                account___external_id: None,
+            };
+
+            for (index, hour) in [9, 11, 13].iter().enumerate() {
+                let valid_time = time(&format!("2020-06-01T{:02}:00:00+00:00", hour));
+                let deposit = DbAccountOperation {
+                    crux__db___id: CruxId::new(&format!("deposit-{}", index)),
+                    account_operation___type: OperationType::Deposit,
+                    account_operation___amount: 10,
+                    account_operation___source_account_id: alice.clone(),
+                    account_operation___target_account_id: None,
+                    account_operation___triggered_by: None,
+                    account_operation___postings: Some(
+                        JournalEntry::new()
+                            .debit(&SystemAccount::CashIn.id(), 10)
+                            .credit(&alice, 10)
+                            .into_postings(),
+                    ),
+                    account_operation___request_id: None,
+                    account_operation___audit: None,
+                    // Stored a moment before the transaction wrote it.
+                    tx___tx_time: Some(
+                        (valid_time - Duration::seconds(1))
+                            .with_timezone(&Utc)
+                            .to_string(),
+                    ),
+                };
+                client
+                    .tx_log(vec![
+                        Action::Put(edn_rs::to_string(deposit), Some(valid_time)),
+                        Action::Put(
+                            edn_rs::to_string(account(10 * (index as i64 + 1))),
+                            Some(valid_time),
+                        ),
+                    ])
+                    .await
+                    .unwrap();
+            }
+
+            let statement = super::statement(
+                &client,
+                &account(30),
+                Some(time("2020-06-01T11:00:00+00:00")),
+                Some(time("2020-06-01T13:00:00+00:00")),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(statement.opening.amount, 10);
+            assert_eq!(statement.closing.amount, 20);
+            assert_eq!(statement.entries.len(), 1);
+            assert_eq!(statement.entries[0].line.id, "deposit-1");
+            assert_eq!(statement.entries[0].line.balance, 20);
+            assert_eq!(
+                statement.entries[0].value,
+                time("2020-06-01T11:00:00+00:00")
+            );
+        });
+    }
+}
@@ -0,0 +1,67 @@
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::http::HeaderMap;
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest};
+use futures::future::{self, Ready};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest request id accepted from clients, longer ones are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Logs JSON lines to stderr, at the level set by `SMAUG_LOG` (`info` by
+/// default), in the `RUST_LOG` format.
+pub fn init() {
+    let filter = EnvFilter::try_from_env("SMAUG_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+
+    tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Id of the HTTP request being handled, taken from its `X-Request-Id`
+/// header or generated. It is echoed in the response and stored on the
+/// operations the request writes.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Reuses the client's id when it looks sane.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let request_id = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|header| header.to_str().ok())
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID_LENGTH
+                    && id.chars().all(|c| c.is_ascii_graphic())
+            });
+
+        match request_id {
+            Some(request_id) => RequestId(request_id.to_string()),
+            None => RequestId(Uuid::new_v4().to_string()),
+        }
+    }
+
+    /// Stores the id on the request for handlers to extract.
+    pub fn attach(&self, request: &ServiceRequest) {
+        request.extensions_mut().insert(self.clone());
+    }
+}
+
+impl FromRequest for RequestId {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let request_id = request.extensions().get::<RequestId>().cloned();
+
+        future::ok(request_id.unwrap_or_else(|| RequestId::from_headers(request.headers())))
+    }
+}
@@ -0,0 +1,681 @@
+use actix::prelude::*;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use edn_derive::{Deserialize, Serialize};
+use edn_rs::{Edn, EdnError};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::sync::Arc;
+use transistor::edn_rs;
+use transistor::types::http::Action;
+use transistor::types::{query::Query, CruxId};
+use uuid::Uuid;
+
+use crate::config::{self, Config};
+use crate::crux::CruxClient;
+use crate::{DbAccountOperation, DbError, DbExecutor, OperationType, ResponseAccountOperation};
+
+/// How often the dispatcher looks for deliveries that are due.
+const DISPATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Delivery settings, read from the configuration file:
+///
+/// ```edn
+/// {:max-attempts 8 :backoff-seconds 30 :max-backoff-seconds 3600 :timeout-seconds 10}
+/// ```
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// Attempts made before a delivery is given up as failed.
+    pub max_attempts: usize,
+    /// Wait after the first failed attempt, doubled after every other one.
+    pub backoff_seconds: usize,
+    pub max_backoff_seconds: usize,
+    pub timeout_seconds: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            backoff_seconds: 30,
+            max_backoff_seconds: 60 * 60,
+            timeout_seconds: 10,
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn from_settings(edn: &Edn) -> Result<Self, EdnError> {
+        let default = Self::default();
+
+        Ok(Self {
+            max_attempts: config::uint(&edn[":max-attempts"])?.unwrap_or(default.max_attempts),
+            backoff_seconds: config::uint(&edn[":backoff-seconds"])?
+                .unwrap_or(default.backoff_seconds),
+            max_backoff_seconds: config::uint(&edn[":max-backoff-seconds"])?
+                .unwrap_or(default.max_backoff_seconds),
+            timeout_seconds: config::uint(&edn[":timeout-seconds"])?
+                .unwrap_or(default.timeout_seconds),
+        })
+    }
+
+    /// Wait before the attempt following the `attempts`th failed one.
+    fn backoff(&self, attempts: usize) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(32) as u32;
+        let seconds = self
+            .backoff_seconds
+            .saturating_mul(2usize.saturating_pow(exponent))
+            .min(self.max_backoff_seconds);
+
+        Duration::seconds(seconds as i64)
+    }
+}
+
+/// A subscription to account operations. Without `events` every operation
+/// type is sent, and without `account-id` operations of every account.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbWebhook {
+    pub crux__db___id: CruxId,                        // :crux.db/id
+    pub webhook___url: String,                        // :webhook/url
+    pub webhook___events: Option<Vec<OperationType>>, // :webhook/events
+    pub webhook___account_id: Option<CruxId>,         // :webhook/account-id
+    pub webhook___secret: String,                     // :webhook/secret
+}
+
+impl DbWebhook {
+    fn matches(&self, operation: &DbAccountOperation) -> bool {
+        let event_matches = match &self.webhook___events {
+            Some(events) => events.contains(&operation.account_operation___type),
+            None => true,
+        };
+
+        let account_matches = match &self.webhook___account_id {
+            Some(account_id) => {
+                &operation.account_operation___source_account_id == account_id
+                    || operation.account_operation___target_account_id.as_ref() == Some(account_id)
+            }
+            None => true,
+        };
+
+        event_matches && account_matches
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbDeliveryAttempt {
+    pub attempt___time: String,               // :attempt/time
+    pub attempt___status_code: Option<usize>, // :attempt/status-code
+    pub attempt___error: Option<String>,      // :attempt/error
+}
+
+/// One operation to be sent to one webhook. Pending deliveries are the queue
+/// the dispatcher works through.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DbDelivery {
+    pub crux__db___id: CruxId,                       // :crux.db/id
+    pub delivery___webhook_id: CruxId,               // :delivery/webhook-id
+    pub delivery___operation_id: CruxId,             // :delivery/operation-id
+    pub delivery___status: DeliveryStatus,           // :delivery/status
+    pub delivery___created_at: String,               // :delivery/created-at
+    pub delivery___next_attempt_at: Option<String>,  // :delivery/next-attempt-at
+    pub delivery___attempts: Vec<DbDeliveryAttempt>, // :delivery/attempts
+}
+
+/// Deliveries of `operations` to every webhook subscribed to them, to be
+/// written in the same transaction as the operations themselves.
+pub async fn enqueue(
+    client: &CruxClient,
+    operations: &[DbAccountOperation],
+) -> Result<Vec<Action>, DbError> {
+    let webhooks = all_webhooks(client).await?;
+    let now = Utc::now().to_string();
+
+    let mut actions = Vec::new();
+
+    for operation in operations {
+        for webhook in webhooks.iter().filter(|webhook| webhook.matches(operation)) {
+            let delivery = DbDelivery {
+                crux__db___id: CruxId::new(&Uuid::new_v4().to_string()),
+                delivery___webhook_id: webhook.crux__db___id.clone(),
+                delivery___operation_id: operation.crux__db___id.clone(),
+                delivery___status: DeliveryStatus::Pending,
+                delivery___created_at: now.clone(),
+                delivery___next_attempt_at: Some(now.clone()),
+                delivery___attempts: Vec::new(),
+            };
+
+            actions.push(Action::Put(edn_rs::to_string(delivery), None));
+        }
+    }
+
+    Ok(actions)
+}
+
+async fn all_webhooks(client: &CruxClient) -> Result<Vec<DbWebhook>, DbError> {
+    let query = Query::find(vec!["?webhook"])?
+        .where_clause(vec!["?webhook :webhook/url ?url"])?
+        .build()?;
+
+    let mut webhooks = Vec::new();
+
+    for row in client.query(query).await? {
+        let crux_webhook = client
+            .entity(edn_rs::to_string(CruxId::new(&row[0])))
+            .await?;
+        webhooks.push(edn_rs::from_edn(&crux_webhook)?);
+    }
+
+    Ok(webhooks)
+}
+
+/// Every delivery made for `webhook_id`, oldest first.
+pub async fn deliveries(
+    client: &CruxClient,
+    webhook_id: &CruxId,
+) -> Result<Vec<DbDelivery>, DbError> {
+    let mut webhook_id_without_colon = edn_rs::to_string(webhook_id.clone());
+    webhook_id_without_colon.remove(0);
+
+    let query = Query::find(vec!["?delivery"])?
+        .where_clause(vec!["?delivery :delivery/webhook-id ?webhook-id"])?
+        .args(vec![&format!("?webhook-id :{}", webhook_id_without_colon)])?
+        .build()?;
+
+    let mut deliveries = Vec::new();
+
+    for row in client.query(query).await? {
+        let crux_delivery = client
+            .entity(edn_rs::to_string(CruxId::new(&row[0])))
+            .await?;
+        deliveries.push(edn_rs::from_edn::<DbDelivery>(&crux_delivery)?);
+    }
+
+    deliveries.sort_by_key(|delivery| {
+        delivery
+            .delivery___created_at
+            .parse::<DateTime<FixedOffset>>()
+            .ok()
+    });
+
+    Ok(deliveries)
+}
+
+/// Makes one attempt for every pending delivery that is due, returning how
+/// many were attempted. A delivery that can't be read or written is logged
+/// and left for the next round, without holding up the others.
+pub async fn deliver_due(
+    client: &CruxClient,
+    http: &reqwest::Client,
+    config: &WebhookConfig,
+) -> Result<usize, DbError> {
+    let query = Query::find(vec!["?delivery"])?
+        .where_clause(vec!["?delivery :delivery/status :delivery-status/pending"])?
+        .build()?;
+
+    let now = Utc::now();
+    let mut attempted = 0;
+
+    for row in client.query(query).await? {
+        let delivery_id = CruxId::new(&row[0]);
+
+        match deliver(client, http, config, &delivery_id, now).await {
+            Ok(true) => attempted += 1,
+            Ok(false) => (),
+            Err(error) => {
+                tracing::error!(delivery = %row[0], error = ?error, "webhook delivery failed")
+            }
+        }
+    }
+
+    Ok(attempted)
+}
+
+/// Attempts the delivery if it's due, telling whether it was.
+async fn deliver(
+    client: &CruxClient,
+    http: &reqwest::Client,
+    config: &WebhookConfig,
+    delivery_id: &CruxId,
+    now: DateTime<Utc>,
+) -> Result<bool, DbError> {
+    let crux_delivery = client
+        .entity(edn_rs::to_string(delivery_id.clone()))
+        .await?;
+    let mut delivery: DbDelivery = edn_rs::from_edn(&crux_delivery)?;
+
+    let due = match &delivery.delivery___next_attempt_at {
+        Some(time) => match time.parse::<DateTime<FixedOffset>>() {
+            Ok(time) => time <= now,
+            Err(_) => true,
+        },
+        None => true,
+    };
+    if !due {
+        return Ok(false);
+    }
+
+    attempt(client, http, config, &mut delivery).await?;
+    client
+        .tx_log(vec![Action::Put(edn_rs::to_string(delivery), None)])
+        .await?;
+
+    Ok(true)
+}
+
+async fn attempt(
+    client: &CruxClient,
+    http: &reqwest::Client,
+    config: &WebhookConfig,
+    delivery: &mut DbDelivery,
+) -> Result<(), DbError> {
+    let now = Utc::now();
+
+    let crux_webhook = client
+        .entity(edn_rs::to_string(delivery.delivery___webhook_id.clone()))
+        .await?;
+    let crux_operation = client
+        .entity(edn_rs::to_string(delivery.delivery___operation_id.clone()))
+        .await?;
+
+    let result = if crux_webhook == Edn::Nil || crux_operation == Edn::Nil {
+        Err(String::from("webhook or operation no longer exists"))
+    } else {
+        match (
+            edn_rs::from_edn::<DbWebhook>(&crux_webhook),
+            edn_rs::from_edn::<DbAccountOperation>(&crux_operation),
+        ) {
+            (Ok(webhook), Ok(operation)) => {
+                send(
+                    http,
+                    config,
+                    &webhook,
+                    &delivery.crux__db___id,
+                    operation,
+                    now,
+                )
+                .await
+            }
+            (Err(error), _) | (_, Err(error)) => Err(format!(
+                "couldn't read the webhook or operation: {:?}",
+                error
+            )),
+        }
+    };
+    let (status_code, error) = match result {
+        Ok(status_code) => (Some(status_code), None),
+        Err(error) => (None, Some(error)),
+    };
+    let delivered = matches!(status_code, Some(code) if (200..300).contains(&code));
+
+    delivery.delivery___attempts.push(DbDeliveryAttempt {
+        attempt___time: now.to_string(),
+        attempt___status_code: status_code,
+        attempt___error: error,
+    });
+
+    let attempts = delivery.delivery___attempts.len();
+    if delivered {
+        delivery.delivery___status = DeliveryStatus::Delivered;
+        delivery.delivery___next_attempt_at = None;
+    } else if attempts >= config.max_attempts {
+        delivery.delivery___status = DeliveryStatus::Failed;
+        delivery.delivery___next_attempt_at = None;
+    } else {
+        delivery.delivery___next_attempt_at = Some((now + config.backoff(attempts)).to_string());
+    }
+
+    Ok(())
+}
+
+/// Posts the operation to the webhook, returning the response status.
+async fn send(
+    http: &reqwest::Client,
+    config: &WebhookConfig,
+    webhook: &DbWebhook,
+    delivery_id: &CruxId,
+    operation: DbAccountOperation,
+    now: DateTime<Utc>,
+) -> Result<usize, String> {
+    let mut delivery_id_without_colon = edn_rs::to_string(delivery_id.clone());
+    delivery_id_without_colon.remove(0);
+
+    let event = edn_rs::to_string(operation.account_operation___type.clone());
+    let body = format!(
+        "{{:delivery-id {}, :event {}, :operation {}}}",
+        edn_rs::to_string(delivery_id_without_colon.clone()),
+        event,
+        edn_rs::to_string(ResponseAccountOperation::from(operation)),
+    );
+    let timestamp = now.timestamp().to_string();
+
+    let response = http
+        .post(&webhook.webhook___url)
+        .timeout(std::time::Duration::from_secs(
+            config.timeout_seconds as u64,
+        ))
+        .header("Content-Type", "application/edn")
+        .header("X-Smaug-Delivery", delivery_id_without_colon)
+        .header("X-Smaug-Event", event.trim_start_matches(':'))
+        .header("X-Smaug-Timestamp", timestamp.as_str())
+        .header(
+            "X-Smaug-Signature",
+            format!(
+                "sha256={}",
+                sign(&webhook.webhook___secret, &timestamp, &body)
+            ),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(response.status().as_u16() as usize)
+}
+
+/// Hex HMAC-SHA256 of `"{timestamp}.{body}"`, which receivers recompute with
+/// the shared secret to authenticate the payload.
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub(crate) struct CreateWebhook {
+    pub webhook: DbWebhook,
+}
+
+impl Message for CreateWebhook {
+    type Result = Result<DbWebhook, DbError>;
+}
+
+impl Handler<CreateWebhook> for DbExecutor {
+    type Result = ResponseFuture<Result<DbWebhook, DbError>>;
+
+    fn handle(&mut self, msg: CreateWebhook, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+
+        Box::pin(async move {
+            client
+                .tx_log(vec![Action::Put(
+                    edn_rs::to_string(msg.webhook.clone()),
+                    None,
+                )])
+                .await?;
+
+            Ok(msg.webhook)
+        })
+    }
+}
+
+pub(crate) struct WebhookDeliveries {
+    pub webhook_id: String,
+}
+
+impl Message for WebhookDeliveries {
+    type Result = Result<Vec<DbDelivery>, DbError>;
+}
+
+impl Handler<WebhookDeliveries> for DbExecutor {
+    type Result = ResponseFuture<Result<Vec<DbDelivery>, DbError>>;
+
+    fn handle(&mut self, msg: WebhookDeliveries, _: &mut Self::Context) -> Self::Result {
+        let client = self.0.clone();
+
+        Box::pin(async move {
+            let webhook_id = CruxId::new(&msg.webhook_id);
+
+            if client.entity(edn_rs::to_string(webhook_id.clone())).await? == Edn::Nil {
+                return Err(DbError::NilEntity);
+            }
+
+            deliveries(&client, &webhook_id).await
+        })
+    }
+}
+
+/// Works through the delivery queue every second, waiting for each round to
+/// finish before the next. Slow receivers only hold up later deliveries.
+pub struct WebhookDispatcher {
+    pub client: CruxClient,
+    pub config: Arc<Config>,
+    pub http: reqwest::Client,
+}
+
+impl Actor for WebhookDispatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(DISPATCH_INTERVAL, |dispatcher, ctx| {
+            dispatcher.dispatch(ctx)
+        });
+    }
+}
+
+impl WebhookDispatcher {
+    fn dispatch(&self, ctx: &mut Context<Self>) {
+        let client = self.client.clone();
+        let config = self.config.clone();
+        let http = self.http.clone();
+
+        async move { deliver_due(&client, &http, &config.webhooks).await }
+            .into_actor(self)
+            .map(|result, _, _| {
+                if let Err(error) = result {
+                    tracing::error!(error = ?error, "webhook delivery failed");
+                }
+            })
+            .wait(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    /// A request received by `receiver`, with lowercase header names.
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> &str {
+            self.headers
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap_or_default()
+        }
+    }
+
+    /// Answers every request on a local port with `status`, keeping them.
+    fn receiver(status: u16) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let requests = received.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(": ") {
+                        headers.push((name.to_lowercase(), value.to_string()));
+                    }
+                }
+
+                let length = headers
+                    .iter()
+                    .find(|(name, _)| name == "content-length")
+                    .map_or(0, |(_, value)| value.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                requests.lock().unwrap().push(Received {
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+        });
+
+        (url, received)
+    }
+
+    /// A webhook at `url` and a deposit, with its delivery pending.
+    async fn pending_delivery(client: &CruxClient, url: &str) {
+        let webhook = DbWebhook {
+            crux__db___id: CruxId::new("hook"),
+            webhook___url: url.to_string(),
+            webhook___events: None,
+            webhook___account_id: None,
+            webhook___secret: String::from("secret"),
+        };
+        client
+            .tx_log(vec![Action::Put(edn_rs::to_string(webhook), None)])
+            .await
+            .unwrap();
+
+        let operation = DbAccountOperation {
+            crux__db___id: CruxId::new("deposit"),
+            account_operation___type: OperationType::Deposit,
+            account_operation___amount: 10,
+            account_operation___source_account_id: CruxId::new("alice"),
+            account_operation___target_account_id: None,
+            account_operation___triggered_by: None,
+            account_operation___postings: None,
+            account_operation___request_id: None,
+            account_operation___audit: None,
+            tx___tx_time: Some(Utc::now().to_string()),
+        };
+        let mut actions = enqueue(client, std::slice::from_ref(&operation))
+            .await
+            .unwrap();
+        actions.push(Action::Put(edn_rs::to_string(operation), None));
+        client.tx_log(actions).await.unwrap();
+    }
+
+    #[test]
+    fn delivers_signed_operations() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let (url, received) = receiver(200);
+            pending_delivery(&client, &url).await;
+
+            let config = WebhookConfig::default();
+            let attempted = deliver_due(&client, &reqwest::Client::new(), &config).await;
+            assert_eq!(attempted.unwrap(), 1);
+
+            let received = received.lock().unwrap().remove(0);
+            let request = &received;
+            assert_eq!(request.header("x-smaug-event"), "operation-type/deposit");
+            assert!(request.body.contains(":operation"));
+            assert_eq!(
+                request.header("x-smaug-signature"),
+                format!(
+                    "sha256={}",
+                    sign("secret", request.header("x-smaug-timestamp"), &request.body)
+                )
+            );
+
+            let deliveries = deliveries(&client, &CruxId::new("hook")).await.unwrap();
+            assert_eq!(deliveries[0].delivery___status, DeliveryStatus::Delivered);
+        });
+    }
+
+    #[test]
+    fn failed_attempts_are_retried_later() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let (url, _) = receiver(500);
+            pending_delivery(&client, &url).await;
+
+            let config = WebhookConfig::default();
+            let attempted = deliver_due(&client, &reqwest::Client::new(), &config).await;
+            assert_eq!(attempted.unwrap(), 1);
+
+            let delivery = &deliveries(&client, &CruxId::new("hook")).await.unwrap()[0];
+            assert_eq!(delivery.delivery___status, DeliveryStatus::Pending);
+            assert_eq!(
+                delivery.delivery___attempts[0].attempt___status_code,
+                Some(500)
+            );
+            assert!(delivery.delivery___next_attempt_at.is_some());
+
+            // Not due again until the backoff has passed.
+            let attempted = deliver_due(&client, &reqwest::Client::new(), &config).await;
+            assert_eq!(attempted.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn unreadable_deliveries_dont_hold_up_the_others() {
+        actix::System::new("test").block_on(async {
+            let client = CruxClient::in_process();
+            let (url, received) = receiver(200);
+            client
+                .tx_log(vec![Action::Put(
+                    String::from(
+                        "{:crux.db/id :a-broken-delivery \
+                         :delivery/status :delivery-status/pending}",
+                    ),
+                    None,
+                )])
+                .await
+                .unwrap();
+            pending_delivery(&client, &url).await;
+
+            let config = WebhookConfig::default();
+            let attempted = deliver_due(&client, &reqwest::Client::new(), &config).await;
+            assert_eq!(attempted.unwrap(), 1);
+            assert_eq!(received.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = WebhookConfig {
+            backoff_seconds: 30,
+            max_backoff_seconds: 100,
+            ..WebhookConfig::default()
+        };
+
+        assert_eq!(config.backoff(1), Duration::seconds(30));
+        assert_eq!(config.backoff(2), Duration::seconds(60));
+        assert_eq!(config.backoff(3), Duration::seconds(100));
+        assert_eq!(config.backoff(100), Duration::seconds(100));
+    }
+}